@@ -0,0 +1,6 @@
+/* The first pages of flash, see shared/src/flash.rs for the rest */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 8K
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+}
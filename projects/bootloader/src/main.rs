@@ -0,0 +1,225 @@
+//! Installs firmware updates and starts the app.
+//!
+//! Runs from the first flash pages on every reset. It carries on with any
+//! update the app left in the staging slot, rolls back images that never
+//! checked in and then starts the app slot, see `shared::boot` for how.
+//!
+//! The app only starts if its header and CRC check out, see `shared::image`.
+//! Pending updates with a bad CRC or an older version than the app they
+//! replace are dropped, see `shared::boot::decide`. Images loaded with the
+//! debugger have no header, so they're only started while it's attached.
+//!
+//! Without an app to start, the bootloader waits for one on USART1 (TX on
+//! PA9, RX on PA10, 115200 baud):
+//!
+//! ```text
+//! bootloader                       host
+//! "recovery\n"           ---->
+//!                        <----     image header (40 bytes)
+//! '>'                    ---->                              the app slot is erased
+//!                        <----     up to 64 bytes of image
+//! '.'                    ---->                              ... until the whole image is sent
+//! '+' or '!'             ---->                              accepted, or start over
+//! ```
+//!
+//! Images are checked against the site key like those sent over the radio.
+
+#![no_main]
+#![no_std]
+
+extern crate panic_semihosting;
+
+use cortex_m::peripheral::{DCB, DWT, SCB};
+use cortex_m_rt::entry;
+use embedded_hal::{serial, watchdog::WatchdogEnable};
+use shared::{
+    boot::{self, Boot},
+    flash::{Flash, APP_SLOT, SLOT_HEADER_SIZE},
+    image::{self, ImageHeader, IMAGE_HEADER_SIZE},
+    secure::SITE_KEY,
+};
+use stm32f1xx_hal::{
+    flash::{FlashSize, SectorSize},
+    pac,
+    prelude::*,
+    serial::{Config, Serial},
+    watchdog::IndependentWatchdog,
+};
+
+/// Where the app's vector table starts
+const APP_START: u32 = APP_SLOT.start + SLOT_HEADER_SIZE;
+/// How long an app on trial may hang before it's reset, in ms
+const TRIAL_WATCHDOG: u32 = 4_000;
+/// Baud rate of the recovery port
+const RECOVERY_BAUD: u32 = 115_200;
+/// Image bytes the host sends before waiting for an ACK
+const RECOVERY_BLOCK: usize = 64;
+/// How long the host may pause in the middle of an image: 1 s at the 8 MHz
+/// we run at
+const RECOVERY_TIMEOUT: u32 = 8_000_000;
+
+mod reply {
+    pub const READY: u8 = b'>';
+    pub const ACK: u8 = b'.';
+    pub const DONE: u8 = b'+';
+    pub const ERROR: u8 = b'!';
+}
+
+#[entry]
+fn main() -> ! {
+    let device = pac::Peripherals::take().unwrap();
+    let core = cortex_m::Peripherals::take().unwrap();
+    let mut flash = device.FLASH.constrain();
+
+    let boot = match boot::prepare(&mut flash.writer(SectorSize::Sz1K, FlashSize::Sz64K)) {
+        Ok(boot) => boot,
+        // The boot state picks up where it left off, try again
+        Err(_) => SCB::sys_reset(),
+    };
+
+    match boot {
+        Boot::Normal => {}
+        Boot::Trial => {
+            // An image that hangs before checking in still counts its boots
+            let mut watchdog = IndependentWatchdog::new(device.IWDG);
+            watchdog.start(TRIAL_WATCHDOG.ms());
+        }
+        Boot::Recover if debugger_attached() && plausible(APP_START) => {}
+        Boot::Recover => recover(device, core, flash),
+    }
+
+    unsafe { jump(APP_START) }
+}
+
+fn debugger_attached() -> bool {
+    // C_DEBUGEN
+    unsafe { (*DCB::ptr()).dhcsr.read() & 1 != 0 }
+}
+
+/// Whether the vector table at `address` looks like something was flashed there
+fn plausible(address: u32) -> bool {
+    let stack = unsafe { core::ptr::read_volatile(address as *const u32) };
+    stack & 0xFFFE_0000 == 0x2000_0000
+}
+
+/// Start the program whose vector table is at `address`
+unsafe fn jump(address: u32) -> ! {
+    let stack = core::ptr::read_volatile(address as *const u32);
+    let reset = core::ptr::read_volatile((address + 4) as *const u32);
+    (*SCB::ptr()).vtor.write(address);
+    cortex_m::register::msp::write(stack);
+    let reset: extern "C" fn() -> ! = core::mem::transmute(reset as usize);
+    reset()
+}
+
+/// Why receiving an image over the recovery port failed
+enum RecoveryError {
+    Timeout,
+    Serial,
+    Flash,
+    /// The header or image didn't check out
+    Image,
+}
+
+impl<E> From<image::VerifyError<E>> for RecoveryError {
+    fn from(e: image::VerifyError<E>) -> Self {
+        match e {
+            image::VerifyError::Image(_) => RecoveryError::Image,
+            image::VerifyError::Flash(_) => RecoveryError::Flash,
+        }
+    }
+}
+
+/// Wait for an image on the recovery port, write it to the app slot and
+/// restart once it checks out
+fn recover(device: pac::Peripherals, mut core: cortex_m::Peripherals, mut flash: stm32f1xx_hal::flash::Parts) -> ! {
+    core.DWT.enable_cycle_counter();
+    let mut rcc = device.RCC.constrain();
+    let clocks = rcc.cfgr.freeze(&mut flash.acr);
+    let mut afio = device.AFIO.constrain(&mut rcc.apb2);
+    let mut gpioa = device.GPIOA.split(&mut rcc.apb2);
+    let pins = (gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh), gpioa.pa10);
+    let serial = Serial::usart1(
+        device.USART1,
+        pins,
+        &mut afio.mapr,
+        Config::default().baudrate(RECOVERY_BAUD.bps()),
+        clocks,
+        &mut rcc.apb2,
+    );
+    let (mut tx, mut rx) = serial.split();
+    let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+
+    loop {
+        send(&mut tx, b"recovery\n");
+        match receive(&mut tx, &mut rx, &mut writer) {
+            Ok(()) => {
+                send(&mut tx, &[reply::DONE]);
+                SCB::sys_reset();
+            }
+            Err(RecoveryError::Timeout) => {}
+            Err(_) => send(&mut tx, &[reply::ERROR]),
+        }
+    }
+}
+
+fn receive<F: Flash>(
+    tx: &mut impl serial::Write<u8>,
+    rx: &mut impl serial::Read<u8>,
+    flash: &mut F,
+) -> Result<(), RecoveryError> {
+    let mut header = [0u8; IMAGE_HEADER_SIZE];
+    read(rx, &mut header)?;
+    let length = ImageHeader::decode(&header).map_err(|_| RecoveryError::Image)?.length;
+
+    // Whatever was in the app slot or pending is gone from here on
+    boot::clear(flash).map_err(|_| RecoveryError::Flash)?;
+    for page in 0..APP_SLOT.pages() {
+        flash.erase(APP_SLOT.page(page)).map_err(|_| RecoveryError::Flash)?;
+    }
+    flash.write(APP_SLOT.start, &header).map_err(|_| RecoveryError::Flash)?;
+    send(tx, &[reply::READY]);
+
+    let mut block = [0xFF; RECOVERY_BLOCK + 1];
+    let mut offset = 0;
+    while offset < length {
+        let n = (length - offset).min(RECOVERY_BLOCK as u32) as usize;
+        read(rx, &mut block[..n])?;
+        // Flash is written in half words, the padding is the erased value
+        block[n] = 0xFF;
+        let padded = n + n % 2;
+        flash
+            .write(APP_SLOT.start + SLOT_HEADER_SIZE + offset, &block[..padded])
+            .map_err(|_| RecoveryError::Flash)?;
+        offset += n as u32;
+        send(tx, &[reply::ACK]);
+    }
+
+    image::verify(flash, APP_SLOT, Some(SITE_KEY))?;
+    Ok(())
+}
+
+/// Fill `buf` from the recovery port, giving up if the host goes quiet
+fn read(rx: &mut impl serial::Read<u8>, buf: &mut [u8]) -> Result<(), RecoveryError> {
+    for byte in buf.iter_mut() {
+        let start = DWT::get_cycle_count();
+        *byte = loop {
+            match rx.read() {
+                Ok(byte) => break byte,
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => return Err(RecoveryError::Serial),
+            }
+            if DWT::get_cycle_count().wrapping_sub(start) >= RECOVERY_TIMEOUT {
+                return Err(RecoveryError::Timeout);
+            }
+        };
+    }
+    Ok(())
+}
+
+fn send(tx: &mut impl serial::Write<u8>, data: &[u8]) {
+    for &byte in data {
+        nb::block!(tx.write(byte)).ok();
+    }
+    nb::block!(tx.flush()).ok();
+}
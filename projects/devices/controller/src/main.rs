@@ -1,5 +1,1268 @@
+//! The hand held controller: buttons, a rotary encoder and potentiometers
+//! turned into commands for the fixtures.
+//!
+//! Inputs are numbered for their bindings, see `shared::bindings`:
+//!
+//! 0. effect button (PA8)
+//! 1. target button (PB8)
+//! 2. speed button (PB9)
+//! 3. encoder (PB6, PB7 on TIM4) and its button (PB12)
+//!
+//! Buttons pull their pin to ground. The pots (PA0, PA1, PA2) set the red,
+//! green and blue of the solid effect. Commands go to every fixture, or to
+//! one of [`GROUPS`], over the same radio stack as `tx`.
+//!
+//! Bindings, scenes and the cue list are edited on USART1 (TX on PA9, RX on
+//! PA10, 115200 baud) or the USB serial port, a command per line, see
+//! `shared::cli`:
+//!
+//! ```text
+//! status                       what the controls are set to
+//! inventory                    the fixtures that announced themselves
+//! set color 3 255 0 0          make fixture 3 red, also `all` or `group 1`
+//! set brightness all 128       dim every fixture to half
+//! effect rainbow speed=5       change what the controls talk to
+//! bindings                     list the bindings
+//! bind 0 double effect solid   add or replace one
+//! unbind 0 double              remove one
+//! defaults                     go back to the default bindings
+//! scenes                       list the scenes
+//! scene capture 2              add what the current target shows to scene 2
+//! scene name 2 sunset          name it
+//! scene fade 2 3000            take 3 s to recall it
+//! scene recall 2               recall it
+//! scene delete 2               delete it
+//! cues                         list the cue list
+//! cue add 2 500 10000          recall scene 2 over 0.5 s, hold it for 10 s
+//! cue add 3 2000 manual        recall scene 3 over 2 s, wait for go
+//! cue insert 0 1 0 manual      put a cue before the first one
+//! cue delete 0                 delete the first cue
+//! cue clear                    delete them all
+//! cue loop on                  start over after the last cue
+//! cue go                       go, also `back`, `pause` and `stop`
+//! cue go 4                     jump to cue 4
+//! config save                  keep all of it over a power cycle
+//! radio survey                 restart and pick the quietest channel again
+//! reboot                       restart the controller
+//! help                         list the commands
+//! ```
+//!
+//! Captured looks are sent to the fixtures straight away, so they can
+//! recall them in step. See `shared::scene` and `shared::cue`.
+//!
+//! Every [`DISCOVER_INTERVAL`] the fixtures are asked to announce themselves,
+//! and the ones that stop answering show as offline in the inventory, see
+//! `shared::inventory`.
+
 #![no_main]
 #![no_std]
 
 extern crate panic_semihosting;
-extern crate stm32f1xx_hal as hal;
\ No newline at end of file
+
+use core::convert::Infallible;
+use cortex_m::{peripheral::DWT, singleton};
+use core::fmt::Write;
+use embedded_hal::{watchdog::Watchdog, Qei as _};
+use embedded_nrf24l01 as nrf;
+use hal::{
+    adc::{Adc, AdcDma, Scan, SampleTime, SetChannels},
+    flash::{FlashSize, SectorSize},
+    gpio::{gpioa::*, gpiob::*, Alternate, Analog, Floating, Input, Output, PullUp, PushPull},
+    pac::{ADC1, SPI2, TIM4, USART1},
+    prelude::*,
+    qei::{Qei, QeiOptions},
+    serial::{self, Config, Rx, Serial, Tx},
+    spi::{Spi, Spi2NoRemap},
+    time::MegaHertz,
+    timer::{Tim4NoRemap, Timer},
+    watchdog::IndependentWatchdog,
+};
+use rtic::{app, Mutex};
+use rtic::cyccnt::U32Ext as _;
+use stm32f1xx_hal as hal;
+
+use nrf::{Configuration, StandbyMode, NRF24L01};
+use shared::{
+    address::{Destination, NodeId, CONTROLLER_ID},
+    bindings::{self, parse_trigger, Binding, Bindings, Command, Controls, Event},
+    cue::{self, Cue, CueList, Player, Transport},
+    boot::{self, BootState},
+    cli::{self, Args, Line},
+    crypto::Entropy,
+    flash::SETTINGS,
+    hopping::{ChannelPlan, Hopper, DEFAULT_CHANNEL, DWELL},
+    input::{Button, Encoder, Smoother},
+    inventory::{Change, FixtureInfo, Inventory, DISCOVER_WINDOW},
+    log,
+    mesh::Mesh,
+    protocol::{Effect, Frame, Message, FRAME_SIZE},
+    radio,
+    scan::Survey,
+    scene::{self, Name, Scenes, DEFAULT_FADE, MAX_PARTS},
+    secure::{peek_header, session_nonce, ControllerLink, SITE_KEY},
+    settings::{key, Settings, SettingsError},
+    telemetry::LinkStats,
+    timesync::{LocalClock, SYNC_INTERVAL},
+    usb::{Log, UsbSerial, CRYSTAL},
+};
+
+type RadioCe = PB0<Output<PushPull>>;
+type RadioCsn = PB1<Output<PushPull>>;
+type RadioSpi2Pins = (
+    PB13<Alternate<PushPull>>,
+    PB14<Input<Floating>>,
+    PB15<Alternate<PushPull>>,
+);
+type RadioSpi = Spi<SPI2, Spi2NoRemap, RadioSpi2Pins>;
+type Radio = NRF24L01<Infallible, RadioCe, RadioCsn, RadioSpi>;
+
+type ButtonPins = (
+    PA8<Input<PullUp>>,
+    PB8<Input<PullUp>>,
+    PB9<Input<PullUp>>,
+    PB12<Input<PullUp>>,
+);
+type EncoderQei = Qei<TIM4, Tim4NoRemap, (PB6<Input<Floating>>, PB7<Input<Floating>>)>;
+type PotDma = AdcDma<PotPins, Scan>;
+
+pub const ID: NodeId = CONTROLLER_ID;
+/// Fixtures we pair with
+pub const FIXTURES: [NodeId; 4] = [1, 2, 3, 4];
+/// Groups the target button steps through after every fixture
+pub const GROUPS: [(&str, u8); 2] = [("stage left", 0), ("stage right", 1)];
+/// How often group and broadcast frames are sent, as nobody acknowledges them
+pub const MULTICAST_REPEATS: usize = 3;
+/// How long to wait for a fixture to answer a request, in cycles
+pub const REPLY_TIMEOUT: u32 = FREQ * 100_000;
+/// How often fixtures are asked to announce themselves, which is also their
+/// heartbeat
+pub const DISCOVER_INTERVAL: u32 = FREQ * 10_000_000;
+/// How often each channel is sampled when picking the rendezvous channel
+pub const SURVEY_ROUNDS: u16 = 20;
+/// How often the inputs are read, in ms
+pub const SCAN_INTERVAL: u32 = 5;
+/// Number of potentiometers, one per colour channel
+pub const POT_COUNT: usize = 3;
+/// Input number of the encoder
+pub const ENCODER: u8 = 3;
+/// Baud rate of the serial port
+pub const SERIAL_BAUD: u32 = 115_200;
+
+const FREQ: u32 = 48;
+const SYSCLK_FREQ: MegaHertz = MegaHertz(FREQ);
+const PCLK1_FREQ: MegaHertz = MegaHertz(FREQ / 2);
+
+/// The potentiometers, sampled by ADC1 in a single scan
+pub struct PotPins(PA0<Analog>, PA1<Analog>, PA2<Analog>);
+
+impl SetChannels<PotPins> for Adc<ADC1> {
+    fn set_samples(&mut self) {
+        for channel in 0..POT_COUNT as u8 {
+            self.set_channel_sample_time(channel, SampleTime::T_28);
+        }
+    }
+
+    fn set_sequence(&mut self) {
+        self.set_regular_sequence(&[0, 1, 2]);
+    }
+}
+
+#[app(device = stm32f1xx_hal::pac, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
+const APP: () = {
+    struct Resources {
+        radio: Option<StandbyMode<Radio>>,
+        buffer: Option<[u8; FRAME_SIZE]>,
+        #[init(0)]
+        seq: u8,
+        link: ControllerLink,
+        hopper: Hopper,
+        stats: LinkStats,
+        mesh: Mesh,
+        /// Our clock is the network time
+        clock: LocalClock,
+        flash: hal::flash::Parts,
+        settings: Settings,
+        /// Only running while the firmware is on trial, see `shared::boot`
+        watchdog: IndependentWatchdog,
+        button_pins: ButtonPins,
+        #[init([Button::new(); 4])]
+        buttons: [Button; 4],
+        qei: EncoderQei,
+        encoder: Encoder,
+        pot_dma: Option<PotDma>,
+        pot_buffer: Option<&'static mut [u16; POT_COUNT]>,
+        #[init([Smoother::new(); POT_COUNT])]
+        pots: [Smoother; POT_COUNT],
+        bindings: Bindings,
+        scenes: Scenes,
+        cues: CueList,
+        #[init(Player::new())]
+        player: Player,
+        #[init(Controls::new(GROUPS.len() as u8))]
+        controls: Controls,
+        /// Time the inputs were last read, in ms
+        #[init(0)]
+        now: u32,
+        serial_tx: Tx<USART1>,
+        serial_rx: Rx<USART1>,
+        #[init(Line::new())]
+        line: Line,
+        usb: UsbSerial,
+        #[init(Inventory::new())]
+        inventory: Inventory,
+    }
+
+    #[init(spawn = [pair, hop, time_sync, scan, discover])]
+    fn init(cx: init::Context) -> init::LateResources {
+        log!("Initializing device!");
+        // Enable the monotonic timer
+        let mut core = cx.core;
+        core.DWT.enable_cycle_counter();
+
+        let mut rcc = cx.device.RCC.constrain();
+        let mut flash = cx.device.FLASH.constrain();
+        let mut afio = cx.device.AFIO.constrain(&mut rcc.apb2);
+        let clocks = rcc
+            .cfgr
+            .use_hse(CRYSTAL)
+            .sysclk(SYSCLK_FREQ)
+            .pclk1(PCLK1_FREQ)
+            .freeze(&mut flash.acr);
+
+        let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
+        let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
+        let usb = UsbSerial::new(cx.device.USB, gpioa.pa11, gpioa.pa12, &mut gpioa.crh, &clocks, "flash controller");
+
+        log!("Setting up the radio!");
+        let spi_pins: RadioSpi2Pins = (
+            gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh),
+            gpiob.pb14.into_floating_input(&mut gpiob.crh),
+            gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh),
+        );
+        let (ce, csn): (RadioCe, RadioCsn) = (
+            gpiob.pb0.into_push_pull_output(&mut gpiob.crl),
+            gpiob.pb1.into_push_pull_output(&mut gpiob.crl),
+        );
+        let spi: RadioSpi = Spi::spi2(
+            cx.device.SPI2,
+            spi_pins,
+            nrf::setup::spi_mode(),
+            nrf::setup::clock_mhz().mhz(),
+            clocks,
+            &mut rcc.apb1,
+        );
+        let mut radio: StandbyMode<Radio> = NRF24L01::new(ce, csn, spi).expect("to create a new radio interface");
+
+        let (mut settings, boots, bindings, scenes, cues, plan) = {
+            let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+            let mut settings = Settings::mount(&mut writer, SETTINGS).expect("to read the settings");
+            let boots = settings.count_boot(&mut writer).expect("to count the boot");
+            let mut data = [0u8; scene::MAX_ENCODED_SIZE];
+            let bindings = match settings.read(&mut writer, key::BINDINGS, &mut data) {
+                Ok(Some(len)) => Bindings::decode(&data[..len]),
+                _ => None,
+            };
+            let scenes = match settings.read(&mut writer, key::SCENES, &mut data) {
+                Ok(Some(len)) => Scenes::decode(&data[..len]).ok(),
+                _ => None,
+            };
+            let cues = match settings.read(&mut writer, key::CUES, &mut data) {
+                Ok(Some(len)) => CueList::decode(&data[..len]).ok(),
+                _ => None,
+            };
+            let plan = match settings.read(&mut writer, key::CHANNEL_PLAN, &mut data) {
+                Ok(Some(len)) => ChannelPlan::decode(&data[..len]).ok(),
+                _ => None,
+            };
+            (
+                settings,
+                boots,
+                bindings.unwrap_or_else(Bindings::defaults),
+                scenes.unwrap_or_default(),
+                cues.unwrap_or_default(),
+                plan,
+            )
+        };
+
+        // Every boot starts a new session, seeded with ADC noise and the boot count
+        let mut adc = Adc::adc1(cx.device.ADC1, &mut rcc.apb2, clocks);
+        let mut entropy = Entropy::new();
+        for _ in 0..64 {
+            entropy.mix(adc.read_vref() as u32 ^ DWT::get_cycle_count());
+        }
+        let link = ControllerLink::new(*SITE_KEY, session_nonce(boots, entropy.nonce()));
+        radio::configure(&mut radio, DEFAULT_CHANNEL).expect("to configure the radio");
+
+        // Fixtures that lose track of the hop sequence wait for us on the
+        // quietest channel. They keep it over a power cycle, so we do too.
+        let (plan, mut radio) = match plan {
+            Some(plan) => (plan, radio),
+            None => {
+                log!("Surveying channels!");
+                let mut survey = Survey::new();
+                let radio = radio::survey(radio, &mut survey, SURVEY_ROUNDS);
+                let plan = ChannelPlan::hopping(survey.quietest_channel());
+                let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+                if let Err(e) = settings.write(&mut writer, key::CHANNEL_PLAN, &plan.encode()) {
+                    log!("couldn't save the channel plan: {:?}", e);
+                }
+                (plan, radio)
+            }
+        };
+        log!("Rendezvous on channel {}", plan.channel);
+        let hopper = Hopper::master(plan, link.hop_seed(), DWT::get_cycle_count());
+        radio.set_frequency(hopper.channel()).expect("to set the channel");
+
+        log!("Setting up the controls!");
+        let button_pins: ButtonPins = (
+            gpioa.pa8.into_pull_up_input(&mut gpioa.crh),
+            gpiob.pb8.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb9.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb12.into_pull_up_input(&mut gpiob.crh),
+        );
+
+        // TIM4 counts the encoder's quadrature edges by itself
+        let qei: EncoderQei = Timer::tim4(cx.device.TIM4, &clocks, &mut rcc.apb1).qei(
+            (gpiob.pb6, gpiob.pb7),
+            &mut afio.mapr,
+            QeiOptions::default(),
+        );
+        let encoder = Encoder::new(qei.count());
+
+        // ADC1 scans the pots and DMA copies the readings out
+        let pot_pins = PotPins(
+            gpioa.pa0.into_analog(&mut gpioa.crl),
+            gpioa.pa1.into_analog(&mut gpioa.crl),
+            gpioa.pa2.into_analog(&mut gpioa.crl),
+        );
+        let dma = cx.device.DMA1.split(&mut rcc.ahb);
+        let pot_dma: PotDma = adc.with_scan_dma(pot_pins, dma.1);
+
+        let serial_pins = (gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh), gpioa.pa10);
+        let mut serial = Serial::usart1(
+            cx.device.USART1,
+            serial_pins,
+            &mut afio.mapr,
+            Config::default().baudrate(SERIAL_BAUD.bps()),
+            clocks,
+            &mut rcc.apb2,
+        );
+        serial.listen(serial::Event::Rxne);
+        let (serial_tx, serial_rx) = serial.split();
+
+        log!("Beginning transmissions!");
+        cx.spawn.pair().expect("to schedule pairing");
+        cx.spawn.hop().expect("to schedule hopping");
+        cx.spawn.time_sync().expect("to schedule time beacons");
+        cx.spawn.scan().expect("to schedule reading the inputs");
+        cx.spawn.discover().expect("to schedule discovery");
+
+        init::LateResources {
+            radio: Some(radio),
+            buffer: Some([0u8; FRAME_SIZE]),
+            link,
+            hopper,
+            stats: LinkStats::new(),
+            mesh: Mesh::new(ID, false),
+            clock: LocalClock::new(),
+            flash,
+            settings,
+            watchdog: IndependentWatchdog::new(cx.device.IWDG),
+            button_pins,
+            qei,
+            encoder,
+            pot_dma: Some(pot_dma),
+            pot_buffer: singleton!(: [u16; POT_COUNT] = [0; POT_COUNT]),
+            bindings,
+            scenes,
+            cues,
+            serial_tx,
+            serial_rx,
+            usb,
+        }
+    }
+
+    /// Read the inputs, play the cue list and queue commands for whatever
+    /// changed.
+    ///
+    /// Runs above the radio tasks, so the inputs are read and cues go on time
+    /// even while the radio waits for a fixture.
+    #[task(
+        priority = 2,
+        resources = [
+            watchdog, button_pins, buttons, qei, encoder, pot_dma, pot_buffer, pots, bindings, controls, now, clock,
+            cues, player
+        ],
+        spawn = [command],
+        schedule = [scan]
+    )]
+    fn scan(cx: scan::Context) {
+        cx.resources.watchdog.feed();
+        *cx.resources.now = cx.resources.now.wrapping_add(SCAN_INTERVAL);
+        let now = *cx.resources.now;
+        let time = cx.resources.clock.micros(DWT::get_cycle_count());
+        let bindings = cx.resources.bindings;
+        let controls = cx.resources.controls;
+        let cues = cx.resources.cues;
+        let player = cx.resources.player;
+        let follow = player.update(cues, time);
+        let spawn = cx.spawn;
+        let mut queue = |command: Option<Command>| {
+            let command = match command {
+                Some(Command::Cue(transport)) => player.apply(transport, cues, time).map(Command::from),
+                command => command,
+            };
+            if let Some(command) = command {
+                if spawn.command(command).is_err() {
+                    log!("dropped {:?}, the radio is busy", command);
+                }
+            }
+        };
+
+        let pins = cx.resources.button_pins;
+        let pressed = [
+            pins.0.is_low().unwrap(),
+            pins.1.is_low().unwrap(),
+            pins.2.is_low().unwrap(),
+            pins.3.is_low().unwrap(),
+        ];
+        for (input, (button, &pressed)) in cx.resources.buttons.iter_mut().zip(pressed.iter()).enumerate() {
+            if let Some(gesture) = button.update(pressed, now) {
+                queue(bindings.get(input as u8, gesture.into()).and_then(|action| controls.apply(action)));
+            }
+        }
+
+        // A fast turn only sends where it ended up
+        let detents = cx.resources.encoder.update(cx.resources.qei.count());
+        let event = if detents > 0 { Event::Clockwise } else { Event::Anticlockwise };
+        let mut turned = None;
+        for _ in 0..detents.abs() {
+            if let Some(action) = bindings.get(ENCODER, event) {
+                turned = controls.apply(action).or(turned);
+            }
+        }
+        queue(turned);
+
+        // The scan only takes a few µs, wait for it here
+        let transfer = cx.resources.pot_dma.take().unwrap().read(cx.resources.pot_buffer.take().unwrap());
+        let (readings, pot_dma) = transfer.wait();
+        for (channel, (pot, &reading)) in cx.resources.pots.iter_mut().zip(readings.iter()).enumerate() {
+            if let Some(level) = pot.update(reading) {
+                controls.moved(channel, level);
+            }
+        }
+        *cx.resources.pot_dma = Some(pot_dma);
+        *cx.resources.pot_buffer = Some(readings);
+        queue(controls.color_due(now));
+        queue(follow.map(Command::from));
+
+        cx.schedule.scan(cx.scheduled + (FREQ * 1_000 * SCAN_INTERVAL).cycles()).unwrap();
+    }
+
+    /// Carry out a command from the controls
+    #[task(capacity = 8, resources = [radio, buffer, seq, link, stats, mesh, scenes, controls])]
+    fn command(mut cx: command::Context, command: Command) {
+        let mut messages = [None; 1 + MAX_PARTS];
+        match command {
+            Command::Send(dest, message) => messages[0] = Some((dest, message)),
+            Command::RecallScene { scene: index, fade } => {
+                let scene = match cx.resources.scenes.lock(|scenes| scenes.get(index).copied()) {
+                    Some(scene) => scene,
+                    None => {
+                        log!("scene {} isn't stored", index);
+                        return;
+                    }
+                };
+                let fade = fade.unwrap_or(scene.fade);
+                // Fixtures that cached the scene start at once, the looks
+                // after it catch the others up
+                for (slot, message) in messages.iter_mut().zip(scene.recall(index, fade)) {
+                    *slot = Some(message);
+                }
+                cx.resources.controls.lock(|controls| {
+                    if let Some(look) = scene.look(controls.target()) {
+                        controls.set_look(look);
+                    }
+                });
+            }
+            // The player carries these out before they're queued
+            Command::Cue(_) => return,
+        }
+
+        let mut standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+        let mut seq = *cx.resources.seq;
+        for &(dest, message) in messages.iter().flatten() {
+            let frame = Frame::new(dest, ID, seq, message);
+            seq = seq.wrapping_add(1);
+            let (sent_standby, sent) = send(standby, cx.resources.link, cx.resources.stats, cx.resources.mesh, &frame, &mut buffer);
+            standby = sent_standby;
+            if sent {
+                log!("transmitted {:?} to {:?}", message, dest);
+            } else {
+                log!("error transmitting {:?}", message);
+            }
+        }
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        *cx.resources.seq = seq;
+    }
+
+    /// Pair with the next fixture we don't have a session with
+    #[task(resources = [radio, link, stats, mesh, hopper, flash], schedule = [pair])]
+    fn pair(cx: pair::Context) {
+        let link = cx.resources.link;
+        if let Some(&fixture) = FIXTURES.iter().find(|&&fixture| !link.is_paired(fixture)) {
+            let standby = cx.resources.radio.take().unwrap();
+            *cx.resources.radio = Some(pair(standby, link, cx.resources.stats, cx.resources.mesh, cx.resources.hopper, fixture));
+
+            // New firmware works once it reaches a fixture
+            if link.is_paired(fixture) {
+                cx.resources.flash.lock(|flash| {
+                    let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+                    if let Ok(BootState::Trial { .. }) = boot::state(&mut writer) {
+                        boot::confirm(&mut writer).ok();
+                    }
+                });
+            }
+        }
+        cx.schedule.pair(cx.scheduled + (FREQ * 1_000_000).cycles()).unwrap();
+    }
+
+    /// Move to the next channel and tell the fixtures where we are
+    #[task(resources = [radio, buffer, link, hopper, stats, mesh], schedule = [hop])]
+    fn hop(cx: hop::Context) {
+        let hopper = cx.resources.hopper;
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        let mut standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+
+        if let Some(channel) = hopper.update(DWT::get_cycle_count(), true) {
+            standby.set_frequency(channel).unwrap();
+        }
+
+        if hopper.plan().hopping {
+            standby = beacon(standby, link, stats, mesh, hopper, &mut buffer);
+        }
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        cx.schedule.hop(cx.scheduled + DWELL.cycles()).unwrap();
+    }
+
+    /// Tell the fixtures the network time, so their effects stay in step
+    #[task(resources = [radio, buffer, link, stats, mesh, clock], schedule = [time_sync])]
+    fn time_sync(mut cx: time_sync::Context) {
+        let standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+
+        let time = cx.resources.clock.lock(|clock| clock.micros(DWT::get_cycle_count()));
+        let beacon = Frame::new(Destination::Broadcast, ID, 0, Message::TimeSync { time });
+        let (standby, _) = send(standby, cx.resources.link, cx.resources.stats, cx.resources.mesh, &beacon, &mut buffer);
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        cx.schedule.time_sync(cx.scheduled + SYNC_INTERVAL.cycles()).unwrap();
+    }
+
+    /// Ask every fixture to announce itself, and keep the inventory up to
+    /// date with the answers
+    #[task(resources = [radio, buffer, seq, link, hopper, stats, mesh, inventory], schedule = [discover])]
+    fn discover(mut cx: discover::Context) {
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        let inventory = &mut cx.resources.inventory;
+        let standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+        let seq = *cx.resources.seq;
+
+        let request = Frame::new(Destination::Broadcast, ID, seq, Message::Discover { window: DISCOVER_WINDOW });
+        let (mut standby, sent) = send(standby, link, stats, mesh, &request, &mut buffer);
+        if sent {
+            let timeout = DISCOVER_WINDOW as u32 * FREQ * 1_000 + REPLY_TIMEOUT;
+            standby = await_announcements(standby, link, stats, mesh, cx.resources.hopper, timeout, |fixture, info| {
+                match inventory.lock(|inventory| inventory.seen(fixture, info)) {
+                    Some(Change::Found) => log!("found fixture {}: {}", fixture, info),
+                    Some(Change::Online) => log!("fixture {} is back online", fixture),
+                    Some(Change::Replaced) => log!("fixture {} is now {}", fixture, info),
+                    None => {}
+                }
+            });
+            inventory.lock(|inventory| inventory.end_round(|entry| log!("fixture {} is offline", entry.id)));
+        }
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        *cx.resources.seq = seq.wrapping_add(1);
+        cx.schedule.discover(cx.scheduled + DISCOVER_INTERVAL.cycles()).unwrap();
+    }
+
+    /// Collect a line from the serial port and carry it out
+    #[task(
+        binds = USART1,
+        priority = 2,
+        resources = [serial_tx, serial_rx, line, bindings, scenes, cues, player, controls, clock, flash, settings, inventory],
+        spawn = [command]
+    )]
+    fn console(cx: console::Context) {
+        let byte = match cx.resources.serial_rx.read() {
+            Ok(byte) => byte,
+            Err(_) => return,
+        };
+        if let Some(line) = cx.resources.line.push(byte) {
+            let spawn = cx.spawn;
+            let mut console = Console {
+                bindings: cx.resources.bindings,
+                scenes: cx.resources.scenes,
+                cues: cx.resources.cues,
+                player: cx.resources.player,
+                controls: cx.resources.controls,
+                now: cx.resources.clock.micros(DWT::get_cycle_count()),
+                flash: cx.resources.flash,
+                settings: cx.resources.settings,
+                inventory: cx.resources.inventory,
+                queue: &mut |command| spawn.command(command).map_err(|_| "the radio is busy"),
+            };
+            cli::run(Console::COMMANDS, &mut console, line.as_str(), cx.resources.serial_tx);
+        }
+    }
+
+    /// Send the log out over USB
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb])]
+    fn usb_tx(cx: usb_tx::Context) {
+        cx.resources.usb.poll();
+    }
+
+    /// Same as [`console`], for lines that come in over USB
+    #[task(
+        binds = USB_LP_CAN_RX0,
+        priority = 2,
+        resources = [usb, bindings, scenes, cues, player, controls, clock, flash, settings, inventory],
+        spawn = [command]
+    )]
+    fn usb_rx(cx: usb_rx::Context) {
+        let usb = cx.resources.usb;
+        let spawn = cx.spawn;
+        usb.poll();
+        while let Some(line) = usb.read_line() {
+            let mut console = Console {
+                bindings: cx.resources.bindings,
+                scenes: cx.resources.scenes,
+                cues: cx.resources.cues,
+                player: cx.resources.player,
+                controls: cx.resources.controls,
+                now: cx.resources.clock.micros(DWT::get_cycle_count()),
+                flash: cx.resources.flash,
+                settings: cx.resources.settings,
+                inventory: cx.resources.inventory,
+                queue: &mut |command| spawn.command(command).map_err(|_| "the radio is busy"),
+            };
+            cli::run(Console::COMMANDS, &mut console, line.as_str(), &mut Log);
+        }
+    }
+
+    extern "C" {
+        fn EXTI0();
+        fn EXTI1();
+    }
+};
+
+/// Keep the bindings, scenes and cue list over a power cycle
+fn save(
+    flash: &mut hal::flash::Parts,
+    settings: &mut Settings,
+    bindings: &Bindings,
+    scenes: &Scenes,
+    cues: &CueList,
+) -> Result<(), SettingsError<hal::flash::Error>> {
+    let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+    let mut binding_data = [0u8; bindings::MAX_ENCODED_SIZE];
+    let len = bindings.encode(&mut binding_data);
+    settings.write(&mut writer, key::BINDINGS, &binding_data[..len])?;
+    let mut scene_data = [0u8; scene::MAX_ENCODED_SIZE];
+    let len = scenes.encode(&mut scene_data);
+    settings.write(&mut writer, key::SCENES, &scene_data[..len])?;
+    let mut cue_data = [0u8; cue::MAX_ENCODED_SIZE];
+    let len = cues.encode(&mut cue_data);
+    settings.write(&mut writer, key::CUES, &cue_data[..len])
+}
+
+/// What commands from the serial port or USB work on
+struct Console<'a> {
+    bindings: &'a mut Bindings,
+    scenes: &'a mut Scenes,
+    cues: &'a mut CueList,
+    player: &'a mut Player,
+    controls: &'a mut Controls,
+    /// Network time, for the cue list
+    now: u64,
+    flash: &'a mut hal::flash::Parts,
+    settings: &'a mut Settings,
+    inventory: &'a Inventory,
+    /// Sends commands to the fixtures
+    queue: &'a mut dyn FnMut(Command) -> Result<(), &'static str>,
+}
+
+impl<'a> Console<'a> {
+    /// See the top of this file
+    const COMMANDS: &'a [cli::Command<Self>] = &[
+        cli::Command { name: "status", usage: "", run: Self::status },
+        cli::Command { name: "inventory", usage: "", run: Self::inventory },
+        cli::Command { name: "set color", usage: "<fixture> <red> <green> <blue>", run: Self::set_color },
+        cli::Command { name: "set brightness", usage: "<fixture> <brightness>", run: Self::set_brightness },
+        cli::Command { name: "effect", usage: "<name> [speed=<speed>]", run: Self::effect },
+        cli::Command { name: "bindings", usage: "", run: Self::list_bindings },
+        cli::Command { name: "bind", usage: "<input> <event> <action>", run: Self::bind },
+        cli::Command { name: "unbind", usage: "<input> <event>", run: Self::unbind },
+        cli::Command { name: "defaults", usage: "", run: Self::defaults },
+        cli::Command { name: "scenes", usage: "", run: Self::list_scenes },
+        cli::Command { name: "scene capture", usage: "<scene>", run: Self::capture_scene },
+        cli::Command { name: "scene name", usage: "<scene> <name>", run: Self::name_scene },
+        cli::Command { name: "scene fade", usage: "<scene> <ms>", run: Self::fade_scene },
+        cli::Command { name: "scene recall", usage: "<scene>", run: Self::recall_scene },
+        cli::Command { name: "scene delete", usage: "<scene>", run: Self::delete_scene },
+        cli::Command { name: "cues", usage: "", run: Self::list_cues },
+        cli::Command { name: "cue add", usage: "<scene> [fade] [hold|manual]", run: Self::add_cue },
+        cli::Command { name: "cue insert", usage: "<cue> <scene> [fade] [hold|manual]", run: Self::insert_cue },
+        cli::Command { name: "cue delete", usage: "<cue>", run: Self::delete_cue },
+        cli::Command { name: "cue clear", usage: "", run: Self::clear_cues },
+        cli::Command { name: "cue loop", usage: "on|off", run: Self::loop_cues },
+        cli::Command { name: "cue go", usage: "[cue]", run: Self::go },
+        cli::Command { name: "cue back", usage: "", run: Self::back },
+        cli::Command { name: "cue pause", usage: "", run: Self::pause },
+        cli::Command { name: "cue stop", usage: "", run: Self::stop },
+        cli::Command { name: "config save", usage: "", run: Self::save },
+        cli::Command { name: "radio survey", usage: "", run: Self::survey },
+        cli::Command { name: "reboot", usage: "", run: cli::reboot },
+    ];
+
+    fn send(&mut self, command: Command) -> Result<(), cli::Error> {
+        (self.queue)(command).map_err(cli::Error::Failed)
+    }
+
+    fn status(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let look = self.controls.look();
+        writeln!(out, "target {:?}", self.controls.target()).ok();
+        writeln!(
+            out,
+            "{} speed {} brightness {} color {} {} {}",
+            look.effect.name(),
+            look.speed,
+            look.brightness,
+            look.color.r,
+            look.color.g,
+            look.color.b
+        )
+        .ok();
+        writeln!(out, "{} scenes, {} cues", self.scenes.iter().count(), self.cues.len()).ok();
+        match self.player.current() {
+            Some(cue) => writeln!(out, "playing cue {}", cue),
+            None => writeln!(out, "cue list stopped"),
+        }
+        .ok();
+        Ok(())
+    }
+
+    fn inventory(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        for entry in self.inventory.iter() {
+            let state = if entry.online { "online" } else { "offline" };
+            writeln!(out, "{} {}: {}", entry.id, state, entry.info).ok();
+        }
+        writeln!(out, "{} of {} online", self.inventory.online(), self.inventory.iter().count()).ok();
+        Ok(())
+    }
+
+    fn set_color(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let color = args.color()?;
+        args.finish()?;
+        self.send(Command::Send(dest, Message::SetColor(color)))
+    }
+
+    fn set_brightness(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let brightness = args.parse("brightness")?;
+        args.finish()?;
+        self.send(Command::Send(dest, Message::SetBrightness(brightness)))
+    }
+
+    /// Change the effect of whatever the controls talk to
+    fn effect(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let effect = Effect::from_name(args.word("effect")?).ok_or(cli::Error::Invalid("effect"))?;
+        let mut look = self.controls.look();
+        look.effect = effect;
+        look.speed = args.option("speed")?.unwrap_or(look.speed);
+        args.finish()?;
+        self.controls.set_look(look);
+        self.send(Command::Send(self.controls.target(), Message::SetEffect { effect, speed: look.speed }))
+    }
+
+    fn list_bindings(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        for binding in self.bindings.iter() {
+            writeln!(out, "{}", binding).ok();
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let binding = Binding::parse(args.rest()).map_err(|_| cli::Error::Invalid("binding"))?;
+        self.bindings.bind(binding).map_err(|_| "too many bindings")?;
+        Ok(())
+    }
+
+    fn unbind(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let (input, event) = parse_trigger(args).map_err(|_| cli::Error::Invalid("input and event"))?;
+        args.finish()?;
+        if !self.bindings.unbind(input, event) {
+            return Err("not bound".into());
+        }
+        Ok(())
+    }
+
+    fn defaults(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        *self.bindings = Bindings::defaults();
+        Ok(())
+    }
+
+    fn list_scenes(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        for (index, scene) in self.scenes.iter() {
+            writeln!(out, "{} {}, fading over {} ms", index, scene.name.as_str(), scene.fade).ok();
+            for part in scene.parts() {
+                let look = part.look;
+                writeln!(
+                    out,
+                    "  {:?}: {} speed {} brightness {} color {} {} {}",
+                    part.dest,
+                    look.effect.name(),
+                    look.speed,
+                    look.brightness,
+                    look.color.r,
+                    look.color.g,
+                    look.color.b
+                )
+                .ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Add what the current target shows to a scene
+    fn capture_scene(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("scene number")?;
+        args.finish()?;
+        let (dest, look) = (self.controls.target(), self.controls.look());
+        let scene = self.scenes.entry(index).ok_or("no such scene")?;
+        scene.set(dest, look).map_err(|_| "the scene covers too many fixtures")?;
+        self.send(Command::Send(dest, Message::StoreScene { scene: index, look }))
+    }
+
+    fn name_scene(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("scene number")?;
+        let name = Name::new(args.word("name")?).ok_or(cli::Error::Invalid("name"))?;
+        args.finish()?;
+        self.scenes.entry(index).ok_or("no such scene")?.name = name;
+        Ok(())
+    }
+
+    fn fade_scene(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("scene number")?;
+        let fade = args.parse("fade time")?;
+        args.finish()?;
+        self.scenes.entry(index).ok_or("no such scene")?.fade = fade;
+        Ok(())
+    }
+
+    fn recall_scene(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let scene = args.parse("scene number")?;
+        args.finish()?;
+        self.send(Command::RecallScene { scene, fade: None })
+    }
+
+    fn delete_scene(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("scene number")?;
+        args.finish()?;
+        if !self.scenes.remove(index) {
+            return Err("no such scene".into());
+        }
+        Ok(())
+    }
+
+    fn list_cues(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        writeln!(out, "{}", if self.cues.looping { "looping" } else { "not looping" }).ok();
+        for (index, cue) in self.cues.iter().enumerate() {
+            let live = if self.player.current() == Some(index as u8) { '>' } else { ' ' };
+            write!(out, "{}{} scene {}, fading over {} ms, ", live, index, cue.scene, cue.fade).ok();
+            match cue.hold {
+                Some(hold) => writeln!(out, "holding {} ms", hold),
+                None => writeln!(out, "waiting for go"),
+            }
+            .ok();
+        }
+        Ok(())
+    }
+
+    fn add_cue(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let cue = self.parse_cue(args)?;
+        self.cues.push(cue).map_err(|_| "too many cues")?;
+        Ok(())
+    }
+
+    fn insert_cue(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("cue number")?;
+        let cue = self.parse_cue(args)?;
+        self.cues.insert(index, cue).map_err(|_| "too many cues")?;
+        Ok(())
+    }
+
+    fn delete_cue(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let index = args.parse("cue number")?;
+        args.finish()?;
+        if !self.cues.remove(index) {
+            return Err("no such cue".into());
+        }
+        Ok(())
+    }
+
+    fn clear_cues(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        self.cues.clear();
+        self.stop(args, out)
+    }
+
+    fn loop_cues(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        self.cues.looping = match args.word("on or off")? {
+            "on" => true,
+            "off" => false,
+            _ => return Err(cli::Error::Invalid("on or off")),
+        };
+        args.finish()
+    }
+
+    fn go(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let cue = match args.optional("cue number")? {
+            Some(index) => Some(self.player.jump(self.cues, index, self.now).ok_or("no such cue")?),
+            None => self.player.apply(Transport::Go, self.cues, self.now),
+        };
+        args.finish()?;
+        self.play(cue)
+    }
+
+    fn back(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let cue = self.player.apply(Transport::Back, self.cues, self.now);
+        self.play(cue)
+    }
+
+    fn pause(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let cue = self.player.apply(Transport::Pause, self.cues, self.now);
+        self.play(cue)
+    }
+
+    fn stop(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let cue = self.player.apply(Transport::Stop, self.cues, self.now);
+        self.play(cue)
+    }
+
+    fn play(&mut self, cue: Option<Cue>) -> Result<(), cli::Error> {
+        match cue {
+            Some(cue) => self.send(cue.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Read `<scene> [fade] [hold]`, the fade defaulting to the scene's and
+    /// the hold to waiting for go
+    fn parse_cue(&self, args: &mut Args) -> Result<Cue, cli::Error> {
+        let scene = args.parse("scene number")?;
+        let fade = match args.optional("fade time")? {
+            Some(fade) => fade,
+            None => self.scenes.get(scene).map_or(DEFAULT_FADE, |scene| scene.fade),
+        };
+        let hold = match args.next() {
+            None | Some("manual") => None,
+            Some(word) => Some(word.parse().map_err(|_| cli::Error::Invalid("hold time"))?),
+        };
+        args.finish()?;
+        Ok(Cue { scene, fade, hold })
+    }
+
+    fn save(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        save(self.flash, self.settings, self.bindings, self.scenes, self.cues).map_err(|_| "couldn't write to flash")?;
+        Ok(())
+    }
+
+    /// Forget our channel plan, so the next boot surveys the channels again.
+    /// Fixtures on the old one find us on the default channel after a while.
+    fn survey(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        self.settings
+            .remove(&mut writer, key::CHANNEL_PLAN)
+            .map_err(|_| "couldn't write to flash")?;
+        cli::reboot(self, args, out)
+    }
+}
+
+/// Seal and send a frame, returning whether it went out.
+///
+/// Unicast frames take the route the mesh learned, and are flooded through
+/// the relays if that doesn't get them acknowledged. Frames for fixtures we
+/// aren't paired with are dropped until they are paired again.
+fn send(
+    standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    frame: &Frame,
+    buffer: &mut [u8; FRAME_SIZE],
+) -> (StandbyMode<Radio>, bool) {
+    let dest = frame.header.dest;
+    let len = match link.seal(frame, buffer) {
+        Ok(len) => len,
+        Err(e) => {
+            log!("error sealing a frame for {:?}: {:?}", dest, e);
+            return (standby, false);
+        }
+    };
+    let len = mesh.stamp(buffer, len);
+
+    let next = mesh.next_hop(dest, DWT::get_cycle_count());
+    let (mut standby, mut sent) = transmit(standby, next, &buffer[..len], stats);
+    if let (Destination::Node(fixture), false) = (dest, sent) {
+        mesh.forget(fixture);
+        let flooded = transmit(standby, Destination::Broadcast, &buffer[..len], stats);
+        standby = flooded.0;
+        sent = flooded.1;
+    }
+
+    *buffer = [0u8; FRAME_SIZE];
+    (standby, sent)
+}
+
+/// Put `data` on air for `dest`, returning whether it went out.
+///
+/// Nobody acknowledges multicast frames, so those are sent a few times instead.
+fn transmit(
+    mut standby: StandbyMode<Radio>,
+    dest: Destination,
+    data: &[u8],
+    stats: &mut LinkStats,
+) -> (StandbyMode<Radio>, bool) {
+    radio::address_to(&mut standby, dest).unwrap();
+    standby.flush_tx().unwrap();
+    standby.flush_rx().unwrap();
+    let mut tx = standby.tx().unwrap();
+
+    let repeats = if dest.is_acknowledged() { 1 } else { MULTICAST_REPEATS };
+    let mut sent = false;
+    for _ in 0..repeats {
+        if tx.can_send().unwrap() {
+            sent |= radio::transmit(&mut tx, dest, data, stats).unwrap();
+        }
+    }
+
+    // Fixtures answer unicast frames with their status
+    if let (Destination::Node(fixture), true) = (dest, sent) {
+        if let Some(status) = radio::ack_status(&mut tx).unwrap() {
+            if let Some(peer) = stats.peer_mut(fixture) {
+                peer.status = Some(status);
+            }
+        }
+    }
+
+    (tx.standby().unwrap(), sent)
+}
+
+/// Ask `fixture` to pair and wait a moment for its answer, see
+/// [`ChannelPlan::pairing_channels`] for where.
+///
+/// Once paired, the fixture is told which channel plan to follow.
+fn pair(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &Hopper,
+    fixture: NodeId,
+) -> StandbyMode<Radio> {
+    let mut buffer = [0u8; FRAME_SIZE];
+    let dest = Destination::Node(fixture);
+    let len = Frame::new(dest, ID, 0, link.pair_request(fixture)).encode(&mut buffer);
+    let len = mesh.stamp(&mut buffer, len);
+
+    let mut sent = false;
+    for channel in hopper.plan().pairing_channels() {
+        standby.set_frequency(channel).unwrap();
+        radio::address_to(&mut standby, dest).unwrap();
+        let mut tx = standby.tx().unwrap();
+        sent = radio::transmit(&mut tx, dest, &buffer[..len], stats).unwrap();
+        standby = tx.standby().unwrap();
+        if sent {
+            break;
+        }
+    }
+    if !sent {
+        standby.set_frequency(hopper.channel()).unwrap();
+        return standby;
+    }
+
+    let (mut standby, response) = await_reply(standby, link, stats, mesh, fixture, REPLY_TIMEOUT);
+    if let Some(Frame { message: Message::PairResponse { nonce, .. }, .. }) = response {
+        link.paired(fixture, &nonce).unwrap();
+        log!("paired with fixture {}", fixture);
+        let plan = Frame::new(dest, ID, 0, Message::SetChannelPlan(hopper.plan()));
+        standby = send(standby, link, stats, mesh, &plan, &mut buffer).0;
+    }
+    standby.set_frequency(hopper.channel()).unwrap();
+    standby
+}
+
+/// Tell the fixtures where in the hop sequence we are, and the lost ones on
+/// the rendezvous channel when it's their turn
+fn beacon(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &Hopper,
+    buffer: &mut [u8; FRAME_SIZE],
+) -> StandbyMode<Radio> {
+    let beacon = |hopper: &Hopper| {
+        let now = DWT::get_cycle_count();
+        let message = Message::HopSync { slot: hopper.slot(), elapsed: hopper.elapsed(now) };
+        Frame::new(Destination::Broadcast, ID, 0, message)
+    };
+    standby = send(standby, link, stats, mesh, &beacon(hopper), buffer).0;
+
+    // Lost fixtures wait for us on the rendezvous channel
+    if hopper.is_rendezvous_slot() {
+        standby.set_frequency(hopper.plan().channel).unwrap();
+        standby = send(standby, link, stats, mesh, &beacon(hopper), buffer).0;
+        standby.set_frequency(hopper.channel()).unwrap();
+    }
+    standby
+}
+
+/// Listen for `timeout` cycles for fixtures announcing themselves, keeping
+/// to the hop sequence and beaconing as they do, and hand each to `found`
+fn await_announcements(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &mut Hopper,
+    timeout: u32,
+    mut found: impl FnMut(NodeId, FixtureInfo),
+) -> StandbyMode<Radio> {
+    let mut buffer = [0u8; FRAME_SIZE];
+    radio::listen_as_controller(&mut standby, ID).unwrap();
+    let mut rx = standby.rx().unwrap();
+    let start = DWT::get_cycle_count();
+    while DWT::get_cycle_count().wrapping_sub(start) < timeout {
+        // The hop task can't run until we're done, and fixtures that miss
+        // its beacons for long lose us
+        let slot = hopper.slot();
+        let channel = hopper.update(DWT::get_cycle_count(), true);
+        if hopper.slot() != slot {
+            let mut standby = rx.standby();
+            if let Some(channel) = channel {
+                standby.set_frequency(channel).unwrap();
+            }
+            if hopper.plan().hopping {
+                standby = beacon(standby, link, stats, mesh, hopper, &mut buffer);
+                radio::listen_as_controller(&mut standby, ID).unwrap();
+            }
+            rx = standby.rx().unwrap();
+        }
+        if rx.can_read().unwrap().is_none() {
+            continue;
+        }
+        let data = rx.read().unwrap();
+        // The power detector latches when a frame arrives
+        let strong = rx.has_carrier().unwrap();
+        let incoming = match mesh.receive(data.as_ref(), DWT::get_cycle_count()) {
+            Some(incoming) if incoming.deliver => incoming,
+            _ => continue,
+        };
+        let received = link.receive(incoming.frame);
+        if received.is_ok() {
+            mesh.learn(&incoming, DWT::get_cycle_count());
+        }
+        match received {
+            Ok(Frame { header, message: Message::Announce(info) }) => {
+                if let Some(peer) = stats.peer_mut(header.src) {
+                    peer.record_received(strong);
+                }
+                found(header.src, info);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                let src = peek_header(incoming.frame).map(|header| header.src);
+                if let Some(peer) = src.ok().and_then(|src| stats.peer_mut(src)) {
+                    peer.record_rejected();
+                }
+            }
+        }
+    }
+    rx.standby()
+}
+
+/// Listen up to `timeout` cycles for the next frame from `fixture`, which
+/// may come through the mesh
+fn await_reply(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    fixture: NodeId,
+    timeout: u32,
+) -> (StandbyMode<Radio>, Option<Frame>) {
+    radio::listen_as_controller(&mut standby, ID).unwrap();
+    let mut rx = standby.rx().unwrap();
+    let start = DWT::get_cycle_count();
+    let mut reply = None;
+    while reply.is_none() && DWT::get_cycle_count().wrapping_sub(start) < timeout {
+        if rx.can_read().unwrap().is_none() {
+            continue;
+        }
+        let data = rx.read().unwrap();
+        // The power detector latches when a frame arrives
+        let strong = rx.has_carrier().unwrap();
+        let incoming = match mesh.receive(data.as_ref(), DWT::get_cycle_count()) {
+            Some(incoming) if incoming.deliver => incoming,
+            _ => continue,
+        };
+        let received = link.receive(incoming.frame);
+        if received.is_ok() {
+            mesh.learn(&incoming, DWT::get_cycle_count());
+        }
+        match received {
+            Ok(frame) if frame.header.src == fixture => {
+                if let Some(peer) = stats.peer_mut(fixture) {
+                    peer.record_received(strong);
+                }
+                reply = Some(frame);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                let src = peek_header(incoming.frame).map(|header| header.src);
+                if let Some(peer) = src.ok().and_then(|src| stats.peer_mut(src)) {
+                    peer.record_rejected();
+                }
+            }
+        }
+    }
+    (rx.standby(), reply)
+}
@@ -1,5 +1,7 @@
+/* Started by the bootloader: the app slot, past the space for the image
+   header. See shared/src/flash.rs */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH : ORIGIN = 0x08002200, LENGTH = 25088
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
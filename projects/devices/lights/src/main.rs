@@ -4,16 +4,50 @@
 extern crate panic_semihosting;
 extern crate stm32f1xx_hal as hal;
 
-use cortex_m::singleton;
-use rtic::app;
+use core::convert::Infallible;
+use core::fmt::Write;
+use cortex_m::{peripheral::{DWT, SCB}, singleton};
+use embedded_hal::watchdog::Watchdog;
+use rtic::{app, Mutex};
 use stm32f1xx_hal::prelude::*;
 use rtic::cyccnt::{U32Ext as _};
-use cortex_m_semihosting::hprintln;
+use embedded_nrf24l01 as nrf;
 use hal::{
+    adc::Adc,
     dma::{dma1::C3, TxDma},
-    gpio::{gpioa, gpioa::*},
-    spi::{Mode, Phase, Polarity, Spi, SpiPayload},
+    flash::{FlashSize, SectorSize},
+    gpio::{gpioa, gpioa::*, gpiob::*, Alternate, Floating, Input, Output, PushPull},
+    pac::USART2,
+    serial::{self, Config, Rx, Serial, StopBits},
+    spi::{Mode, Phase, Polarity, Spi, Spi2NoRemap, SpiPayload},
     time::{MegaHertz},
+    watchdog::IndependentWatchdog,
+};
+use nrf::{Configuration, RxMode, StandbyMode, NRF24L01};
+use shared::{
+    address::{default_id, Destination, NodeId, MAX_FIXTURE_ID},
+    boot::{self, BootState},
+    cli::{self, Args, Command, Line},
+    crypto::Entropy,
+    dmx::{self, DmxConfig, Patch, Personality},
+    fixture::{dim, limit_power, FixtureState},
+    flash::{APP_SLOT, SETTINGS},
+    hopping::{ChannelPlan, Hopper, CHANNEL_COUNT, PLAN_SIZE},
+    image,
+    inventory::{self, Capabilities, Chipset, FixtureInfo, Layout},
+    log,
+    mesh::Mesh,
+    ota::{OtaStatus, Receiver},
+    protocol::{Effect, Frame, Message, FRAME_SIZE},
+    radio,
+    scene::{Look, LOOK_SIZE},
+    secure::{is_secure, peek_header, session_nonce, FixtureLink, SITE_KEY},
+    settings::{key, Settings},
+    status::FixtureStatus,
+    stream::Stream,
+    telemetry::LinkStats,
+    timesync::NetworkClock,
+    usb::{Log, UsbSerial, CRYSTAL},
 };
 
 use smart_leds::RGB8;
@@ -23,8 +57,45 @@ use ws2812::spi_bit_container;
 const LED_COUNT: usize = 50;
 const SYS_CLK: MegaHertz = MegaHertz(48);
 const PCLK1: MegaHertz = MegaHertz(24);
+/// How often link statistics are printed
+const STATS_INTERVAL: u32 = 30 * 48_000_000;
+/// How often the status in our ACK payload is refreshed
+const STATUS_INTERVAL: u32 = 48_000_000;
+/// Most current the LEDs may draw, in mA
+const POWER_BUDGET: u32 = 2_000;
+/// Whether we forward frames for fixtures out of the controller's range
+const RELAY: bool = cfg!(feature = "relay");
+/// What we tell whoever discovers us we can do
+const CAPABILITIES: Capabilities = Capabilities::DMX
+    .with(Capabilities::STREAM)
+    .with(Capabilities::OTA)
+    .with(Capabilities::POWER_LIMIT)
+    .with(if RELAY { Capabilities::RELAY } else { Capabilities::empty() });
+/// Delay before forwarding per node id, so neighbouring relays don't all
+/// transmit at once: 500 us at 48 MHz
+const FORWARD_STAGGER: u32 = 24_000;
+/// Network time per step of the rainbow at speed 1, in microseconds
+const HUE_STEP_US: u64 = 2_083;
+/// How long a new firmware image has to hear from the controller before we
+/// restart, so the bootloader rolls it back if it never does
+const CHECK_IN_WINDOW: u32 = 60 * 48_000_000;
+/// How long what we show has to stay the same before it's saved, so a pot
+/// being turned doesn't wear out the flash
+const SAVE_DELAY: u32 = 5 * 48_000_000;
+/// How long after the last DMX packet we go back to the radio and our own look
+const DMX_TIMEOUT: u32 = 48_000_000;
 spi_bit_container!(LedBitContainer, LED_COUNT);
 
+type RadioCe = PB0<Output<PushPull>>;
+type RadioCsn = PB1<Output<PushPull>>;
+type RadioSpi2Pins = (
+    PB13<Alternate<PushPull>>,
+    PB14<Input<Floating>>,
+    PB15<Alternate<PushPull>>,
+);
+type RadioSpi = Spi<hal::pac::SPI2, Spi2NoRemap, RadioSpi2Pins>;
+type Radio = NRF24L01<Infallible, RadioCe, RadioCsn, RadioSpi>;
+
 type SpiDma = TxDma<
     SpiPayload<
         hal::pac::SPI1,
@@ -45,16 +116,51 @@ const APP: () = {
         spi_dma: Option<SpiDma>,
         led_buffer: Option<&'static mut LedBitContainer>,
         pixels: Option<[RGB8; LED_COUNT]>,
+        clock: NetworkClock,
+        radio: Option<StandbyMode<Radio>>,
+        state: FixtureState,
+        entropy: Entropy,
+        adc: Adc<hal::pac::ADC1>,
+        #[init(false)]
+        power_limited: bool,
         #[init(0)]
-        current_hue: usize,
+        temperature: i8,
+        /// Seconds since power up
+        #[init(0)]
+        uptime: u32,
+        flash: hal::flash::Parts,
+        /// Only running while the firmware is on trial, see `shared::boot`
+        watchdog: IndependentWatchdog,
+        usb: UsbSerial,
+        /// Save the look now, rather than once it stops changing
+        #[init(false)]
+        save_look: bool,
+        /// Frames from a PC, shown over our own effect while they come
+        #[init(Stream::new())]
+        stream: Stream,
+        /// DMX512 in, see `shared::dmx`
+        dmx_serial: Rx<USART2>,
+        #[init(dmx::Receiver::new())]
+        dmx_receiver: dmx::Receiver,
+        /// Our DMX channels, shown over everything else while they come
+        patch: Patch,
+        /// Save the DMX settings now
+        #[init(false)]
+        save_dmx: bool,
+        settings: Settings,
+        /// Boots so far, see `Settings::count_boot`
+        boots: u32,
+        /// Node id to save, used from the next boot on
+        #[init(None)]
+        new_id: Option<NodeId>,
     }
 
-    #[init(schedule = [exe])]
+    #[init(schedule = [exe, housekeeping])]
     fn init(cx: init::Context) -> init::LateResources {
         let mut core = cx.core;
         // Initialize (enable) the monotonic timer (CYCCNT)
         core.DWT.enable_cycle_counter();
-        hprintln!("init @ {:?}", cx.start).unwrap();
+        log!("init @ {:?}", cx.start);
 
         // Cortex-M peripherals
         let mut rcc = cx.device.RCC.constrain();
@@ -62,11 +168,43 @@ const APP: () = {
         let mut mapr = cx.device.AFIO.constrain(&mut rcc.apb2).mapr;
         let clocks = rcc
             .cfgr
+            .use_hse(CRYSTAL)
             .sysclk(SYS_CLK)
             .pclk1(PCLK1)
             .freeze(&mut flash.acr);
 
-        hprintln!("Initialising Ws2812 LEDs").unwrap();
+        // Who we are, where to listen and what to show are in the settings
+        let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        let mut settings = Settings::mount(&mut writer, SETTINGS).expect("to read the settings");
+        // Goes in front of our pairing nonces, so they never repeat
+        let boots = settings.count_boot(&mut writer).expect("to count the boot");
+        let mut id = [0u8; 1];
+        let id = match settings.read(&mut writer, key::NODE_ID, &mut id) {
+            Ok(Some(1)) if (1..=MAX_FIXTURE_ID).contains(&id[0]) => id[0],
+            _ => default_id(unique_id()),
+        };
+        log!("fixture {}, boot {}", id, boots);
+        let mut state = FixtureState::new(id);
+        let mut plan = [0u8; PLAN_SIZE];
+        if let Ok(Some(len)) = settings.read(&mut writer, key::CHANNEL_PLAN, &mut plan) {
+            if let Ok(plan) = ChannelPlan::decode(&plan[..len]) {
+                state.channel_plan = plan;
+            }
+        }
+        // Come back up showing what we showed before losing power
+        let mut look = [0u8; LOOK_SIZE];
+        if let Ok(Some(len)) = settings.read(&mut writer, key::LOOK, &mut look) {
+            if let Ok(look) = Look::decode(&look[..len]) {
+                state.set_look(look, 0, 0);
+            }
+        }
+        let mut dmx = [0u8; dmx::CONFIG_SIZE];
+        let dmx = match settings.read(&mut writer, key::DMX, &mut dmx) {
+            Ok(Some(len)) => DmxConfig::decode(&dmx[..len]).unwrap_or_default(),
+            _ => DmxConfig::new(),
+        };
+
+        log!("Initialising Ws2812 LEDs");
 
         // Set up pins for SPI and create SPI interface
         let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
@@ -75,6 +213,20 @@ const APP: () = {
             gpioa.pa6.into_floating_input(&mut gpioa.crl),
             gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl),
         );
+        let usb = UsbSerial::new(cx.device.USB, gpioa.pa11, gpioa.pa12, &mut gpioa.crh, &clocks, "flash lights");
+
+        // DMX512 comes in on PA3 through an RS-485 transceiver, PA2 is unused
+        let dmx_pins = (gpioa.pa2.into_alternate_push_pull(&mut gpioa.crl), gpioa.pa3);
+        let mut dmx_serial = Serial::usart2(
+            cx.device.USART2,
+            dmx_pins,
+            &mut mapr,
+            Config::default().baudrate(dmx::BAUD_RATE.bps()).stopbits(StopBits::STOP2),
+            clocks,
+            &mut rcc.apb1,
+        );
+        dmx_serial.listen(serial::Event::Rxne);
+        let (_, dmx_serial) = dmx_serial.split();
         let spi_mode = Mode {
             polarity: Polarity::IdleLow,
             phase: Phase::CaptureOnFirstTransition,
@@ -89,10 +241,41 @@ const APP: () = {
             &mut rcc.apb2,
         );
 
+        log!("Setting up the radio!");
+        let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
+        let radio_pins: RadioSpi2Pins = (
+            gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh),
+            gpiob.pb14.into_floating_input(&mut gpiob.crh),
+            gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh),
+        );
+        let (ce, csn): (RadioCe, RadioCsn) = (
+            gpiob.pb0.into_push_pull_output(&mut gpiob.crl),
+            gpiob.pb1.into_push_pull_output(&mut gpiob.crl),
+        );
+        let radio_spi: RadioSpi = Spi::spi2(
+            cx.device.SPI2,
+            radio_pins,
+            nrf::setup::spi_mode(),
+            nrf::setup::clock_mhz().mhz(),
+            clocks,
+            &mut rcc.apb1,
+        );
+        let mut radio: StandbyMode<Radio> = NRF24L01::new(ce, csn, radio_spi).expect("to create a new radio interface");
+        radio::configure(&mut radio, state.channel_plan.channel).expect("to configure the radio");
+        radio::listen_as_fixture(&mut radio, id).expect("to listen as a fixture");
+
+        // Seed pairing nonces with ADC noise
+        let mut adc = Adc::adc1(cx.device.ADC1, &mut rcc.apb2, clocks);
+        let mut entropy = Entropy::new();
+        for _ in 0..64 {
+            entropy.mix(adc.read_vref() as u32 ^ DWT::get_cycle_count());
+        }
+
         let dma = cx.device.DMA1.split(&mut rcc.ahb);
         let spi_dma: SpiDma = spi.with_tx_dma(dma.3);
 
         cx.schedule.exe(cx.start).unwrap();
+        cx.schedule.housekeeping(cx.start).unwrap();
         let led_buffer = singleton!(: LedBitContainer = LedBitContainer::new());
         
 
@@ -108,21 +291,308 @@ const APP: () = {
             spi_dma: Some(result.1),
             led_buffer: singleton!(: LedBitContainer = LedBitContainer::new()),
             pixels: Some(pixels),
+            radio: Some(radio),
+            state,
+            entropy,
+            adc,
+            clock: NetworkClock::new(),
+            flash,
+            watchdog: IndependentWatchdog::new(cx.device.IWDG),
+            usb,
+            dmx_serial,
+            patch: Patch::new(dmx),
+            settings,
+            boots,
+        }
+    }
+
+    #[idle(resources = [radio, state, entropy, power_limited, temperature, uptime, clock, flash, watchdog, save_look, stream, patch, save_dmx, settings, boots, new_id])]
+    fn idle(mut cx: idle::Context) -> ! {
+        let standby = cx.resources.radio.take().expect("Radio is not available");
+        let mut rx = standby.rx().expect("Radio could not be set to receive mode");
+        let (id, plan) = cx.resources.state.lock(|state| (state.id, state.channel_plan));
+        let mut link = FixtureLink::new(id, *SITE_KEY);
+        let mut hopper = Hopper::new(plan, 0, DWT::get_cycle_count());
+        let mut buffer = [0u8; FRAME_SIZE];
+        let mut stats = LinkStats::new();
+        let mut mesh = Mesh::new(id, RELAY);
+        let mut last_dump = DWT::get_cycle_count();
+        let mut last_status = DWT::get_cycle_count();
+        let mut stale_status = true;
+        let mut flash = cx.resources.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        let mut update = Receiver::new();
+        let booted = DWT::get_cycle_count();
+        let mut checked_in = !matches!(boot::state(&mut flash), Ok(BootState::Trial { .. }));
+        // Images loaded by the debugger have no header, and no version
+        let firmware = image::read_header(&mut flash, APP_SLOT).map(|header| header.version.min(u16::MAX as u32) as u16);
+        let info = FixtureInfo {
+            uid: unique_id(),
+            firmware: firmware.unwrap_or(0),
+            leds: LED_COUNT as u16,
+            chipset: Chipset::Ws2812,
+            layout: Layout::Strip,
+            capabilities: CAPABILITIES,
+        };
+        // Who asked us to announce ourselves, their seq, and when and how long after to answer
+        let mut announce: Option<(NodeId, u8, u32, u32)> = None;
+
+        let settings = cx.resources.settings;
+        let boots = *cx.resources.boots;
+        let mut saved = [0u8; LOOK_SIZE];
+        let mut last_look = cx.resources.state.lock(|state| state.look());
+        let mut dmx = [0u8; dmx::CONFIG_SIZE];
+        let mut changed_at = None;
+
+        loop {
+            let now = DWT::get_cycle_count();
+            cx.resources.watchdog.feed();
+            if !checked_in && now.wrapping_sub(booted) >= CHECK_IN_WINDOW {
+                log!("firmware never checked in, restarting");
+                SCB::sys_reset();
+            }
+            if let Some(channel) = hopper.update(now, false) {
+                rx = retune(rx, channel);
+            }
+            // Set by the controller or the command line
+            let plan = cx.resources.state.lock(|state| state.channel_plan);
+            if plan != hopper.plan() {
+                hopper.reseed(plan, link.hop_seed().unwrap_or(0));
+                rx = retune(rx, hopper.channel());
+                if let Err(e) = settings.write(&mut flash, key::CHANNEL_PLAN, &plan.encode()) {
+                    log!("couldn't save the channel plan: {:?}", e);
+                }
+            }
+
+            let look = cx.resources.state.lock(|state| state.look());
+            if look != last_look {
+                last_look = look;
+                changed_at = Some(now);
+            }
+            let save_now = cx.resources.save_look.lock(|save| core::mem::replace(save, false));
+            if save_now || matches!(changed_at, Some(at) if now.wrapping_sub(at) >= SAVE_DELAY) {
+                let len = look.encode(&mut saved);
+                if let Err(e) = settings.write(&mut flash, key::LOOK, &saved[..len]) {
+                    log!("couldn't save the look: {:?}", e);
+                }
+                changed_at = None;
+            }
+            if cx.resources.save_dmx.lock(|save| core::mem::replace(save, false)) {
+                let len = cx.resources.patch.lock(|patch| patch.config).encode(&mut dmx);
+                if let Err(e) = settings.write(&mut flash, key::DMX, &dmx[..len]) {
+                    log!("couldn't save the DMX settings: {:?}", e);
+                }
+            }
+            if let Some(new_id) = cx.resources.new_id.lock(Option::take) {
+                if let Err(e) = settings.write(&mut flash, key::NODE_ID, &[new_id]) {
+                    log!("couldn't save the node id: {:?}", e);
+                }
+            }
+
+            if now.wrapping_sub(last_dump) >= STATS_INTERVAL {
+                dump(&stats);
+                last_dump = now;
+            }
+
+            // Every ACK we send uses up the status loaded for it
+            if stale_status || now.wrapping_sub(last_status) >= STATUS_INTERVAL {
+                let state = cx.resources.state.lock(|state| *state);
+                let status = FixtureStatus {
+                    effect: state.effect,
+                    brightness: state.brightness,
+                    power_limited: cx.resources.power_limited.lock(|limited| *limited),
+                    temperature: cx.resources.temperature.lock(|temperature| *temperature),
+                    uptime: cx.resources.uptime.lock(|uptime| *uptime),
+                };
+                radio::preload_status(&mut rx, &status).unwrap();
+                last_status = now;
+                stale_status = false;
+            }
+
+            if let Some((to, seq, since, backoff)) = announce {
+                if now.wrapping_sub(since) >= backoff {
+                    let answer = Frame::new(Destination::Node(to), id, seq, Message::Announce(info));
+                    if let Ok(len) = link.seal(&answer, &mut buffer) {
+                        rx = reply(rx, id, &mut mesh, to, &mut buffer, len, &mut stats);
+                    }
+                    announce = None;
+                }
+            }
+
+            if rx.can_read().unwrap().is_some() {
+                let data = rx.read().unwrap();
+                // The power detector latches when a frame arrives
+                let strong = rx.has_carrier().unwrap();
+                cx.resources.entropy.mix(now);
+                stale_status = true;
+
+                let incoming = match mesh.receive(data.as_ref(), now) {
+                    Some(incoming) => incoming,
+                    None => continue,
+                };
+                if let Some(forward) = incoming.forward {
+                    cortex_m::asm::delay(id as u32 * FORWARD_STAGGER);
+                    rx = send(rx, id, forward.next, forward.data(), &mut stats).0;
+                }
+                if !incoming.deliver {
+                    continue;
+                }
+
+                let received = link.receive(incoming.frame);
+                if received.is_ok() {
+                    mesh.learn(&incoming, now);
+                }
+                let src = match &received {
+                    Ok(frame) => Some(frame.header.src),
+                    Err(_) => peek_header(incoming.frame).ok().map(|header| header.src),
+                };
+                if let Some(peer) = src.and_then(|src| stats.peer_mut(src)) {
+                    if received.is_ok() {
+                        peer.record_received(strong);
+                    } else {
+                        peer.record_rejected();
+                    }
+                }
+
+                // A frame from the controller we're paired with shows this firmware works
+                if !checked_in && received.is_ok() && is_secure(incoming.frame) {
+                    boot::confirm(&mut flash).unwrap();
+                    checked_in = true;
+                    log!("firmware checked in");
+                }
+
+                match received {
+                    // Only requests made with the site key get this far
+                    Ok(Frame { header, message: Message::PairRequest { nonce, .. } }) => {
+                        let response = link.pair(header.src, &nonce, session_nonce(boots, cx.resources.entropy.nonce()));
+                        let len = response.encode(&mut buffer);
+                        rx = reply(rx, id, &mut mesh, header.src, &mut buffer, len, &mut stats);
+                        hopper.reseed(hopper.plan(), link.hop_seed().unwrap());
+                        log!("paired with {}", header.src);
+                    }
+                    Ok(Frame { header, message: Message::StatusQuery }) => {
+                        let report = stats.peer(header.src).copied().unwrap_or_default().report();
+                        let status = Frame::new(Destination::Node(header.src), id, header.seq, Message::LinkStatus(report));
+                        if let Ok(len) = link.seal(&status, &mut buffer) {
+                            rx = reply(rx, id, &mut mesh, header.src, &mut buffer, len, &mut stats);
+                        }
+                    }
+                    Ok(Frame { header, message: Message::Discover { window } }) => {
+                        // Answer a random part of the window later, so every
+                        // fixture doesn't talk at once
+                        let nonce = cx.resources.entropy.nonce();
+                        let random = u32::from_le_bytes([nonce[0], nonce[1], nonce[2], nonce[3]]);
+                        let backoff = inventory::backoff(window, random, 48_000);
+                        announce = Some((header.src, header.seq, now, backoff));
+                    }
+                    Ok(Frame { header, message: Message::OtaBegin { size } }) => {
+                        log!("receiving a {} byte firmware image", size);
+                        let status = match update.begin(&mut flash, size) {
+                            Ok(()) => OtaStatus::Ready,
+                            Err(e) => OtaStatus::Failed(e),
+                        };
+                        let answer = Frame::new(Destination::Node(header.src), id, header.seq, Message::OtaStatus(status));
+                        if let Ok(len) = link.seal(&answer, &mut buffer) {
+                            rx = reply(rx, id, &mut mesh, header.src, &mut buffer, len, &mut stats);
+                        }
+                    }
+                    Ok(Frame { message: Message::OtaChunk { index, len, data }, .. }) => {
+                        if let Err(e) = update.chunk(&mut flash, index, &data[..len as usize]) {
+                            log!("dropped firmware chunk {}: {:?}", index, e);
+                        }
+                    }
+                    Ok(Frame { header, message: Message::OtaEnd }) => {
+                        let status = update.finish(&mut flash, SITE_KEY);
+                        let answer = Frame::new(Destination::Node(header.src), id, header.seq, Message::OtaStatus(status));
+                        if let Ok(len) = link.seal(&answer, &mut buffer) {
+                            rx = reply(rx, id, &mut mesh, header.src, &mut buffer, len, &mut stats);
+                        }
+                        match status {
+                            OtaStatus::Accepted => {
+                                log!("firmware image accepted, restarting to install it");
+                                SCB::sys_reset();
+                            }
+                            OtaStatus::Failed(e) => log!("firmware image refused: {:?}", e),
+                            _ => {}
+                        }
+                    }
+                    Ok(Frame { message: Message::TimeSync { time }, .. }) => {
+                        cx.resources.clock.lock(|clock| clock.synchronise(now, time));
+                    }
+                    Ok(Frame { message: Message::HopSync { slot, elapsed }, .. }) => {
+                        if let Some(channel) = hopper.sync(now, slot, elapsed) {
+                            rx = retune(rx, channel);
+                        }
+                        // Fixtures out of the controller's range may be waiting
+                        // for a beacon on the rendezvous channel too
+                        if let (Some(forward), true) = (incoming.forward, hopper.is_rendezvous_slot()) {
+                            let mut standby = rx.standby();
+                            standby.set_frequency(hopper.plan().channel).unwrap();
+                            rx = send(standby.rx().unwrap(), id, forward.next, forward.data(), &mut stats).0;
+                            rx = retune(rx, hopper.channel());
+                        }
+                    }
+                    Ok(frame) => {
+                        // Fades start at the same network time on every fixture
+                        let time = cx.resources.clock.lock(|clock| clock.now(now));
+                        if cx.resources.state.lock(|state| state.handle(&frame, time)) {
+                            cx.resources.stream.lock(|stream| stream.apply(&frame.message, time));
+                        }
+                    }
+                    Err(e) => {
+                        log!("dropped frame: {:?}", e);
+                    }
+                }
+            }
         }
     }
 
-    #[task(schedule = [exe], resources = [spi_dma, led_buffer, pixels, current_hue])]
-    fn exe(cx: exe::Context) {
+    /// Count uptime and read the die temperature, once a second
+    #[task(resources = [adc, temperature, uptime], schedule = [housekeeping])]
+    fn housekeeping(cx: housekeeping::Context) {
+        *cx.resources.uptime += 1;
+        let temperature = cx.resources.adc.read_temp();
+        *cx.resources.temperature = temperature.max(i8::MIN as i32).min(i8::MAX as i32) as i8;
+        cx.schedule.housekeeping(cx.scheduled + 48_000_000.cycles()).unwrap();
+    }
+
+    #[task(schedule = [exe], resources = [spi_dma, led_buffer, pixels, clock, state, power_limited, stream, patch])]
+    fn exe(mut cx: exe::Context) {
 
         let leds = cx.resources.led_buffer.take().unwrap(); 
         let spi_dma: SpiDma = cx.resources.spi_dma.take().unwrap();
         let mut color = cx.resources.pixels.take().unwrap();
 
-        let current_hue = *cx.resources.current_hue;
-        for i in 0..LED_COUNT {
-            color[i] = wheel((current_hue + i) as u8 & 255);
+        // Run off the network time, so every fixture shows the same hue
+        let now = DWT::get_cycle_count();
+        let time = cx.resources.clock.now(now);
+        // A desk on the cable wins over the radio
+        let dmx = cx.resources.patch.lock(|patch| if patch.check(now, DMX_TIMEOUT) { Some(*patch) } else { None });
+        let mut state = *cx.resources.state;
+        if let Some(look) = dmx.as_ref().and_then(|patch| patch.look()) {
+            state.set_look(look, 0, time);
+        }
+        let current_hue = ((time / HUE_STEP_US) * state.speed as u64) as usize;
+        let (effect, brightness, solid) = state.output(time);
+        // Streamed frames and DMX pixels are shown as they are
+        if let Some(pixels) = dmx.as_ref().and_then(|patch| patch.pixels()) {
+            for (pixel, level) in color.iter_mut().zip(pixels) {
+                *pixel = level;
+            }
+        } else if let Some(pixels) = cx.resources.stream.pixels(time) {
+            for (pixel, streamed) in color.iter_mut().zip(pixels) {
+                *pixel = streamed;
+            }
+        } else {
+            for i in 0..LED_COUNT {
+                color[i] = dim(match effect {
+                    Effect::Off => RGB8::new(0, 0, 0),
+                    Effect::Solid => solid,
+                    Effect::Rainbow => wheel(current_hue.wrapping_add(i) as u8),
+                }, brightness);
+            }
         }
-        //let _ = hprintln!("{}", color[0]);
+        *cx.resources.power_limited = limit_power(&mut color, POWER_BUDGET);
+        //let _ = log!("{}", color[0]);
         led_spi_bit_pattern(&color, &mut leds.data);
         let tx = spi_dma.write(leds);
         let result: TransferResult = tx.wait();        
@@ -130,16 +600,295 @@ const APP: () = {
         *cx.resources.led_buffer = Some(result.0);
         *cx.resources.spi_dma = Some(result.1);
         *cx.resources.pixels = Some(color);
-        *cx.resources.current_hue = current_hue + 1;
 
         cx.schedule.exe(cx.scheduled + 100_000.cycles()).unwrap();
     }
 
+    /// Put DMX packets back together a byte at a time, keeping our channels
+    #[task(binds = USART2, priority = 3, resources = [dmx_serial, dmx_receiver, patch])]
+    fn dmx_in(cx: dmx_in::Context) {
+        let receiver = cx.resources.dmx_receiver;
+        let levels = match cx.resources.dmx_serial.read() {
+            Ok(byte) => receiver.on_byte(byte),
+            // A break reads as a zero without its stop bits
+            Err(nb::Error::Other(serial::Error::Framing)) => receiver.on_break(),
+            Err(nb::Error::Other(_)) => {
+                receiver.on_error();
+                None
+            }
+            Err(nb::Error::WouldBlock) => None,
+        };
+        if let Some(levels) = levels {
+            cx.resources.patch.update(levels, DWT::get_cycle_count());
+        }
+    }
+
+    /// Send the log out over USB
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb])]
+    fn usb_tx(cx: usb_tx::Context) {
+        cx.resources.usb.poll();
+    }
+
+    /// Hand lines that come in over USB to [`shell`]
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb], spawn = [shell])]
+    fn usb_rx(cx: usb_rx::Context) {
+        let usb = cx.resources.usb;
+        usb.poll();
+        while let Some(line) = usb.read_line() {
+            if cx.spawn.shell(line).is_err() {
+                log!("error: busy");
+            }
+        }
+    }
+
+    /// Carry out a command line, next to the tasks that show the look
+    #[task(capacity = 2, resources = [state, clock, temperature, uptime, power_limited, save_look, patch, save_dmx, new_id])]
+    fn shell(cx: shell::Context, line: Line) {
+        let mut patch = cx.resources.patch;
+        let (mut dmx, receiving) = patch.lock(|patch| (patch.config, patch.check(DWT::get_cycle_count(), DMX_TIMEOUT)));
+        let mut shell = Shell {
+            state: cx.resources.state,
+            now: cx.resources.clock.now(DWT::get_cycle_count()),
+            temperature: *cx.resources.temperature,
+            uptime: *cx.resources.uptime,
+            power_limited: *cx.resources.power_limited,
+            save_look: cx.resources.save_look,
+            dmx: &mut dmx,
+            receiving,
+            save_dmx: cx.resources.save_dmx,
+            new_id: cx.resources.new_id,
+        };
+        cli::run(Shell::COMMANDS, &mut shell, line.as_str(), &mut Log);
+        patch.lock(|patch| patch.config = dmx);
+    }
+
     extern "C" {
         fn EXTI0();
     }
 };
 
+/// What commands from USB work on
+struct Shell<'a> {
+    state: &'a mut FixtureState,
+    /// Network time, for fades
+    now: u64,
+    temperature: i8,
+    uptime: u32,
+    power_limited: bool,
+    save_look: &'a mut bool,
+    dmx: &'a mut DmxConfig,
+    /// DMX packets are coming in
+    receiving: bool,
+    save_dmx: &'a mut bool,
+    /// Node id for `idle` to save
+    new_id: &'a mut Option<NodeId>,
+}
+
+impl<'a> Shell<'a> {
+    const COMMANDS: &'a [Command<Self>] = &[
+        Command { name: "status", usage: "", run: Self::status },
+        Command { name: "set color", usage: "<red> <green> <blue>", run: Self::set_color },
+        Command { name: "set brightness", usage: "<brightness>", run: Self::set_brightness },
+        Command { name: "effect", usage: "<name> [speed=<speed>]", run: Self::effect },
+        Command { name: "node id", usage: "<id>", run: Self::node_id },
+        Command { name: "radio channel", usage: "<channel>", run: Self::radio_channel },
+        Command { name: "radio hopping", usage: "<rendezvous channel>", run: Self::radio_hopping },
+        Command { name: "dmx address", usage: "<channel>", run: Self::dmx_address },
+        Command { name: "dmx personality", usage: "<pixels|look>", run: Self::dmx_personality },
+        Command { name: "config save", usage: "", run: Self::save },
+        Command { name: "reboot", usage: "", run: cli::reboot },
+    ];
+
+    fn status(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        let look = self.state.look();
+        let plan = self.state.channel_plan;
+        match *self.new_id {
+            Some(id) => writeln!(out, "fixture {}, {} after a restart", self.state.id, id),
+            None => writeln!(out, "fixture {}", self.state.id),
+        }
+        .ok();
+        writeln!(
+            out,
+            "{} speed {} brightness {} color {} {} {}",
+            look.effect.name(),
+            look.speed,
+            look.brightness,
+            look.color.r,
+            look.color.g,
+            look.color.b
+        )
+        .ok();
+        let hopping = if plan.hopping { "hopping, rendezvous on" } else { "fixed on" };
+        writeln!(out, "{} channel {}", hopping, plan.channel).ok();
+        writeln!(out, "up {} s, {} C", self.uptime, self.temperature).ok();
+        if self.power_limited {
+            writeln!(out, "dimmed to stay within {} mA", POWER_BUDGET).ok();
+        }
+        let signal = if self.receiving { "receiving" } else { "no signal" };
+        writeln!(out, "dmx {} from channel {}, {}", self.dmx.personality.name(), self.dmx.address, signal).ok();
+        Ok(())
+    }
+
+    fn set_color(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let color = args.color()?;
+        args.finish()?;
+        self.show(Look { color, ..self.state.look() });
+        Ok(())
+    }
+
+    fn set_brightness(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let brightness = args.parse("brightness")?;
+        args.finish()?;
+        self.show(Look { brightness, ..self.state.look() });
+        Ok(())
+    }
+
+    fn effect(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let effect = Effect::from_name(args.word("effect")?).ok_or(cli::Error::Invalid("effect"))?;
+        let look = self.state.look();
+        let speed = args.option("speed")?.unwrap_or(look.speed);
+        args.finish()?;
+        self.show(Look { effect, speed, ..look });
+        Ok(())
+    }
+
+    /// Take another node id from the next restart on, as the radio, the
+    /// session and the mesh all go by it
+    fn node_id(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let id = args.parse("id")?;
+        args.finish()?;
+        if id == 0 || id > MAX_FIXTURE_ID {
+            return Err(cli::Error::Invalid("id"));
+        }
+        *self.new_id = Some(id);
+        Ok(())
+    }
+
+    /// Stop following the controller's hop sequence and stay on one channel
+    fn radio_channel(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        self.state.channel_plan = ChannelPlan::fixed(channel(args)?);
+        Ok(())
+    }
+
+    /// Follow the controller's hop sequence, waiting for it on the rendezvous
+    /// channel when lost
+    fn radio_hopping(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        self.state.channel_plan = ChannelPlan::hopping(channel(args)?);
+        Ok(())
+    }
+
+    /// Set our first DMX channel
+    fn dmx_address(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let address = args.parse("channel")?;
+        args.finish()?;
+        self.patch(DmxConfig { address, ..*self.dmx }, "channel")
+    }
+
+    fn dmx_personality(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let personality = Personality::from_name(args.word("personality")?).ok_or(cli::Error::Invalid("personality"))?;
+        args.finish()?;
+        self.patch(DmxConfig { personality, ..*self.dmx }, "personality")
+    }
+
+    /// Listen to other DMX channels, if all of them fit in the universe
+    fn patch(&mut self, config: DmxConfig, what: &'static str) -> Result<(), cli::Error> {
+        let last = config.address as usize + config.personality.footprint(LED_COUNT) - 1;
+        if config.address == 0 || last > dmx::UNIVERSE_SIZE {
+            return Err(cli::Error::Invalid(what));
+        }
+        *self.dmx = config;
+        *self.save_dmx = true;
+        Ok(())
+    }
+
+    fn save(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        *self.save_look = true;
+        Ok(())
+    }
+
+    fn show(&mut self, look: Look) {
+        self.state.set_look(look, 0, self.now);
+    }
+}
+
+/// The one argument of the radio commands
+fn channel(args: &mut Args) -> Result<u8, cli::Error> {
+    let channel = args.parse("channel")?;
+    args.finish()?;
+    if channel >= CHANNEL_COUNT {
+        return Err(cli::Error::Invalid("channel"));
+    }
+    Ok(channel)
+}
+
+/// Move the receiver to another channel
+fn retune(rx: RxMode<Radio>, channel: u8) -> RxMode<Radio> {
+    let mut standby = rx.standby();
+    standby.set_frequency(channel).unwrap();
+    standby.rx().unwrap()
+}
+
+/// Put `data` on air for `dest`, then go back to listening as `id`
+fn send(rx: RxMode<Radio>, id: NodeId, dest: Destination, data: &[u8], stats: &mut LinkStats) -> (RxMode<Radio>, bool) {
+    let mut standby = rx.standby();
+    radio::address_to(&mut standby, dest).unwrap();
+    // Don't send the status waiting for our next ACK
+    standby.flush_tx().unwrap();
+    let mut tx = standby.tx().unwrap();
+    let sent = radio::transmit(&mut tx, dest, data, stats).unwrap();
+    let mut standby = tx.standby().unwrap();
+    radio::listen_as_fixture(&mut standby, id).unwrap();
+    (standby.rx().unwrap(), sent)
+}
+
+/// Send the first `len` bytes of `buffer` back to `to`, flooding it through
+/// the mesh if it's out of range
+fn reply(
+    rx: RxMode<Radio>,
+    id: NodeId,
+    mesh: &mut Mesh,
+    to: NodeId,
+    buffer: &mut [u8; FRAME_SIZE],
+    len: usize,
+    stats: &mut LinkStats,
+) -> RxMode<Radio> {
+    let len = mesh.stamp(buffer, len);
+    let dest = mesh.next_hop(Destination::Node(to), DWT::get_cycle_count());
+    let (rx, sent) = send(rx, id, dest, &buffer[..len], stats);
+    if sent {
+        return rx;
+    }
+    mesh.forget(to);
+    let (rx, sent) = send(rx, id, Destination::Broadcast, &buffer[..len], stats);
+    if !sent {
+        log!("error transmitting");
+    }
+    rx
+}
+
+/// The microcontroller's 96 bit unique id, folded into 64 bits
+fn unique_id() -> u64 {
+    // Programmed at the factory, see the device electronic signature in the
+    // reference manual
+    let words = unsafe { core::ptr::read_volatile(0x1FFF_F7E8 as *const [u32; 3]) };
+    (words[0] as u64) << 32 | (words[1] ^ words[2]) as u64
+}
+
+/// Print link statistics for every peer, and the channels frames were lost on
+fn dump(stats: &LinkStats) {
+    for (id, peer) in stats.peers() {
+        log!("link {}: {}", id, peer);
+    }
+    for channel in 0..CHANNEL_COUNT {
+        let losses = stats.channel_losses(channel);
+        if losses > 0 {
+            log!("channel {}: {} lost", channel, losses);
+        }
+    }
+}
+
 pub fn led_spi_bit_pattern(
     leds: &[RGB8],
     mut output: &mut [u8]
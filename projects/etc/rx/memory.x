@@ -1,4 +1,5 @@
-/* Linker script for the STM32F103C8T6 */
+/* Linker script for the STM32F103C8T6. Bench tools are flashed without the
+   bootloader, so they get all of flash */
 MEMORY
 {
   FLASH : ORIGIN = 0x08000000, LENGTH = 64K
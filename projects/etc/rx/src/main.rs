@@ -4,6 +4,7 @@
 extern crate panic_semihosting;
 
 use core::convert::Infallible;
+use core::fmt::Write;
 use embedded_nrf24l01 as nrf;
 use hal::{
     gpio::{gpiob::*, Alternate, Floating, Input, Output, PushPull, PullUp},
@@ -13,13 +14,23 @@ use rtic::cyccnt::{U32Ext as _};
 use rtic::app;
 use stm32f1::stm32f103::SPI2;
 use stm32f1xx_hal as hal;
-use cortex_m_semihosting::hprintln;
 use hal::{
     spi::{Spi, Spi2NoRemap},
     time::MegaHertz,
 };
 
-use nrf::{Configuration, CrcMode, DataRate, StandbyMode, NRF24L01, RxMode};
+use nrf::{Configuration, StandbyMode, NRF24L01};
+use shared::{
+    address::NodeId,
+    cli::{self, Args, Command},
+    hopping::{CHANNEL_COUNT, DEFAULT_CHANNEL},
+    mesh,
+    protocol::{Frame, FRAME_SIZE},
+    radio,
+    log,
+    scan::Survey,
+    usb::{Log, UsbSerial, CRYSTAL},
+};
 
 type RadioCe = PB0<Output<PushPull>>;
 type RadioCsn = PB1<Output<PushPull>>;
@@ -32,10 +43,10 @@ type RadioSpi2Pins = (
 type RadioSpi = Spi<SPI2, Spi2NoRemap, RadioSpi2Pins>;
 type Radio = NRF24L01<Infallible, RadioCe, RadioCsn, RadioSpi>;
 
-// From https://github.com/davidji/rust-rc
-pub const TX_ADDRESS: [u8; 5] = ['R' as u8, 'C' as u8, 'T' as u8, 'X' as u8, 0x00];
-pub const RX_ADDRESS: [u8; 5] = ['R' as u8, 'C' as u8, 'R' as u8, 'X' as u8, 0x00];
-pub const BUFFER_SIZE: usize = 32;
+/// Listen like the first fixture would
+pub const ID: NodeId = 1;
+/// How often each channel is sampled per sweep in scanner mode
+pub const SCAN_ROUNDS: u16 = 100;
 
 const FREQ: u32 = 48;
 const SYSCLK_FREQ: MegaHertz = MegaHertz(FREQ);
@@ -45,29 +56,43 @@ const PCLK1_FREQ: MegaHertz = MegaHertz(FREQ / 2);
 const APP: () = {
     struct Resources {
         radio: Option<StandbyMode<Radio>>,
-        buffer: Option<[u8; BUFFER_SIZE]>,
+        buffer: Option<[u8; FRAME_SIZE]>,
+        usb: UsbSerial,
+        /// Channel to listen on, set on the command line
+        #[init(DEFAULT_CHANNEL)]
+        channel: u8,
+        /// Frames heard
+        #[init(0)]
+        frames: u32,
+        /// Frames heard that didn't decode
+        #[init(0)]
+        dropped: u32,
         // irq: Option<RadioIrq>,
     }
 
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
-        hprintln!("Initializing device!").unwrap();
+        log!("Initializing device!");
         // Enable the monotonic timer
         let mut core = cx.core;
         core.DWT.enable_cycle_counter();
         // let _ = cx.start;
 
-        hprintln!("Setting up peripherals!").unwrap();
+        log!("Setting up peripherals!");
         let mut rcc = cx.device.RCC.constrain();
         let mut flash = cx.device.FLASH.constrain();
         let _mapr = cx.device.AFIO.constrain(&mut rcc.apb2).mapr;
         let clocks = rcc
             .cfgr
+            .use_hse(CRYSTAL)
             .sysclk(SYSCLK_FREQ)
             .pclk1(PCLK1_FREQ)
             .freeze(&mut flash.acr);
 
-        hprintln!("Setting up SPI!").unwrap();
+        let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
+        let usb = UsbSerial::new(cx.device.USB, gpioa.pa11, gpioa.pa12, &mut gpioa.crh, &clocks, "flash rx");
+
+        log!("Setting up SPI!");
         let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
 
         let spi_pins: RadioSpi2Pins = (
@@ -91,7 +116,7 @@ const APP: () = {
             &mut rcc.apb1,
         );
 
-        hprintln!("Setting up the radio!").unwrap();
+        log!("Setting up the radio!");
         
         // Set up interrupt
         // let mut irq = gpiob.pb10.into_pull_up_input();
@@ -102,47 +127,136 @@ const APP: () = {
         // Create radio
         let mut radio: StandbyMode<Radio> = NRF24L01::new(ce, csn, spi).expect("to create a new radio interface");
 
-        radio.set_frequency(FREQ as u8).expect("to set frequency");
-        radio.set_rx_addr(0, &RX_ADDRESS).expect("to set address");
-        radio.set_tx_addr(&RX_ADDRESS).expect("to set tx address");
-        radio.set_rf(DataRate::R250Kbps, 0).expect("to set frequency");
-        radio.set_auto_retransmit(0b0100, 15).expect("to set retransmit");
-        radio.set_auto_ack(&[true; 6]).unwrap();
-        radio.set_crc(Some(CrcMode::TwoBytes)).expect("to set crc mode");
-        radio.set_pipes_rx_lengths(&[None; 6]).expect("to set pipes length");
-        radio.set_pipes_rx_enable(&[true, false, false, false, false, false]).unwrap();
-        radio.flush_tx().expect("to flush tx");
-        radio.flush_rx().expect("to flush rx");
+        // We don't follow the hop sequence, so only rendezvous beacons and
+        // fixed channel traffic show up here
+        radio::configure(&mut radio, DEFAULT_CHANNEL).expect("to configure the radio");
+        radio::listen_as_fixture(&mut radio, ID).expect("to listen as a fixture");
         
         radio.set_interrupt_mask(true, true, true).unwrap();
         radio.set_interrupt_mask(false, false, false).unwrap();
         radio.clear_interrupts().unwrap();
 
-        hprintln!("Beginning to receive transmissions!").unwrap();
+        log!("Beginning to receive transmissions!");
         
         init::LateResources {
             radio: Some(radio),
-            buffer: Some([0u8; 32]),
+            buffer: Some([0u8; FRAME_SIZE]),
+            usb,
         }
     }
 
-    #[idle(resources = [radio])]
-    fn idle(cx: idle::Context) -> ! {
+    #[idle(resources = [radio, channel, frames, dropped])]
+    fn idle(mut cx: idle::Context) -> ! {
         let nrf = cx.resources.radio.take().expect("Radio is not available");
-        let rx = &mut nrf.rx().expect("Radio could not be set to receive mode");
+        if cfg!(feature = "scanner") {
+            scan(nrf);
+        }
+        let mut rx = nrf.rx().expect("Radio could not be set to receive mode");
+        let mut tuned = DEFAULT_CHANNEL;
 
         loop {
+            let channel = cx.resources.channel.lock(|channel| *channel);
+            if channel != tuned {
+                let mut standby = rx.standby();
+                standby.set_frequency(channel).unwrap();
+                rx = standby.rx().unwrap();
+                tuned = channel;
+                log!("listening on channel {}", channel);
+            }
+
             let pipe = rx.can_read().unwrap();
 
-            if pipe.is_some() {
+            if let Some(pipe) = pipe {
                 let data = rx.read().unwrap();
-                hprintln!("{:?}", data.as_ref());
+                let (data, trailer) = match mesh::split(data.as_ref()) {
+                    Some(split) => split,
+                    None => {
+                        log!("pipe {}: runt {:?}", pipe, data.as_ref());
+                        cx.resources.dropped.lock(|dropped| *dropped += 1);
+                        continue;
+                    }
+                };
+                cx.resources.frames.lock(|frames| *frames += 1);
+                match Frame::decode(data) {
+                    Ok(frame) => log!("pipe {}: {:?} {:?}", pipe, frame, trailer),
+                    Err(e) => {
+                        log!("pipe {}: {:?} {:?} {:?}", pipe, e, data, trailer);
+                        cx.resources.dropped.lock(|dropped| *dropped += 1);
+                    }
+                }
                 // cx.resources.LED.toggle().unwrap();
             }
         }
     }
 
+    /// Send the log out over USB
+    #[task(binds = USB_HP_CAN_TX, resources = [usb])]
+    fn usb_tx(cx: usb_tx::Context) {
+        cx.resources.usb.poll();
+    }
+
+    /// Carry out command lines that come in over USB
+    #[task(binds = USB_LP_CAN_RX0, resources = [usb, channel, frames, dropped])]
+    fn usb_rx(cx: usb_rx::Context) {
+        let usb = cx.resources.usb;
+        usb.poll();
+        while let Some(line) = usb.read_line() {
+            let mut shell = Shell {
+                channel: cx.resources.channel,
+                frames: *cx.resources.frames,
+                dropped: *cx.resources.dropped,
+            };
+            cli::run(Shell::COMMANDS, &mut shell, line.as_str(), &mut Log);
+        }
+    }
+
     extern "C" {
         fn EXTI0();
     }
 };
+
+/// What commands from USB work on
+struct Shell<'a> {
+    channel: &'a mut u8,
+    frames: u32,
+    dropped: u32,
+}
+
+impl<'a> Shell<'a> {
+    const COMMANDS: &'a [Command<Self>] = &[
+        Command { name: "status", usage: "", run: Self::status },
+        Command { name: "radio channel", usage: "<channel>", run: Self::radio_channel },
+        Command { name: "reboot", usage: "", run: cli::reboot },
+    ];
+
+    fn status(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        writeln!(out, "listening as fixture {} on channel {}", ID, self.channel).ok();
+        writeln!(out, "{} frames, {} dropped", self.frames, self.dropped).ok();
+        Ok(())
+    }
+
+    fn radio_channel(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let channel = args.parse("channel")?;
+        args.finish()?;
+        if channel >= CHANNEL_COUNT {
+            return Err(cli::Error::Invalid("channel"));
+        }
+        *self.channel = channel;
+        Ok(())
+    }
+}
+
+/// Survey the spectrum forever, printing a histogram after every sweep
+fn scan(mut standby: StandbyMode<Radio>) -> ! {
+    log!("Scanning all channels, {} samples each", SCAN_ROUNDS);
+    let mut survey = Survey::new();
+    loop {
+        survey.clear();
+        standby = radio::survey(standby, &mut survey, SCAN_ROUNDS);
+        for channel in 0..CHANNEL_COUNT {
+            log!("{}", survey.row(channel));
+        }
+        log!("quietest channel: {}", survey.quietest_channel());
+    }
+}
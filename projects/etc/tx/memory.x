@@ -1,6 +1,8 @@
-/* Linker script for the STM32F103C8T6 */
+/* Linker script for the STM32F103C8T6. Bench tools are flashed without the
+   bootloader, so they get all of flash but the settings pages at the end,
+   see shared/src/flash.rs */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 60K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
@@ -4,6 +4,8 @@
 extern crate panic_semihosting;
 
 use core::convert::Infallible;
+use core::fmt::Write;
+use cortex_m::peripheral::DWT;
 use embedded_nrf24l01 as nrf;
 use hal::{
     gpio::{gpiob::*, Alternate, Floating, Input, Output, PushPull},
@@ -13,13 +15,36 @@ use rtic::cyccnt::{U32Ext as _};
 use rtic::app;
 use stm32f1::stm32f103::SPI2;
 use stm32f1xx_hal as hal;
-use cortex_m_semihosting::hprintln;
 use hal::{
+    adc::Adc,
+    flash::{FlashSize, SectorSize},
     spi::{Spi, Spi2NoRemap},
     time::MegaHertz,
 };
 
-use nrf::{Configuration, CrcMode, DataRate, StandbyMode, NRF24L01};
+use nrf::{Configuration, StandbyMode, NRF24L01};
+use shared::{
+    address::{Destination, GroupId, NodeId, CONTROLLER_ID},
+    cli::{self, Args, Command, Line},
+    crypto::Entropy,
+    dongle::{Outcome, Packet},
+    flash::SETTINGS,
+    hopping::{ChannelPlan, Hopper, CHANNEL_COUNT, DEFAULT_CHANNEL, DWELL, PLAN_SIZE},
+    image::ImageHeader,
+    inventory::MAX_DISCOVER_WINDOW,
+    log,
+    mesh::Mesh,
+    ota::{OtaStatus, Sender},
+    protocol::{Effect, Frame, Header, Message, FRAME_SIZE},
+    radio,
+    scan::Survey,
+    secure::{peek_header, session_nonce, ControllerLink, SITE_KEY},
+    settings::{key, Settings},
+    telemetry::LinkStats,
+    timesync::{LocalClock, SYNC_INTERVAL},
+    usb::{self, Log, UsbSerial, CRYSTAL},
+};
+use smart_leds::RGB8;
 
 type RadioCe = PB0<Output<PushPull>>;
 type RadioCsn = PB1<Output<PushPull>>;
@@ -31,10 +56,34 @@ type RadioSpi2Pins = (
 type RadioSpi = Spi<SPI2, Spi2NoRemap, RadioSpi2Pins>;
 type Radio = NRF24L01<Infallible, RadioCe, RadioCsn, RadioSpi>;
 
-// From https://github.com/davidji/rust-rc
-pub const TX_ADDRESS: [u8; 5] = ['R' as u8, 'C' as u8, 'T' as u8, 'X' as u8, 0x00];
-pub const RX_ADDRESS: [u8; 5] = ['R' as u8, 'C' as u8, 'R' as u8, 'X' as u8, 0x00];
-pub const BUFFER_SIZE: usize = 32;
+pub const ID: NodeId = CONTROLLER_ID;
+/// Fixtures we pair with, so the host can reach them through us
+pub const FIXTURES: [NodeId; 4] = [1, 2, 3, 4];
+/// Fixture the demo sequence talks to directly
+pub const FIXTURE_ID: NodeId = 1;
+/// Groups by name, as the fixtures only know their ids
+pub const GROUPS: [(&str, u8); 2] = [("stage left", 0), ("stage right", 1)];
+/// How often group and broadcast frames are sent, as nobody acknowledges them
+pub const MULTICAST_REPEATS: usize = 3;
+/// How long to wait for a fixture to answer a request, in cycles
+pub const REPLY_TIMEOUT: u32 = FREQ * 100_000;
+/// How often each channel is sampled when picking the rendezvous channel
+pub const SURVEY_ROUNDS: u16 = 20;
+/// How often link statistics are printed and the fixture asked for its own
+pub const STATUS_INTERVAL: u32 = FREQ * 30_000_000;
+/// How long a fixture may take to erase its staging slot or check an image
+pub const OTA_REPLY_TIMEOUT: u32 = FREQ * 2_000_000;
+/// Firmware chunks sent in a row, before the other tasks get a turn
+pub const OTA_BURST: usize = 8;
+/// Time between bursts of firmware chunks
+pub const OTA_INTERVAL: u32 = FREQ * 5_000;
+
+/// Firmware image to push to the fixture, built with `--features ota` and
+/// the absolute path of the raw binary in `OTA_IMAGE`
+#[cfg(feature = "ota")]
+const OTA_IMAGE: Option<&[u8]> = Some(include_bytes!(env!("OTA_IMAGE")));
+#[cfg(not(feature = "ota"))]
+const OTA_IMAGE: Option<&[u8]> = None;
 
 const FREQ: u32 = 48;
 const SYSCLK_FREQ: MegaHertz = MegaHertz(FREQ);
@@ -44,27 +93,47 @@ const PCLK1_FREQ: MegaHertz = MegaHertz(FREQ / 2);
 const APP: () = {
     struct Resources {
         radio: Option<StandbyMode<Radio>>,
-        buffer: Option<[u8; BUFFER_SIZE]>,
+        buffer: Option<[u8; FRAME_SIZE]>,
+        #[init(0)]
+        seq: u8,
+        link: ControllerLink,
+        hopper: Hopper,
+        stats: LinkStats,
+        mesh: Mesh,
+        /// Our clock is the network time
+        clock: LocalClock,
+        /// The firmware update in progress
+        update: Option<Sender<'static>>,
+        usb: UsbSerial,
+        flash: hal::flash::Parts,
+        settings: Settings,
+        /// Whether the demo sequence is running
+        #[init(false)]
+        demo: bool,
     }
-    #[init(spawn = [transmit])]
+    #[init(spawn = [transmit, hop, time_sync, update], schedule = [status])]
     fn init(cx: init::Context) -> init::LateResources {
-        hprintln!("Initializing device!").unwrap();
+        log!("Initializing device!");
         // Enable the monotonic timer
         let mut core = cx.core;
         core.DWT.enable_cycle_counter();
         let _ = cx.start;
 
-        hprintln!("Setting up peripherals!").unwrap();
+        log!("Setting up peripherals!");
         let mut rcc = cx.device.RCC.constrain();
         let mut flash = cx.device.FLASH.constrain();
         let _mapr = cx.device.AFIO.constrain(&mut rcc.apb2).mapr;
         let clocks = rcc
             .cfgr
+            .use_hse(CRYSTAL)
             .sysclk(SYSCLK_FREQ)
             .pclk1(PCLK1_FREQ)
             .freeze(&mut flash.acr);
 
-        hprintln!("Setting up SPI!").unwrap();
+        let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
+        let usb = UsbSerial::new(cx.device.USB, gpioa.pa11, gpioa.pa12, &mut gpioa.crh, &clocks, "flash tx");
+
+        log!("Setting up SPI!");
         let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
 
         let spi_pins: RadioSpi2Pins = (
@@ -88,69 +157,771 @@ const APP: () = {
             &mut rcc.apb1,
         );
 
-        hprintln!("Setting up the radio!").unwrap();
+        log!("Setting up the radio!");
         let mut radio: StandbyMode<Radio> = NRF24L01::new(ce, csn, spi).expect("to create a new radio interface");
 
-        radio.set_frequency(FREQ as u8).expect("to set frequency");
-        radio.set_rx_addr(0, &RX_ADDRESS).expect("to set address");
-        radio.set_tx_addr(&RX_ADDRESS).expect("to set tx address");
-        radio.set_rf(DataRate::R250Kbps, 0).expect("to set frequency");
-        radio.set_auto_retransmit(0b0100, 15).expect("to set retransmit");
-        radio.set_crc(Some(CrcMode::TwoBytes)).expect("to set crc mode");
-        radio.set_pipes_rx_lengths(&[None; 6]).expect("to set pipes length");
-        radio.flush_tx().expect("to flush tx");
-        radio.flush_rx().expect("to flush rx");
-
-        hprintln!("Beginning transmissions!").unwrap();
+        let (mut settings, boots, plan) = {
+            let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+            let mut settings = Settings::mount(&mut writer, SETTINGS).expect("to read the settings");
+            let boots = settings.count_boot(&mut writer).expect("to count the boot");
+            let mut plan = [0u8; PLAN_SIZE];
+            let plan = match settings.read(&mut writer, key::CHANNEL_PLAN, &mut plan) {
+                Ok(Some(len)) => ChannelPlan::decode(&plan[..len]).ok(),
+                _ => None,
+            };
+            (settings, boots, plan)
+        };
+
+        // Every boot starts a new session, seeded with ADC noise and the boot count
+        let mut adc = Adc::adc1(cx.device.ADC1, &mut rcc.apb2, clocks);
+        let mut entropy = Entropy::new();
+        for _ in 0..64 {
+            entropy.mix(adc.read_vref() as u32 ^ DWT::get_cycle_count());
+        }
+        let link = ControllerLink::new(*SITE_KEY, session_nonce(boots, entropy.nonce()));
+
+        // Sign the image we were built with, as version `OTA_VERSION`
+        let update = OTA_IMAGE.map(|image| {
+            let version = option_env!("OTA_VERSION").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let header = ImageHeader::sign(SITE_KEY, version, entropy.nonce(), image);
+            Sender::new(&header, image)
+        });
+        radio::configure(&mut radio, DEFAULT_CHANNEL).expect("to configure the radio");
+
+        // Fixtures that lose track of the hop sequence wait for us on the
+        // quietest channel. They keep it over a power cycle, so we do too.
+        let (plan, mut radio) = match plan {
+            Some(plan) => (plan, radio),
+            None => {
+                log!("Surveying channels!");
+                let mut survey = Survey::new();
+                let radio = radio::survey(radio, &mut survey, SURVEY_ROUNDS);
+                let plan = ChannelPlan::hopping(survey.quietest_channel());
+                let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+                if let Err(e) = settings.write(&mut writer, key::CHANNEL_PLAN, &plan.encode()) {
+                    log!("couldn't save the channel plan: {:?}", e);
+                }
+                (plan, radio)
+            }
+        };
+        log!("Rendezvous on channel {}", plan.channel);
+
+        let hopper = Hopper::master(plan, link.hop_seed(), DWT::get_cycle_count());
+        radio.set_frequency(hopper.channel()).expect("to set the channel");
+
+        log!("Beginning transmissions!");
         cx.spawn.transmit().expect("to schedule a transmission");
+        cx.spawn.hop().expect("to schedule hopping");
+        cx.spawn.time_sync().expect("to schedule time beacons");
+        cx.spawn.update().expect("to schedule the firmware update");
+        cx.schedule.status(cx.start + STATUS_INTERVAL.cycles()).expect("to schedule status reports");
         
         init::LateResources {
             radio: Some(radio),
-            buffer: Some([0u8; 32]),
+            buffer: Some([0u8; FRAME_SIZE]),
+            link,
+            hopper,
+            stats: LinkStats::new(),
+            mesh: Mesh::new(ID, false),
+            clock: LocalClock::new(),
+            update,
+            usb,
+            flash,
+            settings,
         }
     }
 
-    #[task(resources = [radio, buffer], schedule = [transmit])]
+    #[task(resources = [radio, buffer, seq, link, hopper, stats, mesh, demo], schedule = [transmit])]
     fn transmit(cx: transmit::Context) {
-        hprintln!("Attempting to send message.").unwrap();
-        let message = b"message";
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        if let Some(&fixture) = FIXTURES.iter().find(|&&fixture| !link.is_paired(fixture)) {
+            log!("Pairing with fixture {}.", fixture);
+            let standby = cx.resources.radio.take().unwrap();
+            *cx.resources.radio = Some(pair(standby, link, stats, mesh, cx.resources.hopper, fixture));
+        }
+        if !*cx.resources.demo || !link.is_paired(FIXTURE_ID) {
+            cx.schedule.transmit(cx.scheduled + (FREQ * 1_000_000).cycles()).unwrap();
+            return;
+        }
+
+        log!("Attempting to send message.");
+        let seq = *cx.resources.seq;
+        let (dest, message) = demo_step(seq);
+
+        let standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
 
+        let (standby, sent) = send(standby, link, stats, mesh, &Frame::new(dest, ID, seq, message), &mut buffer);
+        if sent {
+            log!("transmitted {:?} to {:?}", message, dest);
+        } else {
+            // If we can't transmit this time, perhaps we can next time...
+            log!("error transmitting");
+        }
+
+        // Give back ownership of the radio and buffer, and schedule another loop
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        *cx.resources.seq = seq.wrapping_add(1);
+        cx.schedule.transmit(cx.scheduled + (FREQ * 1_000_000).cycles()).unwrap();
+
+    }
+
+    /// Move to the next channel and tell the fixtures where we are
+    #[task(resources = [radio, buffer, link, hopper, stats, mesh], schedule = [hop])]
+    fn hop(cx: hop::Context) {
+        let hopper = cx.resources.hopper;
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
         let mut standby = cx.resources.radio.take().unwrap();
         let mut buffer = cx.resources.buffer.take().unwrap();
 
-        // modify our buffer to include our message
-        for (i, letter) in message.iter().enumerate() {
-            buffer[i] = *letter;
+        if let Some(channel) = hopper.update(DWT::get_cycle_count(), true) {
+            standby.set_frequency(channel).unwrap();
         }
-        
-        // Get the device ready to transmit
-        standby.flush_tx().unwrap();
-        standby.flush_rx().unwrap();
-        let mut tx = standby.tx().unwrap();
 
-        // We can send a maximum of 32 bytes per packet with the NRF24L01
-        // this isnt sending our message...
-        if tx.can_send().unwrap() {
-            tx.send(&buffer).unwrap();
-            match tx.wait_empty() {
-                Ok(_) => { hprintln!("transmitted!").unwrap(); },
-                Err(_) => { hprintln!("error transmitting").unwrap(); } // If we can't transmit this time, perhaps we can next time...
+        if hopper.plan().hopping {
+            standby = beacon(standby, link, stats, mesh, hopper, &mut buffer);
+        }
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        cx.schedule.hop(cx.scheduled + DWELL.cycles()).unwrap();
+    }
+
+    /// Tell the fixtures the network time, so their effects stay in step
+    #[task(resources = [radio, buffer, link, stats, mesh, clock], schedule = [time_sync])]
+    fn time_sync(cx: time_sync::Context) {
+        let standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+
+        let time = cx.resources.clock.micros(DWT::get_cycle_count());
+        let beacon = Frame::new(Destination::Broadcast, ID, 0, Message::TimeSync { time });
+        let (standby, _) = send(standby, cx.resources.link, cx.resources.stats, cx.resources.mesh, &beacon, &mut buffer);
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+        cx.schedule.time_sync(cx.scheduled + SYNC_INTERVAL.cycles()).unwrap();
+    }
+
+    /// Print our link statistics, then ask the fixture for its side of the link
+    #[task(resources = [radio, buffer, seq, link, stats, mesh], schedule = [status])]
+    fn status(cx: status::Context) {
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        dump(stats);
+
+        if link.is_paired(FIXTURE_ID) {
+            let seq = *cx.resources.seq;
+            let query = Frame::new(Destination::Node(FIXTURE_ID), ID, seq, Message::StatusQuery);
+            let standby = cx.resources.radio.take().unwrap();
+            let mut buffer = cx.resources.buffer.take().unwrap();
+
+            let (standby, sent) = send(standby, link, stats, mesh, &query, &mut buffer);
+            let (standby, reply) = if sent {
+                await_reply(standby, link, stats, mesh, FIXTURE_ID, REPLY_TIMEOUT)
+            } else {
+                (standby, None)
+            };
+            match reply {
+                Some(Frame { message: Message::LinkStatus(report), .. }) => {
+                    log!("fixture {} sees: {}", FIXTURE_ID, report);
+                }
+                _ => log!("fixture {} did not report its status", FIXTURE_ID),
             }
-        } else {
-            hprintln!("Not ready to transmit.").unwrap();
+
+            *cx.resources.radio = Some(standby);
+            *cx.resources.buffer = Some(buffer);
+            *cx.resources.seq = seq.wrapping_add(1);
+        }
+        cx.schedule.status(cx.scheduled + STATUS_INTERVAL.cycles()).unwrap();
+    }
+
+    /// Push the firmware image to the fixture, a burst of chunks at a time
+    /// so hopping and the other tasks keep going
+    #[task(resources = [radio, buffer, seq, link, stats, mesh, update], schedule = [update])]
+    fn update(cx: update::Context) {
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        let sender = match cx.resources.update {
+            Some(sender) => sender,
+            None => return,
+        };
+        if !link.is_paired(FIXTURE_ID) {
+            cx.schedule.update(cx.scheduled + (FREQ * 1_000_000).cycles()).unwrap();
+            return;
         }
 
-        // Clear the buffer
-        buffer = [0u8; 32];
-        
-        // Give back ownership of the radio and buffer, and schedule another loop
-        *cx.resources.radio = Some(tx.standby().unwrap());
+        let mut standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+        let mut seq = *cx.resources.seq;
+        let mut outcome = None;
+        let before = sender.progress();
+        for _ in 0..OTA_BURST {
+            let message = sender.next_message();
+            let frame = Frame::new(Destination::Node(FIXTURE_ID), ID, seq, message);
+            seq = seq.wrapping_add(1);
+            let (sent_standby, sent) = send(standby, link, stats, mesh, &frame, &mut buffer);
+            standby = sent_standby;
+
+            // Lost chunks are reported missing at the end, only the start
+            // and end are answered
+            if let Message::OtaBegin { .. } | Message::OtaEnd = message {
+                if sent {
+                    let (replied, reply) = await_reply(standby, link, stats, mesh, FIXTURE_ID, OTA_REPLY_TIMEOUT);
+                    standby = replied;
+                    if let Some(Frame { message: Message::OtaStatus(status), .. }) = reply {
+                        if let OtaStatus::Missing(index) = status {
+                            log!("fixture {} is missing firmware from chunk {} on", FIXTURE_ID, index);
+                        }
+                        outcome = sender.handle(status);
+                    }
+                }
+                break;
+            }
+        }
+        let progress = sender.progress();
+
+        *cx.resources.radio = Some(standby);
         *cx.resources.buffer = Some(buffer);
-        cx.schedule.transmit(cx.scheduled + (FREQ * 1_000_000).cycles()).unwrap();
+        *cx.resources.seq = seq;
+        match outcome {
+            Some(Ok(())) => {
+                // It restarts into the image without its keys, and confirms
+                // the image once it's paired again
+                log!("fixture {} accepted the firmware image", FIXTURE_ID);
+                link.unpair(FIXTURE_ID);
+                *cx.resources.update = None;
+            }
+            Some(Err(e)) => {
+                log!("fixture {} refused the firmware image: {:?}", FIXTURE_ID, e);
+                *cx.resources.update = None;
+            }
+            None => {
+                if progress / 10 != before / 10 {
+                    log!("firmware update at {}%", progress);
+                }
+                cx.schedule.update(cx.scheduled + OTA_INTERVAL.cycles()).unwrap();
+            }
+        }
+    }
+
+    /// Send the log out over USB. Above the radio tasks, which can wait a
+    /// while for a fixture, as the host expects answers within milliseconds.
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb])]
+    fn usb_tx(cx: usb_tx::Context) {
+        cx.resources.usb.poll();
+    }
+
+    /// Hand lines that come in over USB to [`shell`], and frames to [`forward`]
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb], spawn = [shell, forward])]
+    fn usb_rx(cx: usb_rx::Context) {
+        let serial = cx.resources.usb;
+        serial.poll();
+        while let Some(input) = serial.read() {
+            match input {
+                usb::Input::Line(line) => {
+                    if cx.spawn.shell(line).is_err() {
+                        log!("error: busy");
+                    }
+                }
+                usb::Input::Packet(Packet::Send(frame)) => {
+                    if cx.spawn.forward(frame).is_err() {
+                        let Header { seq, dest, .. } = frame.header;
+                        usb::send(&Packet::Sent { seq, dest, outcome: Outcome::Busy });
+                    }
+                }
+                usb::Input::Packet(_) => log!("error: the dongle only sends frames"),
+                usb::Input::Invalid(e) => log!("error: dropped a packet, {:?}", e),
+            }
+        }
+    }
 
+    /// Send a frame from the host, telling it how that went, and pass the
+    /// answer to requests back
+    #[task(capacity = 8, resources = [radio, buffer, link, hopper, stats, mesh])]
+    fn forward(cx: forward::Context, frame: Frame) {
+        let link = cx.resources.link;
+        let stats = cx.resources.stats;
+        let mesh = cx.resources.mesh;
+        let frame = Frame { header: Header { src: ID, ..frame.header }, ..frame };
+        let Header { seq, dest, .. } = frame.header;
+        if let Destination::Node(fixture) = dest {
+            if !link.is_paired(fixture) {
+                usb::send(&Packet::Sent { seq, dest, outcome: Outcome::Lost });
+                return;
+            }
+        }
+
+        let standby = cx.resources.radio.take().unwrap();
+        let mut buffer = cx.resources.buffer.take().unwrap();
+        let (mut standby, sent) = send(standby, link, stats, mesh, &frame, &mut buffer);
+        let outcome = match (dest, sent) {
+            (Destination::Node(fixture), true) => Outcome::Acked(stats.peer(fixture).and_then(|peer| peer.status)),
+            (_, true) => Outcome::Sent,
+            (_, false) => Outcome::Lost,
+        };
+        usb::send(&Packet::Sent { seq, dest, outcome });
+
+        let timeout = match frame.message {
+            Message::StatusQuery => Some(REPLY_TIMEOUT),
+            Message::OtaBegin { .. } | Message::OtaEnd => Some(OTA_REPLY_TIMEOUT),
+            _ => None,
+        };
+        if let (Destination::Node(fixture), true, Some(timeout)) = (dest, sent, timeout) {
+            let (replied, reply) = await_reply(standby, link, stats, mesh, fixture, timeout);
+            standby = replied;
+            if let Some(Frame { message: Message::OtaStatus(OtaStatus::Accepted), .. }) = reply {
+                link.unpair(fixture);
+            }
+            match reply {
+                Some(reply) => usb::send(&Packet::Received(reply)),
+                None => log!("fixture {} did not answer", fixture),
+            }
+        }
+        // Every fixture answers a discover in its own time
+        if let (Message::Discover { window }, true) = (frame.message, sent) {
+            let timeout = window.min(MAX_DISCOVER_WINDOW) as u32 * FREQ * 1_000 + REPLY_TIMEOUT;
+            let hopper = cx.resources.hopper;
+            standby = await_announcements(standby, link, stats, mesh, hopper, timeout, |reply| usb::send(&Packet::Received(reply)));
+        }
+
+        *cx.resources.radio = Some(standby);
+        *cx.resources.buffer = Some(buffer);
+    }
+
+    /// Carry out a command line, between the radio tasks
+    #[task(capacity = 2, resources = [radio, buffer, seq, link, hopper, stats, mesh, demo, flash, settings])]
+    fn shell(cx: shell::Context, line: Line) {
+        let mut shell = Shell {
+            radio: cx.resources.radio,
+            buffer: cx.resources.buffer,
+            seq: cx.resources.seq,
+            link: cx.resources.link,
+            hopper: cx.resources.hopper,
+            stats: cx.resources.stats,
+            mesh: cx.resources.mesh,
+            demo: cx.resources.demo,
+            flash: cx.resources.flash,
+            settings: cx.resources.settings,
+        };
+        cli::run(Shell::COMMANDS, &mut shell, line.as_str(), &mut Log);
     }
 
     extern "C" {
         fn EXTI0();
     }
 };
+
+/// What commands from USB work on
+struct Shell<'a> {
+    radio: &'a mut Option<StandbyMode<Radio>>,
+    buffer: &'a mut Option<[u8; FRAME_SIZE]>,
+    seq: &'a mut u8,
+    link: &'a mut ControllerLink,
+    hopper: &'a mut Hopper,
+    stats: &'a mut LinkStats,
+    mesh: &'a mut Mesh,
+    demo: &'a mut bool,
+    flash: &'a mut hal::flash::Parts,
+    settings: &'a mut Settings,
+}
+
+impl<'a> Shell<'a> {
+    const COMMANDS: &'a [Command<Self>] = &[
+        Command { name: "status", usage: "", run: Self::status },
+        Command { name: "set color", usage: "<fixture> <red> <green> <blue>", run: Self::set_color },
+        Command { name: "set brightness", usage: "<fixture> <brightness>", run: Self::set_brightness },
+        Command { name: "effect", usage: "<name> [speed=<speed>]", run: Self::effect },
+        Command { name: "radio channel", usage: "<channel>", run: Self::radio_channel },
+        Command { name: "demo", usage: "on|off", run: Self::demo },
+        Command { name: "dongle", usage: "", run: Self::dongle },
+        Command { name: "reboot", usage: "", run: cli::reboot },
+    ];
+
+    fn status(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        for &fixture in FIXTURES.iter() {
+            let paired = if self.link.is_paired(fixture) { "paired" } else { "not paired" };
+            writeln!(out, "{} with fixture {}", paired, fixture).ok();
+        }
+        let plan = self.hopper.plan();
+        let hopping = if plan.hopping { "hopping, rendezvous on" } else { "fixed on" };
+        writeln!(out, "{} channel {}", hopping, plan.channel).ok();
+        writeln!(out, "demo {}", if *self.demo { "on" } else { "off" }).ok();
+        dump(self.stats);
+        Ok(())
+    }
+
+    fn set_color(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let color = args.color()?;
+        args.finish()?;
+        self.tell(dest, Message::SetColor(color))
+    }
+
+    fn set_brightness(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let brightness = args.parse("brightness")?;
+        args.finish()?;
+        self.tell(dest, Message::SetBrightness(brightness))
+    }
+
+    /// Change the effect of every fixture
+    fn effect(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let effect = Effect::from_name(args.word("effect")?).ok_or(cli::Error::Invalid("effect"))?;
+        let speed = args.option("speed")?.unwrap_or(1);
+        args.finish()?;
+        self.tell(Destination::Broadcast, Message::SetEffect { effect, speed })
+    }
+
+    /// Stop hopping and stay on one channel, taking the fixtures along
+    fn radio_channel(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let channel = args.parse("channel")?;
+        args.finish()?;
+        if channel >= CHANNEL_COUNT {
+            return Err(cli::Error::Invalid("channel"));
+        }
+        let plan = ChannelPlan::fixed(channel);
+        self.tell(Destination::Broadcast, Message::SetChannelPlan(plan))?;
+        self.hopper.reseed(plan, self.link.hop_seed());
+        if let Some(standby) = self.radio {
+            standby.set_frequency(channel).map_err(|_| "couldn't retune the radio")?;
+        }
+        // The fixtures keep it, so we have to as well
+        let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        self.settings
+            .write(&mut writer, key::CHANNEL_PLAN, &plan.encode())
+            .map_err(|_| "couldn't write to flash")?;
+        Ok(())
+    }
+
+    fn demo(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        *self.demo = match args.word("on or off")? {
+            "on" => true,
+            "off" => false,
+            _ => return Err(cli::Error::Invalid("on or off")),
+        };
+        args.finish()
+    }
+
+    /// Carry packets instead of text until the port is closed, see
+    /// [`shared::dongle`]
+    fn dongle(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        args.finish()?;
+        usb::set_framed(true);
+        Ok(())
+    }
+
+    /// Send `message` to `dest` right away
+    fn tell(&mut self, dest: Destination, message: Message) -> Result<(), cli::Error> {
+        let frame = Frame::new(dest, ID, *self.seq, message);
+        *self.seq = self.seq.wrapping_add(1);
+        let standby = self.radio.take().unwrap();
+        let mut buffer = self.buffer.take().unwrap();
+        let (standby, sent) = send(standby, self.link, self.stats, self.mesh, &frame, &mut buffer);
+        *self.radio = Some(standby);
+        *self.buffer = Some(buffer);
+        if !sent {
+            return Err("nobody acknowledged it".into());
+        }
+        Ok(())
+    }
+}
+
+/// Seal and send a frame, returning whether it went out.
+///
+/// Unicast frames take the route the mesh learned, and are flooded through
+/// the relays if that doesn't get them acknowledged. Frames for fixtures we
+/// aren't paired with are dropped until they are paired again.
+fn send(
+    standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    frame: &Frame,
+    buffer: &mut [u8; FRAME_SIZE],
+) -> (StandbyMode<Radio>, bool) {
+    let dest = frame.header.dest;
+    let len = match link.seal(frame, buffer) {
+        Ok(len) => len,
+        Err(e) => {
+            log!("error sealing a frame for {:?}: {:?}", dest, e);
+            return (standby, false);
+        }
+    };
+    let len = mesh.stamp(buffer, len);
+
+    let next = mesh.next_hop(dest, DWT::get_cycle_count());
+    let (mut standby, mut sent) = transmit(standby, next, &buffer[..len], stats);
+    if let (Destination::Node(fixture), false) = (dest, sent) {
+        mesh.forget(fixture);
+        let flooded = transmit(standby, Destination::Broadcast, &buffer[..len], stats);
+        standby = flooded.0;
+        sent = flooded.1;
+    }
+
+    // Clear the buffer
+    *buffer = [0u8; FRAME_SIZE];
+    (standby, sent)
+}
+
+/// Put `data` on air for `dest`, returning whether it went out.
+///
+/// Nobody acknowledges multicast frames, so those are sent a few times instead.
+fn transmit(
+    mut standby: StandbyMode<Radio>,
+    dest: Destination,
+    data: &[u8],
+    stats: &mut LinkStats,
+) -> (StandbyMode<Radio>, bool) {
+    // Get the device ready to transmit
+    radio::address_to(&mut standby, dest).unwrap();
+    standby.flush_tx().unwrap();
+    standby.flush_rx().unwrap();
+    let mut tx = standby.tx().unwrap();
+
+    let repeats = if dest.is_acknowledged() { 1 } else { MULTICAST_REPEATS };
+    let mut sent = false;
+    for _ in 0..repeats {
+        // We can send a maximum of 32 bytes per packet with the NRF24L01
+        if tx.can_send().unwrap() {
+            sent |= radio::transmit(&mut tx, dest, data, stats).unwrap();
+        }
+    }
+
+    // Fixtures answer unicast frames with their status
+    if let (Destination::Node(fixture), true) = (dest, sent) {
+        if let Some(status) = radio::ack_status(&mut tx).unwrap() {
+            if let Some(peer) = stats.peer_mut(fixture) {
+                peer.status = Some(status);
+            }
+        }
+    }
+
+    (tx.standby().unwrap(), sent)
+}
+
+/// Ask `fixture` to pair and wait a moment for its answer, see
+/// [`ChannelPlan::pairing_channels`] for where.
+///
+/// Once paired, the fixture is told which channel plan to follow. Relays
+/// don't listen on the pairing channels, so the fixture has to be in range.
+fn pair(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &Hopper,
+    fixture: NodeId,
+) -> StandbyMode<Radio> {
+    let mut buffer = [0u8; FRAME_SIZE];
+    let dest = Destination::Node(fixture);
+    let len = Frame::new(dest, ID, 0, link.pair_request(fixture)).encode(&mut buffer);
+    let len = mesh.stamp(&mut buffer, len);
+
+    let mut sent = false;
+    for channel in hopper.plan().pairing_channels() {
+        standby.set_frequency(channel).unwrap();
+        radio::address_to(&mut standby, dest).unwrap();
+        let mut tx = standby.tx().unwrap();
+        sent = radio::transmit(&mut tx, dest, &buffer[..len], stats).unwrap();
+        standby = tx.standby().unwrap();
+        if sent {
+            break;
+        }
+    }
+    if !sent {
+        log!("fixture {} did not receive the pair request", fixture);
+        standby.set_frequency(hopper.channel()).unwrap();
+        return standby;
+    }
+
+    let (mut standby, response) = await_reply(standby, link, stats, mesh, fixture, REPLY_TIMEOUT);
+    if let Some(Frame { message: Message::PairResponse { nonce, .. }, .. }) = response {
+        link.paired(fixture, &nonce).unwrap();
+        log!("paired with fixture {}", fixture);
+        let plan = Frame::new(dest, ID, 0, Message::SetChannelPlan(hopper.plan()));
+        standby = send(standby, link, stats, mesh, &plan, &mut buffer).0;
+    }
+    standby.set_frequency(hopper.channel()).unwrap();
+    standby
+}
+
+/// Tell the fixtures where in the hop sequence we are, and the lost ones on
+/// the rendezvous channel when it's their turn
+fn beacon(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &Hopper,
+    buffer: &mut [u8; FRAME_SIZE],
+) -> StandbyMode<Radio> {
+    let beacon = |hopper: &Hopper| {
+        let now = DWT::get_cycle_count();
+        let message = Message::HopSync { slot: hopper.slot(), elapsed: hopper.elapsed(now) };
+        Frame::new(Destination::Broadcast, ID, 0, message)
+    };
+    standby = send(standby, link, stats, mesh, &beacon(hopper), buffer).0;
+
+    // Lost fixtures wait for us on the rendezvous channel
+    if hopper.is_rendezvous_slot() {
+        standby.set_frequency(hopper.plan().channel).unwrap();
+        standby = send(standby, link, stats, mesh, &beacon(hopper), buffer).0;
+        standby.set_frequency(hopper.channel()).unwrap();
+    }
+    standby
+}
+
+/// Listen for `timeout` cycles for fixtures announcing themselves, keeping
+/// to the hop sequence and beaconing as they do, and hand each announcement to `found`
+fn await_announcements(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    hopper: &mut Hopper,
+    timeout: u32,
+    mut found: impl FnMut(Frame),
+) -> StandbyMode<Radio> {
+    let mut buffer = [0u8; FRAME_SIZE];
+    radio::listen_as_controller(&mut standby, ID).unwrap();
+    let mut rx = standby.rx().unwrap();
+    let start = DWT::get_cycle_count();
+    while DWT::get_cycle_count().wrapping_sub(start) < timeout {
+        // The hop task can't run until we're done, and fixtures that miss
+        // its beacons for long lose us
+        let slot = hopper.slot();
+        let channel = hopper.update(DWT::get_cycle_count(), true);
+        if hopper.slot() != slot {
+            let mut standby = rx.standby();
+            if let Some(channel) = channel {
+                standby.set_frequency(channel).unwrap();
+            }
+            if hopper.plan().hopping {
+                standby = beacon(standby, link, stats, mesh, hopper, &mut buffer);
+                radio::listen_as_controller(&mut standby, ID).unwrap();
+            }
+            rx = standby.rx().unwrap();
+        }
+        if rx.can_read().unwrap().is_none() {
+            continue;
+        }
+        let data = rx.read().unwrap();
+        // The power detector latches when a frame arrives
+        let strong = rx.has_carrier().unwrap();
+        let incoming = match mesh.receive(data.as_ref(), DWT::get_cycle_count()) {
+            Some(incoming) if incoming.deliver => incoming,
+            _ => continue,
+        };
+        let received = link.receive(incoming.frame);
+        if received.is_ok() {
+            mesh.learn(&incoming, DWT::get_cycle_count());
+        }
+        match received {
+            Ok(frame @ Frame { message: Message::Announce(_), .. }) => {
+                if let Some(peer) = stats.peer_mut(frame.header.src) {
+                    peer.record_received(strong);
+                }
+                found(frame);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                let src = peek_header(incoming.frame).map(|header| header.src);
+                if let Some(peer) = src.ok().and_then(|src| stats.peer_mut(src)) {
+                    peer.record_rejected();
+                }
+            }
+        }
+    }
+    rx.standby()
+}
+
+/// Listen up to `timeout` cycles for the next frame from `fixture`, which
+/// may come through the mesh
+fn await_reply(
+    mut standby: StandbyMode<Radio>,
+    link: &mut ControllerLink,
+    stats: &mut LinkStats,
+    mesh: &mut Mesh,
+    fixture: NodeId,
+    timeout: u32,
+) -> (StandbyMode<Radio>, Option<Frame>) {
+    radio::listen_as_controller(&mut standby, ID).unwrap();
+    let mut rx = standby.rx().unwrap();
+    let start = DWT::get_cycle_count();
+    let mut reply = None;
+    while reply.is_none() && DWT::get_cycle_count().wrapping_sub(start) < timeout {
+        if rx.can_read().unwrap().is_none() {
+            continue;
+        }
+        let data = rx.read().unwrap();
+        // The power detector latches when a frame arrives
+        let strong = rx.has_carrier().unwrap();
+        let incoming = match mesh.receive(data.as_ref(), DWT::get_cycle_count()) {
+            Some(incoming) if incoming.deliver => incoming,
+            _ => continue,
+        };
+        let received = link.receive(incoming.frame);
+        if received.is_ok() {
+            mesh.learn(&incoming, DWT::get_cycle_count());
+        }
+        match received {
+            Ok(frame) if frame.header.src == fixture => {
+                if let Some(peer) = stats.peer_mut(fixture) {
+                    peer.record_received(strong);
+                }
+                reply = Some(frame);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                let src = peek_header(incoming.frame).map(|header| header.src);
+                if let Some(peer) = src.ok().and_then(|src| stats.peer_mut(src)) {
+                    peer.record_rejected();
+                }
+            }
+        }
+    }
+    (rx.standby(), reply)
+}
+
+/// Print link statistics for every fixture, and the channels frames were lost on
+fn dump(stats: &LinkStats) {
+    for (id, peer) in stats.peers() {
+        log!("link {}: {}", id, peer);
+        if let Some(status) = peer.status {
+            log!("fixture {}: {}", id, status);
+        }
+    }
+    for channel in 0..CHANNEL_COUNT {
+        let losses = stats.channel_losses(channel);
+        if losses > 0 {
+            log!("channel {}: {} lost", channel, losses);
+        }
+    }
+}
+
+/// Look up a group by its name
+fn group(name: &str) -> Option<GroupId> {
+    GROUPS
+        .iter()
+        .find(|(group_name, _)| *group_name == name)
+        .and_then(|(_, id)| GroupId::new(*id))
+}
+
+/// Cycle through unicast, group and broadcast messages
+fn demo_step(seq: u8) -> (Destination, Message) {
+    let stage_left = group("stage left").unwrap();
+    match seq % 4 {
+        0 => (Destination::Node(FIXTURE_ID), Message::JoinGroup(stage_left)),
+        1 => (Destination::Group(stage_left), Message::SetColor(RGB8::new(255, 0, 0))),
+        2 => (Destination::Group(stage_left), Message::SetEffect { effect: Effect::Solid, speed: 0 }),
+        _ => (Destination::Broadcast, Message::SetEffect { effect: Effect::Rainbow, speed: 1 }),
+    }
+}
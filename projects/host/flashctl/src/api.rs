@@ -0,0 +1,379 @@
+//! The daemon's JSON API, over HTTP.
+//!
+//! ```text
+//! GET  /fixtures            every fixture and what it shows
+//! GET  /fixtures/<id>       one of them
+//! PUT  /fixtures/<id>       change what it shows, with any of
+//!                           {"effect", "speed", "brightness", "color": [r, g, b], "fade"}
+//! GET  /fixtures/<id>/link  ask it how it is and how its side of the link is doing
+//! GET  /effects             the effects there are
+//! POST /scenes/<scene>      recall a scene on every fixture, with {"fade"} if wanted
+//! GET  /events              server-sent events
+//! ```
+//!
+//! With a `fade`, in ms, a `PUT` fades to the new look as a whole. Without,
+//! each part changes on its own and the rest is left alone. Effects go by
+//! their names, `off`, `solid` or `rainbow`. The event stream has a
+//! `fixture` event whenever what one shows changes, starting with all of
+//! them, and a `telemetry` event for each fixture whenever they're asked
+//! how they are. Fixtures are listed with what they announced about
+//! themselves, or a null `info` until they do:
+//!
+//! ```text
+//! fixture    {"id", "online", "effect", "speed", "brightness", "color",
+//!             "info": {"uid", "firmware", "leds", "chipset", "layout", "capabilities"}}
+//! telemetry  {"id", "online", "status": {"effect", "brightness", "power_limited", "temperature", "uptime"},
+//!             "link": {"sent", "acked", "lost", "retransmits", "received", "strong", "rejected", "latency"}}
+//! ```
+//!
+//! A fixture that doesn't acknowledge a change gets a 502, and errors come
+//! as `{"error"}`.
+
+use crate::daemon::{Daemon, Light};
+use crate::dongle::Error;
+use crate::http::{Reply, Request};
+use serde_json::{json, Value};
+use shared::address::NodeId;
+use shared::dongle::Outcome;
+use shared::inventory::FixtureInfo;
+use shared::protocol::{Effect, Message};
+use shared::scene::{Look, DEFAULT_FADE};
+use shared::status::FixtureStatus;
+use shared::telemetry::LinkReport;
+use smart_leds::RGB8;
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+
+/// Every effect, in the order of their bytes
+pub const EFFECTS: [Effect; 3] = [Effect::Off, Effect::Solid, Effect::Rainbow];
+
+/// The reply to `request`
+pub fn respond<P: Read + Write>(daemon: &mut Daemon<P>, request: &Request) -> Result<Reply, Error> {
+    let path: Vec<&str> = request.path.split('/').filter(|part| !part.is_empty()).collect();
+    let reply = match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["fixtures"]) => ok(Value::Array(daemon.lights().iter().map(light).collect())),
+        ("GET", ["fixtures", id]) => match fixture(daemon, id) {
+            Some(id) => ok(light(daemon.light(id).unwrap())),
+            None => not_found(),
+        },
+        ("PUT", ["fixtures", id]) => match fixture(daemon, id) {
+            Some(id) => set(daemon, id, &request.body)?,
+            None => not_found(),
+        },
+        ("GET", ["fixtures", id, "link"]) => match fixture(daemon, id) {
+            Some(id) => match daemon.query(id)? {
+                (None, None) => Reply::Json(502, error("the fixture didn't answer")),
+                (status, link_report) => ok(json!({
+                    "id": id,
+                    "status": status.as_ref().map(self::status),
+                    "link": link_report.as_ref().map(link),
+                })),
+            },
+            None => not_found(),
+        },
+        ("GET", ["effects"]) => ok(EFFECTS.iter().map(|effect| effect.name()).collect()),
+        ("POST", ["scenes", scene]) => match scene.parse() {
+            Ok(scene) => recall(daemon, scene, &request.body)?,
+            Err(_) => not_found(),
+        },
+        ("GET", ["events"]) => Reply::Events(daemon.subscribe()),
+        (_, ["fixtures"]) | (_, ["fixtures", _]) | (_, ["fixtures", _, "link"]) | (_, ["effects"]) | (_, ["scenes", _]) | (_, ["events"]) => {
+            Reply::Json(405, error("method not allowed"))
+        }
+        _ => not_found(),
+    };
+    Ok(reply)
+}
+
+/// What a fixture shows
+pub fn light(light: &Light) -> Value {
+    json!({
+        "id": light.id,
+        "online": light.online,
+        "effect": light.effect.name(),
+        "speed": light.speed,
+        "brightness": light.brightness,
+        "color": [light.color.r, light.color.g, light.color.b],
+        "info": light.info.as_ref().map(info),
+    })
+}
+
+/// What a fixture announced about itself
+pub fn info(info: &FixtureInfo) -> Value {
+    json!({
+        "uid": format!("{:016x}", info.uid),
+        "firmware": info.firmware,
+        "leds": info.leds,
+        "chipset": info.chipset.name(),
+        "layout": info.layout.name(),
+        "capabilities": info.capabilities.names().collect::<Vec<_>>(),
+    })
+}
+
+/// What a fixture reported about itself
+pub fn status(status: &FixtureStatus) -> Value {
+    json!({
+        "effect": status.effect.name(),
+        "brightness": status.brightness,
+        "power_limited": status.power_limited,
+        "temperature": status.temperature,
+        "uptime": status.uptime,
+    })
+}
+
+/// A fixture's side of its link
+pub fn link(report: &LinkReport) -> Value {
+    json!({
+        "sent": report.sent,
+        "acked": report.acked,
+        "lost": report.sent.wrapping_sub(report.acked),
+        "retransmits": report.retransmits,
+        "received": report.received,
+        "strong": report.strong,
+        "rejected": report.rejected,
+        "latency": report.latency,
+    })
+}
+
+/// The body of an error reply
+pub fn error(why: &str) -> String {
+    json!({ "error": why }).to_string()
+}
+
+/// Change what fixture `id` shows, as the body says
+fn set<P: Read + Write>(daemon: &mut Daemon<P>, id: NodeId, body: &[u8]) -> Result<Reply, Error> {
+    let current = *daemon.light(id).unwrap();
+    let body: Value = match serde_json::from_slice(body) {
+        Ok(body @ Value::Object(_)) => body,
+        _ => return Ok(bad_request("expected a JSON object")),
+    };
+    let effect = match body.get("effect") {
+        Some(name) => match name.as_str().and_then(Effect::from_name) {
+            Some(effect) => Some(effect),
+            None => return Ok(bad_request("invalid effect")),
+        },
+        None => None,
+    };
+    let (speed, brightness, fade) = match (number(&body, "speed"), number(&body, "brightness"), number(&body, "fade")) {
+        (Ok(speed), Ok(brightness), Ok(fade)) => (speed, brightness, fade),
+        (Err(name), _, _) | (_, Err(name), _) | (_, _, Err(name)) => return Ok(bad_request(&format!("invalid {}", name))),
+    };
+    let color = match body.get("color") {
+        Some(Value::Array(levels)) if levels.len() == 3 => {
+            let levels: Vec<u8> = levels.iter().filter_map(|level| level.as_u64()?.try_into().ok()).collect();
+            match levels.as_slice() {
+                &[r, g, b] => Some(RGB8::new(r, g, b)),
+                _ => return Ok(bad_request("invalid color")),
+            }
+        }
+        Some(_) => return Ok(bad_request("invalid color")),
+        None => None,
+    };
+
+    let messages = match fade {
+        Some(fade) => vec![Message::SetLook {
+            look: Look {
+                effect: effect.unwrap_or(current.effect),
+                speed: speed.unwrap_or(current.speed),
+                brightness: brightness.unwrap_or(current.brightness),
+                color: color.unwrap_or(current.color),
+            },
+            fade,
+        }],
+        None => {
+            let mut messages = Vec::new();
+            messages.extend(color.map(Message::SetColor));
+            messages.extend(brightness.map(Message::SetBrightness));
+            if effect.is_some() || speed.is_some() {
+                messages.push(Message::SetEffect {
+                    effect: effect.unwrap_or(current.effect),
+                    speed: speed.unwrap_or(current.speed),
+                });
+            }
+            messages
+        }
+    };
+    for message in messages {
+        if let Some(reply) = failed(daemon.send(id, message)?) {
+            return Ok(reply);
+        }
+    }
+    Ok(ok(light(daemon.light(id).unwrap())))
+}
+
+/// Recall `scene` on every fixture
+fn recall<P: Read + Write>(daemon: &mut Daemon<P>, scene: u8, body: &[u8]) -> Result<Reply, Error> {
+    let fade = match serde_json::from_slice(body) {
+        Ok(body @ Value::Object(_)) => number(&body, "fade"),
+        _ if body.is_empty() => Ok(None),
+        _ => return Ok(bad_request("expected a JSON object")),
+    };
+    let fade = match fade {
+        Ok(fade) => fade.unwrap_or(DEFAULT_FADE),
+        Err(_) => return Ok(bad_request("invalid fade")),
+    };
+    if let Some(reply) = failed(daemon.broadcast(Message::RecallScene { scene, fade })?) {
+        return Ok(reply);
+    }
+    Ok(ok(json!({ "scene": scene, "fade": fade })))
+}
+
+/// Fixture `id`, if the daemon knows it
+fn fixture<P: Read + Write>(daemon: &Daemon<P>, id: &str) -> Option<NodeId> {
+    id.parse().ok().filter(|&id| daemon.light(id).is_some())
+}
+
+/// The number called `name` in `body`, if it's there, or the name if it
+/// isn't a `T`
+fn number<T: TryFrom<u64>>(body: &Value, name: &'static str) -> Result<Option<T>, &'static str> {
+    match body.get(name) {
+        Some(value) => match value.as_u64().and_then(|value| T::try_from(value).ok()) {
+            Some(value) => Ok(Some(value)),
+            None => Err(name),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The reply for a frame that didn't get out, if it didn't
+fn failed(outcome: Outcome) -> Option<Reply> {
+    match outcome {
+        Outcome::Acked(_) | Outcome::Sent => None,
+        Outcome::Lost => Some(Reply::Json(502, error("the fixture didn't acknowledge it"))),
+        Outcome::Busy => Some(Reply::Json(503, error("the dongle is busy"))),
+    }
+}
+
+fn ok(body: Value) -> Reply {
+    Reply::Json(200, body.to_string())
+}
+
+fn bad_request(why: &str) -> Reply {
+    Reply::Json(400, error(why))
+}
+
+fn not_found() -> Reply {
+    Reply::Json(404, error("not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dongle, FakeDongle};
+    use serialport::SerialPort;
+
+    fn dongle(fake: &FakeDongle) -> Dongle<Box<dyn SerialPort>> {
+        Dongle::open(fake.path().to_str().unwrap()).expect("to open the fake dongle")
+    }
+
+    /// The status and body of the reply to `method` on `path`
+    fn call<P: Read + Write>(daemon: &mut Daemon<P>, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let request = Request { method: method.into(), path: path.into(), body: body.as_bytes().to_vec() };
+        match respond(daemon, &request).unwrap() {
+            Reply::Json(status, body) => (status, serde_json::from_str(&body).unwrap()),
+            Reply::Events(_) => panic!("{} {} got an event stream", method, path),
+        }
+    }
+
+    #[test]
+    fn requests_are_routed() {
+        let fake = FakeDongle::start(&[1, 2]).unwrap();
+        let mut dongle = dongle(&fake);
+        let mut daemon = Daemon::new(&mut dongle);
+        assert_eq!(daemon.find(&mut String::new()).unwrap(), 2);
+
+        let (status, fixtures) = call(&mut daemon, "GET", "/fixtures", "");
+        assert_eq!((status, fixtures.as_array().map(Vec::len)), (200, Some(2)));
+        let (status, fixture) = call(&mut daemon, "GET", "/fixtures/2/", "");
+        assert_eq!((status, &fixture["id"], &fixture["info"]["leds"]), (200, &json!(2), &json!(50)));
+        assert_eq!(call(&mut daemon, "GET", "/effects", ""), (200, json!(["off", "solid", "rainbow"])));
+        let (status, link) = call(&mut daemon, "GET", "/fixtures/1/link", "");
+        assert_eq!((status, &link["status"]["effect"]), (200, &json!("rainbow")));
+        assert_eq!(call(&mut daemon, "POST", "/scenes/3", ""), (200, json!({ "scene": 3, "fade": DEFAULT_FADE })));
+        let events = Request { method: "GET".into(), path: "/events".into(), body: Vec::new() };
+        assert!(matches!(respond(&mut daemon, &events), Ok(Reply::Events(_))));
+
+        for &(method, path) in &[
+            ("GET", "/fixtures/3"),
+            ("GET", "/fixtures/x"),
+            ("PUT", "/fixtures/300"),
+            ("GET", "/fixtures/3/link"),
+            ("POST", "/scenes/x"),
+            ("GET", "/"),
+            ("GET", "/fixtures/1/status"),
+        ] {
+            let not_found = (404, json!({ "error": "not found" }));
+            assert_eq!(call(&mut daemon, method, path, "{}"), not_found, "{} {}", method, path);
+        }
+        for &(method, path) in &[
+            ("DELETE", "/fixtures/1"),
+            ("POST", "/fixtures"),
+            ("PUT", "/fixtures/1/link"),
+            ("PUT", "/effects"),
+            ("GET", "/scenes/3"),
+            ("POST", "/events"),
+        ] {
+            assert_eq!(call(&mut daemon, method, path, "").0, 405, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn puts_change_what_fixtures_show() {
+        let fake = FakeDongle::start(&[4]).unwrap();
+        let mut dongle = dongle(&fake);
+        let mut daemon = Daemon::new(&mut dongle);
+        daemon.find(&mut String::new()).unwrap();
+
+        let body = r#"{"effect": "solid", "brightness": 9, "color": [1, 2, 3]}"#;
+        let (status, fixture) = call(&mut daemon, "PUT", "/fixtures/4", body);
+        assert_eq!(status, 200);
+        assert_eq!((&fixture["effect"], &fixture["brightness"]), (&json!("solid"), &json!(9)));
+        assert_eq!(fixture["color"], json!([1, 2, 3]));
+        let (status, fixture) = call(&mut daemon, "PUT", "/fixtures/4", r#"{"speed": 7, "fade": 100}"#);
+        assert_eq!((status, &fixture["speed"], &fixture["brightness"]), (200, &json!(7), &json!(9)));
+        assert_eq!(daemon.light(4).map(|light| (light.effect, light.speed)), Some((Effect::Solid, 7)));
+    }
+
+    #[test]
+    fn bad_puts_get_a_400() {
+        let fake = FakeDongle::start(&[4]).unwrap();
+        let mut dongle = dongle(&fake);
+        let mut daemon = Daemon::new(&mut dongle);
+        daemon.find(&mut String::new()).unwrap();
+        let before = *daemon.light(4).unwrap();
+
+        for &(body, why) in &[
+            ("", "expected a JSON object"),
+            ("[1, 2]", "expected a JSON object"),
+            (r#"{"effect": "strobe"}"#, "invalid effect"),
+            (r#"{"effect": 1}"#, "invalid effect"),
+            (r#"{"color": [1, 2]}"#, "invalid color"),
+            (r#"{"color": [1, 2, 256]}"#, "invalid color"),
+            (r#"{"color": "red"}"#, "invalid color"),
+            (r#"{"brightness": 256}"#, "invalid brightness"),
+            (r#"{"speed": -1}"#, "invalid speed"),
+            (r#"{"effect": "solid", "fade": 1e3}"#, "invalid fade"),
+        ] {
+            assert_eq!(call(&mut daemon, "PUT", "/fixtures/4", body), (400, json!({ "error": why })), "{}", body);
+        }
+        // Nothing was sent for them
+        assert_eq!(daemon.light(4), Some(&before));
+    }
+
+    #[test]
+    fn unacknowledged_puts_get_a_502() {
+        let fake = FakeDongle::start(&[1, 2]).unwrap();
+        let mut dongle = dongle(&fake);
+        let mut daemon = Daemon::new(&mut dongle);
+        daemon.find(&mut String::new()).unwrap();
+        fake.switch_off(2);
+
+        let (status, body) = call(&mut daemon, "PUT", "/fixtures/2", r#"{"brightness": 5}"#);
+        assert_eq!((status, body), (502, json!({ "error": "the fixture didn't acknowledge it" })));
+        let (_, fixture) = call(&mut daemon, "GET", "/fixtures/2", "");
+        assert_eq!((&fixture["online"], &fixture["brightness"]), (&json!(false), &json!(255)));
+        assert_eq!(call(&mut daemon, "GET", "/fixtures/2/link", "").0, 502);
+        // The other one still answers
+        assert_eq!(call(&mut daemon, "PUT", "/fixtures/1", r#"{"brightness": 5}"#).0, 200);
+    }
+}
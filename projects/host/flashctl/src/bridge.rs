@@ -0,0 +1,173 @@
+//! Turning DMX levels into messages for fixtures, by a [`ChannelMap`].
+//!
+//! Desks send every universe over and over, so only what changed is sent
+//! on. Pixels are streamed, and the stream kept alive while the desk is
+//! heard, so fixtures go back to their own effect once it goes quiet.
+
+use crate::dmx::Levels;
+use crate::map::{ChannelMap, Entry, Part};
+use crate::stream::Encoder;
+use shared::address::Destination;
+use shared::dmx;
+use shared::protocol::Message;
+use smart_leds::RGB8;
+use std::time::{Duration, Instant};
+
+/// How long a desk may go without sending a universe before it's taken to
+/// be gone, as E1.31 has it
+pub const SOURCE_TIMEOUT: Duration = Duration::from_millis(2_500);
+/// How often unchanged pixels are shown again, well within the time
+/// fixtures wait for them
+pub const STREAM_REFRESH: Duration = Duration::from_millis(250);
+/// How far back a sequence number may be before it's taken as a new start
+const SEQUENCE_WINDOW: i8 = -20;
+
+/// Pixels streamed to one destination
+struct Stream {
+    dest: Destination,
+    encoder: Encoder,
+    pixels: Vec<RGB8>,
+    changed: bool,
+    /// When a universe with some of its pixels last came
+    heard: Instant,
+    shown: Option<Instant>,
+}
+
+/// Forwards DMX levels to fixtures
+pub struct Bridge {
+    map: ChannelMap,
+    /// What was last sent for each entry of the map, if it got there
+    sent: Vec<Option<Vec<u8>>>,
+    /// Last sequence number of each universe
+    sequences: Vec<(u16, u8)>,
+    streams: Vec<Stream>,
+}
+
+impl Bridge {
+    pub fn new(map: ChannelMap) -> Self {
+        let mut streams: Vec<Stream> = Vec::new();
+        for entry in &map.entries {
+            if let Part::Pixels { first, count } = entry.part {
+                let len = match streams.iter().position(|stream| stream.dest == entry.dest) {
+                    Some(i) => streams.remove(i).pixels.len().max(first + count),
+                    None => first + count,
+                };
+                streams.push(Stream {
+                    dest: entry.dest,
+                    encoder: Encoder::new(len),
+                    pixels: vec![RGB8::default(); len],
+                    changed: false,
+                    heard: Instant::now(),
+                    shown: None,
+                });
+            }
+        }
+        Self {
+            sent: vec![None; map.entries.len()],
+            sequences: Vec::new(),
+            map,
+            streams,
+        }
+    }
+
+    /// The messages carrying what changed in a universe, received at `now`
+    pub fn receive(&mut self, levels: &Levels, now: Instant) -> Vec<(Destination, Message)> {
+        let mut messages = Vec::new();
+        // Streams stop being refreshed once they aren't heard
+        if levels.terminated {
+            self.sequences.retain(|&(universe, _)| universe != levels.universe);
+            return messages;
+        }
+        if !self.in_sequence(levels) {
+            return messages;
+        }
+        for (entry, sent) in self.map.entries.iter().zip(self.sent.iter_mut()) {
+            if entry.universe != levels.universe {
+                continue;
+            }
+            let channels = match entry.levels(levels.channels) {
+                Some(channels) => channels,
+                None => continue,
+            };
+            if let Part::Pixels { first, .. } = entry.part {
+                let stream = self.streams.iter_mut().find(|stream| stream.dest == entry.dest).unwrap();
+                for (pixel, level) in stream.pixels[first..].iter_mut().zip(channels.chunks(3)) {
+                    let level = RGB8::new(level[0], level[1], level[2]);
+                    stream.changed |= *pixel != level;
+                    *pixel = level;
+                }
+                stream.heard = now;
+                continue;
+            }
+            if sent.as_deref() == Some(channels) {
+                continue;
+            }
+            messages.push((entry.dest, message(entry, channels)));
+            *sent = Some(channels.to_vec());
+        }
+        messages.extend(self.refresh(now));
+        messages
+    }
+
+    /// Streamed pixels that changed or are due to be shown again at `now`
+    pub fn refresh(&mut self, now: Instant) -> Vec<(Destination, Message)> {
+        let mut messages = Vec::new();
+        for stream in &mut self.streams {
+            let due = match stream.shown {
+                Some(shown) => now.duration_since(shown) >= STREAM_REFRESH,
+                None => stream.changed,
+            };
+            if !(stream.changed || due) || now.duration_since(stream.heard) >= SOURCE_TIMEOUT {
+                continue;
+            }
+            let dest = stream.dest;
+            messages.extend(stream.encoder.encode(&stream.pixels).into_iter().map(|message| (dest, message)));
+            stream.changed = false;
+            stream.shown = Some(now);
+        }
+        messages
+    }
+
+    /// Send what's for `dest` again, as some of it was lost
+    pub fn lost(&mut self, dest: Destination) {
+        for (entry, sent) in self.map.entries.iter().zip(self.sent.iter_mut()) {
+            if entry.dest == dest {
+                *sent = None;
+            }
+        }
+        for stream in self.streams.iter_mut().filter(|stream| stream.dest == dest) {
+            stream.encoder.resend();
+        }
+    }
+
+    /// Whether `levels` are newer than the last of their universe
+    fn in_sequence(&mut self, levels: &Levels) -> bool {
+        let last = match self.sequences.iter_mut().find(|(universe, _)| *universe == levels.universe) {
+            Some((_, last)) => last,
+            None => {
+                self.sequences.push((levels.universe, levels.sequence));
+                return true;
+            }
+        };
+        // Sources that don't count send 0
+        let behind = levels.sequence.wrapping_sub(*last) as i8;
+        if levels.sequence != 0 && behind <= 0 && behind > SEQUENCE_WINDOW {
+            return false;
+        }
+        *last = levels.sequence;
+        true
+    }
+}
+
+/// The message setting `entry` to its `channels`
+fn message(entry: &Entry, channels: &[u8]) -> Message {
+    match entry.part {
+        Part::Dimmer => Message::SetBrightness(channels[0]),
+        Part::Rgb => Message::SetColor(RGB8::new(channels[0], channels[1], channels[2])),
+        Part::Effect => Message::SetEffect {
+            effect: dmx::effect(channels[0]),
+            speed: channels[1],
+        },
+        Part::Pixels { .. } => unreachable!("pixels are streamed"),
+    }
+}
@@ -0,0 +1,470 @@
+//! Serving fixtures to other programs, for as long as we run.
+//!
+//! The daemon has the fixtures announce themselves every
+//! [`DISCOVER_INTERVAL`], adding the ones it hadn't heard of and taking the
+//! ones that stop answering offline, see [`shared::inventory`]. It keeps
+//! track of what they show and asks them how they are every so often. Home
+//! Assistant reaches
+//! them through an MQTT broker, see [`home`], anything else through the
+//! JSON API, see [`api`]. Only the daemon talks to the dongle, so commands
+//! are carried out one at a time, in the order they come.
+//!
+//! [`home`]: crate::home
+//! [`api`]: crate::api
+
+use crate::dongle::{Dongle, Error};
+use crate::http::{self, Exchange};
+use crate::mqtt::{self, Client, Publish};
+use crate::{api, home};
+use serde_json::json;
+use shared::address::{Destination, NodeId};
+use shared::dongle::Outcome;
+use shared::inventory::{Change, FixtureInfo, Inventory, DISCOVER_WINDOW};
+use shared::protocol::{Effect, Frame, Message};
+use shared::status::FixtureStatus;
+use shared::telemetry::LinkReport;
+use smart_leds::RGB8;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often fixtures are asked how they are, unless told otherwise
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How often fixtures are asked to announce themselves, which is also their
+/// heartbeat
+pub const DISCOVER_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait before connecting to the broker again
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to wait for a message or a request at a time
+const TICK: Duration = Duration::from_millis(20);
+
+/// What a fixture shows, as far as we know
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Light {
+    pub id: NodeId,
+    pub effect: Effect,
+    /// The effect turning it on brings back
+    pub lit: Effect,
+    pub speed: u8,
+    pub brightness: u8,
+    /// Fixtures don't report it, so it's what we last set
+    pub color: RGB8,
+    /// It answered the last time we asked it something
+    pub online: bool,
+    /// What it announced about itself
+    pub info: Option<FixtureInfo>,
+}
+
+impl Light {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            effect: Effect::Solid,
+            lit: Effect::Solid,
+            speed: 1,
+            brightness: 255,
+            color: RGB8::new(255, 255, 255),
+            online: true,
+            info: None,
+        }
+    }
+
+    /// Take in what a fixture reported
+    pub fn update(&mut self, status: &FixtureStatus) {
+        self.set_effect(status.effect);
+        self.brightness = status.brightness;
+        self.online = true;
+    }
+
+    /// Take in a change the fixture acknowledged
+    pub fn apply(&mut self, message: &Message) {
+        match *message {
+            Message::SetColor(color) => self.color = color,
+            Message::SetBrightness(brightness) => self.brightness = brightness,
+            Message::SetEffect { effect, speed } => {
+                self.set_effect(effect);
+                self.speed = speed;
+            }
+            Message::SetLook { look, .. } => {
+                self.set_effect(look.effect);
+                self.speed = look.speed;
+                self.brightness = look.brightness;
+                self.color = look.color;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_effect(&mut self, effect: Effect) {
+        self.effect = effect;
+        if effect != Effect::Off {
+            self.lit = effect;
+        }
+    }
+}
+
+/// How to reach a broker, for Home Assistant
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// A host, with or without a port
+    pub broker: String,
+    pub options: mqtt::Options,
+    /// Where Home Assistant looks for discovery configs
+    pub prefix: String,
+}
+
+/// Serves fixtures over MQTT and HTTP
+pub struct Daemon<'a, P> {
+    dongle: &'a mut Dongle<P>,
+    lights: Vec<Light>,
+    inventory: Inventory,
+    mqtt: Option<MqttConfig>,
+    client: Option<Client>,
+    /// When to connect to the broker next, while not connected
+    reconnect: Instant,
+    requests: Option<Receiver<Exchange>>,
+    /// Clients of the event stream
+    subscribers: Vec<Sender<String>>,
+    poll: Duration,
+    polled: Instant,
+    discovered: Instant,
+}
+
+impl<'a, P: Read + Write> Daemon<'a, P> {
+    pub fn new(dongle: &'a mut Dongle<P>) -> Self {
+        let now = Instant::now();
+        Self {
+            dongle,
+            lights: Vec::new(),
+            inventory: Inventory::new(),
+            mqtt: None,
+            client: None,
+            reconnect: now,
+            requests: None,
+            subscribers: Vec::new(),
+            poll: POLL_INTERVAL,
+            polled: now,
+            discovered: now,
+        }
+    }
+
+    /// Serve Home Assistant through a broker
+    pub fn mqtt(&mut self, config: MqttConfig) {
+        let will = (home::BRIDGE_TOPIC.to_string(), home::bridge_state(false).to_string());
+        self.mqtt = Some(MqttConfig {
+            options: mqtt::Options { will: Some(will), ..config.options },
+            ..config
+        });
+    }
+
+    /// Serve the API to what comes in on `requests`, see [`http::listen`]
+    pub fn http(&mut self, requests: Receiver<Exchange>) {
+        self.requests = Some(requests);
+    }
+
+    /// Ask fixtures that often how they are
+    pub fn poll_every(&mut self, interval: Duration) {
+        self.poll = interval;
+    }
+
+    /// Have the fixtures announce themselves, noting them on `log` and
+    /// returning how many did
+    pub fn find(&mut self, log: &mut dyn std::fmt::Write) -> Result<usize, Error> {
+        self.discover(log)?;
+        Ok(self.inventory.online())
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn light(&self, id: NodeId) -> Option<&Light> {
+        self.lights.iter().find(|light| light.id == id)
+    }
+
+    pub fn inventory(&self) -> &Inventory {
+        &self.inventory
+    }
+
+    /// Serve until the dongle is lost, noting what happens on `log`
+    pub fn run(&mut self, log: &mut dyn std::fmt::Write) -> Result<(), Error> {
+        loop {
+            if self.discovered.elapsed() >= DISCOVER_INTERVAL {
+                self.discover(log)?;
+            }
+            if self.polled.elapsed() >= self.poll {
+                self.polled = Instant::now();
+                self.poll_lights()?;
+            }
+            self.connect(log);
+            let publish = match self.client.as_mut().map(|client| client.poll(TICK)) {
+                Some(Ok(publish)) => publish,
+                Some(Err(e)) => {
+                    writeln!(log, "lost the broker: {}", e).ok();
+                    self.disconnected();
+                    None
+                }
+                None => {
+                    if let Some(exchange) = self.wait(TICK) {
+                        self.serve(exchange)?;
+                    }
+                    None
+                }
+            };
+            if let Some(publish) = publish {
+                self.command(publish)?;
+            }
+            while let Some(exchange) = self.requests.as_ref().and_then(|requests| requests.try_recv().ok()) {
+                self.serve(exchange)?;
+            }
+        }
+    }
+
+    /// Send `message` to fixture `id`, taking the change in once it's
+    /// acknowledged
+    pub fn send(&mut self, id: NodeId, message: Message) -> Result<Outcome, Error> {
+        let outcome = self.dongle.send(Destination::Node(id), message)?;
+        if let Some(i) = self.lights.iter().position(|light| light.id == id) {
+            let light = &mut self.lights[i];
+            match outcome {
+                Outcome::Acked(_) | Outcome::Sent => {
+                    light.apply(&message);
+                    light.online = true;
+                }
+                Outcome::Lost => light.online = false,
+                Outcome::Busy => {}
+            }
+            // Even if nothing changed, so whoever asked sees what did
+            self.changed(i);
+        }
+        Ok(outcome)
+    }
+
+    /// Send `message` to every fixture, which isn't acknowledged
+    pub fn broadcast(&mut self, message: Message) -> Result<Outcome, Error> {
+        self.dongle.send(Destination::Broadcast, message)
+    }
+
+    /// What fixture `id` reports about itself and its side of the link
+    pub fn query(&mut self, id: NodeId) -> Result<(Option<FixtureStatus>, Option<LinkReport>), Error> {
+        let (outcome, answer) = self.dongle.request(id, Message::StatusQuery)?;
+        let link = match answer {
+            Some(Frame { message: Message::LinkStatus(report), .. }) => Some(report),
+            _ => None,
+        };
+        let status = match outcome {
+            Outcome::Acked(status) => status,
+            _ => None,
+        };
+        if let Some(i) = self.lights.iter().position(|light| light.id == id) {
+            let before = self.lights[i];
+            let light = &mut self.lights[i];
+            match outcome {
+                Outcome::Acked(Some(status)) => light.update(&status),
+                Outcome::Acked(None) => light.online = true,
+                Outcome::Lost => light.online = false,
+                Outcome::Sent | Outcome::Busy => {}
+            }
+            if self.lights[i] != before {
+                self.changed(i);
+            }
+        }
+        Ok((status, link))
+    }
+
+    /// A stream of events, starting with what every fixture shows
+    pub fn subscribe(&mut self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        for light in &self.lights {
+            sender.send(http::event("fixture", &api::light(light).to_string())).ok();
+        }
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// A round of discovery: add the fixtures we hadn't heard of, and tell
+    /// Home Assistant and event streams about the ones that came and went
+    fn discover(&mut self, log: &mut dyn std::fmt::Write) -> Result<(), Error> {
+        self.discovered = Instant::now();
+        let (outcome, found) = self.dongle.discover(DISCOVER_WINDOW)?;
+        // Nobody heard the discover, so nobody missed it
+        if outcome != Outcome::Sent {
+            return Ok(());
+        }
+        for (id, info) in found {
+            let change = self.inventory.seen(id, info);
+            let i = match self.lights.iter().position(|light| light.id == id) {
+                Some(i) => i,
+                None => {
+                    self.lights.push(Light::new(id));
+                    // Start from what it shows
+                    self.query(id)?;
+                    self.lights.len() - 1
+                }
+            };
+            let before = self.lights[i];
+            self.lights[i].info = Some(info);
+            self.lights[i].online = true;
+            // The discovery config names the firmware
+            if before.info != Some(info) {
+                self.announce_light(i);
+            }
+            if change.is_some() || self.lights[i] != before {
+                self.changed(i);
+            }
+            match change {
+                Some(Change::Found) => writeln!(log, "found fixture {}: {}", id, info),
+                Some(Change::Online) => writeln!(log, "fixture {} is back online", id),
+                Some(Change::Replaced) => writeln!(log, "fixture {} is now {}", id, info),
+                None => Ok(()),
+            }
+            .ok();
+        }
+        let mut offline = Vec::new();
+        self.inventory.end_round(|entry| offline.push(entry.id));
+        for id in offline {
+            writeln!(log, "fixture {} is offline", id).ok();
+            if let Some(i) = self.lights.iter().position(|light| light.id == id) {
+                self.lights[i].online = false;
+                self.changed(i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask every fixture how it is, telling event streams
+    fn poll_lights(&mut self) -> Result<(), Error> {
+        let ids: Vec<NodeId> = self.lights.iter().map(|light| light.id).collect();
+        for id in ids {
+            let (status, link) = self.query(id)?;
+            let online = self.lights.iter().any(|light| light.id == id && light.online);
+            let telemetry = json!({
+                "id": id,
+                "online": online,
+                "status": status.as_ref().map(api::status),
+                "link": link.as_ref().map(api::link),
+            });
+            self.notify(&http::event("telemetry", &telemetry.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Tell Home Assistant and event streams about the light at `i`
+    fn changed(&mut self, i: usize) {
+        let light = self.lights[i];
+        if let Some(client) = self.client.as_mut() {
+            if home::publish_states(client, &light).is_err() {
+                self.disconnected();
+            }
+        }
+        self.notify(&http::event("fixture", &api::light(&light).to_string()));
+    }
+
+    fn notify(&mut self, event: &str) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.to_string()).is_ok());
+    }
+
+    /// A command from Home Assistant
+    fn command(&mut self, publish: Publish) -> Result<(), Error> {
+        let prefix = match &self.mqtt {
+            Some(config) => config.prefix.clone(),
+            None => return Ok(()),
+        };
+        // Home Assistant forgets lights that aren't retained when it restarts
+        if publish.topic == format!("{}/status", prefix) {
+            if publish.payload == home::bridge_state(true).as_bytes() {
+                self.announce(&prefix);
+            }
+            return Ok(());
+        }
+        // Retained commands would be carried out again on every start
+        let (fixture, what) = match home::command_topic(&publish.topic) {
+            Some(command) if !publish.retain => command,
+            _ => return Ok(()),
+        };
+        let message = match self.light(fixture) {
+            Some(light) => light.command(what, &String::from_utf8_lossy(&publish.payload)),
+            None => None,
+        };
+        if let Some(message) = message {
+            self.send(fixture, message)?;
+        }
+        Ok(())
+    }
+
+    /// Connect to the broker if it's time to
+    fn connect(&mut self, log: &mut dyn std::fmt::Write) {
+        let config = match &self.mqtt {
+            Some(config) if self.client.is_none() && Instant::now() >= self.reconnect => config,
+            _ => return,
+        };
+        match Client::connect(&config.broker, &config.options) {
+            Ok(client) => {
+                writeln!(log, "connected to {}", config.broker).ok();
+                let prefix = config.prefix.clone();
+                self.client = Some(client);
+                let status = format!("{}/status", prefix);
+                if let Some(Err(_)) = self.client.as_mut().map(|client| client.subscribe(&["flash/+/+/set", &status])) {
+                    self.disconnected();
+                }
+                self.announce(&prefix);
+            }
+            Err(e) => {
+                writeln!(log, "couldn't connect to {}: {}", config.broker, e).ok();
+                self.reconnect = Instant::now() + RECONNECT_DELAY;
+            }
+        }
+    }
+
+    fn announce(&mut self, prefix: &str) {
+        if let Some(client) = self.client.as_mut() {
+            if home::announce(client, prefix, &self.lights).is_err() {
+                self.disconnected();
+            }
+        }
+    }
+
+    /// Have Home Assistant add the light at `i`, or take in what changed
+    fn announce_light(&mut self, i: usize) {
+        let prefix = match &self.mqtt {
+            Some(config) => config.prefix.clone(),
+            None => return,
+        };
+        if let Some(client) = self.client.as_mut() {
+            if home::announce(client, &prefix, &self.lights[i..=i]).is_err() {
+                self.disconnected();
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.client = None;
+        self.reconnect = Instant::now() + RECONNECT_DELAY;
+    }
+
+    /// Wait up to `timeout` for a request, without a broker to wait on
+    fn wait(&mut self, timeout: Duration) -> Option<Exchange> {
+        let requests = match &self.requests {
+            Some(requests) => requests,
+            None => {
+                thread::sleep(timeout);
+                return None;
+            }
+        };
+        match requests.recv_timeout(timeout) {
+            Ok(exchange) => Some(exchange),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.requests = None;
+                None
+            }
+        }
+    }
+
+    fn serve(&mut self, exchange: Exchange) -> Result<(), Error> {
+        let reply = api::respond(self, &exchange.request)?;
+        exchange.reply(reply);
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+//! DMX over Ethernet: the data packets of E1.31 (sACN) and Art-Net.
+//!
+//! Both carry the levels of one universe of up to 512 channels over UDP.
+//! E1.31 numbers universes from 1 and sends them to a multicast group per
+//! universe, Art-Net numbers them from 0 and broadcasts or unicasts them.
+//! Anything else on the ports, like Art-Net polls or E1.31 sync packets,
+//! is left alone.
+
+use shared::dmx::UNIVERSE_SIZE;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Port E1.31 is sent to
+pub const E131_PORT: u16 = 5568;
+/// Port Art-Net is sent to
+pub const ARTNET_PORT: u16 = 6454;
+
+const ACN_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+/// Offsets into an E1.31 data packet
+const E131_ROOT_VECTOR: usize = 18;
+const E131_FRAMING_VECTOR: usize = 40;
+const E131_SEQUENCE: usize = 111;
+const E131_OPTIONS: usize = 112;
+const E131_UNIVERSE: usize = 113;
+const E131_DMP_VECTOR: usize = 117;
+const E131_VALUE_COUNT: usize = 123;
+const E131_START_CODE: usize = 125;
+/// Set in the options of packets for visualisers, not for lights
+const E131_PREVIEW: u8 = 0x80;
+/// Set in the options of the last packets a source sends
+const E131_TERMINATED: u8 = 0x40;
+
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+/// Size of an ArtDmx header
+const ARTNET_HEADER: usize = 18;
+
+/// Why a packet was left alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    /// It isn't a packet of the protocol, or it's cut short
+    Invalid,
+    /// It's a packet of the protocol without levels for lights
+    NotDmx,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::Invalid => write!(f, "invalid packet"),
+            PacketError::NotDmx => write!(f, "not DMX data"),
+        }
+    }
+}
+
+/// Where a packet came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    E131,
+    ArtNet,
+}
+
+impl Protocol {
+    pub fn parse(self, packet: &[u8]) -> Result<Levels<'_>, PacketError> {
+        match self {
+            Protocol::E131 => parse_e131(packet),
+            Protocol::ArtNet => parse_artnet(packet),
+        }
+    }
+}
+
+/// The levels of one universe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Levels<'a> {
+    pub universe: u16,
+    /// Counts up with every packet the source sends, 0 if it doesn't count
+    pub sequence: u8,
+    /// Channel 1 first
+    pub channels: &'a [u8],
+    /// The source stopped sending this universe
+    pub terminated: bool,
+}
+
+/// The multicast group E1.31 sends `universe` to
+pub fn e131_group(universe: u16) -> Ipv4Addr {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, hi, lo)
+}
+
+/// Read an E1.31 data packet
+pub fn parse_e131(packet: &[u8]) -> Result<Levels<'_>, PacketError> {
+    let half = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+    let word = |i: usize| u32::from_be_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]]);
+    if packet.len() < E131_FRAMING_VECTOR + 4 || half(0) != 0x0010 || &packet[4..16] != ACN_IDENTIFIER {
+        return Err(PacketError::Invalid);
+    }
+    // Sync and discovery packets have other vectors, and sync packets are
+    // shorter than any data packet
+    if word(E131_ROOT_VECTOR) != VECTOR_ROOT_E131_DATA || word(E131_FRAMING_VECTOR) != VECTOR_E131_DATA_PACKET {
+        return Err(PacketError::NotDmx);
+    }
+    if packet.len() < E131_START_CODE || packet[E131_DMP_VECTOR] != VECTOR_DMP_SET_PROPERTY {
+        return Err(PacketError::Invalid);
+    }
+    let options = packet[E131_OPTIONS];
+    // The count includes the start code
+    let count = half(E131_VALUE_COUNT) as usize;
+    let values = packet.get(E131_START_CODE..E131_START_CODE + count).ok_or(PacketError::Invalid)?;
+    match values.split_first() {
+        Some((0, channels)) if options & E131_PREVIEW == 0 => Ok(Levels {
+            universe: half(E131_UNIVERSE),
+            sequence: packet[E131_SEQUENCE],
+            channels: &channels[..channels.len().min(UNIVERSE_SIZE)],
+            terminated: options & E131_TERMINATED != 0,
+        }),
+        Some(_) => Err(PacketError::NotDmx),
+        None => Err(PacketError::Invalid),
+    }
+}
+
+/// Read an ArtDmx packet
+pub fn parse_artnet(packet: &[u8]) -> Result<Levels<'_>, PacketError> {
+    if packet.len() < 10 || &packet[..8] != ARTNET_ID {
+        return Err(PacketError::Invalid);
+    }
+    if u16::from_le_bytes([packet[8], packet[9]]) != OP_DMX {
+        return Err(PacketError::NotDmx);
+    }
+    if packet.len() < ARTNET_HEADER {
+        return Err(PacketError::Invalid);
+    }
+    let len = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    let channels = packet.get(ARTNET_HEADER..ARTNET_HEADER + len).ok_or(PacketError::Invalid)?;
+    Ok(Levels {
+        // Sub-net and universe, then net
+        universe: u16::from_le_bytes([packet[14], packet[15] & 0x7f]),
+        sequence: packet[12],
+        channels: &channels[..len.min(UNIVERSE_SIZE)],
+        terminated: false,
+    })
+}
+
+/// Listen on both ports, joining the E1.31 groups of `universes`, and hand
+/// the packets that come in over to the receiver
+pub fn listen(universes: &[u16]) -> io::Result<Receiver<(Protocol, Vec<u8>)>> {
+    let e131 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, E131_PORT))?;
+    for &universe in universes {
+        // Desks sending to us directly still get through
+        if let Err(e) = e131.join_multicast_v4(&e131_group(universe), &Ipv4Addr::UNSPECIFIED) {
+            eprintln!("couldn't join the group of universe {}: {}", universe, e);
+        }
+    }
+    let artnet = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, ARTNET_PORT))?;
+    let (sender, receiver) = mpsc::channel();
+    for (socket, protocol) in [(e131, Protocol::E131), (artnet, Protocol::ArtNet)].iter() {
+        let socket = socket.try_clone()?;
+        let sender = sender.clone();
+        let protocol = *protocol;
+        thread::spawn(move || receive(socket, protocol, sender));
+    }
+    Ok(receiver)
+}
+
+fn receive(socket: UdpSocket, protocol: Protocol, packets: Sender<(Protocol, Vec<u8>)>) -> io::Result<()> {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf)?;
+        if packets.send((protocol, buf[..len].to_vec())).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// An E1.31 data packet for `universe`, start code 0 then `channels`
+    fn e131(universe: u16, sequence: u8, options: u8, channels: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; E131_START_CODE + 1];
+        packet[..2].copy_from_slice(&0x0010u16.to_be_bytes());
+        packet[4..16].copy_from_slice(ACN_IDENTIFIER);
+        packet[E131_ROOT_VECTOR..E131_ROOT_VECTOR + 4].copy_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet[E131_FRAMING_VECTOR..E131_FRAMING_VECTOR + 4].copy_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        packet[E131_SEQUENCE] = sequence;
+        packet[E131_OPTIONS] = options;
+        packet[E131_UNIVERSE..E131_UNIVERSE + 2].copy_from_slice(&universe.to_be_bytes());
+        packet[E131_DMP_VECTOR] = VECTOR_DMP_SET_PROPERTY;
+        let count = channels.len() as u16 + 1;
+        packet[E131_VALUE_COUNT..E131_VALUE_COUNT + 2].copy_from_slice(&count.to_be_bytes());
+        packet.extend_from_slice(channels);
+        packet
+    }
+
+    /// An ArtDmx packet for `universe`
+    fn artnet(universe: u16, sequence: u8, channels: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; ARTNET_HEADER];
+        packet[..8].copy_from_slice(ARTNET_ID);
+        packet[8..10].copy_from_slice(&OP_DMX.to_le_bytes());
+        packet[11] = 14;
+        packet[12] = sequence;
+        packet[14..16].copy_from_slice(&universe.to_le_bytes());
+        packet[16..18].copy_from_slice(&(channels.len() as u16).to_be_bytes());
+        packet.extend_from_slice(channels);
+        packet
+    }
+
+    #[test]
+    fn e131_levels_are_read() {
+        let packet = e131(0x0102, 7, 0, &[1, 2, 3]);
+        let levels = parse_e131(&packet).unwrap();
+        assert_eq!(levels, Levels { universe: 0x0102, sequence: 7, channels: &[1, 2, 3], terminated: false });
+
+        // The last packets of a source still carry levels
+        let packet = e131(1, 8, E131_TERMINATED, &[4]);
+        assert!(parse_e131(&packet).unwrap().terminated);
+        assert_eq!(Protocol::E131.parse(&packet), parse_e131(&packet));
+    }
+
+    #[test]
+    fn e131_universes_are_cut_to_size() {
+        let packet = e131(1, 0, 0, &[9; UNIVERSE_SIZE + 1]);
+        assert_eq!(parse_e131(&packet).unwrap().channels.len(), UNIVERSE_SIZE);
+    }
+
+    #[test]
+    fn e131_packets_not_for_lights_are_left_alone() {
+        assert_eq!(parse_e131(&e131(1, 0, E131_PREVIEW, &[1])), Err(PacketError::NotDmx));
+        let mut packet = e131(1, 0, 0, &[1]);
+        packet[E131_START_CODE] = 0xDD;
+        assert_eq!(parse_e131(&packet), Err(PacketError::NotDmx));
+
+        // A sync packet: the extended root vector, then the sync vector
+        let mut sync = e131(1, 0, 0, &[])[..49].to_vec();
+        sync[E131_ROOT_VECTOR..E131_ROOT_VECTOR + 4].copy_from_slice(&8u32.to_be_bytes());
+        sync[E131_FRAMING_VECTOR..E131_FRAMING_VECTOR + 4].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(parse_e131(&sync), Err(PacketError::NotDmx));
+    }
+
+    #[test]
+    fn broken_e131_packets_are_refused() {
+        let packet = e131(1, 0, 0, &[1, 2, 3]);
+        // Cut anywhere, even in the middle of the levels
+        for len in 0..packet.len() {
+            assert_eq!(parse_e131(&packet[..len]), Err(PacketError::Invalid), "cut to {}", len);
+        }
+        let mut other = packet.clone();
+        other[4] = b'B';
+        assert_eq!(parse_e131(&other), Err(PacketError::Invalid));
+        let mut other = packet;
+        other[E131_DMP_VECTOR] = 0x01;
+        assert_eq!(parse_e131(&other), Err(PacketError::Invalid));
+    }
+
+    #[test]
+    fn artnet_levels_are_read() {
+        let packet = artnet(0x7F12, 3, &[10, 20]);
+        let levels = parse_artnet(&packet).unwrap();
+        assert_eq!(levels, Levels { universe: 0x7F12, sequence: 3, channels: &[10, 20], terminated: false });
+        assert_eq!(Protocol::ArtNet.parse(&packet), Ok(levels));
+
+        // Only 15 bits of the port address are the universe
+        assert_eq!(parse_artnet(&artnet(0x8001, 0, &[0])).unwrap().universe, 1);
+        assert_eq!(parse_artnet(&artnet(1, 0, &[0; UNIVERSE_SIZE + 2])).unwrap().channels.len(), UNIVERSE_SIZE);
+    }
+
+    #[test]
+    fn other_artnet_packets_are_left_alone() {
+        let mut poll = artnet(0, 0, &[])[..14].to_vec();
+        poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        assert_eq!(parse_artnet(&poll), Err(PacketError::NotDmx));
+
+        let packet = artnet(0, 0, &[1, 2, 3]);
+        for len in 0..packet.len() {
+            assert_eq!(parse_artnet(&packet[..len]), Err(PacketError::Invalid), "cut to {}", len);
+        }
+        assert_eq!(parse_artnet(b"Art-Nyt\0\0\x50"), Err(PacketError::Invalid));
+    }
+
+    #[test]
+    fn universes_have_their_own_groups() {
+        assert_eq!(e131_group(1), Ipv4Addr::new(239, 255, 0, 1));
+        assert_eq!(e131_group(0x1234), Ipv4Addr::new(239, 255, 0x12, 0x34));
+    }
+
+    #[test]
+    fn packets_sent_to_us_are_handed_over() {
+        let packets = listen(&[1]).unwrap();
+        let desk = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let e131 = e131(1, 1, 0, &[255]);
+        desk.send_to(&e131, (Ipv4Addr::LOCALHOST, E131_PORT)).unwrap();
+        let timeout = Duration::from_secs(1);
+        assert_eq!(packets.recv_timeout(timeout).unwrap(), (Protocol::E131, e131));
+        let artnet = artnet(0, 1, &[255]);
+        desk.send_to(&artnet, (Ipv4Addr::LOCALHOST, ARTNET_PORT)).unwrap();
+        assert_eq!(packets.recv_timeout(timeout).unwrap(), (Protocol::ArtNet, artnet));
+    }
+}
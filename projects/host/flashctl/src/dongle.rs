@@ -0,0 +1,239 @@
+//! A connection to the dongle.
+
+use serialport::SerialPort;
+use shared::address::{Destination, NodeId, CONTROLLER_ID};
+use shared::cli;
+use shared::dongle::{Deframer, Outcome, Packet, MAX_FRAMED_SIZE};
+use shared::inventory::{FixtureInfo, MAX_DISCOVER_WINDOW};
+use shared::protocol::{Frame, Message};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How long the dongle may take to say how sending went
+pub const SENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a fixture may take to answer a request. The dongle waits up to
+/// two seconds for a fixture checking a firmware image.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long announcements may still take after the discover window
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Baud rate of the port, which USB ignores
+const BAUD_RATE: u32 = 115_200;
+/// How long a read waits for the next byte
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// What went wrong talking to the dongle
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The port couldn't be opened
+    Serial(serialport::Error),
+    /// The dongle didn't answer in time
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Serial(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "the dongle didn't answer"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+/// For commands on the [`shared::cli`] command line
+impl From<Error> for cli::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Timeout => cli::Error::Failed("the dongle didn't answer"),
+            _ => cli::Error::Failed("lost the dongle"),
+        }
+    }
+}
+
+/// What the dongle sent, besides the log
+enum Incoming {
+    Sent { seq: u8, outcome: Outcome },
+    Received(Frame),
+}
+
+/// The dongle, taking packets
+pub struct Dongle<P> {
+    port: P,
+    deframer: Deframer,
+    seq: u8,
+    /// Frames that came in while waiting for something else
+    received: VecDeque<Frame>,
+}
+
+impl Dongle<Box<dyn SerialPort>> {
+    /// Open the dongle's serial port, like `/dev/ttyACM0`
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut port = serialport::new(path, BAUD_RATE).timeout(READ_TIMEOUT).open()?;
+        // The dongle takes packets while DTR is set. Ptys don't have it.
+        port.write_data_terminal_ready(true).ok();
+        Self::new(port)
+    }
+}
+
+impl<P: Read + Write> Dongle<P> {
+    /// Switch the dongle on `port` over to packets. Reads from the port have
+    /// to time out rather than block.
+    pub fn new(mut port: P) -> Result<Self, Error> {
+        // The zero ends anything before it, in case the dongle took packets
+        // already
+        port.write_all(b"dongle\n\0")?;
+        let mut dongle = Self {
+            port,
+            deframer: Deframer::new(),
+            seq: 0,
+            received: VecDeque::new(),
+        };
+        // The answer is the first packet, what's before it is old log
+        let deadline = Instant::now() + SENT_TIMEOUT;
+        while dongle.read_byte(deadline)? != 0 {}
+        Ok(dongle)
+    }
+
+    /// Send `message` to `dest`, returning how that went
+    pub fn send(&mut self, dest: Destination, message: Message) -> Result<Outcome, Error> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let frame = Frame::new(dest, CONTROLLER_ID, seq, message);
+        self.write(&Packet::Send(frame))?;
+
+        let deadline = Instant::now() + SENT_TIMEOUT;
+        loop {
+            match self.read(deadline)? {
+                Incoming::Sent { seq: sent, outcome } if sent == seq => return Ok(outcome),
+                Incoming::Sent { .. } => {}
+                Incoming::Received(frame) => self.received.push_back(frame),
+            }
+        }
+    }
+
+    /// Send `message` to `fixture` and wait for its answer, if it got it
+    pub fn request(&mut self, fixture: NodeId, message: Message) -> Result<(Outcome, Option<Frame>), Error> {
+        self.received.retain(|frame| frame.header.src != fixture);
+        let outcome = self.send(Destination::Node(fixture), message)?;
+        let answer = match outcome {
+            Outcome::Acked(_) => self.receive_from(fixture, REPLY_TIMEOUT)?,
+            _ => None,
+        };
+        Ok((outcome, answer))
+    }
+
+    /// Ask every fixture to announce itself within `window` ms, returning
+    /// how sending that went and the fixtures that did
+    pub fn discover(&mut self, window: u16) -> Result<(Outcome, Vec<(NodeId, FixtureInfo)>), Error> {
+        self.received.retain(|frame| !matches!(frame.message, Message::Announce(_)));
+        let outcome = self.send(Destination::Broadcast, Message::Discover { window })?;
+        let mut found = Vec::new();
+        if outcome != Outcome::Sent {
+            return Ok((outcome, found));
+        }
+        let window = Duration::from_millis(window.min(MAX_DISCOVER_WINDOW).into());
+        let deadline = Instant::now() + window + ANNOUNCE_TIMEOUT;
+        loop {
+            match self.read(deadline) {
+                Ok(Incoming::Received(Frame { header, message: Message::Announce(info) })) => {
+                    // Relays may pass the same answer on twice
+                    if !found.iter().any(|&(id, _)| id == header.src) {
+                        found.push((header.src, info));
+                    }
+                }
+                Ok(Incoming::Received(frame)) => self.received.push_back(frame),
+                Ok(Incoming::Sent { .. }) => {}
+                Err(Error::Timeout) => return Ok((outcome, found)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The next frame a fixture sent us, waiting up to `timeout` for it
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Frame>, Error> {
+        if let Some(frame) = self.received.pop_front() {
+            return Ok(Some(frame));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.read(deadline) {
+                Ok(Incoming::Received(frame)) => return Ok(Some(frame)),
+                Ok(Incoming::Sent { .. }) => {}
+                Err(Error::Timeout) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The next frame from `fixture`, keeping the others
+    fn receive_from(&mut self, fixture: NodeId, timeout: Duration) -> Result<Option<Frame>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.read(deadline) {
+                Ok(Incoming::Received(frame)) if frame.header.src == fixture => return Ok(Some(frame)),
+                Ok(Incoming::Received(frame)) => self.received.push_back(frame),
+                Ok(Incoming::Sent { .. }) => {}
+                Err(Error::Timeout) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write(&mut self, packet: &Packet) -> Result<(), Error> {
+        let mut framed = [0u8; MAX_FRAMED_SIZE];
+        let len = packet.frame(&mut framed);
+        self.port.write_all(&framed[..len])?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// The next packet, printing the log to stderr as it comes
+    fn read(&mut self, deadline: Instant) -> Result<Incoming, Error> {
+        loop {
+            let byte = self.read_byte(deadline)?;
+            let len = match self.deframer.push(byte) {
+                Some(len) => len,
+                None => continue,
+            };
+            match Packet::decode(self.deframer.packet(len)) {
+                Ok(Packet::Sent { seq, outcome, .. }) => return Ok(Incoming::Sent { seq, outcome }),
+                Ok(Packet::Received(frame)) => return Ok(Incoming::Received(frame)),
+                Ok(Packet::Text(text)) => eprint!("{}", text),
+                Ok(Packet::Send(_)) => {}
+                Err(e) => eprintln!("dropped a packet from the dongle: {:?}", e),
+            }
+        }
+    }
+
+    fn read_byte(&mut self, deadline: Instant) -> Result<u8, Error> {
+        let mut byte = [0u8; 1];
+        loop {
+            match self.port.read(&mut byte) {
+                Ok(1) => return Ok(byte[0]),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
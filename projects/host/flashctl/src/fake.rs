@@ -0,0 +1,289 @@
+//! A dongle and fixtures that only exist on a pty.
+//!
+//! [`FakeDongle::start`] opens a pty and answers on it like `tx` in dongle
+//! mode would, with paired fixtures that always hear it until they're
+//! switched off. They keep track of their look, groups, scenes and firmware
+//! updates, and answer status queries and discovers, so anything using
+//! [`Dongle`](crate::Dongle) can run against it.
+//! Closing the pty ends dongle mode, like closing the real one's port.
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use shared::address::{Destination, GroupId, NodeId, CONTROLLER_ID};
+use shared::cli::Line;
+use shared::dongle::{Deframer, Outcome, Packet, MAX_FRAMED_SIZE};
+use shared::inventory::{Capabilities, Chipset, FixtureInfo, Layout};
+use shared::ota::{chunk_count, OtaStatus};
+use shared::protocol::{Effect, Frame, Message};
+use shared::scene::Look;
+use shared::status::FixtureStatus;
+use shared::telemetry::LinkReport;
+use smart_leds::RGB8;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to look for a client while nobody has the pty open
+const HANG_UP_POLL: Duration = Duration::from_millis(10);
+
+/// A dongle answering on a pty until the program ends
+pub struct FakeDongle {
+    path: PathBuf,
+    off: Arc<Mutex<Vec<NodeId>>>,
+}
+
+impl FakeDongle {
+    /// Start a dongle paired with `fixtures`
+    pub fn start(fixtures: &[NodeId]) -> io::Result<Self> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let path = PathBuf::from(ptsname_r(&master)?);
+
+        // Bytes have to pass as they are, without echo or line editing
+        let port = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut termios = tcgetattr(port.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(port.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+        let off = Arc::new(Mutex::new(Vec::new()));
+        let state = State {
+            fixtures: fixtures.iter().map(|&id| Fixture::new(id)).collect(),
+            off: off.clone(),
+            framed: false,
+            line: Line::new(),
+            deframer: Deframer::new(),
+        };
+        thread::spawn(move || state.serve(master));
+        Ok(Self { path, off })
+    }
+
+    /// The pty to open in place of the dongle's serial port
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Have fixture `id` stop hearing anything, like it lost power
+    pub fn switch_off(&self, id: NodeId) {
+        self.off.lock().unwrap().push(id);
+    }
+}
+
+struct State {
+    fixtures: Vec<Fixture>,
+    /// Fixtures that were switched off
+    off: Arc<Mutex<Vec<NodeId>>>,
+    /// The client sent `dongle`
+    framed: bool,
+    line: Line,
+    deframer: Deframer,
+}
+
+impl State {
+    fn serve(mut self, mut port: PtyMaster) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            let len = match port.read(&mut buf) {
+                Ok(len) => len,
+                // Nobody has the pty open, which ends dongle mode like
+                // closing the dongle's port does
+                Err(e) if e.raw_os_error() == Some(Errno::EIO as i32) => {
+                    self.framed = false;
+                    self.line = Line::new();
+                    self.deframer = Deframer::new();
+                    thread::sleep(HANG_UP_POLL);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for &byte in &buf[..len] {
+                self.receive(&mut port, byte)?;
+            }
+        }
+    }
+
+    fn receive(&mut self, port: &mut PtyMaster, byte: u8) -> io::Result<()> {
+        if !self.framed {
+            return match self.line.push(byte) {
+                Some(line) if line.as_str().trim() == "dongle" => {
+                    self.framed = true;
+                    reply(port, &Packet::Text("ok\n"))
+                }
+                Some(_) => port.write_all(b"error: the fake dongle only knows dongle\n"),
+                None => Ok(()),
+            };
+        }
+        let len = match self.deframer.push(byte) {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        match Packet::decode(self.deframer.packet(len)) {
+            Ok(Packet::Send(frame)) => self.forward(port, frame),
+            Ok(Packet::Text(_)) => reply(port, &Packet::Text("error: the fake dongle takes no commands\n")),
+            Ok(_) => reply(port, &Packet::Text("error: the dongle only sends frames\n")),
+            Err(_) => reply(port, &Packet::Text("error: dropped a packet\n")),
+        }
+    }
+
+    /// Hand `frame` to the fixtures it's for, answering like `tx` would
+    fn forward(&mut self, port: &mut PtyMaster, frame: Frame) -> io::Result<()> {
+        let Frame { header, message } = frame;
+        let off = self.off.lock().unwrap().clone();
+        let mut fixtures = self.fixtures.iter_mut().filter(|fixture| !off.contains(&fixture.id));
+        let outcome = match header.dest {
+            Destination::Node(id) => match fixtures.find(|fixture| fixture.id == id) {
+                Some(fixture) => {
+                    let answer = fixture.handle(message);
+                    let outcome = Outcome::Acked(Some(fixture.status()));
+                    reply(port, &Packet::Sent { seq: header.seq, dest: header.dest, outcome })?;
+                    if let Some(answer) = answer {
+                        let frame = Frame::new(Destination::Node(CONTROLLER_ID), id, 0, answer);
+                        reply(port, &Packet::Received(frame))?;
+                    }
+                    return Ok(());
+                }
+                None => Outcome::Lost,
+            },
+            Destination::Group(group) => {
+                for fixture in fixtures.filter(|fixture| fixture.groups.contains(&group)) {
+                    fixture.handle(message);
+                }
+                Outcome::Sent
+            }
+            Destination::Broadcast => {
+                let answers: Vec<Frame> = fixtures
+                    .filter_map(|fixture| {
+                        let answer = fixture.handle(message)?;
+                        Some(Frame::new(Destination::Node(CONTROLLER_ID), fixture.id, 0, answer))
+                    })
+                    .collect();
+                reply(port, &Packet::Sent { seq: header.seq, dest: header.dest, outcome: Outcome::Sent })?;
+                // Only discovers are answered by everyone
+                for frame in answers {
+                    reply(port, &Packet::Received(frame))?;
+                }
+                return Ok(());
+            }
+        };
+        reply(port, &Packet::Sent { seq: header.seq, dest: header.dest, outcome })
+    }
+}
+
+fn reply(port: &mut PtyMaster, packet: &Packet) -> io::Result<()> {
+    let mut framed = [0u8; MAX_FRAMED_SIZE];
+    let len = packet.frame(&mut framed);
+    port.write_all(&framed[..len])
+}
+
+/// A fixture that hears everything
+struct Fixture {
+    id: NodeId,
+    look: Look,
+    groups: Vec<GroupId>,
+    scenes: HashMap<u8, Look>,
+    /// Chunks of the update in progress, and whether they arrived
+    update: Option<Vec<bool>>,
+    report: LinkReport,
+    started: Instant,
+}
+
+impl Fixture {
+    fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            look: Look {
+                effect: Effect::Rainbow,
+                speed: 1,
+                brightness: 255,
+                color: RGB8::new(255, 255, 255),
+            },
+            groups: Vec::new(),
+            scenes: HashMap::new(),
+            update: None,
+            report: LinkReport::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn status(&self) -> FixtureStatus {
+        FixtureStatus {
+            effect: self.look.effect,
+            brightness: self.look.brightness,
+            power_limited: false,
+            temperature: 25,
+            uptime: self.started.elapsed().as_secs() as u32,
+        }
+    }
+
+    /// What it tells about itself, like a fixture built from `lights`
+    fn info(&self) -> FixtureInfo {
+        FixtureInfo {
+            uid: 0xFA4E_0000_0000_0000 | self.id as u64,
+            firmware: 1,
+            leds: 50,
+            chipset: Chipset::Ws2812,
+            layout: Layout::Strip,
+            capabilities: Capabilities::DMX
+                .with(Capabilities::STREAM)
+                .with(Capabilities::OTA)
+                .with(Capabilities::POWER_LIMIT),
+        }
+    }
+
+    /// Act on a message, returning the answer to requests
+    fn handle(&mut self, message: Message) -> Option<Message> {
+        self.report.received = self.report.received.saturating_add(1);
+        self.report.strong = self.report.strong.saturating_add(1);
+        match message {
+            Message::SetBrightness(brightness) => self.look.brightness = brightness,
+            Message::SetEffect { effect, speed } => {
+                self.look.effect = effect;
+                self.look.speed = speed;
+            }
+            Message::SetColor(color) => self.look.color = color,
+            Message::SetLook { look, .. } => self.look = look,
+            Message::JoinGroup(group) if !self.groups.contains(&group) => self.groups.push(group),
+            Message::LeaveGroup(group) => self.groups.retain(|&g| g != group),
+            Message::ClearGroups => self.groups.clear(),
+            Message::StoreScene { scene, look } => {
+                self.scenes.insert(scene, look);
+            }
+            Message::RecallScene { scene, .. } => {
+                if let Some(&look) = self.scenes.get(&scene) {
+                    self.look = look;
+                }
+            }
+            Message::StatusQuery => return Some(Message::LinkStatus(self.report)),
+            Message::Discover { .. } => return Some(Message::Announce(self.info())),
+            Message::OtaBegin { size } => {
+                self.update = Some(vec![false; chunk_count(size)]);
+                return Some(Message::OtaStatus(OtaStatus::Ready));
+            }
+            Message::OtaChunk { index, .. } => {
+                if let Some(chunk) = self.update.as_mut().and_then(|chunks| chunks.get_mut(index as usize)) {
+                    *chunk = true;
+                }
+            }
+            Message::OtaEnd => {
+                let chunks = self.update.as_ref()?;
+                let status = match chunks.iter().position(|&arrived| !arrived) {
+                    Some(index) => OtaStatus::Missing(index as u16),
+                    None => {
+                        self.update = None;
+                        OtaStatus::Accepted
+                    }
+                };
+                return Some(Message::OtaStatus(status));
+            }
+            _ => {}
+        }
+        None
+    }
+}
@@ -0,0 +1,238 @@
+//! Fixtures as lights in Home Assistant, through its MQTT integration.
+//!
+//! Each fixture is announced with a retained discovery config, so it shows
+//! up as a light with a brightness, a colour and an effect. It uses Home
+//! Assistant's default schema, a topic for each of them:
+//!
+//! ```text
+//! homeassistant/light/flash_<id>/config   discovery config, JSON
+//! homeassistant/status                    online when Home Assistant starts
+//! flash/bridge                            online, or offline once we're gone
+//! flash/<id>/available                    online, or offline if it stops answering
+//! flash/<id>/state[/set]                  ON or OFF
+//! flash/<id>/brightness[/set]             0 to 255
+//! flash/<id>/rgb[/set]                    <red>,<green>,<blue>
+//! flash/<id>/effect[/set]                 solid or rainbow
+//! ```
+//!
+//! Commands come in on the `/set` topics, and the state is published,
+//! retained, once the fixture acknowledges the change. Off is the `off`
+//! effect, and on brings back the one before it.
+
+use crate::daemon::Light;
+use crate::mqtt::{self, Client};
+use shared::address::NodeId;
+use shared::protocol::{Effect, Message};
+use smart_leds::RGB8;
+
+/// Where Home Assistant looks for discovery configs, unless told otherwise
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+/// Says whether we're running, for all the lights
+pub const BRIDGE_TOPIC: &str = "flash/bridge";
+/// The effects offered, besides off
+pub const EFFECTS: [Effect; 2] = [Effect::Solid, Effect::Rainbow];
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+impl Light {
+    /// The message carrying out a command on `/set` topic `what`, if it is
+    /// one
+    pub fn command(&self, what: &str, payload: &str) -> Option<Message> {
+        let effect = |effect| Message::SetEffect { effect, speed: self.speed };
+        match (what, payload.trim()) {
+            ("state", "ON") => Some(effect(self.lit)),
+            ("state", "OFF") => Some(effect(Effect::Off)),
+            ("brightness", brightness) => brightness.parse().ok().map(Message::SetBrightness),
+            ("rgb", rgb) => {
+                let mut levels = rgb.split(',').map(|level| level.trim().parse::<u8>());
+                match (levels.next(), levels.next(), levels.next(), levels.next()) {
+                    (Some(Ok(r)), Some(Ok(g)), Some(Ok(b)), None) => Some(Message::SetColor(RGB8::new(r, g, b))),
+                    _ => None,
+                }
+            }
+            ("effect", name) => Effect::from_name(name).filter(|e| EFFECTS.contains(e)).map(effect),
+            _ => None,
+        }
+    }
+
+    /// The config that has Home Assistant add the light, and where it goes
+    pub fn discovery(&self, prefix: &str) -> (String, String) {
+        let topic = |what: &str| format!("{}/{}", self.topic(), what);
+        let effects: Vec<String> = EFFECTS.iter().map(|effect| format!("\"{}\"", effect.name())).collect();
+        let firmware = match self.info {
+            Some(info) => format!(",\"sw_version\":\"{}\",\"serial_number\":\"{:016x}\"", info.firmware, info.uid),
+            None => String::new(),
+        };
+        let config = format!(
+            concat!(
+                "{{\"name\":null,\"unique_id\":\"flash_{id}\",",
+                "\"device\":{{\"identifiers\":[\"flash_{id}\"],\"name\":\"Fixture {id}\",\"manufacturer\":\"flash\",\"model\":\"lights\"{firmware}}},",
+                "\"availability\":[{{\"topic\":\"{bridge}\"}},{{\"topic\":\"{available}\"}}],\"availability_mode\":\"all\",",
+                "\"state_topic\":\"{state}\",\"command_topic\":\"{state}/set\",",
+                "\"brightness_state_topic\":\"{brightness}\",\"brightness_command_topic\":\"{brightness}/set\",",
+                "\"rgb_state_topic\":\"{rgb}\",\"rgb_command_topic\":\"{rgb}/set\",",
+                "\"effect_state_topic\":\"{effect}\",\"effect_command_topic\":\"{effect}/set\",\"effect_list\":[{effects}]}}",
+            ),
+            id = self.id,
+            firmware = firmware,
+            bridge = BRIDGE_TOPIC,
+            available = topic("available"),
+            state = topic("state"),
+            brightness = topic("brightness"),
+            rgb = topic("rgb"),
+            effect = topic("effect"),
+            effects = effects.join(","),
+        );
+        (format!("{}/light/flash_{}/config", prefix, self.id), config)
+    }
+
+    /// The state topics and what to publish on them
+    pub fn states(&self) -> Vec<(String, String)> {
+        let topic = |what: &str| format!("{}/{}", self.topic(), what);
+        let color = self.color;
+        vec![
+            (topic("available"), if self.online { ONLINE } else { OFFLINE }.to_string()),
+            (topic("state"), if self.effect == Effect::Off { "OFF" } else { "ON" }.to_string()),
+            (topic("brightness"), self.brightness.to_string()),
+            (topic("rgb"), format!("{},{},{}", color.r, color.g, color.b)),
+            (topic("effect"), self.lit.name().to_string()),
+        ]
+    }
+
+    /// Where the light's topics start
+    pub fn topic(&self) -> String {
+        format!("flash/{}", self.id)
+    }
+}
+
+/// The fixture and `/set` topic a command came in on
+pub fn command_topic(topic: &str) -> Option<(NodeId, &str)> {
+    let mut parts = topic.split('/');
+    match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("flash"), Some(id), Some(what), Some("set"), None) => Some((id.parse().ok()?, what)),
+        _ => None,
+    }
+}
+
+/// What to publish to [`BRIDGE_TOPIC`] while we're running or not
+pub fn bridge_state(running: bool) -> &'static str {
+    if running {
+        ONLINE
+    } else {
+        OFFLINE
+    }
+}
+
+/// Have Home Assistant add `lights`, and tell it what they show
+pub fn announce(client: &mut Client, prefix: &str, lights: &[Light]) -> Result<(), mqtt::Error> {
+    client.publish(BRIDGE_TOPIC, bridge_state(true).as_bytes(), true)?;
+    for light in lights {
+        let (topic, config) = light.discovery(prefix);
+        client.publish(&topic, config.as_bytes(), true)?;
+        publish_states(client, light)?;
+    }
+    Ok(())
+}
+
+pub fn publish_states(client: &mut Client, light: &Light) -> Result<(), mqtt::Error> {
+    for (topic, state) in light.states() {
+        client.publish(&topic, state.as_bytes(), true)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use shared::inventory::{Capabilities, Chipset, FixtureInfo, Layout};
+
+    fn light() -> Light {
+        Light { lit: Effect::Rainbow, speed: 3, ..Light::new(7) }
+    }
+
+    #[test]
+    fn commands_become_messages() {
+        let light = light();
+        let effect = |effect| Some(Message::SetEffect { effect, speed: 3 });
+        assert_eq!(light.command("state", "ON"), effect(Effect::Rainbow));
+        assert_eq!(light.command("state", "OFF\n"), effect(Effect::Off));
+        assert_eq!(light.command("brightness", " 128 "), Some(Message::SetBrightness(128)));
+        assert_eq!(light.command("rgb", "1, 2,3"), Some(Message::SetColor(RGB8::new(1, 2, 3))));
+        assert_eq!(light.command("effect", "solid"), effect(Effect::Solid));
+    }
+
+    #[test]
+    fn bad_commands_are_ignored() {
+        let light = light();
+        for &(what, payload) in &[
+            ("state", "on"),
+            ("brightness", "256"),
+            ("brightness", "-1"),
+            ("rgb", "1,2"),
+            ("rgb", "1,2,3,4"),
+            ("rgb", "1,2,300"),
+            // Off is turning it off, not an effect
+            ("effect", "off"),
+            ("effect", "strobe"),
+            ("speed", "1"),
+        ] {
+            assert_eq!(light.command(what, payload), None, "{} {:?} was taken", what, payload);
+        }
+    }
+
+    #[test]
+    fn command_topics_name_a_fixture() {
+        assert_eq!(command_topic("flash/3/rgb/set"), Some((3, "rgb")));
+        assert_eq!(command_topic("flash/255/state/set"), Some((255, "state")));
+        let topics = ["flash/3/rgb", "flash/3/rgb/set/again", "flash/x/rgb/set", "flash/256/rgb/set", "other/3/rgb/set"];
+        for topic in &topics {
+            assert_eq!(command_topic(topic), None, "{} was taken", topic);
+        }
+    }
+
+    #[test]
+    fn discovery_configs_are_json() {
+        let (topic, config) = light().discovery("ha");
+        assert_eq!(topic, "ha/light/flash_7/config");
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["unique_id"], "flash_7");
+        assert_eq!(config["command_topic"], "flash/7/state/set");
+        assert_eq!(config["rgb_state_topic"], "flash/7/rgb");
+        assert_eq!(config["effect_list"], serde_json::json!(["solid", "rainbow"]));
+        assert_eq!(config["availability"][0]["topic"], BRIDGE_TOPIC);
+        assert!(config["device"].get("sw_version").is_none());
+
+        let info = FixtureInfo {
+            uid: 0xABCD,
+            firmware: 12,
+            leds: 30,
+            chipset: Chipset::Ws2812,
+            layout: Layout::Strip,
+            capabilities: Capabilities::empty(),
+        };
+        let (_, config) = Light { info: Some(info), ..light() }.discovery(DISCOVERY_PREFIX);
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["device"]["sw_version"], "12");
+        assert_eq!(config["device"]["serial_number"], "000000000000abcd");
+    }
+
+    #[test]
+    fn states_show_what_is_lit() {
+        let light = Light { effect: Effect::Off, online: false, color: RGB8::new(9, 8, 7), ..light() };
+        let states = light.states();
+        let states: Vec<(&str, &str)> = states.iter().map(|(topic, state)| (topic.as_str(), state.as_str())).collect();
+        assert_eq!(
+            states,
+            [
+                ("flash/7/available", "offline"),
+                ("flash/7/state", "OFF"),
+                ("flash/7/brightness", "255"),
+                ("flash/7/rgb", "9,8,7"),
+                // Turning it on brings back the effect it had
+                ("flash/7/effect", "rainbow"),
+            ]
+        );
+    }
+}
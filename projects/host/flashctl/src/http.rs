@@ -0,0 +1,264 @@
+//! Just enough of an HTTP/1.1 server for the daemon's API.
+//!
+//! Each connection gets a thread, which reads one request, hands it to
+//! whoever reads the [`Receiver`] from [`listen`] and writes the [`Reply`]
+//! back before hanging up. A reply can also be a stream of server-sent
+//! events, which stays open until the client goes away:
+//!
+//! ```text
+//! event: <name>
+//! data: <JSON>
+//!
+//! ```
+//!
+//! Browsers only let pages from other origins use it when it's given the one
+//! origin to allow. Without one, replies carry no CORS headers at all.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Port the API listens on, unless told otherwise
+pub const PORT: u16 = 8080;
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an event stream may go quiet before a comment keeps it open
+const EVENT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Longest request line or header taken
+const MAX_LINE: usize = 8 * 1024;
+/// Largest request body taken
+const MAX_BODY: usize = 64 * 1024;
+
+/// What a client asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Without the query
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// What goes back
+pub enum Reply {
+    /// A status code and a JSON body
+    Json(u16, String),
+    /// Server-sent events, each already formatted by [`event`]
+    Events(Receiver<String>),
+}
+
+/// A request waiting for its reply
+pub struct Exchange {
+    pub request: Request,
+    reply: Sender<Reply>,
+}
+
+impl Exchange {
+    pub fn reply(self, reply: Reply) {
+        // The client may be gone already
+        self.reply.send(reply).ok();
+    }
+}
+
+/// A server-sent event called `name` carrying `data`, on a line of its own
+pub fn event(name: &str, data: &str) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+/// Take requests on `addr` and hand them over to the receiver, letting
+/// pages from `cors` use them, if given, like `http://localhost:3000`
+pub fn listen(addr: impl ToSocketAddrs, cors: Option<&str>) -> io::Result<Receiver<Exchange>> {
+    let listener = TcpListener::bind(addr)?;
+    let cors = Arc::new(cors.map(cors_headers).unwrap_or_default());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sender = sender.clone();
+            let cors = cors.clone();
+            thread::spawn(move || serve(stream, sender, &cors));
+        }
+    });
+    Ok(receiver)
+}
+
+fn serve(mut stream: TcpStream, requests: Sender<Exchange>, cors: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(_) => return respond(&mut stream, 400, "{\"error\":\"bad request\"}", cors),
+    };
+    // Browsers ask before sending a PUT to another origin
+    if request.method == "OPTIONS" {
+        return respond(&mut stream, 204, "", cors);
+    }
+    let (reply, replied) = mpsc::channel();
+    if requests.send(Exchange { request, reply }).is_err() {
+        return respond(&mut stream, 503, "{\"error\":\"shutting down\"}", cors);
+    }
+    match replied.recv() {
+        Ok(Reply::Json(status, body)) => respond(&mut stream, status, &body, cors),
+        Ok(Reply::Events(events)) => stream_events(&mut stream, events, cors),
+        Err(_) => respond(&mut stream, 503, "{\"error\":\"shutting down\"}", cors),
+    }
+}
+
+fn read_request(stream: impl Read) -> io::Result<Request> {
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+    let mut reader = BufReader::new(stream);
+    let line = read_line(&mut reader)?;
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(invalid()),
+    };
+    let path = target.split('?').next().unwrap_or("").to_string();
+    let mut length = 0;
+    loop {
+        let header = read_line(&mut reader)?;
+        if header.is_empty() {
+            break;
+        }
+        let mut pair = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (pair.next(), pair.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| invalid())?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(invalid());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, body })
+}
+
+/// A line without its line ending
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let line = String::from_utf8(line).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str, cors: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        cors,
+        body
+    )?;
+    stream.flush()
+}
+
+fn stream_events(stream: &mut TcpStream, events: Receiver<String>, cors: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}Connection: close\r\n\r\n",
+        cors
+    )?;
+    stream.flush()?;
+    loop {
+        match events.recv_timeout(EVENT_KEEP_ALIVE) {
+            Ok(event) => stream.write_all(event.as_bytes())?,
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": still here\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        stream.flush()?;
+    }
+}
+
+/// The headers letting pages from `origin` use the API
+fn cors_headers(origin: &str) -> String {
+    format!(
+        "Access-Control-Allow-Origin: {}\r\n\
+         Access-Control-Allow-Methods: GET, PUT, POST, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Content-Type\r\n\
+         Vary: Origin\r\n",
+        origin
+    )
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(request: &[u8]) -> io::Result<Request> {
+        read_request(Cursor::new(request))
+    }
+
+    fn kind(result: io::Result<Request>) -> io::ErrorKind {
+        result.expect_err("the request was taken").kind()
+    }
+
+    #[test]
+    fn requests_are_read_whole() {
+        let request = read(b"PUT /fixtures/1?fade=10 HTTP/1.1\r\nHost: flash\r\ncontent-LENGTH: 5\r\n\r\nhello");
+        let request = request.unwrap();
+        assert_eq!(request, Request { method: "PUT".into(), path: "/fixtures/1".into(), body: b"hello".to_vec() });
+        // Bare line feeds do too, and no length is no body
+        let request = read(b"GET /effects HTTP/1.1\nHost: flash\n\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.body.len()), ("GET", "/effects", 0));
+    }
+
+    #[test]
+    fn unfinished_requests_are_refused() {
+        assert_eq!(kind(read(b"GET / HTTP/1.1")), io::ErrorKind::InvalidData);
+        assert_eq!(kind(read(b"GET / HTTP/1.1\r\nHost: flash\r\n")), io::ErrorKind::InvalidData);
+        assert_eq!(kind(read(b"GET / HTTP/1.1\r\nHost: flash")), io::ErrorKind::InvalidData);
+        assert_eq!(kind(read(b"PUT / HTTP/1.1\r\nContent-Length: 6\r\n\r\nhello")), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        assert_eq!(kind(read(b"GET\r\n\r\n")), io::ErrorKind::InvalidData);
+        assert_eq!(kind(read(b"PUT / HTTP/1.1\r\nContent-Length: five\r\n\r\nhello")), io::ErrorKind::InvalidData);
+        assert_eq!(kind(read(b"GET /\xff HTTP/1.1\r\n\r\n")), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn long_lines_are_refused() {
+        let mut request = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        request.resize(request.len() + MAX_LINE, b'x');
+        request.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(kind(read(&request)), io::ErrorKind::InvalidData);
+
+        let mut request = b"GET /".to_vec();
+        request.resize(MAX_LINE - b" HTTP/1.1\n".len(), b'x');
+        request.extend_from_slice(b" HTTP/1.1\n\n");
+        assert!(read(&request).is_ok());
+    }
+
+    #[test]
+    fn large_bodies_are_refused() {
+        let request = |length: usize| {
+            let mut request = format!("PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length).into_bytes();
+            request.resize(request.len() + length, b'x');
+            read(&request)
+        };
+        assert_eq!(request(MAX_BODY).unwrap().body.len(), MAX_BODY);
+        assert_eq!(kind(request(MAX_BODY + 1)), io::ErrorKind::InvalidData);
+    }
+}
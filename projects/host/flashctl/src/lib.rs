@@ -0,0 +1,30 @@
+//! Control fixtures from a PC, through `tx` running as a USB dongle.
+//!
+//! [`Dongle`] speaks the packets of [`shared::dongle`] over the dongle's
+//! serial port, sending frames and waiting for how that went and for the
+//! fixture's answer. [`FakeDongle`] pretends to be one on a pty, with
+//! fixtures behind it, so all of this can be tried without hardware.
+//! [`Encoder`] turns frames of pixels into messages to stream them live.
+//! [`Bridge`] does the same for DMX from a lighting desk, received by
+//! [`dmx`] and patched to fixtures by a [`ChannelMap`]. The [`Daemon`]
+//! serves fixtures to Home Assistant over [`mqtt`], see [`home`], and to
+//! anything else through a JSON [`api`] over [`http`].
+
+pub mod api;
+pub mod bridge;
+pub mod daemon;
+pub mod dmx;
+pub mod dongle;
+pub mod fake;
+pub mod home;
+pub mod http;
+pub mod map;
+pub mod mqtt;
+pub mod stream;
+
+pub use bridge::Bridge;
+pub use daemon::Daemon;
+pub use dongle::{Dongle, Error};
+pub use fake::FakeDongle;
+pub use map::ChannelMap;
+pub use stream::Encoder;
@@ -0,0 +1,479 @@
+//! Control fixtures from the command line, through the dongle.
+//!
+//! ```text
+//! flashctl <port> <command>     run one command
+//! flashctl <port>               run commands from stdin, one per line
+//! flashctl fake [<fixture>...]  pretend to be a dongle on a pty
+//! ```
+//!
+//! The commands, see `help`:
+//!
+//! ```text
+//! discover [window=<ms>]
+//! status <fixture>
+//! set color <fixture> <red> <green> <blue>
+//! set brightness <fixture> <brightness>
+//! effect <fixture> <name> [speed=<speed>]
+//! look <fixture> <effect> <red> <green> <blue> [brightness=<brightness>] [speed=<speed>] [fade=<ms>]
+//! palette <first fixture> <red> <green> <blue> ...
+//! scene store <scene> <fixture> <effect> <red> <green> <blue> [brightness=<brightness>] [speed=<speed>]
+//! scene recall <scene> [fade=<ms>]
+//! ota <fixture> <image> [version=<version>]
+//! stream <fixture> <pixels> <file> [fps=<fps>]
+//! dmx <channel map>
+//! daemon [mqtt=<broker>] [http=<address>] [cors=<origin>] [prefix=<topic>] [user=<name>] [password=<password>] [poll=<s>]
+//! ```
+//!
+//! `<fixture>` is a fixture's id, `group <id>` or `all`, like on the
+//! devices. The firmware image for `ota` is the raw binary, signed here with
+//! the site key the way `tx` does. `discover` lists the fixtures that
+//! announce themselves, see [`shared::inventory`]. `stream` shows raw RGB frames of
+//! `<pixels>` pixels from a file, or `-` for stdin, until it ends. `dmx`
+//! forwards E1.31 and Art-Net from a desk until killed, see
+//! [`flashctl::map`] for the channel map. `daemon` serves the fixtures it
+//! finds to Home Assistant through an MQTT broker, and the JSON API over
+//! HTTP, until killed, see [`flashctl::home`] and [`flashctl::api`].
+
+use flashctl::bridge::STREAM_REFRESH;
+use flashctl::dmx::{self, ARTNET_PORT, E131_PORT};
+use flashctl::daemon::{MqttConfig, POLL_INTERVAL};
+use flashctl::home::DISCOVERY_PREFIX;
+use flashctl::{http, mqtt};
+use flashctl::{Bridge, ChannelMap, Daemon, Dongle, Encoder, FakeDongle};
+use shared::address::{Destination, NodeId, MAX_FIXTURE_ID};
+use shared::cli::{self, Args, Command};
+use shared::dongle::Outcome;
+use shared::image::{ImageHeader, MAX_IMAGE_SIZE};
+use shared::inventory::{DISCOVER_WINDOW, MAX_DISCOVER_WINDOW};
+use shared::ota::{OtaStatus, Sender};
+use shared::protocol::{Effect, Frame, Message};
+use shared::scene::{Look, DEFAULT_FADE};
+use shared::secure::SITE_KEY;
+use shared::stream::MAX_STREAM_PIXELS;
+use smart_leds::RGB8;
+use std::env;
+use std::fmt::{self, Write};
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::process;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Fixtures the fake dongle has, unless told otherwise
+const FAKE_FIXTURES: [NodeId; 4] = [1, 2, 3, 4];
+/// Times the start or end of an update is sent before giving up
+const OTA_ATTEMPTS: usize = 5;
+/// Frames streamed a second, unless told otherwise
+const STREAM_FPS: u32 = 25;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None | Some("help") | Some("--help") => {
+            eprintln!("usage: flashctl <port> [<command>] | flashctl fake [<fixture>...]");
+            process::exit(2);
+        }
+        Some("fake") => fake(&args[1..]),
+        Some(port) => control(port, &args[1..]),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Run `command`, or the lines on stdin, on the dongle at `port`
+fn control(port: &str, command: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut dongle = Dongle::open(port)?;
+    let mut session = Session { dongle: &mut dongle };
+    if !command.is_empty() {
+        return match cli::dispatch(Session::COMMANDS, &mut session, &command.join(" "), &mut Stdout) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.to_string().into()),
+        };
+    }
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            cli::run(Session::COMMANDS, &mut session, &line, &mut Stdout);
+        }
+    }
+    Ok(())
+}
+
+/// Answer on a pty as a dongle with `fixtures` until killed
+fn fake(fixtures: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let fixtures = match fixtures {
+        [] => FAKE_FIXTURES.to_vec(),
+        ids => ids.iter().map(|id| id.parse()).collect::<Result<_, _>>()?,
+    };
+    let dongle = FakeDongle::start(&fixtures)?;
+    println!("{}", dongle.path().display());
+    loop {
+        thread::park();
+    }
+}
+
+/// Answers printed as they come
+struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+/// What commands work on
+struct Session<'a> {
+    dongle: &'a mut Dongle<Box<dyn serialport::SerialPort>>,
+}
+
+impl<'a> Session<'a> {
+    const COMMANDS: &'a [Command<Self>] = &[
+        Command { name: "discover", usage: "[window=<ms>]", run: Self::discover },
+        Command { name: "status", usage: "<fixture>", run: Self::status },
+        Command { name: "set color", usage: "<fixture> <red> <green> <blue>", run: Self::set_color },
+        Command { name: "set brightness", usage: "<fixture> <brightness>", run: Self::set_brightness },
+        Command { name: "effect", usage: "<fixture> <name> [speed=<speed>]", run: Self::effect },
+        Command {
+            name: "look",
+            usage: "<fixture> <effect> <red> <green> <blue> [brightness=<brightness>] [speed=<speed>] [fade=<ms>]",
+            run: Self::look,
+        },
+        Command { name: "palette", usage: "<first fixture> <red> <green> <blue> ...", run: Self::palette },
+        Command {
+            name: "scene store",
+            usage: "<scene> <fixture> <effect> <red> <green> <blue> [brightness=<brightness>] [speed=<speed>]",
+            run: Self::scene_store,
+        },
+        Command { name: "scene recall", usage: "<scene> [fade=<ms>]", run: Self::scene_recall },
+        Command { name: "ota", usage: "<fixture> <image> [version=<version>]", run: Self::ota },
+        Command { name: "stream", usage: "<fixture> <pixels> <file> [fps=<fps>]", run: Self::stream },
+        Command { name: "dmx", usage: "<channel map>", run: Self::dmx },
+        Command {
+            name: "daemon",
+            usage: "[mqtt=<broker>] [http=<address>] [cors=<origin>] [prefix=<topic>] [user=<name>] \
+                    [password=<password>] [poll=<s>]",
+            run: Self::daemon,
+        },
+    ];
+
+    /// Ask every fixture to announce itself, and list them
+    fn discover(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let window = args.option("window")?.unwrap_or(DISCOVER_WINDOW);
+        args.finish()?;
+        if window > MAX_DISCOVER_WINDOW {
+            return Err(cli::Error::Invalid("window"));
+        }
+        let (outcome, mut found) = self.dongle.discover(window)?;
+        check(outcome)?;
+        found.sort_by_key(|&(id, _)| id);
+        for (id, info) in &found {
+            writeln!(out, "fixture {}: {}", id, info).ok();
+        }
+        writeln!(out, "found {} fixtures", found.len()).ok();
+        Ok(())
+    }
+
+    /// Print a fixture's status and its side of the link
+    fn status(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let fixture = fixture(args)?;
+        args.finish()?;
+        let (outcome, answer) = self.dongle.request(fixture, Message::StatusQuery)?;
+        check(outcome)?;
+        if let Outcome::Acked(Some(status)) = outcome {
+            writeln!(out, "fixture {}: {}", fixture, status).ok();
+        }
+        match answer {
+            Some(Frame { message: Message::LinkStatus(report), .. }) => writeln!(out, "link: {}", report).ok(),
+            _ => writeln!(out, "fixture {} did not report its link", fixture).ok(),
+        };
+        Ok(())
+    }
+
+    fn set_color(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let color = args.color()?;
+        args.finish()?;
+        self.tell(dest, Message::SetColor(color))
+    }
+
+    fn set_brightness(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let brightness = args.parse("brightness")?;
+        args.finish()?;
+        self.tell(dest, Message::SetBrightness(brightness))
+    }
+
+    fn effect(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let effect = effect(args)?;
+        let speed = args.option("speed")?.unwrap_or(1);
+        args.finish()?;
+        self.tell(dest, Message::SetEffect { effect, speed })
+    }
+
+    /// Change everything a fixture shows, fading to it
+    fn look(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let look = look(args)?;
+        let fade = args.option("fade")?.unwrap_or(DEFAULT_FADE);
+        args.finish()?;
+        self.tell(dest, Message::SetLook { look, fade })
+    }
+
+    /// Give fixtures from `first` on a colour each
+    fn palette(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let first = fixture(args)?;
+        let mut fixture = first;
+        while args.clone().next().is_some() {
+            if fixture > MAX_FIXTURE_ID {
+                return Err(cli::Error::TooLong);
+            }
+            let color = args.color()?;
+            if let Err(e) = self.tell(Destination::Node(fixture), Message::SetColor(color)) {
+                writeln!(out, "fixture {}: {}", fixture, e).ok();
+            }
+            fixture += 1;
+        }
+        if fixture == first {
+            return Err(cli::Error::Missing("red"));
+        }
+        Ok(())
+    }
+
+    /// Teach fixtures the look for a scene
+    fn scene_store(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let scene = args.parse("scene")?;
+        let dest = args.destination()?;
+        let look = look(args)?;
+        args.finish()?;
+        self.tell(dest, Message::StoreScene { scene, look })
+    }
+
+    fn scene_recall(&mut self, args: &mut Args, _: &mut dyn Write) -> Result<(), cli::Error> {
+        let scene = args.parse("scene")?;
+        let fade = args.option("fade")?.unwrap_or(DEFAULT_FADE);
+        args.finish()?;
+        self.tell(Destination::Broadcast, Message::RecallScene { scene, fade })
+    }
+
+    /// Push a firmware image to a fixture
+    fn ota(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let fixture = fixture(args)?;
+        let path = args.word("image")?;
+        let version = args.option("version")?.unwrap_or(0);
+        args.finish()?;
+        let image = std::fs::read(path).map_err(|_| "couldn't read the image")?;
+        if image.len() > MAX_IMAGE_SIZE as usize {
+            return Err("the image doesn't fit the app slot".into());
+        }
+        let nonce = nonce().map_err(|_| "couldn't read /dev/urandom")?;
+        let header = ImageHeader::sign(SITE_KEY, version, nonce, &image);
+        let mut sender = Sender::new(&header, &image);
+
+        let dest = Destination::Node(fixture);
+        let mut unanswered = 0;
+        let mut shown = 0;
+        loop {
+            let message = sender.next_message();
+            if let Message::OtaChunk { .. } = message {
+                // Lost chunks are reported missing at the end
+                self.dongle.send(dest, message)?;
+                let progress = sender.progress();
+                if progress / 10 != shown / 10 {
+                    writeln!(out, "{}%", progress).ok();
+                    shown = progress;
+                }
+                continue;
+            }
+            let status = match self.dongle.request(fixture, message)? {
+                (_, Some(Frame { message: Message::OtaStatus(status), .. })) => status,
+                _ => {
+                    unanswered += 1;
+                    if unanswered == OTA_ATTEMPTS {
+                        return Err("the fixture stopped answering".into());
+                    }
+                    continue;
+                }
+            };
+            unanswered = 0;
+            if let OtaStatus::Missing(index) = status {
+                writeln!(out, "fixture {} is missing firmware from chunk {} on", fixture, index).ok();
+            }
+            match sender.handle(status) {
+                Some(Ok(())) => {
+                    writeln!(out, "fixture {} accepted the firmware image", fixture).ok();
+                    return Ok(());
+                }
+                Some(Err(e)) => {
+                    writeln!(out, "fixture {} refused the firmware image: {:?}", fixture, e).ok();
+                    return Err("the update failed".into());
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Show raw RGB frames from a file or stdin, paced at `fps`
+    fn stream(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let dest = args.destination()?;
+        let len: usize = args.parse("pixels")?;
+        let path = args.word("file")?;
+        let fps = args.option("fps")?.unwrap_or(STREAM_FPS);
+        args.finish()?;
+        if len == 0 || len > MAX_STREAM_PIXELS {
+            return Err(cli::Error::Invalid("pixels"));
+        }
+        if fps == 0 {
+            return Err(cli::Error::Invalid("fps"));
+        }
+        let mut input: Box<dyn Read> = match path {
+            "-" => Box::new(io::stdin()),
+            path => Box::new(File::open(path).map_err(|_| "couldn't open the file")?),
+        };
+
+        let mut encoder = Encoder::new(len);
+        let mut frame = vec![0u8; len * 3];
+        let interval = Duration::from_secs(1) / fps;
+        let mut next = Instant::now();
+        let mut shown = 0;
+        while input.read_exact(&mut frame).is_ok() {
+            let pixels: Vec<RGB8> = frame.chunks(3).map(|p| RGB8::new(p[0], p[1], p[2])).collect();
+            for message in encoder.encode(&pixels) {
+                if let Outcome::Lost | Outcome::Busy = self.dongle.send(dest, message)? {
+                    encoder.resend();
+                }
+            }
+            shown += 1;
+            next += interval;
+            if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        // Fixtures go back to their own effect by themselves
+        writeln!(out, "streamed {} frames", shown).ok();
+        Ok(())
+    }
+
+    /// Forward DMX from a desk to fixtures, for as long as we run
+    fn dmx(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let path = args.word("channel map")?;
+        args.finish()?;
+        let text = std::fs::read_to_string(path).map_err(|_| "couldn't read the channel map")?;
+        let map = ChannelMap::parse(&text).map_err(|e| {
+            writeln!(out, "{}", e).ok();
+            cli::Error::Invalid("channel map")
+        })?;
+        let packets = dmx::listen(&map.universes()).map_err(|_| "couldn't listen for DMX")?;
+        writeln!(out, "listening for E1.31 on port {} and Art-Net on port {}", E131_PORT, ARTNET_PORT).ok();
+
+        let mut bridge = Bridge::new(map);
+        loop {
+            let messages = match packets.recv_timeout(STREAM_REFRESH) {
+                Ok((protocol, packet)) => match protocol.parse(&packet) {
+                    Ok(levels) => bridge.receive(&levels, Instant::now()),
+                    Err(_) => continue,
+                },
+                Err(RecvTimeoutError::Timeout) => bridge.refresh(Instant::now()),
+                Err(RecvTimeoutError::Disconnected) => return Err("stopped listening for DMX".into()),
+            };
+            for (dest, message) in messages {
+                if let Outcome::Lost | Outcome::Busy = self.dongle.send(dest, message)? {
+                    bridge.lost(dest);
+                }
+            }
+        }
+    }
+
+    /// Serve the fixtures to Home Assistant and the API, for as long as we
+    /// run
+    fn daemon(&mut self, args: &mut Args, out: &mut dyn Write) -> Result<(), cli::Error> {
+        let broker: Option<String> = args.option("mqtt")?;
+        let http: Option<String> = args.option("http")?;
+        let cors: Option<String> = args.option("cors")?;
+        let prefix = args.option("prefix")?.unwrap_or_else(|| DISCOVERY_PREFIX.to_string());
+        let options = mqtt::Options {
+            client_id: "flashctl".to_string(),
+            username: args.option("user")?,
+            password: args.option("password")?,
+            will: None,
+        };
+        let poll = args.option("poll")?.map(Duration::from_secs).unwrap_or(POLL_INTERVAL);
+        args.finish()?;
+        if broker.is_none() && http.is_none() {
+            return Err(cli::Error::Missing("mqtt or http"));
+        }
+        if poll == Duration::from_secs(0) {
+            return Err(cli::Error::Invalid("poll"));
+        }
+        if cors.is_some() && http.is_none() {
+            return Err(cli::Error::Missing("http"));
+        }
+
+        let mut daemon = Daemon::new(self.dongle);
+        daemon.poll_every(poll);
+        if let Some(broker) = broker {
+            daemon.mqtt(MqttConfig { broker, options, prefix });
+        }
+        if let Some(http) = http {
+            // A port alone is only served to this machine
+            let addr = match http.parse::<u16>() {
+                Ok(port) => format!("127.0.0.1:{}", port),
+                Err(_) => http,
+            };
+            let requests = http::listen(&addr, cors.as_deref()).map_err(|_| "couldn't listen for HTTP")?;
+            daemon.http(requests);
+            writeln!(out, "serving the API on http://{}", addr).ok();
+        }
+        let found = daemon.find(out)?;
+        writeln!(out, "found {} fixtures", found).ok();
+        daemon.run(out)?;
+        Ok(())
+    }
+
+    /// Send `message` to `dest`
+    fn tell(&mut self, dest: Destination, message: Message) -> Result<(), cli::Error> {
+        check(self.dongle.send(dest, message)?)
+    }
+}
+
+/// Whether the dongle got a frame out
+fn check(outcome: Outcome) -> Result<(), cli::Error> {
+    match outcome {
+        Outcome::Acked(_) | Outcome::Sent => Ok(()),
+        Outcome::Lost => Err("nobody acknowledged it".into()),
+        Outcome::Busy => Err("the dongle is busy".into()),
+    }
+}
+
+/// A single fixture's id
+fn fixture(args: &mut Args) -> Result<NodeId, cli::Error> {
+    match args.destination()? {
+        Destination::Node(fixture) => Ok(fixture),
+        _ => Err(cli::Error::Invalid("fixture")),
+    }
+}
+
+fn effect(args: &mut Args) -> Result<Effect, cli::Error> {
+    Effect::from_name(args.word("effect")?).ok_or(cli::Error::Invalid("effect"))
+}
+
+/// An effect and colour, with the brightness and speed as options
+fn look(args: &mut Args) -> Result<Look, cli::Error> {
+    Ok(Look {
+        effect: effect(args)?,
+        color: args.color()?,
+        brightness: args.option("brightness")?.unwrap_or(255),
+        speed: args.option("speed")?.unwrap_or(1),
+    })
+}
+
+/// A nonce for signing an image, which must never repeat
+fn nonce() -> io::Result<[u8; 8]> {
+    let mut nonce = [0u8; 8];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
@@ -0,0 +1,209 @@
+//! Which DMX channels control what on which fixtures.
+//!
+//! A channel map is a text file with a line per part of a fixture, giving
+//! the universe and first channel it's patched to, and who it controls:
+//!
+//! ```text
+//! # <universe> <channel> <fixture> <part>
+//! 1 1 3 dimmer
+//! 1 2 3 rgb
+//! 1 5 3 effect
+//! 1 7 group 2 rgb
+//! 2 1 4 pixels 50
+//! 2 151 4 pixels 50 first=50
+//! ```
+//!
+//! `<fixture>` is a fixture's id, `group <id>` or `all`, like on the command
+//! line. The parts are:
+//!
+//! ```text
+//! dimmer                         1 channel, the brightness
+//! rgb                            3 channels, the colour
+//! effect                         2 channels, the effect and its speed
+//! pixels <count> [first=<pixel>] 3 channels a pixel, streamed
+//! ```
+//!
+//! The effect channel picks off, solid or rainbow by thirds. Pixels are
+//! streamed like `stream` does, so several lines can each cover a segment of
+//! the same fixture. Channels count from 1, universes the way the desk's
+//! protocol does.
+
+use shared::address::Destination;
+use shared::cli::{self, Args};
+use shared::dmx::UNIVERSE_SIZE;
+use shared::stream::MAX_STREAM_PIXELS;
+use std::fmt;
+
+/// What some channels control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    Dimmer,
+    Rgb,
+    /// The effect, then its speed
+    Effect,
+    /// `count` pixels from pixel `first` on
+    Pixels { first: usize, count: usize },
+}
+
+impl Part {
+    /// Channels the part takes
+    pub fn channels(self) -> usize {
+        match self {
+            Part::Dimmer => 1,
+            Part::Rgb => 3,
+            Part::Effect => 2,
+            Part::Pixels { count, .. } => count * 3,
+        }
+    }
+}
+
+/// A line of the channel map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub universe: u16,
+    /// The part's first channel, counting from 1
+    pub channel: usize,
+    pub dest: Destination,
+    pub part: Part,
+}
+
+impl Entry {
+    /// The part's levels among those of its universe, if they're all there
+    pub fn levels<'a>(&self, channels: &'a [u8]) -> Option<&'a [u8]> {
+        channels.get(self.channel - 1..self.channel - 1 + self.part.channels())
+    }
+}
+
+/// A line of the channel map that couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapError {
+    /// Counting from 1
+    pub line: usize,
+    pub error: cli::Error,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// Every line of a channel map
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelMap {
+    pub entries: Vec<Entry>,
+}
+
+impl ChannelMap {
+    /// Read a channel map, skipping empty lines and `#` comments
+    pub fn parse(text: &str) -> Result<Self, MapError> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = parse_entry(&mut Args::new(line)).map_err(|error| MapError { line: i + 1, error })?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// The universes something is patched to
+    pub fn universes(&self) -> Vec<u16> {
+        let mut universes: Vec<u16> = self.entries.iter().map(|entry| entry.universe).collect();
+        universes.sort_unstable();
+        universes.dedup();
+        universes
+    }
+}
+
+fn parse_entry(args: &mut Args) -> Result<Entry, cli::Error> {
+    let universe = args.parse("universe")?;
+    let channel = args.parse("channel")?;
+    let dest = args.destination()?;
+    let part = match args.word("part")? {
+        "dimmer" => Part::Dimmer,
+        "rgb" => Part::Rgb,
+        "effect" => Part::Effect,
+        "pixels" => {
+            let count: usize = args.parse("count")?;
+            let first = args.option("first")?.unwrap_or(0);
+            if count == 0 || first + count > MAX_STREAM_PIXELS {
+                return Err(cli::Error::Invalid("count"));
+            }
+            Part::Pixels { first, count }
+        }
+        _ => return Err(cli::Error::Invalid("part")),
+    };
+    args.finish()?;
+    if channel == 0 || channel - 1 + part.channels() > UNIVERSE_SIZE {
+        return Err(cli::Error::Invalid("channel"));
+    }
+    Ok(Entry { universe, channel, dest, part })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::address::GroupId;
+
+    #[test]
+    fn maps_are_read_line_by_line() {
+        let text = "# <universe> <channel> <fixture> <part>\n\n1 1 3 dimmer\n1 2 group 2 rgb # the wash\n\
+                    2 5 all effect\n2 151 4 pixels 50 first=50\n";
+        let map = ChannelMap::parse(text).unwrap();
+        let group = Destination::Group(GroupId::new(2).unwrap());
+        assert_eq!(
+            map.entries,
+            [
+                Entry { universe: 1, channel: 1, dest: Destination::Node(3), part: Part::Dimmer },
+                Entry { universe: 1, channel: 2, dest: group, part: Part::Rgb },
+                Entry { universe: 2, channel: 5, dest: Destination::Broadcast, part: Part::Effect },
+                Entry {
+                    universe: 2,
+                    channel: 151,
+                    dest: Destination::Node(4),
+                    part: Part::Pixels { first: 50, count: 50 },
+                },
+            ]
+        );
+        assert_eq!(map.universes(), [1, 2]);
+        assert_eq!(ChannelMap::parse("# nothing yet\n").unwrap(), ChannelMap::default());
+    }
+
+    #[test]
+    fn errors_say_which_line() {
+        let error = |text| ChannelMap::parse(text).unwrap_err();
+        assert_eq!(error("1 1 3 dimmer\n1 2 3 strobe"), MapError { line: 2, error: cli::Error::Invalid("part") });
+        assert_eq!(error("1 1 3"), MapError { line: 1, error: cli::Error::Missing("part") });
+        assert_eq!(error("1 1 3 rgb please").error, cli::Error::TooLong);
+        assert_eq!(error("x 1 3 rgb").error, cli::Error::Invalid("universe"));
+        assert_eq!(error("1 1 3 pixels 0").error, cli::Error::Invalid("count"));
+        assert_eq!(error("1 1 3 pixels 200 first=100").error, cli::Error::Invalid("count"));
+        let error = error("\n\n1 1 3 pixels 50 first=x");
+        assert_eq!(error.to_string(), format!("line 3: {}", cli::Error::Invalid("first")));
+    }
+
+    #[test]
+    fn parts_fit_their_universe() {
+        assert_eq!(ChannelMap::parse("1 0 3 dimmer").unwrap_err().error, cli::Error::Invalid("channel"));
+        assert!(ChannelMap::parse("1 510 3 rgb").is_ok());
+        assert_eq!(ChannelMap::parse("1 511 3 rgb").unwrap_err().error, cli::Error::Invalid("channel"));
+        assert!(ChannelMap::parse("1 1 3 pixels 170").is_ok());
+        assert_eq!(ChannelMap::parse("1 4 3 pixels 170").unwrap_err().error, cli::Error::Invalid("channel"));
+    }
+
+    #[test]
+    fn entries_pick_their_levels() {
+        let channels: Vec<u8> = (1..=10).collect();
+        let entry = |channel, part| Entry { universe: 1, channel, dest: Destination::Broadcast, part };
+        assert_eq!(entry(1, Part::Dimmer).levels(&channels), Some(&[1][..]));
+        assert_eq!(entry(8, Part::Rgb).levels(&channels), Some(&[8, 9, 10][..]));
+        // Desks may send fewer channels than a universe has
+        assert_eq!(entry(9, Part::Rgb).levels(&channels), None);
+        assert_eq!(entry(4, Part::Pixels { first: 0, count: 2 }).levels(&channels), Some(&[4, 5, 6, 7, 8, 9][..]));
+    }
+}
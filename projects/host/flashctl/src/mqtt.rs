@@ -0,0 +1,463 @@
+//! Just enough of an MQTT 3.1.1 client to talk to a broker like Mosquitto.
+//!
+//! Everything goes at QoS 0 over a plain TCP connection. Each packet has a
+//! fixed header, then its body:
+//!
+//! ```text
+//! | type (4 bits) | flags (4 bits) | remaining length (1 to 4 bytes) | body |
+//! ```
+//!
+//! The remaining length takes 7 bits a byte, low bits first, the top bit
+//! saying another byte follows. Strings in bodies are a big endian length,
+//! then UTF-8. [`Client::poll`] pings the broker while nothing else is
+//! sent, so it has to be called at least every half [`KEEP_ALIVE`].
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Port brokers listen on, unless told otherwise
+pub const PORT: u16 = 1883;
+/// How long the broker waits for a packet from us before hanging up
+pub const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// How long the broker may take to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest packet taken from the broker
+const MAX_PACKET: usize = 64 * 1024;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+/// Retain flag of a PUBLISH
+const RETAIN: u8 = 0x01;
+
+/// What went wrong talking to the broker
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The broker turned the connection down, with its return code
+    Refused(u8),
+    /// The broker sent something that isn't MQTT
+    Protocol,
+    /// The broker stopped answering
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Refused(4) | Error::Refused(5) => write!(f, "the broker refused our login"),
+            Error::Refused(code) => write!(f, "the broker refused the connection ({})", code),
+            Error::Protocol => write!(f, "the broker doesn't speak MQTT"),
+            Error::Timeout => write!(f, "the broker didn't answer"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// How to connect
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Published, retained, by the broker once we're gone
+    pub will: Option<(String, String)>,
+}
+
+/// A message from the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// A connection to a broker
+pub struct Client {
+    stream: TcpStream,
+    /// What came in, up to the end of the last whole packet and past it
+    incoming: Vec<u8>,
+    next_id: u16,
+    /// When we last sent something
+    sent: Instant,
+    /// When the broker last sent something
+    heard: Instant,
+}
+
+impl Client {
+    /// Connect to `broker`, a host with or without a port
+    pub fn connect(broker: &str, options: &Options) -> Result<Self, Error> {
+        let stream = if broker.contains(':') {
+            TcpStream::connect(broker)?
+        } else {
+            TcpStream::connect((broker, PORT))?
+        };
+        stream.set_nodelay(true)?;
+        let now = Instant::now();
+        let mut client = Self {
+            stream,
+            incoming: Vec::new(),
+            next_id: 1,
+            sent: now,
+            heard: now,
+        };
+
+        let mut flags = 0x02; // clean session
+        let mut body = Vec::new();
+        string(&mut body, "MQTT");
+        body.push(4); // 3.1.1
+        body.push(0);
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        string(&mut body, &options.client_id);
+        if let Some((topic, payload)) = &options.will {
+            flags |= 0x04 | 0x20;
+            string(&mut body, topic);
+            string(&mut body, payload);
+        }
+        if let Some(username) = &options.username {
+            flags |= 0x80;
+            string(&mut body, username);
+        }
+        if let Some(password) = &options.password {
+            flags |= 0x40;
+            string(&mut body, password);
+        }
+        body[7] = flags;
+        client.send(CONNECT, &body)?;
+
+        let deadline = now + CONNECT_TIMEOUT;
+        loop {
+            let timeout = deadline.checked_duration_since(Instant::now()).ok_or(Error::Timeout)?;
+            if let Some((CONNACK, body)) = client.packet(timeout)? {
+                return match body.get(1) {
+                    Some(0) => Ok(client),
+                    Some(&code) => Err(Error::Refused(code)),
+                    None => Err(Error::Protocol),
+                };
+            }
+        }
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+        let mut body = Vec::new();
+        string(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.send(if retain { PUBLISH | RETAIN } else { PUBLISH }, &body)
+    }
+
+    /// Ask for what's published to `filters`, which may have wildcards
+    pub fn subscribe(&mut self, filters: &[&str]) -> Result<(), Error> {
+        let mut body = self.next_id.to_be_bytes().to_vec();
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        for filter in filters {
+            string(&mut body, filter);
+            body.push(0);
+        }
+        self.send(SUBSCRIBE, &body)
+    }
+
+    /// The next message, waiting up to `timeout` for it
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<Publish>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.sent.elapsed() >= KEEP_ALIVE / 2 {
+                self.send(PINGREQ, &[])?;
+            }
+            // It answers pings, so it's gone if it says nothing for longer
+            if self.heard.elapsed() >= KEEP_ALIVE {
+                return Err(Error::Timeout);
+            }
+            let left = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min((KEEP_ALIVE / 2).checked_sub(self.sent.elapsed()).unwrap_or_default()),
+                None => return Ok(None),
+            };
+            let (kind, body) = match self.packet(left)? {
+                Some(packet) => packet,
+                None => continue,
+            };
+            match kind & 0xf0 {
+                PUBLISH => return self.received(kind, body).map(Some),
+                SUBACK | PINGRESP => {}
+                _ => return Err(Error::Protocol),
+            }
+        }
+    }
+
+    /// Hang up, so the broker doesn't publish the will
+    pub fn disconnect(mut self) -> Result<(), Error> {
+        self.send(DISCONNECT, &[])
+    }
+
+    /// A PUBLISH from the broker
+    fn received(&mut self, kind: u8, body: Vec<u8>) -> Result<Publish, Error> {
+        let len = match body.get(..2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => return Err(Error::Protocol),
+        };
+        let topic = body.get(2..2 + len).ok_or(Error::Protocol)?;
+        let topic = String::from_utf8(topic.to_vec()).map_err(|_| Error::Protocol)?;
+        // Only a broker that ignores our QoS sends an id
+        let mut payload = 2 + len;
+        let qos = (kind >> 1) & 0x03;
+        if qos > 0 {
+            let id = body.get(payload..payload + 2).ok_or(Error::Protocol)?.to_vec();
+            payload += 2;
+            if qos == 1 {
+                self.send(PUBACK, &id)?;
+            }
+        }
+        Ok(Publish {
+            topic,
+            payload: body[payload..].to_vec(),
+            retain: kind & RETAIN != 0,
+        })
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> Result<(), Error> {
+        let mut packet = vec![kind];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            packet.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)?;
+        self.sent = Instant::now();
+        Ok(())
+    }
+
+    /// The next whole packet's first byte and body, if one comes within
+    /// `timeout`
+    fn packet(&mut self, timeout: Duration) -> Result<Option<(u8, Vec<u8>)>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some((start, len)) = self.whole()? {
+                let body = self.incoming[start..start + len].to_vec();
+                let kind = self.incoming[0];
+                self.incoming.drain(..start + len);
+                return Ok(Some((kind, body)));
+            }
+            let left = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::from_millis(0) => left,
+                _ => return Ok(None),
+            };
+            self.stream.set_read_timeout(Some(left))?;
+            let mut buf = [0u8; 1024];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(len) => {
+                    self.incoming.extend_from_slice(&buf[..len]);
+                    self.heard = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Where the body of the first packet starts and how long it is, once
+    /// it's all there
+    fn whole(&self) -> Result<Option<(usize, usize)>, Error> {
+        let mut len = 0;
+        for i in 1..5 {
+            let byte = match self.incoming.get(i) {
+                Some(&byte) => byte,
+                None => return Ok(None),
+            };
+            len |= (byte as usize & 0x7f) << (7 * (i - 1));
+            if byte & 0x80 != 0 {
+                continue;
+            }
+            if len > MAX_PACKET {
+                return Err(Error::Protocol);
+            }
+            return Ok(if self.incoming.len() >= i + 1 + len { Some((i + 1, len)) } else { None });
+        }
+        Err(Error::Protocol)
+    }
+}
+
+/// Append `s` as an MQTT string
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Read a packet the way a broker would, its first byte and body
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let kind = byte[0];
+        let mut len = 0;
+        for shift in (0..4).map(|i| 7 * i) {
+            stream.read_exact(&mut byte).unwrap();
+            len |= (byte[0] as usize & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).unwrap();
+        (kind, body)
+    }
+
+    /// Connect to a broker that answers with return code `code`, returning
+    /// how that went, its end of the connection and the CONNECT it got
+    fn connect(options: &Options, code: u8) -> (Result<Client, Error>, TcpStream, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (kind, body) = read_packet(&mut stream);
+            assert_eq!(kind, CONNECT);
+            stream.write_all(&[CONNACK, 2, 0, code]).unwrap();
+            (stream, body)
+        });
+        let client = Client::connect(&address, options);
+        let (stream, body) = broker.join().unwrap();
+        (client, stream, body)
+    }
+
+    fn connected() -> (Client, TcpStream) {
+        let (client, stream, _) = connect(&Options { client_id: "test".into(), ..Options::default() }, 0);
+        (client.unwrap(), stream)
+    }
+
+    fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        string(&mut body, topic);
+        body.extend_from_slice(payload);
+        let mut packet = vec![PUBLISH, body.len() as u8];
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn connect_carries_the_options() {
+        let options = Options {
+            client_id: "flash".into(),
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            will: Some(("flash/bridge".into(), "offline".into())),
+        };
+        let (client, _, body) = connect(&options, 0);
+        assert!(client.is_ok());
+        assert_eq!(body[..6], *b"\0\x04MQTT");
+        // Version 4, then clean session, a retained will, a username and a
+        // password, and the keep alive
+        assert_eq!(body[6..10], [4, 0xe6, 0, 30]);
+        let mut strings = Vec::new();
+        for s in &["flash", "flash/bridge", "offline", "user", "pass"] {
+            string(&mut strings, s);
+        }
+        assert_eq!(body[10..], *strings);
+
+        let (_, _, body) = connect(&Options { client_id: "flash".into(), ..Options::default() }, 0);
+        assert_eq!(body[7], 0x02);
+    }
+
+    #[test]
+    fn refused_connections_say_why() {
+        let (client, _, _) = connect(&Options::default(), 5);
+        match client {
+            Err(error @ Error::Refused(5)) => assert_eq!(error.to_string(), "the broker refused our login"),
+            Err(error) => panic!("connecting failed with {}", error),
+            Ok(_) => panic!("the broker let us in"),
+        }
+    }
+
+    #[test]
+    fn long_packets_take_more_length_bytes() {
+        let (mut client, mut broker) = connected();
+        client.publish("t", &[0x55; 200], true).unwrap();
+        let mut start = [0u8; 3];
+        broker.read_exact(&mut start).unwrap();
+        // 2 + 1 + 200 = 203 is 75 and one 128
+        assert_eq!(start, [PUBLISH | RETAIN, 0x80 | 75, 1]);
+
+        let mut packet = vec![PUBLISH, 0x80 | 75, 1];
+        string(&mut packet, "t");
+        packet.extend_from_slice(&[0x55; 200]);
+        broker.write_all(&packet).unwrap();
+        let publish = client.poll(Duration::from_secs(2)).unwrap().unwrap();
+        assert_eq!((publish.topic.as_str(), publish.payload.len(), publish.retain), ("t", 200, false));
+    }
+
+    #[test]
+    fn packets_are_taken_whole() {
+        let (mut client, mut broker) = connected();
+        let first = publish("flash/1/state/set", b"ON");
+        broker.write_all(&first[..1]).unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            broker.write_all(&first[1..5]).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            // The rest of it and the next one at once
+            let mut rest = first[5..].to_vec();
+            rest.extend(publish("flash/2/state/set", b"OFF"));
+            broker.write_all(&rest).unwrap();
+            broker
+        });
+        let publish = client.poll(Duration::from_secs(2)).unwrap().unwrap();
+        assert_eq!((publish.topic.as_str(), publish.payload.as_slice()), ("flash/1/state/set", &b"ON"[..]));
+        let publish = client.poll(Duration::from_secs(2)).unwrap().unwrap();
+        assert_eq!((publish.topic.as_str(), publish.payload.as_slice()), ("flash/2/state/set", &b"OFF"[..]));
+        let _broker = writer.join().unwrap();
+        assert!(client.poll(Duration::from_millis(20)).unwrap().is_none());
+    }
+
+    #[test]
+    fn qos_1_messages_are_acknowledged() {
+        let (mut client, mut broker) = connected();
+        let mut body = Vec::new();
+        string(&mut body, "flash/1/rgb/set");
+        body.extend_from_slice(&[0x12, 0x34]);
+        body.extend_from_slice(b"1,2,3");
+        let mut packet = vec![PUBLISH | 0x02 | RETAIN, body.len() as u8];
+        packet.extend(body);
+        broker.write_all(&packet).unwrap();
+
+        let publish = client.poll(Duration::from_secs(2)).unwrap().unwrap();
+        assert_eq!(publish, Publish { topic: "flash/1/rgb/set".into(), payload: b"1,2,3".to_vec(), retain: true });
+        assert_eq!(read_packet(&mut broker), (PUBACK, vec![0x12, 0x34]));
+    }
+
+    #[test]
+    fn lengths_past_the_limits_are_refused() {
+        // A fifth length byte, more than MAX_PACKET, and a cut publish
+        for packet in &[&[PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01][..], &[PUBLISH, 0x80, 0x80, 0x08], &[PUBLISH, 1, 0]] {
+            let (mut client, mut broker) = connected();
+            broker.write_all(packet).unwrap();
+            assert!(matches!(client.poll(Duration::from_secs(2)), Err(Error::Protocol)), "{:?} was taken", packet);
+        }
+        let (mut client, mut broker) = connected();
+        broker.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        assert!(matches!(client.poll(Duration::from_secs(2)), Err(Error::Protocol)));
+    }
+}
@@ -0,0 +1,256 @@
+//! Turning frames of pixels into the messages of [`shared::stream`].
+
+use shared::protocol::Message;
+use shared::stream::{encode_run, MAX_RUN, MAX_RUNS, MAX_STREAM_COLORS, MAX_STREAM_PIXELS, PALETTE_SIZE};
+use smart_leds::RGB8;
+use std::collections::HashMap;
+
+/// Frames between ones sent whole, so fixtures that missed part of one
+/// catch up
+pub const KEYFRAME_INTERVAL: u32 = 25;
+/// Unchanged pixels after which a range of changed ones ends
+const GAP: usize = 8;
+
+/// Keeps track of what fixtures were sent, to send them only what changes
+pub struct Encoder {
+    /// The canvas the fixtures draw on, as we left it
+    canvas: Vec<u8>,
+    palette: [RGB8; PALETTE_SIZE],
+    frame: u8,
+    /// Frames until the next is sent whole
+    keyframe_in: u32,
+}
+
+impl Encoder {
+    /// An encoder for frames of `len` pixels, at most [`MAX_STREAM_PIXELS`]
+    pub fn new(len: usize) -> Self {
+        assert!(len <= MAX_STREAM_PIXELS, "streams have at most {} pixels", MAX_STREAM_PIXELS);
+        Self {
+            canvas: vec![0; len],
+            palette: [RGB8::default(); PALETTE_SIZE],
+            frame: 0,
+            keyframe_in: 0,
+        }
+    }
+
+    /// Send the next frame whole, after some of one got lost
+    pub fn resend(&mut self) {
+        self.keyframe_in = 0;
+    }
+
+    /// The messages drawing `pixels` on the fixtures, ending with the one
+    /// showing them. Pixels past the length of the stream are left out.
+    pub fn encode(&mut self, pixels: &[RGB8]) -> Vec<Message> {
+        let pixels = &pixels[..pixels.len().min(self.canvas.len())];
+        let whole = self.keyframe_in == 0;
+        self.keyframe_in = if whole { KEYFRAME_INTERVAL - 1 } else { self.keyframe_in - 1 };
+        self.frame = self.frame.wrapping_add(1);
+
+        let palette = self.palette_for(pixels);
+        let indices: Vec<u8> = pixels.iter().map(|&pixel| nearest(&palette, pixel)).collect();
+        let mut messages = Vec::new();
+
+        // Palette entries that changed, as few messages as they fit in
+        let mut entry = 0;
+        while entry < PALETTE_SIZE {
+            if !whole && palette[entry] == self.palette[entry] {
+                entry += 1;
+                continue;
+            }
+            let first = entry;
+            let mut colors = [RGB8::default(); MAX_STREAM_COLORS];
+            while entry < PALETTE_SIZE && entry - first < MAX_STREAM_COLORS && (whole || palette[entry] != self.palette[entry]) {
+                colors[entry - first] = palette[entry];
+                entry += 1;
+            }
+            messages.push(Message::StreamPalette {
+                frame: self.frame,
+                first: first as u8,
+                len: (entry - first) as u8,
+                colors,
+            });
+        }
+        self.palette = palette;
+
+        // Ranges of pixels that changed
+        let changed = |canvas: &[u8], i: usize| whole || canvas[i] != indices[i];
+        let mut pixel = 0;
+        while pixel < indices.len() {
+            if !changed(&self.canvas, pixel) {
+                pixel += 1;
+                continue;
+            }
+            let start = pixel;
+            let mut runs = [0u8; MAX_RUNS];
+            let mut len = 0;
+            while pixel < indices.len() && len < MAX_RUNS {
+                let unchanged = (pixel..indices.len().min(pixel + GAP)).all(|i| !changed(&self.canvas, i));
+                if unchanged {
+                    break;
+                }
+                let index = indices[pixel];
+                let run = indices[pixel..].iter().take(MAX_RUN).take_while(|&&i| i == index).count();
+                runs[len] = encode_run(run, index);
+                len += 1;
+                pixel += run;
+            }
+            self.canvas[start..pixel].copy_from_slice(&indices[start..pixel]);
+            messages.push(Message::StreamPixels {
+                frame: self.frame,
+                start: start as u8,
+                len: len as u8,
+                runs,
+            });
+        }
+
+        messages.push(Message::StreamShow { frame: self.frame });
+        messages
+    }
+
+    /// A palette for `pixels`: their most common colours, keeping the
+    /// entries of ones already in it where they are
+    fn palette_for(&self, pixels: &[RGB8]) -> [RGB8; PALETTE_SIZE] {
+        let mut counts: HashMap<(u8, u8, u8), usize> = HashMap::new();
+        for pixel in pixels {
+            *counts.entry((pixel.r, pixel.g, pixel.b)).or_insert(0) += 1;
+        }
+        let mut common: Vec<_> = counts.into_iter().collect();
+        // Ties are broken by colour, so the same frame gets the same palette
+        common.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let wanted: Vec<RGB8> = common.iter().take(PALETTE_SIZE).map(|&((r, g, b), _)| RGB8::new(r, g, b)).collect();
+
+        let mut palette = self.palette;
+        let kept: Vec<Option<usize>> = wanted.iter().map(|color| palette.iter().position(|entry| entry == color)).collect();
+        let mut free = (0..PALETTE_SIZE).filter(|entry| !kept.contains(&Some(*entry)));
+        for (&color, entry) in wanted.iter().zip(&kept) {
+            if entry.is_none() {
+                palette[free.next().unwrap()] = color;
+            }
+        }
+        palette
+    }
+}
+
+/// The palette entry closest to `color`
+fn nearest(palette: &[RGB8; PALETTE_SIZE], color: RGB8) -> u8 {
+    let distance = |entry: &RGB8| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(entry.r, color.r) + d(entry.g, color.g) + d(entry.b, color.b)
+    };
+    (0..PALETTE_SIZE).min_by_key(|&entry| distance(&palette[entry])).unwrap() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::protocol::FRAME_SIZE;
+    use shared::secure::MAX_SECURE_PAYLOAD;
+    use shared::stream::{decode_run, Stream};
+
+    /// `len` pixels, each a colour of its own up to `colors` of them
+    fn frame(len: usize, colors: usize) -> Vec<RGB8> {
+        (0..len).map(|i| RGB8::new((i % colors) as u8 * 10, 0, 0)).collect()
+    }
+
+    /// The length of every run drawn
+    fn run_lengths(messages: &[Message]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        for message in messages {
+            if let Message::StreamPixels { len, runs, .. } = message {
+                lengths.extend(runs[..*len as usize].iter().map(|&run| decode_run(run).0));
+            }
+        }
+        lengths
+    }
+
+    /// What a fixture shows after the messages
+    fn shown(stream: &mut Stream, messages: &[Message]) -> Vec<RGB8> {
+        for message in messages {
+            stream.apply(message, 0);
+        }
+        stream.pixels(0).unwrap().collect()
+    }
+
+    #[test]
+    fn messages_fit_a_secured_frame() {
+        let mut encoder = Encoder::new(MAX_STREAM_PIXELS);
+        for message in encoder.encode(&frame(MAX_STREAM_PIXELS, PALETTE_SIZE)) {
+            let mut out = [0u8; FRAME_SIZE];
+            let len = message.encode(&mut out);
+            assert!(len <= MAX_SECURE_PAYLOAD, "{:?} takes {} bytes", message, len);
+            match message {
+                Message::StreamPalette { len, .. } => assert!(len as usize <= MAX_STREAM_COLORS),
+                Message::StreamPixels { len, .. } => assert!(len as usize <= MAX_RUNS),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn keyframes_are_split_and_drawn_whole() {
+        let mut encoder = Encoder::new(MAX_STREAM_PIXELS);
+        let pixels = frame(MAX_STREAM_PIXELS, PALETTE_SIZE);
+        let messages = encoder.encode(&pixels);
+
+        let palettes = messages.iter().filter(|m| matches!(m, Message::StreamPalette { .. })).count();
+        assert_eq!(palettes, PALETTE_SIZE.div_ceil(MAX_STREAM_COLORS));
+        // Every pixel differs from the one before, so a run each
+        let runs = messages.iter().filter(|m| matches!(m, Message::StreamPixels { .. })).count();
+        assert_eq!(runs, MAX_STREAM_PIXELS.div_ceil(MAX_RUNS));
+        assert_eq!(messages.last(), Some(&Message::StreamShow { frame: 1 }));
+
+        assert_eq!(shown(&mut Stream::new(), &messages), pixels);
+    }
+
+    #[test]
+    fn runs_are_at_most_a_byte_long() {
+        let mut encoder = Encoder::new(40);
+        let runs = run_lengths(&encoder.encode(&[RGB8::new(1, 2, 3); 40]));
+        assert_eq!(runs, [MAX_RUN, MAX_RUN, 40 - 2 * MAX_RUN]);
+    }
+
+    #[test]
+    fn only_changes_are_sent_between_keyframes() {
+        let mut encoder = Encoder::new(100);
+        let mut stream = Stream::new();
+        let mut pixels = frame(100, 4);
+        shown(&mut stream, &encoder.encode(&pixels));
+
+        // Nothing changed
+        assert_eq!(encoder.encode(&pixels), [Message::StreamShow { frame: 2 }]);
+
+        pixels[60] = pixels[1];
+        let messages = encoder.encode(&pixels);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], Message::StreamPixels { start: 60, len: 1, .. }));
+        assert_eq!(shown(&mut stream, &messages)[..100], pixels[..]);
+
+        // Lost messages are made up for by the next frame sent whole
+        encoder.resend();
+        let messages = encoder.encode(&pixels);
+        assert!(messages.iter().any(|m| matches!(m, Message::StreamPalette { .. })));
+        assert_eq!(shown(&mut Stream::new(), &messages)[..100], pixels[..]);
+    }
+
+    #[test]
+    fn new_colours_take_free_palette_entries() {
+        let mut encoder = Encoder::new(10);
+        encoder.encode(&frame(10, 2));
+        let messages = encoder.encode(&frame(10, 3));
+        // Only the new colour's entry is sent
+        match messages[0] {
+            Message::StreamPalette { len, colors, .. } => {
+                assert_eq!(len, 1);
+                assert_eq!(colors[0], RGB8::new(20, 0, 0));
+            }
+            ref other => panic!("sent {:?} first", other),
+        }
+    }
+
+    #[test]
+    fn frames_are_cut_to_the_stream() {
+        let mut encoder = Encoder::new(3);
+        let drawn: usize = run_lengths(&encoder.encode(&frame(10, 10))).iter().sum();
+        assert_eq!(drawn, 3);
+    }
+}
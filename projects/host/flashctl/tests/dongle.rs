@@ -0,0 +1,114 @@
+//! The dongle against [`FakeDongle`], the way the commands use it.
+
+use flashctl::dongle::ANNOUNCE_TIMEOUT;
+use flashctl::{Dongle, FakeDongle};
+use serialport::SerialPort;
+use shared::address::{Destination, GroupId, NodeId};
+use shared::dongle::Outcome;
+use shared::image::ImageHeader;
+use shared::inventory::{DISCOVER_WINDOW, MAX_DISCOVER_WINDOW};
+use shared::ota::{OtaStatus, Sender};
+use shared::protocol::{Effect, Frame, Message};
+use shared::secure::SITE_KEY;
+use std::time::{Duration, Instant};
+
+fn dongle(fixtures: &[NodeId]) -> Dongle<Box<dyn SerialPort>> {
+    let fake = FakeDongle::start(fixtures).expect("to start the fake dongle");
+    Dongle::open(fake.path().to_str().unwrap()).expect("to open the fake dongle")
+}
+
+#[test]
+fn sent_frames_are_acked_by_their_fixture() {
+    let mut dongle = dongle(&[1, 2]);
+
+    match dongle.send(Destination::Node(2), Message::SetBrightness(42)).unwrap() {
+        Outcome::Acked(Some(status)) => assert_eq!(status.brightness, 42),
+        outcome => panic!("fixture 2 answered {:?}", outcome),
+    }
+    let effect = Message::SetEffect { effect: Effect::Solid, speed: 1 };
+    match dongle.send(Destination::Node(1), effect).unwrap() {
+        Outcome::Acked(Some(status)) => assert_eq!((status.effect, status.brightness), (Effect::Solid, 255)),
+        outcome => panic!("fixture 1 answered {:?}", outcome),
+    }
+    assert_eq!(dongle.send(Destination::Node(3), Message::SetBrightness(1)).unwrap(), Outcome::Lost);
+    let group = Destination::Group(GroupId::new(4).unwrap());
+    assert_eq!(dongle.send(group, Message::SetBrightness(1)).unwrap(), Outcome::Sent);
+}
+
+#[test]
+fn requests_get_their_fixtures_answer() {
+    let mut dongle = dongle(&[1, 2]);
+
+    for &fixture in &[2, 1] {
+        match dongle.request(fixture, Message::StatusQuery).unwrap() {
+            (Outcome::Acked(_), Some(Frame { header, message: Message::LinkStatus(report) })) => {
+                assert_eq!(header.src, fixture);
+                assert_eq!(report.received, 1);
+            }
+            answer => panic!("fixture {} answered {:?}", fixture, answer),
+        }
+    }
+    // Nobody answers for a fixture that isn't there
+    assert!(matches!(dongle.request(3, Message::StatusQuery).unwrap(), (Outcome::Lost, None)));
+}
+
+#[test]
+fn discover_finds_every_fixture() {
+    let mut dongle = dongle(&[1, 5, 9]);
+
+    let (outcome, mut found) = dongle.discover(DISCOVER_WINDOW).unwrap();
+    assert_eq!(outcome, Outcome::Sent);
+    found.sort_by_key(|&(id, _)| id);
+    let ids: Vec<NodeId> = found.iter().map(|&(id, _)| id).collect();
+    assert_eq!(ids, [1, 5, 9]);
+    for (id, info) in found {
+        assert_eq!(info.uid & 0xFF, id as u64);
+    }
+    // Answers don't linger for whatever comes next
+    assert!(dongle.receive(Duration::from_millis(50)).unwrap().is_none());
+}
+
+#[test]
+fn discover_waits_no_longer_than_the_longest_window() {
+    let mut dongle = dongle(&[2]);
+
+    let started = Instant::now();
+    let (_, found) = dongle.discover(u16::MAX).unwrap();
+    assert_eq!(found.len(), 1);
+    let longest = Duration::from_millis(MAX_DISCOVER_WINDOW.into()) + ANNOUNCE_TIMEOUT;
+    assert!(started.elapsed() < longest + Duration::from_millis(500), "waited {:?}", started.elapsed());
+}
+
+#[test]
+fn updates_resend_what_went_missing() {
+    let mut dongle = dongle(&[3]);
+    let image: Vec<u8> = (0..1000u32).map(|n| n as u8).collect();
+    let header = ImageHeader::sign(SITE_KEY, 2, [7; 8], &image);
+    let mut sender = Sender::new(&header, &image);
+
+    let mut dropped = false;
+    let mut statuses = Vec::new();
+    let outcome = loop {
+        let message = sender.next_message();
+        if let Message::OtaChunk { index, .. } = message {
+            // Chunk 5 gets lost on the way the first time round
+            if index == 5 && !dropped {
+                dropped = true;
+            } else {
+                assert!(matches!(dongle.send(Destination::Node(3), message).unwrap(), Outcome::Acked(_)));
+            }
+            continue;
+        }
+        let status = match dongle.request(3, message).unwrap() {
+            (_, Some(Frame { message: Message::OtaStatus(status), .. })) => status,
+            answer => panic!("fixture 3 answered {:?}", answer),
+        };
+        statuses.push(status);
+        if let Some(outcome) = sender.handle(status) {
+            break outcome;
+        }
+    };
+    assert_eq!(outcome, Ok(()));
+    assert_eq!(statuses, [OtaStatus::Ready, OtaStatus::Missing(5), OtaStatus::Accepted]);
+    assert_eq!(sender.progress(), 100);
+}
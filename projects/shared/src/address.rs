@@ -0,0 +1,192 @@
+//! Addressing for fixtures, groups and broadcasts.
+//!
+//! Every node on the network has a one byte id. The controller is always `0`,
+//! fixtures use `1..=127`. On the air, the destination of a frame is encoded
+//! in a single byte as well:
+//!
+//! | byte          | destination            |
+//! |---------------|------------------------|
+//! | `0x00`        | the controller         |
+//! | `0x01..=0x7F` | a single fixture       |
+//! | `0x80..=0xFE` | group `0..=126`        |
+//! | `0xFF`        | every fixture          |
+//!
+//! The nRF24 only gives us 6 RX pipes, and pipes 2 to 5 must share the upper
+//! four bytes of pipe 1's address. Fixtures listen on pipe 1 for their own
+//! address, pipe 2 for the shared group address and pipe 3 for the broadcast
+//! address. Group membership is checked in software against a [`GroupTable`],
+//! so a fixture can belong to any number of groups.
+
+/// Width of every radio address, in bytes
+pub const ADDRESS_WIDTH: usize = 5;
+
+/// Upper four bytes shared by every address on our network
+pub const NETWORK_PREFIX: [u8; 4] = *b"FLSH";
+
+/// Id of the controller
+pub const CONTROLLER_ID: NodeId = 0;
+/// Highest id a fixture can use
+pub const MAX_FIXTURE_ID: NodeId = 0x7F;
+
+/// Least significant address byte of the pipe every group frame is sent to
+pub const GROUP_PIPE_LSB: u8 = 0xFE;
+/// Least significant address byte of the pipe every broadcast is sent to
+pub const BROADCAST_PIPE_LSB: u8 = 0xFF;
+
+const GROUP_BASE: u8 = 0x80;
+const BROADCAST: u8 = 0xFF;
+
+/// One byte id of a device on the network
+pub type NodeId = u8;
+
+/// Id of a group of fixtures, in the range `0..=126`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupId(u8);
+
+impl GroupId {
+    /// Highest valid group id
+    pub const MAX: u8 = BROADCAST - GROUP_BASE - 1;
+
+    pub fn new(id: u8) -> Option<Self> {
+        if id <= Self::MAX {
+            Some(GroupId(id))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// Who a frame is meant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// A single node, either a fixture or the controller
+    Node(NodeId),
+    /// Every fixture that is a member of the group
+    Group(GroupId),
+    /// Every fixture in range
+    Broadcast,
+}
+
+impl Destination {
+    /// Encode the destination into its on-air byte
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Destination::Node(id) => id,
+            Destination::Group(group) => GROUP_BASE + group.0,
+            Destination::Broadcast => BROADCAST,
+        }
+    }
+
+    /// Decode the destination from its on-air byte
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            BROADCAST => Destination::Broadcast,
+            b if b >= GROUP_BASE => Destination::Group(GroupId(b - GROUP_BASE)),
+            id => Destination::Node(id),
+        }
+    }
+
+    /// The radio address a transmitter must send to in order to reach this destination
+    pub fn radio_address(self) -> [u8; ADDRESS_WIDTH] {
+        match self {
+            Destination::Node(id) => node_address(id),
+            Destination::Group(_) => pipe_address(GROUP_PIPE_LSB),
+            Destination::Broadcast => pipe_address(BROADCAST_PIPE_LSB),
+        }
+    }
+
+    /// Whether a receiver will acknowledge frames sent to this destination.
+    ///
+    /// Several fixtures answering the same frame would only collide, so group
+    /// and broadcast frames are sent without auto-ack.
+    pub fn is_acknowledged(self) -> bool {
+        matches!(self, Destination::Node(_))
+    }
+}
+
+/// The unicast radio address of a node
+pub fn node_address(id: NodeId) -> [u8; ADDRESS_WIDTH] {
+    pipe_address(id)
+}
+
+/// The node id a fixture takes until it's given one, picked from its unique
+/// id so fixtures fresh from the bench rarely share one
+pub fn default_id(uid: u64) -> NodeId {
+    (uid % MAX_FIXTURE_ID as u64) as NodeId + 1
+}
+
+/// Build a full address from its least significant byte.
+///
+/// Addresses are written to the nRF24 LSB first, so the distinguishing byte
+/// comes first.
+pub fn pipe_address(lsb: u8) -> [u8; ADDRESS_WIDTH] {
+    let mut address = [lsb; ADDRESS_WIDTH];
+    address[1..].copy_from_slice(&NETWORK_PREFIX);
+    address
+}
+
+/// The set of groups a fixture is a member of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GroupTable {
+    bits: [u32; 4],
+}
+
+impl GroupTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, group: GroupId) -> bool {
+        let g = group.0 as usize;
+        self.bits[g / 32] & (1 << (g % 32)) != 0
+    }
+
+    pub fn join(&mut self, group: GroupId) {
+        let g = group.0 as usize;
+        self.bits[g / 32] |= 1 << (g % 32);
+    }
+
+    pub fn leave(&mut self, group: GroupId) {
+        let g = group.0 as usize;
+        self.bits[g / 32] &= !(1 << (g % 32));
+    }
+
+    pub fn clear(&mut self) {
+        self.bits = [0; 4];
+    }
+
+    /// Iterate over every group in the table
+    pub fn iter(&self) -> impl Iterator<Item = GroupId> + '_ {
+        (0..=GroupId::MAX)
+            .map(GroupId)
+            .filter(move |group| self.contains(*group))
+    }
+
+    /// Whether a frame sent to `dest` is meant for the fixture `me`
+    pub fn accepts(&self, me: NodeId, dest: Destination) -> bool {
+        match dest {
+            Destination::Node(id) => id == me,
+            Destination::Group(group) => self.contains(group),
+            Destination::Broadcast => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_ids_are_fixture_ids() {
+        for uid in [0, 1, 0x7E, 0x7F, u64::MAX, 0x0036_0034_3233_5113].iter() {
+            let id = default_id(*uid);
+            assert!(id != CONTROLLER_ID && id <= MAX_FIXTURE_ID, "{:x} gets {}", uid, id);
+        }
+        assert_eq!(default_id(0x0036_0034_3233_5113), default_id(0x0036_0034_3233_5113));
+        assert_ne!(default_id(0x0036_0034_3233_5113), default_id(0x0036_0034_3233_5114));
+    }
+}
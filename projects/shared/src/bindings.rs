@@ -0,0 +1,689 @@
+//! What the controller's inputs do.
+//!
+//! [`Bindings`] maps an input and what it did to an [`Action`], and
+//! [`Controls`] carries actions out, keeping track of what the controls are
+//! set to and what to send. The table is stored in flash with
+//! [`Bindings::encode`], and edited on the serial port a binding per line:
+//!
+//! ```text
+//! <input> <event> <action>
+//! 0 short next-effect
+//! 3 cw brightness +8
+//! 1 long group all
+//! 2 double scene 4
+//! 0 double go
+//! ```
+//!
+//! Events are `short`, `long` and `double` presses and `cw` and `ccw` turns
+//! by one detent. See [`Action`] for the actions.
+
+use crate::address::{Destination, GroupId};
+use crate::cue::{Cue, Transport};
+use crate::input::Gesture;
+use crate::protocol::{Effect, Message};
+use crate::scene::Look;
+use core::fmt;
+use smart_leds::RGB8;
+
+/// Most bindings a table holds
+pub const MAX_BINDINGS: usize = 24;
+/// Size of an encoded binding
+pub const BINDING_SIZE: usize = 4;
+/// Size of the largest encoded table
+pub const MAX_ENCODED_SIZE: usize = MAX_BINDINGS * BINDING_SIZE;
+/// Shortest time between two colour changes, so turning a pot doesn't flood
+/// the radio, in ms
+pub const COLOR_INTERVAL: u32 = 50;
+
+/// Why a binding couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line ends before the binding does
+    Missing,
+    /// An event, action or effect we don't know about
+    Unknown,
+    /// A number out of range
+    InvalidValue,
+    /// More words follow the binding
+    TooLong,
+}
+
+/// Something an input did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Short,
+    Long,
+    Double,
+    /// Turned one detent clockwise
+    Clockwise,
+    /// Turned one detent anticlockwise
+    Anticlockwise,
+}
+
+impl From<Gesture> for Event {
+    fn from(gesture: Gesture) -> Self {
+        match gesture {
+            Gesture::Short => Event::Short,
+            Gesture::Long => Event::Long,
+            Gesture::Double => Event::Double,
+        }
+    }
+}
+
+impl Event {
+    const ALL: [Event; 5] = [Event::Short, Event::Long, Event::Double, Event::Clockwise, Event::Anticlockwise];
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Event::Short => 0,
+            Event::Long => 1,
+            Event::Double => 2,
+            Event::Clockwise => 3,
+            Event::Anticlockwise => 4,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Short => "short",
+            Event::Long => "long",
+            Event::Double => "double",
+            Event::Clockwise => "cw",
+            Event::Anticlockwise => "ccw",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|event| event.name() == name)
+    }
+}
+
+/// What a binding does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// `next-effect`
+    NextEffect,
+    /// `previous-effect`
+    PreviousEffect,
+    /// `effect <name>`
+    Effect(Effect),
+    /// `brightness +<n>` or `brightness -<n>`
+    Brightness(i8),
+    /// `brightness <n>`
+    SetBrightness(u8),
+    /// `speed +<n>` or `speed -<n>`
+    Speed(i8),
+    /// `speed <n>`
+    SetSpeed(u8),
+    /// `next-group`, stepping through the groups and then every fixture
+    NextGroup,
+    /// `group <id>` or `group all`
+    SelectGroup(Option<GroupId>),
+    /// `scene <n>`
+    RecallScene(u8),
+    /// `go`, `back`, `pause` or `stop`, playing the cue list
+    Cue(Transport),
+}
+
+mod kind {
+    pub const NEXT_EFFECT: u8 = 0;
+    pub const PREVIOUS_EFFECT: u8 = 1;
+    pub const EFFECT: u8 = 2;
+    pub const BRIGHTNESS: u8 = 3;
+    pub const SET_BRIGHTNESS: u8 = 4;
+    pub const SPEED: u8 = 5;
+    pub const SET_SPEED: u8 = 6;
+    pub const NEXT_GROUP: u8 = 7;
+    pub const SELECT_GROUP: u8 = 8;
+    pub const RECALL_SCENE: u8 = 9;
+    pub const CUE: u8 = 10;
+}
+
+/// Stands for every fixture in an encoded [`Action::SelectGroup`]
+const ALL_GROUPS: u8 = 0xFF;
+
+impl Action {
+    pub fn encode(self) -> [u8; 2] {
+        match self {
+            Action::NextEffect => [kind::NEXT_EFFECT, 0],
+            Action::PreviousEffect => [kind::PREVIOUS_EFFECT, 0],
+            Action::Effect(effect) => [kind::EFFECT, effect.to_byte()],
+            Action::Brightness(change) => [kind::BRIGHTNESS, change as u8],
+            Action::SetBrightness(brightness) => [kind::SET_BRIGHTNESS, brightness],
+            Action::Speed(change) => [kind::SPEED, change as u8],
+            Action::SetSpeed(speed) => [kind::SET_SPEED, speed],
+            Action::NextGroup => [kind::NEXT_GROUP, 0],
+            Action::SelectGroup(group) => [kind::SELECT_GROUP, group.map_or(ALL_GROUPS, GroupId::get)],
+            Action::RecallScene(scene) => [kind::RECALL_SCENE, scene],
+            Action::Cue(transport) => [kind::CUE, transport.to_byte()],
+        }
+    }
+
+    pub fn decode(data: [u8; 2]) -> Option<Self> {
+        let [code, arg] = data;
+        Some(match code {
+            kind::NEXT_EFFECT => Action::NextEffect,
+            kind::PREVIOUS_EFFECT => Action::PreviousEffect,
+            kind::EFFECT => Action::Effect(Effect::from_byte(arg)?),
+            kind::BRIGHTNESS => Action::Brightness(arg as i8),
+            kind::SET_BRIGHTNESS => Action::SetBrightness(arg),
+            kind::SPEED => Action::Speed(arg as i8),
+            kind::SET_SPEED => Action::SetSpeed(arg),
+            kind::NEXT_GROUP => Action::NextGroup,
+            kind::SELECT_GROUP if arg == ALL_GROUPS => Action::SelectGroup(None),
+            kind::SELECT_GROUP => Action::SelectGroup(Some(GroupId::new(arg)?)),
+            kind::RECALL_SCENE => Action::RecallScene(arg),
+            kind::CUE => Action::Cue(Transport::from_byte(arg)?),
+            _ => return None,
+        })
+    }
+
+    /// Read an action from the words of a line
+    pub fn parse<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self, ParseError> {
+        let name = words.next().ok_or(ParseError::Missing)?;
+        let action = match name {
+            "next-effect" => Action::NextEffect,
+            "previous-effect" => Action::PreviousEffect,
+            "next-group" => Action::NextGroup,
+            "effect" => {
+                let effect = words.next().ok_or(ParseError::Missing)?;
+                Action::Effect(Effect::from_name(effect).ok_or(ParseError::Unknown)?)
+            }
+            "brightness" | "speed" => {
+                let value = words.next().ok_or(ParseError::Missing)?;
+                let relative = value.starts_with('+') || value.starts_with('-');
+                match (name, relative) {
+                    ("brightness", true) => Action::Brightness(parse_number(value)?),
+                    ("brightness", false) => Action::SetBrightness(parse_number(value)?),
+                    (_, true) => Action::Speed(parse_number(value)?),
+                    (_, false) => Action::SetSpeed(parse_number(value)?),
+                }
+            }
+            "group" => match words.next().ok_or(ParseError::Missing)? {
+                "all" => Action::SelectGroup(None),
+                id => Action::SelectGroup(Some(GroupId::new(parse_number(id)?).ok_or(ParseError::InvalidValue)?)),
+            },
+            "scene" => Action::RecallScene(parse_number(words.next().ok_or(ParseError::Missing)?)?),
+            name => Action::Cue(Transport::from_name(name).ok_or(ParseError::Unknown)?),
+        };
+        Ok(action)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Action::NextEffect => write!(f, "next-effect"),
+            Action::PreviousEffect => write!(f, "previous-effect"),
+            Action::Effect(effect) => write!(f, "effect {}", effect.name()),
+            Action::Brightness(change) => write!(f, "brightness {:+}", change),
+            Action::SetBrightness(brightness) => write!(f, "brightness {}", brightness),
+            Action::Speed(change) => write!(f, "speed {:+}", change),
+            Action::SetSpeed(speed) => write!(f, "speed {}", speed),
+            Action::NextGroup => write!(f, "next-group"),
+            Action::SelectGroup(None) => write!(f, "group all"),
+            Action::SelectGroup(Some(group)) => write!(f, "group {}", group.get()),
+            Action::RecallScene(scene) => write!(f, "scene {}", scene),
+            Action::Cue(transport) => write!(f, "{}", transport.name()),
+        }
+    }
+}
+
+fn parse_number<T: core::str::FromStr>(word: &str) -> Result<T, ParseError> {
+    word.parse().map_err(|_| ParseError::InvalidValue)
+}
+
+/// An input, what it did and what that does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    pub input: u8,
+    pub event: Event,
+    pub action: Action,
+}
+
+impl Binding {
+    pub const fn new(input: u8, event: Event, action: Action) -> Self {
+        Self { input, event, action }
+    }
+
+    /// Read a binding written like its [`fmt::Display`] output
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut words = line.split_whitespace();
+        let (input, event) = parse_trigger(&mut words)?;
+        let action = Action::parse(&mut words)?;
+        if words.next().is_some() {
+            return Err(ParseError::TooLong);
+        }
+        Ok(Self { input, event, action })
+    }
+
+    pub fn encode(&self) -> [u8; BINDING_SIZE] {
+        let [kind, arg] = self.action.encode();
+        [self.input, self.event.to_byte(), kind, arg]
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            input: data[0],
+            event: Event::from_byte(data[1])?,
+            action: Action::decode([data[2], data[3]])?,
+        })
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.input, self.event.name(), self.action)
+    }
+}
+
+/// Read the input and event at the start of a binding
+pub fn parse_trigger<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<(u8, Event), ParseError> {
+    let input = parse_number(words.next().ok_or(ParseError::Missing)?)?;
+    let event = Event::from_name(words.next().ok_or(ParseError::Missing)?).ok_or(ParseError::Unknown)?;
+    Ok((input, event))
+}
+
+/// The table is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+/// The bindings of every input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bindings {
+    entries: [Option<Binding>; MAX_BINDINGS],
+}
+
+impl Bindings {
+    pub const fn empty() -> Self {
+        Self { entries: [None; MAX_BINDINGS] }
+    }
+
+    /// What the controller does out of the box, for its inputs:
+    ///
+    /// 0. effect button
+    /// 1. target button
+    /// 2. speed button
+    /// 3. encoder, and the button it has
+    pub fn defaults() -> Self {
+        const DEFAULTS: [Binding; 12] = [
+            Binding::new(0, Event::Short, Action::NextEffect),
+            Binding::new(0, Event::Long, Action::Effect(Effect::Off)),
+            Binding::new(0, Event::Double, Action::PreviousEffect),
+            Binding::new(1, Event::Short, Action::NextGroup),
+            Binding::new(1, Event::Long, Action::SelectGroup(None)),
+            Binding::new(2, Event::Short, Action::Speed(1)),
+            Binding::new(2, Event::Long, Action::SetSpeed(1)),
+            Binding::new(2, Event::Double, Action::Speed(-1)),
+            Binding::new(3, Event::Short, Action::SetBrightness(u8::MAX)),
+            Binding::new(3, Event::Long, Action::SetBrightness(0)),
+            Binding::new(3, Event::Clockwise, Action::Brightness(8)),
+            Binding::new(3, Event::Anticlockwise, Action::Brightness(-8)),
+        ];
+        let mut bindings = Self::empty();
+        for (entry, binding) in bindings.entries.iter_mut().zip(DEFAULTS.iter()) {
+            *entry = Some(*binding);
+        }
+        bindings
+    }
+
+    /// What `input` doing `event` does
+    pub fn get(&self, input: u8, event: Event) -> Option<Action> {
+        self.iter()
+            .find(|binding| binding.input == input && binding.event == event)
+            .map(|binding| binding.action)
+    }
+
+    /// Add a binding, replacing the one for the same input and event
+    pub fn bind(&mut self, binding: Binding) -> Result<(), Full> {
+        let slot = self
+            .entries
+            .iter()
+            .position(|entry| matches!(entry, Some(b) if b.input == binding.input && b.event == binding.event))
+            .or_else(|| self.entries.iter().position(Option::is_none))
+            .ok_or(Full)?;
+        self.entries[slot] = Some(binding);
+        Ok(())
+    }
+
+    /// Remove the binding for `input` and `event`, returning whether there was one
+    pub fn unbind(&mut self, input: u8, event: Event) -> bool {
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| matches!(entry, Some(b) if b.input == input && b.event == event));
+        match position {
+            Some(position) => {
+                // Keep the table in the order bindings were added
+                self.entries[position..].rotate_left(1);
+                self.entries[MAX_BINDINGS - 1] = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Binding> {
+        self.entries.iter().flatten()
+    }
+
+    /// Write the table into `out`, returning its length
+    pub fn encode(&self, out: &mut [u8; MAX_ENCODED_SIZE]) -> usize {
+        let mut len = 0;
+        for binding in self.iter() {
+            out[len..len + BINDING_SIZE].copy_from_slice(&binding.encode());
+            len += BINDING_SIZE;
+        }
+        len
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if !data.len().is_multiple_of(BINDING_SIZE) || data.len() > MAX_ENCODED_SIZE {
+            return None;
+        }
+        let mut bindings = Self::empty();
+        for (entry, data) in bindings.entries.iter_mut().zip(data.chunks(BINDING_SIZE)) {
+            *entry = Some(Binding::decode(data)?);
+        }
+        Some(bindings)
+    }
+}
+
+/// What carrying out an action takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Send a message
+    Send(Destination, Message),
+    /// Recall a stored scene, over its own fade time unless given one in ms
+    RecallScene { scene: u8, fade: Option<u32> },
+    /// Play the cue list
+    Cue(Transport),
+}
+
+impl From<Cue> for Command {
+    fn from(cue: Cue) -> Self {
+        Command::RecallScene {
+            scene: cue.scene,
+            fade: Some(cue.fade),
+        }
+    }
+}
+
+/// What the controls are set to, and who they talk to
+#[derive(Debug, Clone, Copy)]
+pub struct Controls {
+    /// Every fixture if `None`
+    target: Option<GroupId>,
+    /// Groups [`Action::NextGroup`] steps through
+    groups: u8,
+    effect: Effect,
+    speed: u8,
+    brightness: u8,
+    color: RGB8,
+    /// The pots moved since the colour was last sent
+    color_changed: bool,
+    /// When the colour was last sent, in ms
+    color_sent: u32,
+}
+
+impl Controls {
+    /// Controls talking to every fixture, with `groups` to pick from
+    pub const fn new(groups: u8) -> Self {
+        Self {
+            target: None,
+            groups,
+            effect: Effect::Solid,
+            speed: 1,
+            brightness: u8::MAX,
+            color: RGB8 { r: 0, g: 0, b: 0 },
+            color_changed: false,
+            color_sent: 0,
+        }
+    }
+
+    /// Where commands go
+    pub fn target(&self) -> Destination {
+        self.target.map_or(Destination::Broadcast, Destination::Group)
+    }
+
+    /// What the target was last told to show
+    pub fn look(&self) -> Look {
+        Look {
+            effect: self.effect,
+            speed: self.speed,
+            brightness: self.brightness,
+            color: self.color,
+        }
+    }
+
+    /// Catch up with a look the target was given some other way, like a scene
+    pub fn set_look(&mut self, look: Look) {
+        self.effect = look.effect;
+        self.speed = look.speed;
+        self.brightness = look.brightness;
+        self.color = look.color;
+        self.color_changed = false;
+    }
+
+    fn send(&self, message: Message) -> Option<Command> {
+        Some(Command::Send(self.target(), message))
+    }
+
+    fn set_effect(&mut self, effect: Effect) -> Option<Command> {
+        self.effect = effect;
+        self.send(Message::SetEffect { effect, speed: self.speed })
+    }
+
+    fn set_speed(&mut self, speed: u8) -> Option<Command> {
+        self.speed = speed;
+        self.set_effect(self.effect)
+    }
+
+    fn set_brightness(&mut self, brightness: u8) -> Option<Command> {
+        self.brightness = brightness;
+        self.send(Message::SetBrightness(brightness))
+    }
+
+    /// Carry out `action`, returning what to do about it
+    pub fn apply(&mut self, action: Action) -> Option<Command> {
+        match action {
+            Action::NextEffect => self.set_effect(next_effect(self.effect)),
+            Action::PreviousEffect => self.set_effect(previous_effect(self.effect)),
+            Action::Effect(effect) => self.set_effect(effect),
+            Action::Brightness(change) => self.set_brightness(offset(self.brightness, change)),
+            Action::SetBrightness(brightness) => self.set_brightness(brightness),
+            Action::Speed(change) => self.set_speed(offset(self.speed, change)),
+            Action::SetSpeed(speed) => self.set_speed(speed),
+            Action::NextGroup => {
+                self.target = match self.target {
+                    None if self.groups > 0 => GroupId::new(0),
+                    Some(group) if group.get() + 1 < self.groups => GroupId::new(group.get() + 1),
+                    _ => None,
+                };
+                None
+            }
+            Action::SelectGroup(group) => {
+                self.target = group;
+                None
+            }
+            Action::RecallScene(scene) => Some(Command::RecallScene { scene, fade: None }),
+            Action::Cue(transport) => Some(Command::Cue(transport)),
+        }
+    }
+
+    /// Pot `channel` moved to `level`
+    pub fn moved(&mut self, channel: usize, level: u8) {
+        match channel {
+            0 => self.color.r = level,
+            1 => self.color.g = level,
+            _ => self.color.b = level,
+        }
+        self.color_changed = true;
+    }
+
+    /// The colour, once the pots moved and it was last sent long enough ago
+    pub fn color_due(&mut self, now: u32) -> Option<Command> {
+        if !self.color_changed || now.wrapping_sub(self.color_sent) < COLOR_INTERVAL {
+            return None;
+        }
+        self.color_changed = false;
+        self.color_sent = now;
+        self.send(Message::SetColor(self.color))
+    }
+}
+
+fn offset(value: u8, change: i8) -> u8 {
+    (value as i16 + change as i16).max(0).min(u8::MAX as i16) as u8
+}
+
+fn next_effect(effect: Effect) -> Effect {
+    match effect {
+        Effect::Off => Effect::Solid,
+        Effect::Solid => Effect::Rainbow,
+        Effect::Rainbow => Effect::Off,
+    }
+}
+
+fn previous_effect(effect: Effect) -> Effect {
+    match effect {
+        Effect::Off => Effect::Rainbow,
+        Effect::Solid => Effect::Off,
+        Effect::Rainbow => Effect::Solid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(id: u8) -> Option<GroupId> {
+        GroupId::new(id)
+    }
+
+    /// One of every action, and how it's written
+    const ACTIONS: [(Action, &str); 13] = [
+        (Action::NextEffect, "next-effect"),
+        (Action::PreviousEffect, "previous-effect"),
+        (Action::Effect(Effect::Rainbow), "effect rainbow"),
+        (Action::Brightness(8), "brightness +8"),
+        (Action::Brightness(-128), "brightness -128"),
+        (Action::SetBrightness(255), "brightness 255"),
+        (Action::Speed(-1), "speed -1"),
+        (Action::SetSpeed(0), "speed 0"),
+        (Action::NextGroup, "next-group"),
+        (Action::SelectGroup(None), "group all"),
+        (Action::RecallScene(4), "scene 4"),
+        (Action::Cue(Transport::Go), "go"),
+        (Action::Cue(Transport::Stop), "stop"),
+    ];
+
+    #[test]
+    fn gestures_look_up_their_action() {
+        let bindings = Bindings::defaults();
+        assert_eq!(bindings.get(0, Gesture::Short.into()), Some(Action::NextEffect));
+        assert_eq!(bindings.get(0, Gesture::Double.into()), Some(Action::PreviousEffect));
+        assert_eq!(bindings.get(3, Event::Anticlockwise), Some(Action::Brightness(-8)));
+        assert_eq!(bindings.get(1, Gesture::Double.into()), None);
+        assert_eq!(bindings.get(4, Event::Short), None);
+
+        let mut controls = Controls::new(2);
+        let action = bindings.get(3, Event::Clockwise).unwrap();
+        assert_eq!(controls.apply(action), Some(Command::Send(Destination::Broadcast, Message::SetBrightness(255))));
+        let action = bindings.get(1, Event::Short).unwrap();
+        assert_eq!(controls.apply(action), None);
+        assert_eq!(controls.target(), Destination::Group(group(0).unwrap()));
+        assert_eq!(
+            controls.apply(Action::Brightness(-8)),
+            Some(Command::Send(controls.target(), Message::SetBrightness(247)))
+        );
+    }
+
+    #[test]
+    fn next_group_comes_back_round_to_every_fixture() {
+        let mut controls = Controls::new(2);
+        let targets: Vec<_> = (0..4)
+            .map(|_| {
+                controls.apply(Action::NextGroup);
+                controls.target()
+            })
+            .collect();
+        let (first, second) = (Destination::Group(group(0).unwrap()), Destination::Group(group(1).unwrap()));
+        assert_eq!(targets, [first, second, Destination::Broadcast, first]);
+    }
+
+    #[test]
+    fn actions_survive_encoding_and_writing_out() {
+        for &(action, text) in ACTIONS.iter() {
+            assert_eq!(Action::decode(action.encode()), Some(action), "{}", text);
+            assert_eq!(action.to_string(), text);
+            assert_eq!(Action::parse(&mut text.split_whitespace()), Ok(action), "{}", text);
+        }
+        assert_eq!(Action::decode([kind::EFFECT, 9]), None);
+        assert_eq!(Action::decode([kind::CUE + 1, 0]), None);
+    }
+
+    #[test]
+    fn tables_survive_encoding() {
+        let mut bindings = Bindings::empty();
+        for (input, &(action, _)) in ACTIONS.iter().enumerate() {
+            bindings.bind(Binding::new(input as u8, Event::Double, action)).unwrap();
+        }
+        let mut out = [0; MAX_ENCODED_SIZE];
+        let len = bindings.encode(&mut out);
+        assert_eq!(len, ACTIONS.len() * BINDING_SIZE);
+        assert_eq!(Bindings::decode(&out[..len]), Some(bindings));
+        assert_eq!(Bindings::decode(&out[..len - 1]), None);
+
+        let defaults = Bindings::defaults();
+        let len = defaults.encode(&mut out);
+        assert_eq!(Bindings::decode(&out[..len]), Some(defaults));
+    }
+
+    #[test]
+    fn lines_edit_the_table() {
+        let mut bindings = Bindings::defaults();
+        let lines = ["0 short next-effect", "3 cw brightness +8", "1 long group all", "2 double scene 4", "0 double go"];
+        for line in lines.iter() {
+            let binding = Binding::parse(line).unwrap();
+            assert_eq!(binding.to_string(), *line);
+            bindings.bind(binding).unwrap();
+        }
+
+        // Rebinding replaces, in place
+        assert_eq!(bindings.iter().count(), 12);
+        assert_eq!(bindings.get(2, Event::Double), Some(Action::RecallScene(4)));
+        assert_eq!(bindings.iter().nth(7), Some(&Binding::new(2, Event::Double, Action::RecallScene(4))));
+
+        assert!(bindings.unbind(0, Event::Short));
+        assert!(!bindings.unbind(0, Event::Short));
+        assert_eq!(bindings.get(0, Event::Short), None);
+        assert_eq!(bindings.iter().next(), Some(&Binding::new(0, Event::Long, Action::Effect(Effect::Off))));
+    }
+
+    #[test]
+    fn bad_lines_say_what_is_wrong() {
+        assert_eq!(Binding::parse("0 short"), Err(ParseError::Missing));
+        assert_eq!(Binding::parse("0 brightness"), Err(ParseError::Unknown));
+        assert_eq!(Binding::parse("0 short effect"), Err(ParseError::Missing));
+        assert_eq!(Binding::parse("0 short effect strobe"), Err(ParseError::Unknown));
+        assert_eq!(Binding::parse("0 short jump"), Err(ParseError::Unknown));
+        assert_eq!(Binding::parse("256 short go"), Err(ParseError::InvalidValue));
+        assert_eq!(Binding::parse("0 cw brightness +200"), Err(ParseError::InvalidValue));
+        assert_eq!(Binding::parse("0 long group 200"), Err(ParseError::InvalidValue));
+        assert_eq!(Binding::parse("0 short go now"), Err(ParseError::TooLong));
+    }
+
+    #[test]
+    fn full_tables_refuse_new_bindings_only() {
+        let mut bindings = Bindings::empty();
+        for input in 0..MAX_BINDINGS as u8 {
+            bindings.bind(Binding::new(input, Event::Short, Action::NextEffect)).unwrap();
+        }
+        assert_eq!(bindings.bind(Binding::new(0, Event::Long, Action::NextEffect)), Err(Full));
+        assert_eq!(bindings.bind(Binding::new(0, Event::Short, Action::NextGroup)), Ok(()));
+    }
+}
@@ -0,0 +1,533 @@
+//! What the bootloader should do next, and the slot swap that installs updates.
+//!
+//! The boot state page holds an append-only log of 32 bit records. Nothing
+//! is ever changed in place, so losing power leaves at most the record being
+//! written incomplete, and the bootloader picks up after the last complete
+//! one. An update goes through these records:
+//!
+//! ```text
+//! Pending            the app received and checked an image in the staging slot
+//! Step 0 ... Step n  the bootloader swapped the slots one page at a time
+//! Trial              the new image is in the app slot, on probation
+//! Attempt ...        the bootloader started it, once per boot
+//! Confirmed          the new image checked in, the update is done
+//! ```
+//!
+//! An image that doesn't check in within [`MAX_TRIAL_BOOTS`] boots is swapped
+//! back out, logged as `Rollback`, the steps of the second swap and
+//! `RolledBack`. The bootloader logs `Cancelled` instead of starting a swap
+//! for an update it refuses.
+//!
+//! Each page of a swap takes three steps through the scratch page, and every
+//! step only overwrites a page whose contents are safe elsewhere, so it can
+//! simply be done again after losing power part way:
+//!
+//! ```text
+//! 0: app page     -> scratch
+//! 1: staging page -> app page
+//! 2: scratch      -> staging page
+//! ```
+//!
+//! Images are checked with [`crate::image::verify`] before they're swapped in
+//! or started, see [`decide`].
+
+use crate::flash::{self, Flash, Region, APP_SLOT, BOOT_STATE, SCRATCH, STAGING_SLOT};
+use crate::image::{self, ImageHeader, VerifyError};
+
+/// Boots a new image gets to check in before it's rolled back
+pub const MAX_TRIAL_BOOTS: u8 = 3;
+/// Steps to swap the app and staging slots
+pub const SWAP_STEPS: u16 = APP_SLOT.pages() as u16 * 3;
+
+/// A log record is `| arg (16) | tag | !tag |` once fully written
+mod record {
+    pub const PENDING: u8 = 0x01;
+    pub const STEP: u8 = 0x02;
+    pub const TRIAL: u8 = 0x03;
+    pub const ATTEMPT: u8 = 0x04;
+    pub const CONFIRMED: u8 = 0x05;
+    pub const ROLLBACK: u8 = 0x06;
+    pub const ROLLED_BACK: u8 = 0x07;
+    pub const CANCELLED: u8 = 0x08;
+}
+
+/// Errors while reading or changing the boot state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootError<E> {
+    Flash(E),
+    /// No room for another record, which a single update never fills
+    LogFull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootState {
+    /// The app slot holds the image to run
+    Idle,
+    /// An update waits in the staging slot, `done` swap steps are complete
+    Install { done: u16 },
+    /// A new image was installed and hasn't checked in, after `attempts` boots
+    Trial { attempts: u8 },
+    /// Swapping a new image back out, `done` swap steps are complete
+    Rollback { done: u16 },
+}
+
+/// How the bootloader starts the app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boot {
+    /// The app was confirmed earlier, or rolled back to
+    Normal,
+    /// The app is on trial, and has to [`confirm`] it works
+    Trial,
+    /// Nothing in the app slot checks out
+    Recover,
+}
+
+/// What the bootloader does next, see [`decide`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Start the app, after logging an attempt if it's on trial
+    Start(Boot),
+    /// Drop the pending update
+    Cancel,
+    /// Swap in the pending update, after the `done` steps already logged
+    Install { done: u16 },
+    /// Swap the image on trial back out, after the `done` steps already logged
+    Rollback { done: u16 },
+}
+
+fn encode(tag: u8, arg: u16) -> u32 {
+    (arg as u32) << 16 | (tag as u32) << 8 | !tag as u32 & 0xFF
+}
+
+/// The tag and argument of a complete record
+fn decode(word: u32) -> Option<(u8, u16)> {
+    let tag = (word >> 8) as u8;
+    let arg = (word >> 16) as u16;
+    if word as u8 != !tag || arg == u16::MAX {
+        return None;
+    }
+    Some((tag, arg))
+}
+
+/// Fold every record of the log into the current state, returning it and
+/// where the next record goes
+fn scan<F: Flash>(flash: &mut F) -> Result<(BootState, Option<u32>), F::Error> {
+    let mut state = BootState::Idle;
+    let mut word = [0u8; 4];
+    for address in (BOOT_STATE.start..BOOT_STATE.end()).step_by(4) {
+        flash.read(address, &mut word)?;
+        let word = u32::from_le_bytes(word);
+        if word == u32::MAX {
+            return Ok((state, Some(address)));
+        }
+        // Skip what losing power left of a record
+        let (tag, arg) = match decode(word) {
+            Some(record) => record,
+            None => continue,
+        };
+        state = match (tag, state) {
+            (record::PENDING, _) => BootState::Install { done: 0 },
+            (record::STEP, BootState::Install { .. }) => BootState::Install { done: arg + 1 },
+            (record::STEP, BootState::Rollback { .. }) => BootState::Rollback { done: arg + 1 },
+            (record::TRIAL, _) => BootState::Trial { attempts: 0 },
+            (record::ATTEMPT, BootState::Trial { attempts }) => BootState::Trial {
+                attempts: attempts.saturating_add(1),
+            },
+            (record::ROLLBACK, _) => BootState::Rollback { done: 0 },
+            (record::CONFIRMED, _) | (record::ROLLED_BACK, _) | (record::CANCELLED, _) => BootState::Idle,
+            _ => state,
+        };
+    }
+    Ok((state, None))
+}
+
+fn append<F: Flash>(flash: &mut F, tag: u8, arg: u16) -> Result<(), BootError<F::Error>> {
+    let (_, next) = scan(flash).map_err(BootError::Flash)?;
+    let address = next.ok_or(BootError::LogFull)?;
+    flash
+        .write(address, &encode(tag, arg).to_le_bytes())
+        .map_err(BootError::Flash)
+}
+
+pub fn state<F: Flash>(flash: &mut F) -> Result<BootState, F::Error> {
+    scan(flash).map(|(state, _)| state)
+}
+
+/// Ask the bootloader to install the image in the staging slot on the next
+/// boot. Check it with [`crate::image::verify`] first.
+pub fn request_install<F: Flash>(flash: &mut F) -> Result<(), BootError<F::Error>> {
+    flash.erase(BOOT_STATE.start).map_err(BootError::Flash)?;
+    append(flash, record::PENDING, 0)
+}
+
+/// Tell the bootloader the image on trial works, so it's kept
+pub fn confirm<F: Flash>(flash: &mut F) -> Result<(), BootError<F::Error>> {
+    match state(flash).map_err(BootError::Flash)? {
+        BootState::Trial { .. } => append(flash, record::CONFIRMED, 0),
+        _ => Ok(()),
+    }
+}
+
+/// Drop a pending update before the bootloader started installing it
+pub fn cancel<F: Flash>(flash: &mut F) -> Result<(), BootError<F::Error>> {
+    match state(flash).map_err(BootError::Flash)? {
+        BootState::Install { done: 0 } => append(flash, record::CANCELLED, 0),
+        _ => Ok(()),
+    }
+}
+
+/// Forget about any update, as when the app slot was written directly
+pub fn clear<F: Flash>(flash: &mut F) -> Result<(), F::Error> {
+    flash.erase(BOOT_STATE.start)
+}
+
+/// Do one step of swapping the app and staging slots
+fn swap_step<F: Flash>(flash: &mut F, step: u16) -> Result<(), F::Error> {
+    let page = step as u32 / 3;
+    let (app, staging) = (APP_SLOT.page(page), STAGING_SLOT.page(page));
+    let (from, to) = match step % 3 {
+        0 => (app, SCRATCH.start),
+        1 => (staging, app),
+        _ => (SCRATCH.start, staging),
+    };
+    flash.erase(to)?;
+    flash::copy(flash, from, to, flash::PAGE_SIZE)
+}
+
+/// Swap the slots, starting after the `done` steps already logged
+fn swap<F: Flash>(flash: &mut F, done: u16) -> Result<(), BootError<F::Error>> {
+    for step in done..SWAP_STEPS {
+        swap_step(flash, step).map_err(BootError::Flash)?;
+        append(flash, record::STEP, step)?;
+    }
+    Ok(())
+}
+
+/// What to do in `state`, given the headers of the images in the app and
+/// staging slots that check out.
+///
+/// Pending updates that are damaged or older than the app they replace are
+/// dropped. An image on trial is rolled back once it had [`MAX_TRIAL_BOOTS`]
+/// boots, or straight away if it's damaged. Swaps already under way are
+/// always finished, as neither slot checks out part way through.
+pub fn decide(state: BootState, app: Option<&ImageHeader>, update: Option<&ImageHeader>) -> Decision {
+    match state {
+        BootState::Idle if app.is_some() => Decision::Start(Boot::Normal),
+        BootState::Idle => Decision::Start(Boot::Recover),
+        BootState::Install { done: 0 } => match (update, app) {
+            (Some(update), Some(app)) if update.version < app.version => Decision::Cancel,
+            (Some(_), _) => Decision::Install { done: 0 },
+            (None, _) => Decision::Cancel,
+        },
+        BootState::Install { done } => Decision::Install { done },
+        BootState::Trial { attempts } if attempts >= MAX_TRIAL_BOOTS || app.is_none() => {
+            Decision::Rollback { done: 0 }
+        }
+        BootState::Trial { .. } => Decision::Start(Boot::Trial),
+        BootState::Rollback { done } => Decision::Rollback { done },
+    }
+}
+
+/// The header of the image in `slot`, if it checks out
+fn check<F: Flash>(flash: &mut F, slot: Region) -> Result<Option<ImageHeader>, BootError<F::Error>> {
+    match image::verify(flash, slot, None) {
+        Ok(header) => Ok(Some(header)),
+        Err(VerifyError::Image(_)) => Ok(None),
+        Err(VerifyError::Flash(e)) => Err(BootError::Flash(e)),
+    }
+}
+
+/// Carry on with any update in progress and decide how to start the app.
+///
+/// Run by the bootloader on every boot, it does what [`decide`] says until
+/// the app can be started.
+pub fn prepare<F: Flash>(flash: &mut F) -> Result<Boot, BootError<F::Error>> {
+    loop {
+        let state = state(flash).map_err(BootError::Flash)?;
+        // Only check the slots that hold a whole image
+        let app = match state {
+            BootState::Idle | BootState::Install { done: 0 } | BootState::Trial { .. } => check(flash, APP_SLOT)?,
+            _ => None,
+        };
+        let update = match state {
+            BootState::Install { done: 0 } => check(flash, STAGING_SLOT)?,
+            _ => None,
+        };
+        match decide(state, app.as_ref(), update.as_ref()) {
+            Decision::Start(boot) => {
+                if boot == Boot::Trial {
+                    append(flash, record::ATTEMPT, 0)?;
+                }
+                return Ok(boot);
+            }
+            Decision::Cancel => append(flash, record::CANCELLED, 0)?,
+            Decision::Install { done } => {
+                swap(flash, done)?;
+                append(flash, record::TRIAL, 0)?;
+            }
+            Decision::Rollback { done } => {
+                if let BootState::Trial { .. } = state {
+                    append(flash, record::ROLLBACK, 0)?;
+                }
+                swap(flash, done)?;
+                append(flash, record::ROLLED_BACK, 0)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::ram::{PowerCut, RamFlash};
+    use crate::flash::{PAGE_SIZE, SLOT_HEADER_SIZE};
+
+    /// Power one swap step takes: erasing the page, writing it a half word at
+    /// a time and logging the step
+    const STEP_POWER: usize = 1 + PAGE_SIZE as usize / 2 + 2;
+
+    /// A slot holding image `version`, different for every page and version
+    fn contents(version: u8) -> Vec<u8> {
+        let image: Vec<u8> = (SLOT_HEADER_SIZE..APP_SLOT.size)
+            .map(|i| version.wrapping_add((i / PAGE_SIZE * 7 + i) as u8))
+            .collect();
+        let mut slot = ImageHeader::sign(&[0; 32], version as u32, [version; 8], &image)
+            .encode()
+            .to_vec();
+        slot.resize(SLOT_HEADER_SIZE as usize, 0xFF);
+        slot.extend(image);
+        slot
+    }
+
+    /// An `app` in the app slot and an `update` waiting in the staging slot
+    fn pending_with(app: u8, update: u8) -> RamFlash {
+        let mut flash = RamFlash::new();
+        flash.write(APP_SLOT.start, &contents(app)).unwrap();
+        flash.write(STAGING_SLOT.start, &contents(update)).unwrap();
+        request_install(&mut flash).unwrap();
+        flash
+    }
+
+    fn pending() -> RamFlash {
+        pending_with(1, 2)
+    }
+
+    fn version(flash: &mut RamFlash, slot: Region) -> Option<u32> {
+        check(flash, slot).unwrap().map(|header| header.version)
+    }
+
+    /// Whether the slots hold the app then the update, or the other way
+    /// round once `swapped`
+    fn slots(flash: &mut RamFlash, swapped: bool) -> bool {
+        let expected = if swapped { (Some(2), Some(1)) } else { (Some(1), Some(2)) };
+        (version(flash, APP_SLOT), version(flash, STAGING_SLOT)) == expected
+    }
+
+    fn installed(flash: &mut RamFlash) -> bool {
+        slots(flash, true)
+    }
+
+    fn rolled_back(flash: &mut RamFlash) -> bool {
+        slots(flash, false)
+    }
+
+    #[test]
+    fn updates_are_installed_on_trial_and_kept_once_confirmed() {
+        let mut flash = pending();
+        assert_eq!(state(&mut flash), Ok(BootState::Install { done: 0 }));
+        assert_eq!(prepare(&mut flash), Ok(Boot::Trial));
+        assert!(installed(&mut flash));
+        assert_eq!(state(&mut flash), Ok(BootState::Trial { attempts: 1 }));
+
+        confirm(&mut flash).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert!(installed(&mut flash));
+        // Confirming again changes nothing
+        confirm(&mut flash).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+    }
+
+    #[test]
+    fn images_that_never_check_in_are_rolled_back() {
+        let mut flash = pending();
+        for _ in 0..MAX_TRIAL_BOOTS {
+            assert_eq!(prepare(&mut flash), Ok(Boot::Trial));
+        }
+        assert_eq!(state(&mut flash), Ok(BootState::Trial { attempts: MAX_TRIAL_BOOTS }));
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert!(rolled_back(&mut flash));
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+        // Too late to confirm it
+        confirm(&mut flash).unwrap();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert!(rolled_back(&mut flash));
+    }
+
+    #[test]
+    fn only_updates_not_started_can_be_cancelled() {
+        let mut flash = pending();
+        cancel(&mut flash).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert!(rolled_back(&mut flash));
+
+        let mut flash = pending();
+        flash.power = Some(STEP_POWER + 1);
+        assert_eq!(prepare(&mut flash), Err(BootError::Flash(PowerCut)));
+        flash.power = None;
+        assert_eq!(state(&mut flash), Ok(BootState::Install { done: 1 }));
+        cancel(&mut flash).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Install { done: 1 }));
+        assert_eq!(prepare(&mut flash), Ok(Boot::Trial));
+        assert!(installed(&mut flash));
+    }
+
+    #[test]
+    fn torn_records_are_skipped() {
+        let mut flash = pending();
+        // Half of a step record, as losing power would leave it
+        flash.write(BOOT_STATE.start + 4, &encode(record::STEP, 0).to_le_bytes()[..2]).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Install { done: 0 }));
+        append(&mut flash, record::CANCELLED, 0).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+    }
+
+    #[test]
+    fn the_log_refuses_records_once_full() {
+        let mut flash = RamFlash::new();
+        for _ in 0..BOOT_STATE.size / 4 {
+            append(&mut flash, record::ATTEMPT, 0).unwrap();
+        }
+        assert_eq!(append(&mut flash, record::CONFIRMED, 0), Err(BootError::LogFull));
+        // A new update starts a new log
+        request_install(&mut flash).unwrap();
+        assert_eq!(state(&mut flash), Ok(BootState::Install { done: 0 }));
+    }
+
+    #[test]
+    fn decisions() {
+        let old = ImageHeader::sign(&[0; 32], 1, [0; 8], b"old");
+        let new = ImageHeader::sign(&[0; 32], 2, [0; 8], b"new");
+        let pending = BootState::Install { done: 0 };
+
+        // A valid app starts, without one the bootloader waits for one
+        assert_eq!(decide(BootState::Idle, Some(&old), None), Decision::Start(Boot::Normal));
+        assert_eq!(decide(BootState::Idle, None, None), Decision::Start(Boot::Recover));
+
+        // Updates are installed over a damaged app, but not when they're damaged or older
+        assert_eq!(decide(pending, Some(&old), Some(&new)), Decision::Install { done: 0 });
+        assert_eq!(decide(pending, Some(&new), Some(&new)), Decision::Install { done: 0 });
+        assert_eq!(decide(pending, None, Some(&new)), Decision::Install { done: 0 });
+        assert_eq!(decide(pending, Some(&new), Some(&old)), Decision::Cancel);
+        assert_eq!(decide(pending, Some(&old), None), Decision::Cancel);
+        assert_eq!(decide(BootState::Install { done: 7 }, None, None), Decision::Install { done: 7 });
+
+        // Images on trial get their boots, unless they're damaged
+        let trial = |attempts| BootState::Trial { attempts };
+        assert_eq!(decide(trial(0), Some(&new), None), Decision::Start(Boot::Trial));
+        assert_eq!(decide(trial(MAX_TRIAL_BOOTS - 1), Some(&new), None), Decision::Start(Boot::Trial));
+        assert_eq!(decide(trial(MAX_TRIAL_BOOTS), Some(&new), None), Decision::Rollback { done: 0 });
+        assert_eq!(decide(trial(0), None, None), Decision::Rollback { done: 0 });
+        assert_eq!(decide(BootState::Rollback { done: 7 }, None, None), Decision::Rollback { done: 7 });
+    }
+
+    #[test]
+    fn older_or_damaged_updates_are_dropped() {
+        let mut flash = pending_with(2, 1);
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+        assert_eq!((version(&mut flash, APP_SLOT), version(&mut flash, STAGING_SLOT)), (Some(2), Some(1)));
+
+        let mut flash = pending();
+        flash.erase(STAGING_SLOT.page(3)).unwrap();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert_eq!(version(&mut flash, APP_SLOT), Some(1));
+    }
+
+    #[test]
+    fn damaged_images_on_trial_are_rolled_back_straight_away() {
+        let mut flash = pending();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Trial));
+        flash.erase(APP_SLOT.page(3)).unwrap();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Normal));
+        assert_eq!(version(&mut flash, APP_SLOT), Some(1));
+
+        // And without a good image to go back to, the bootloader waits for one
+        let mut flash = pending();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Trial));
+        flash.erase(APP_SLOT.page(3)).unwrap();
+        flash.erase(STAGING_SLOT.page(3)).unwrap();
+        assert_eq!(prepare(&mut flash), Ok(Boot::Recover));
+        assert_eq!(state(&mut flash), Ok(BootState::Idle));
+    }
+
+    /// Power cuts worth trying over a swap: before and after every erase,
+    /// part way through every copy, before and half way through every record,
+    /// and the records after the swap. A cut anywhere else in a copy is the
+    /// same as the one we try.
+    fn cuts() -> impl Iterator<Item = usize> {
+        let swap = SWAP_STEPS as usize * STEP_POWER;
+        (0..swap + 4).filter(move |&cut| cut >= swap || matches!(cut % STEP_POWER, 0 | 1 | 2 | 300 | 513 | 514))
+    }
+
+    /// Losing power anywhere during an install and booting again finishes it,
+    /// and the image is still on trial
+    #[test]
+    fn power_cuts_during_an_install() {
+        let pending = pending();
+        for cut in cuts() {
+            let mut flash = pending.clone();
+            flash.power = Some(cut);
+            let first = prepare(&mut flash);
+            flash.power = None;
+            if let Err(e) = first {
+                assert_eq!(e, BootError::Flash(PowerCut));
+                assert_eq!(prepare(&mut flash), Ok(Boot::Trial), "cut {}", cut);
+            }
+            assert!(installed(&mut flash), "cut {}", cut);
+            assert_eq!(state(&mut flash), Ok(BootState::Trial { attempts: 1 }), "cut {}", cut);
+        }
+    }
+
+    /// Losing power anywhere during a rollback and booting again finishes it
+    #[test]
+    fn power_cuts_during_a_rollback() {
+        let mut exhausted = pending();
+        for _ in 0..MAX_TRIAL_BOOTS {
+            prepare(&mut exhausted).unwrap();
+        }
+
+        for cut in cuts() {
+            let mut flash = exhausted.clone();
+            flash.power = Some(cut);
+            let first = prepare(&mut flash);
+            flash.power = None;
+            if let Err(e) = first {
+                assert_eq!(e, BootError::Flash(PowerCut));
+                assert_eq!(prepare(&mut flash), Ok(Boot::Normal), "cut {}", cut);
+            }
+            assert!(rolled_back(&mut flash), "cut {}", cut);
+            assert_eq!(state(&mut flash), Ok(BootState::Idle), "cut {}", cut);
+        }
+    }
+
+    /// Losing power while logging a confirmation leaves the image on trial
+    /// or confirmed, and either way it's kept
+    #[test]
+    fn power_cuts_while_confirming() {
+        for cut in 0..3 {
+            let mut flash = pending();
+            prepare(&mut flash).unwrap();
+            flash.power = Some(cut);
+            let confirmed = confirm(&mut flash).is_ok();
+            flash.power = None;
+            if !confirmed {
+                assert_eq!(state(&mut flash), Ok(BootState::Trial { attempts: 1 }), "cut {}", cut);
+                confirm(&mut flash).unwrap();
+            }
+            assert_eq!(prepare(&mut flash), Ok(Boot::Normal), "cut {}", cut);
+            assert!(installed(&mut flash));
+        }
+    }
+}
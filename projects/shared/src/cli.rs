@@ -0,0 +1,421 @@
+//! A command line, on the serial port or USB, a command per line.
+//!
+//! Every app has its own table of [`Command`]s. A command's name is one or
+//! more words, and [`run`] picks the longest name the line starts with,
+//! handing the rest of the line to it as [`Args`]: words, and `name=value`
+//! options anywhere among them.
+//!
+//! ```text
+//! status
+//! set color 3 255 0 0
+//! effect rainbow speed=5
+//! radio channel 76
+//! config save
+//! reboot
+//! ```
+//!
+//! `help` lists the table.
+
+use crate::address::{Destination, GroupId, CONTROLLER_ID, MAX_FIXTURE_ID};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use cortex_m::peripheral::SCB;
+use smart_leds::RGB8;
+
+/// Longest command line taken
+pub const LINE_LENGTH: usize = 64;
+
+/// Why a command didn't run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No command has that name
+    Unknown,
+    /// The line ends before the argument named
+    Missing(&'static str),
+    /// The argument named isn't valid
+    Invalid(&'static str),
+    /// More words follow the command
+    TooLong,
+    /// The command couldn't be carried out
+    Failed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Unknown => write!(f, "unknown command, try help"),
+            Error::Missing(what) => write!(f, "missing the {}", what),
+            Error::Invalid(what) => write!(f, "invalid {}", what),
+            Error::TooLong => write!(f, "too many arguments"),
+            Error::Failed(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<&'static str> for Error {
+    fn from(why: &'static str) -> Self {
+        Error::Failed(why)
+    }
+}
+
+/// Carries a command out on the app's `C`, answering on the writer
+pub type Handler<C> = fn(&mut C, &mut Args, &mut dyn Write) -> Result<(), Error>;
+
+/// An entry in an app's table
+pub struct Command<C> {
+    /// The words that pick it
+    pub name: &'static str,
+    /// The arguments it takes, for `help`
+    pub usage: &'static str,
+    pub run: Handler<C>,
+}
+
+/// What follows a command's name
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a> {
+    /// All of it, for looking options up
+    line: &'a str,
+    /// What hasn't been read yet
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line, rest: line }
+    }
+
+    /// What's left of the line, options and all
+    pub fn rest(&self) -> &'a str {
+        self.rest
+    }
+
+    /// The next word, naming it `what` if it's missing
+    pub fn word(&mut self, what: &'static str) -> Result<&'a str, Error> {
+        self.next().ok_or(Error::Missing(what))
+    }
+
+    /// The next word read as a `T`, like a number
+    pub fn parse<T: FromStr>(&mut self, what: &'static str) -> Result<T, Error> {
+        self.word(what)?.parse().map_err(|_| Error::Invalid(what))
+    }
+
+    /// Like [`parse`](Self::parse), for an argument that may be left out
+    pub fn optional<T: FromStr>(&mut self, what: &'static str) -> Result<Option<T>, Error> {
+        match self.next() {
+            Some(word) => word.parse().map(Some).map_err(|_| Error::Invalid(what)),
+            None => Ok(None),
+        }
+    }
+
+    /// A colour, as its red, green and blue from 0 to 255
+    pub fn color(&mut self) -> Result<RGB8, Error> {
+        Ok(RGB8::new(self.parse("red")?, self.parse("green")?, self.parse("blue")?))
+    }
+
+    /// Who to talk to: `all`, `group <id>` or a fixture's id
+    pub fn destination(&mut self) -> Result<Destination, Error> {
+        match self.word("fixture")? {
+            "all" => Ok(Destination::Broadcast),
+            "group" => GroupId::new(self.parse("group")?)
+                .map(Destination::Group)
+                .ok_or(Error::Invalid("group")),
+            id => match id.parse() {
+                Ok(id) if id != CONTROLLER_ID && id <= MAX_FIXTURE_ID => Ok(Destination::Node(id)),
+                _ => Err(Error::Invalid("fixture")),
+            },
+        }
+    }
+
+    /// The value of the `name=value` option, wherever it is on the line
+    pub fn option<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, Error> {
+        let value = self.line.split_whitespace().find_map(|word| {
+            let mut pair = word.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        });
+        match value {
+            Some(value) => value.parse().map(Some).map_err(|_| Error::Invalid(name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check nothing but options is left
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self.next() {
+            Some(_) => Err(Error::TooLong),
+            None => Ok(()),
+        }
+    }
+
+    /// The next word, options included
+    fn next_word(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (word, rest) = rest.split_at(end);
+        self.rest = rest;
+        Some(word)
+    }
+}
+
+/// The words, skipping options
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            let word = self.next_word()?;
+            if !word.contains('=') {
+                return Some(word);
+            }
+        }
+    }
+}
+
+/// Carry out `line` on `context`, answering `ok` or what went wrong
+pub fn run<C>(commands: &[Command<C>], context: &mut C, line: &str, out: &mut dyn Write) {
+    match dispatch(commands, context, line, out) {
+        Ok(()) => writeln!(out, "ok").ok(),
+        Err(e) => writeln!(out, "error: {}", e).ok(),
+    };
+}
+
+/// Carry out `line` on `context`, with `help` listing `commands`
+pub fn dispatch<C>(commands: &[Command<C>], context: &mut C, line: &str, out: &mut dyn Write) -> Result<(), Error> {
+    if let Some(rest) = strip("help", line) {
+        return Args::new(rest).finish().map(|()| help(commands, out));
+    }
+    let (command, rest) = commands
+        .iter()
+        .filter_map(|command| strip(command.name, line).map(|rest| (command, rest)))
+        .max_by_key(|(command, _)| command.name.split_whitespace().count())
+        .ok_or(Error::Unknown)?;
+    (command.run)(context, &mut Args::new(rest), out)
+}
+
+/// List the commands and their arguments
+pub fn help<C>(commands: &[Command<C>], out: &mut dyn Write) {
+    for command in commands {
+        match command.usage {
+            "" => writeln!(out, "{}", command.name),
+            usage => writeln!(out, "{} {}", command.name, usage),
+        }
+        .ok();
+    }
+    writeln!(out, "help").ok();
+}
+
+/// Restart the app, as the `reboot` command
+pub fn reboot<C>(_: &mut C, _: &mut Args, _: &mut dyn Write) -> Result<(), Error> {
+    SCB::sys_reset()
+}
+
+/// What follows `name` on `line`, if the line starts with it
+fn strip<'a>(name: &str, line: &'a str) -> Option<&'a str> {
+    let mut args = Args::new(line);
+    for word in name.split_whitespace() {
+        if args.next_word()? != word {
+            return None;
+        }
+    }
+    Some(args.rest)
+}
+
+/// A line being typed
+#[derive(Clone, Copy)]
+pub struct Line {
+    buf: [u8; LINE_LENGTH],
+    len: usize,
+}
+
+impl Line {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_LENGTH],
+            len: 0,
+        }
+    }
+
+    /// Add a byte, returning the line once it ends and starting the next.
+    /// Lines that don't fit are cut short, and zeros, which end packets in
+    /// [dongle](crate::dongle) mode, are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Line> {
+        match byte {
+            0 => None,
+            b'\r' | b'\n' => {
+                let mut line = core::mem::take(self);
+                // Cutting the line short may have split a character
+                if let Err(e) = core::str::from_utf8(&line.buf[..line.len]) {
+                    if e.error_len().is_none() {
+                        line.len = e.valid_up_to();
+                    }
+                }
+                match core::str::from_utf8(&line.buf[..line.len]) {
+                    Ok(text) if !text.trim().is_empty() => Some(line),
+                    _ => None,
+                }
+            }
+            _ => {
+                if self.len < LINE_LENGTH {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+/// A whole line, cut short if it doesn't fit
+impl From<&str> for Line {
+    fn from(text: &str) -> Self {
+        let mut end = text.len().min(LINE_LENGTH);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut line = Line::new();
+        line.buf[..end].copy_from_slice(&text.as_bytes()[..end]);
+        line.len = end;
+        line
+    }
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the commands were given, as the app's context
+    #[derive(Default)]
+    struct Calls(Vec<String>);
+
+    fn radio(calls: &mut Calls, args: &mut Args, _: &mut dyn Write) -> Result<(), Error> {
+        calls.0.push(format!("radio {}", args.rest().trim()));
+        Ok(())
+    }
+
+    fn radio_channel(calls: &mut Calls, args: &mut Args, _: &mut dyn Write) -> Result<(), Error> {
+        let channel: u8 = args.parse("channel")?;
+        args.finish()?;
+        calls.0.push(format!("radio channel {}", channel));
+        Ok(())
+    }
+
+    fn effect(calls: &mut Calls, args: &mut Args, _: &mut dyn Write) -> Result<(), Error> {
+        let effect = args.word("effect")?;
+        let speed: Option<u8> = args.option("speed")?;
+        let fade: Option<u32> = args.option("fade")?;
+        args.finish()?;
+        calls.0.push(format!("effect {} {:?} {:?}", effect, speed, fade));
+        Ok(())
+    }
+
+    fn commands() -> [Command<Calls>; 3] {
+        [
+            Command { name: "radio", usage: "...", run: radio },
+            Command { name: "radio channel", usage: "<channel>", run: radio_channel },
+            Command { name: "effect", usage: "<effect> [speed=<n>] [fade=<ms>]", run: effect },
+        ]
+    }
+
+    /// Run `line`, returning the outcome, what was called and the answer
+    fn run_line(line: &str) -> (Result<(), Error>, Vec<String>, String) {
+        let mut calls = Calls::default();
+        let mut out = String::new();
+        let result = dispatch(&commands(), &mut calls, line, &mut out);
+        (result, calls.0, out)
+    }
+
+    #[test]
+    fn the_longest_name_wins() {
+        assert_eq!(run_line("radio channel 76").1, ["radio channel 76"]);
+        assert_eq!(run_line("  radio   channel\t76 ").1, ["radio channel 76"]);
+        assert_eq!(run_line("radio hopping 12").1, ["radio hopping 12"]);
+        assert_eq!(run_line("radio channels").1, ["radio channels"]);
+        assert_eq!(run_line("radi").0, Err(Error::Unknown));
+        assert_eq!(run_line("").0, Err(Error::Unknown));
+    }
+
+    #[test]
+    fn options_go_anywhere() {
+        let expected = ["effect rainbow Some(5) None"];
+        assert_eq!(run_line("effect rainbow speed=5").1, expected);
+        assert_eq!(run_line("effect speed=5 rainbow").1, expected);
+        assert_eq!(run_line("effect rainbow fade=300 speed=7").1, ["effect rainbow Some(7) Some(300)"]);
+        assert_eq!(run_line("effect rainbow speed").0, Err(Error::TooLong));
+        assert_eq!(run_line("effect rainbow speed=fast").0, Err(Error::Invalid("speed")));
+        assert_eq!(run_line("effect rainbow speed=").0, Err(Error::Invalid("speed")));
+        assert_eq!(run_line("effect rainbow colour=red").1, ["effect rainbow None None"]);
+    }
+
+    #[test]
+    fn errors_name_the_argument() {
+        assert_eq!(run_line("effect").0, Err(Error::Missing("effect")));
+        assert_eq!(run_line("effect speed=5").0, Err(Error::Missing("effect")));
+        assert_eq!(run_line("radio channel").0, Err(Error::Missing("channel")));
+        assert_eq!(run_line("radio channel 256").0, Err(Error::Invalid("channel")));
+        assert_eq!(run_line("radio channel 76 2").0, Err(Error::TooLong));
+        assert_eq!(run_line("help me").0, Err(Error::TooLong));
+
+        let mut out = String::new();
+        run(&commands(), &mut Calls::default(), "radio channel x", &mut out);
+        assert_eq!(out, "error: invalid channel\n");
+    }
+
+    #[test]
+    fn help_lists_the_table() {
+        let (result, calls, out) = run_line("help");
+        assert_eq!(result, Ok(()));
+        assert!(calls.is_empty());
+        assert_eq!(out, "radio ...\nradio channel <channel>\neffect <effect> [speed=<n>] [fade=<ms>]\nhelp\n");
+    }
+
+    #[test]
+    fn destinations() {
+        let destination = |line| Args::new(line).destination();
+        assert_eq!(destination("all"), Ok(Destination::Broadcast));
+        assert_eq!(destination("group 3"), Ok(Destination::Group(GroupId::new(3).unwrap())));
+        assert_eq!(destination("12"), Ok(Destination::Node(12)));
+        assert_eq!(destination("group"), Err(Error::Missing("group")));
+        assert_eq!(destination("group 200"), Err(Error::Invalid("group")));
+        assert_eq!(destination("0"), Err(Error::Invalid("fixture")));
+        assert_eq!(destination("128"), Err(Error::Invalid("fixture")));
+        assert_eq!(destination(""), Err(Error::Missing("fixture")));
+    }
+
+    #[test]
+    fn lines_end_at_returns_and_newlines() {
+        let mut line = Line::new();
+        let bytes = b"status\r\n\n  \nradio\0 channel 5\n";
+        let typed: Vec<_> = bytes.iter().filter_map(|&byte| line.push(byte)).collect();
+        let typed: Vec<_> = typed.iter().map(Line::as_str).collect();
+        assert_eq!(typed, ["status", "radio channel 5"]);
+    }
+
+    #[test]
+    fn long_lines_are_cut_between_characters() {
+        // 63 bytes, then a character of 3
+        let text = format!("{}€ and more", "a".repeat(LINE_LENGTH - 1));
+        assert_eq!(Line::from(text.as_str()).as_str(), &text[..LINE_LENGTH - 1]);
+
+        let mut line = Line::new();
+        let typed = text.bytes().chain(Some(b'\n')).find_map(|byte| line.push(byte));
+        assert_eq!(typed.as_ref().map(Line::as_str), Some(&text[..LINE_LENGTH - 1]));
+
+        let short = "é".repeat(LINE_LENGTH / 2);
+        assert_eq!(Line::from(short.as_str()).as_str(), short);
+    }
+}
@@ -0,0 +1,146 @@
+//! Consistent overhead byte stuffing, for framing packets on a byte stream.
+//!
+//! An encoded packet has no zero bytes in it, so a zero marks where it ends.
+//! A receiver that starts listening halfway through a packet, or loses
+//! bytes, drops the one packet and picks up again at the next zero. Encoding
+//! adds one byte for every 254, plus the zero.
+
+/// Why an encoded packet couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A code byte points past the end of the packet, or is zero
+    Invalid,
+    /// The decoded packet doesn't fit
+    TooLong,
+}
+
+/// Largest encoding of `len` bytes, zero included
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 2
+}
+
+/// Encode `data` into `out` and end it with a zero, returning the length.
+/// `out` has to hold [`max_encoded_len`] bytes.
+pub fn encode(data: &[u8], out: &mut [u8]) -> usize {
+    let mut code_at = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for &byte in data {
+        if byte != 0 {
+            out[len] = byte;
+            len += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+    out[len] = 0;
+    len + 1
+}
+
+/// Decode a packet, without the zero that ended it, into `out`, returning
+/// its length
+pub fn decode(data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    let mut at = 0;
+    while at < data.len() {
+        let code = data[at] as usize;
+        if code == 0 || at + code > data.len() {
+            return Err(Error::Invalid);
+        }
+        let run = &data[at + 1..at + code];
+        out.get_mut(len..len + run.len()).ok_or(Error::TooLong)?.copy_from_slice(run);
+        len += run.len();
+        at += code;
+        // Every run but the longest and the last stands for a zero after it
+        if code < 0xFF && at < data.len() {
+            *out.get_mut(len).ok_or(Error::TooLong)? = 0;
+            len += 1;
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode and decode `data`, checking the encoding along the way
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0xAA; max_encoded_len(data.len())];
+        let len = encode(data, &mut encoded);
+        assert!(len <= max_encoded_len(data.len()));
+        assert_eq!(encoded[len - 1], 0);
+        assert!(!encoded[..len - 1].contains(&0), "{:?} encoded with a zero in it", data);
+        let mut decoded = vec![0; data.len()];
+        assert_eq!(decode(&encoded[..len - 1], &mut decoded), Ok(data.len()));
+        encoded.truncate(len);
+        encoded
+    }
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        assert_eq!(round_trip(&[]), [1, 0]);
+        assert_eq!(round_trip(&[0]), [1, 1, 0]);
+        assert_eq!(round_trip(&[0, 0]), [1, 1, 1, 0]);
+        assert_eq!(round_trip(&[0x11, 0x22, 0, 0x33]), [3, 0x11, 0x22, 2, 0x33, 0]);
+        assert_eq!(round_trip(&[0x11, 0, 0]), [2, 0x11, 1, 1, 0]);
+        let mixed: Vec<u8> = (0..600u32).map(|n| (n % 7) as u8).collect();
+        round_trip(&mixed);
+    }
+
+    #[test]
+    fn the_longest_run_needs_no_zero() {
+        let run = [0x42; 254];
+        let encoded = round_trip(&run);
+        assert_eq!(encoded.len(), max_encoded_len(run.len()));
+        assert_eq!((encoded[0], encoded[255], encoded[256]), (0xFF, 1, 0));
+
+        let longer = [0x42; 255];
+        let encoded = round_trip(&longer);
+        assert_eq!((encoded[0], encoded[255], encoded[256]), (0xFF, 2, 0x42));
+    }
+
+    #[test]
+    fn zeros_at_a_block_boundary_are_kept() {
+        let mut data = vec![0x42; 254];
+        data.push(0);
+        let encoded = round_trip(&data);
+        assert_eq!(&encoded[255..], [1, 1, 0]);
+
+        let mut data = vec![0];
+        data.extend_from_slice(&[0x42; 254]);
+        data.push(0);
+        round_trip(&data);
+    }
+
+    #[test]
+    fn corrupt_packets_are_refused() {
+        let mut out = [0; 8];
+        // Zero codes, and codes running past the end
+        assert_eq!(decode(&[0], &mut out), Err(Error::Invalid));
+        assert_eq!(decode(&[2, 0x11, 0], &mut out), Err(Error::Invalid));
+        assert_eq!(decode(&[3, 0x11], &mut out), Err(Error::Invalid));
+        assert_eq!(decode(&[2, 0x11, 5, 0x22], &mut out), Err(Error::Invalid));
+        // Nothing is nothing
+        assert_eq!(decode(&[], &mut out), Ok(0));
+    }
+
+    #[test]
+    fn packets_that_dont_fit_are_refused() {
+        let mut encoded = [0; 16];
+        let len = encode(&[1, 2, 3, 4, 0], &mut encoded);
+        let mut out = [0; 4];
+        assert_eq!(decode(&encoded[..len - 1], &mut out), Err(Error::TooLong));
+        let mut out = [0; 5];
+        assert_eq!(decode(&encoded[..len - 1], &mut out), Ok(5));
+        assert_eq!(out, [1, 2, 3, 4, 0]);
+        let mut out = [0; 3];
+        assert_eq!(decode(&encoded[..len - 1], &mut out), Err(Error::TooLong));
+    }
+}
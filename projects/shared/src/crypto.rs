@@ -0,0 +1,486 @@
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439), sized for the radio.
+//!
+//! A full 16 byte Poly1305 tag would take half of a 32 byte frame, so secure
+//! frames carry the first [`TAG_SIZE`] bytes only. Keys are never sent over
+//! the air: both sides share a 32 byte site key and derive per-pair keys from
+//! it with HChaCha20 and the nonces exchanged while pairing.
+
+/// Size of every key
+pub const KEY_SIZE: usize = 32;
+/// Size of the ChaCha20 nonce
+pub const NONCE_SIZE: usize = 12;
+/// Size of the truncated tag appended to every secure frame
+pub const TAG_SIZE: usize = 8;
+
+pub type Key = [u8; KEY_SIZE];
+
+/// The tag did not match, the frame was forged or corrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthError;
+
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn rounds(s: &mut [u32; 16]) {
+    for _ in 0..10 {
+        quarter_round(s, 0, 4, 8, 12);
+        quarter_round(s, 1, 5, 9, 13);
+        quarter_round(s, 2, 6, 10, 14);
+        quarter_round(s, 3, 7, 11, 15);
+        quarter_round(s, 0, 5, 10, 15);
+        quarter_round(s, 1, 6, 11, 12);
+        quarter_round(s, 2, 7, 8, 13);
+        quarter_round(s, 3, 4, 9, 14);
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn initial_state(key: &Key, input: &[u8; 16]) -> [u32; 16] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&SIGMA);
+    for i in 0..8 {
+        state[4 + i] = read_u32(&key[i * 4..]);
+    }
+    for i in 0..4 {
+        state[12 + i] = read_u32(&input[i * 4..]);
+    }
+    state
+}
+
+/// Compute a single 64 byte ChaCha20 keystream block
+pub fn chacha20_block(key: &Key, counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; 64] {
+    let mut input = [0u8; 16];
+    input[..4].copy_from_slice(&counter.to_le_bytes());
+    input[4..].copy_from_slice(nonce);
+
+    let initial = initial_state(key, &input);
+    let mut state = initial;
+    rounds(&mut state);
+
+    let mut block = [0u8; 64];
+    for i in 0..16 {
+        let word = state[i].wrapping_add(initial[i]);
+        block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    block
+}
+
+/// Encrypt or decrypt `data` in place, starting at block `counter`
+pub fn chacha20_xor(key: &Key, counter: u32, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+/// Derive a new key from `key` and 16 bytes of input (HChaCha20)
+pub fn hchacha20(key: &Key, input: &[u8; 16]) -> Key {
+    let mut state = initial_state(key, input);
+    rounds(&mut state);
+
+    let mut out = [0u8; KEY_SIZE];
+    for (i, &word) in state[..4].iter().chain(state[12..].iter()).enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// Poly1305 one-time authenticator, using 26 bit limbs
+pub struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    filled: usize,
+}
+
+impl Poly1305 {
+    pub fn new(key: &[u8; 32]) -> Self {
+        // r is clamped as the spec requires
+        let r = [
+            read_u32(&key[0..]) & 0x03ff_ffff,
+            (read_u32(&key[3..]) >> 2) & 0x03ff_ff03,
+            (read_u32(&key[6..]) >> 4) & 0x03ff_c0ff,
+            (read_u32(&key[9..]) >> 6) & 0x03f0_3fff,
+            (read_u32(&key[12..]) >> 8) & 0x000f_ffff,
+        ];
+        let pad = [
+            read_u32(&key[16..]),
+            read_u32(&key[20..]),
+            read_u32(&key[24..]),
+            read_u32(&key[28..]),
+        ];
+        Self {
+            r,
+            h: [0; 5],
+            pad,
+            buffer: [0; 16],
+            filled: 0,
+        }
+    }
+
+    fn block(&mut self, block: &[u8; 16], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+
+        h[0] += read_u32(&block[0..]) & 0x03ff_ffff;
+        h[1] += (read_u32(&block[3..]) >> 2) & 0x03ff_ffff;
+        h[2] += (read_u32(&block[6..]) >> 4) & 0x03ff_ffff;
+        h[3] += (read_u32(&block[9..]) >> 6) & 0x03ff_ffff;
+        h[4] += (read_u32(&block[12..]) >> 8) | hibit;
+
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m(h[0], r0) + m(h[1], s4) + m(h[2], s3) + m(h[3], s2) + m(h[4], s1);
+        let mut d1 = m(h[0], r1) + m(h[1], r0) + m(h[2], s4) + m(h[3], s3) + m(h[4], s2);
+        let mut d2 = m(h[0], r2) + m(h[1], r1) + m(h[2], r0) + m(h[3], s4) + m(h[4], s3);
+        let mut d3 = m(h[0], r3) + m(h[1], r2) + m(h[2], r1) + m(h[3], r0) + m(h[4], s4);
+        let mut d4 = m(h[0], r4) + m(h[1], r3) + m(h[2], r2) + m(h[3], r1) + m(h[4], r0);
+
+        let mut c = (d0 >> 26) as u32;
+        h[0] = d0 as u32 & 0x03ff_ffff;
+        d1 += c as u64;
+        c = (d1 >> 26) as u32;
+        h[1] = d1 as u32 & 0x03ff_ffff;
+        d2 += c as u64;
+        c = (d2 >> 26) as u32;
+        h[2] = d2 as u32 & 0x03ff_ffff;
+        d3 += c as u64;
+        c = (d3 >> 26) as u32;
+        h[3] = d3 as u32 & 0x03ff_ffff;
+        d4 += c as u64;
+        c = (d4 >> 26) as u32;
+        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += c * 5;
+        c = h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+        h[1] += c;
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (16 - self.filled).min(data.len());
+            self.buffer[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == 16 {
+                let block = self.buffer;
+                self.block(&block, 1 << 24);
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Pad the message with zeroes up to the next 16 byte boundary
+    pub fn pad_to_block(&mut self) {
+        if self.filled != 0 {
+            self.update(&[0u8; 16][self.filled..]);
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 16] {
+        if self.filled != 0 {
+            let mut block = [0u8; 16];
+            block[..self.filled].copy_from_slice(&self.buffer[..self.filled]);
+            block[self.filled] = 1;
+            self.block(&block, 0);
+        }
+
+        // Fully carry h
+        let h = &mut self.h;
+        let mut c = h[1] >> 26;
+        h[1] &= 0x03ff_ffff;
+        for limb in h[2..].iter_mut() {
+            *limb += c;
+            c = *limb >> 26;
+            *limb &= 0x03ff_ffff;
+        }
+        h[0] += c * 5;
+        c = h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+        h[1] += c;
+
+        // Compute h - p and select it if h >= p
+        let mut g = [0u32; 5];
+        g[0] = h[0].wrapping_add(5);
+        c = g[0] >> 26;
+        g[0] &= 0x03ff_ffff;
+        for i in 1..4 {
+            g[i] = h[i].wrapping_add(c);
+            c = g[i] >> 26;
+            g[i] &= 0x03ff_ffff;
+        }
+        g[4] = h[4].wrapping_add(c).wrapping_sub(1 << 26);
+
+        let mask = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !mask) | (g[i] & mask);
+        }
+
+        // h = h % 2^128, then add the pad
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; 16];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let sum = words[i] as u64 + self.pad[i] as u64 + carry;
+            tag[i * 4..i * 4 + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
+
+fn aead_tag(key: &Key, nonce: &[u8; NONCE_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut otk = [0u8; 32];
+    otk.copy_from_slice(&block[..32]);
+
+    let mut mac = Poly1305::new(&otk);
+    mac.update(aad);
+    mac.pad_to_block();
+    mac.update(ciphertext);
+    mac.pad_to_block();
+    mac.update(&(aad.len() as u64).to_le_bytes());
+    mac.update(&(ciphertext.len() as u64).to_le_bytes());
+    mac.finalize()
+}
+
+/// Encrypt `buffer` in place and return the full 16 byte tag.
+///
+/// Callers on the radio only transmit the first [`TAG_SIZE`] bytes.
+pub fn seal(key: &Key, nonce: &[u8; NONCE_SIZE], aad: &[u8], buffer: &mut [u8]) -> [u8; 16] {
+    chacha20_xor(key, 1, nonce, buffer);
+    aead_tag(key, nonce, aad, buffer)
+}
+
+/// Check a tag truncated to [`TAG_SIZE`] bytes and decrypt `buffer` in place.
+///
+/// `buffer` is left untouched if the tag does not match. Shorter tags are
+/// refused, so a forger can't get away with guessing fewer bytes.
+pub fn open(
+    key: &Key,
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buffer: &mut [u8],
+    tag: &[u8],
+) -> Result<(), AuthError> {
+    let expected = aead_tag(key, nonce, aad, buffer);
+    if tag.len() != TAG_SIZE {
+        return Err(AuthError);
+    }
+    // Compare in constant time
+    let diff = tag
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return Err(AuthError);
+    }
+    chacha20_xor(key, 1, nonce, buffer);
+    Ok(())
+}
+
+/// Derive the key used between the controller and one fixture
+pub fn pair_key(site_key: &Key, controller_nonce: &[u8; 8], fixture_nonce: &[u8; 8]) -> Key {
+    let mut input = [0u8; 16];
+    input[..8].copy_from_slice(controller_nonce);
+    input[8..].copy_from_slice(fixture_nonce);
+    hchacha20(site_key, &input)
+}
+
+/// Derive the key pair requests and responses are authenticated with for one
+/// controller session
+pub fn pairing_key(site_key: &Key, controller_nonce: &[u8; 8]) -> Key {
+    let mut input = *b"pairing.........";
+    input[8..].copy_from_slice(controller_nonce);
+    hchacha20(site_key, &input)
+}
+
+/// Derive the key used for group and broadcast frames for one controller session
+pub fn multicast_key(site_key: &Key, controller_nonce: &[u8; 8]) -> Key {
+    let mut input = *b"multicast.......";
+    input[8..].copy_from_slice(controller_nonce);
+    hchacha20(site_key, &input)
+}
+
+/// Derive the key firmware images are authenticated with
+pub fn firmware_key(site_key: &Key) -> Key {
+    hchacha20(site_key, b"firmware image..")
+}
+
+/// Collects unpredictable samples (ADC noise, cycle counts) into pairing nonces
+pub struct Entropy {
+    pool: Key,
+    samples: u32,
+}
+
+impl Entropy {
+    pub fn new() -> Self {
+        Self {
+            pool: [0; KEY_SIZE],
+            samples: 0,
+        }
+    }
+
+    /// Mix a sample into the pool
+    pub fn mix(&mut self, sample: u32) {
+        let mut input = [0u8; 16];
+        input[..4].copy_from_slice(&sample.to_le_bytes());
+        input[4..8].copy_from_slice(&self.samples.to_le_bytes());
+        self.pool = hchacha20(&self.pool, &input);
+        self.samples = self.samples.wrapping_add(1);
+    }
+
+    /// Take a nonce out of the pool
+    pub fn nonce(&mut self) -> [u8; 8] {
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&hchacha20(&self.pool, b"pairing nonce...")[..8]);
+        self.mix(0);
+        nonce
+    }
+}
+
+impl Default for Entropy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn key(bytes: impl Iterator<Item = u8>) -> Key {
+        let mut key = [0; KEY_SIZE];
+        key.iter_mut().zip(bytes).for_each(|(k, b)| *k = b);
+        key
+    }
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for \
+        the future, sunscreen would be it.";
+    const AAD: &str = "50515253c0c1c2c3c4c5c6c7";
+
+    /// RFC 8439 2.3.2
+    #[test]
+    fn block_function() {
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let block = chacha20_block(&key(0..), 1, &nonce);
+        let expected = hex(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e",
+        );
+        assert_eq!(&block[..], &expected[..]);
+    }
+
+    /// RFC 8439 2.5.2
+    #[test]
+    fn poly1305() {
+        let mut otk = [0; 32];
+        otk.copy_from_slice(&hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b"));
+        let mut mac = Poly1305::new(&otk);
+        // In pieces, to go through the buffering
+        mac.update(b"Cryptographic Forum");
+        mac.update(b" Research Group");
+        assert_eq!(&mac.finalize()[..], &hex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+    }
+
+    /// RFC 8439 2.8.2
+    #[test]
+    fn aead() {
+        let key = key(0x80..);
+        let nonce = [0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad = hex(AAD);
+        let mut buffer = SUNSCREEN.to_vec();
+        let tag = seal(&key, &nonce, &aad, &mut buffer);
+        let expected = hex(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+             3ff4def08e4b7a9de576d26586cec64b6116",
+        );
+        assert_eq!(buffer, expected);
+        assert_eq!(&tag[..], &hex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+
+        assert_eq!(open(&key, &nonce, &aad, &mut buffer, &tag[..TAG_SIZE]), Ok(()));
+        assert_eq!(buffer, SUNSCREEN);
+    }
+
+    /// draft-irtf-cfrg-xchacha 2.2.1
+    #[test]
+    fn hchacha() {
+        let input = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0, 0x31, 0x41, 0x59, 0x27];
+        let expected = hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc");
+        assert_eq!(&hchacha20(&key(0..), &input)[..], &expected[..]);
+    }
+
+    /// draft-irtf-cfrg-xchacha A.3.1: XChaCha20 is ChaCha20 with a key
+    /// derived by HChaCha20, the way pair keys are
+    #[test]
+    fn xchacha() {
+        let iv = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+        let mut input = [0; 16];
+        input.copy_from_slice(&iv[..16]);
+        let subkey = hchacha20(&key(0x80..), &input);
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[4..].copy_from_slice(&iv[16..]);
+
+        let mut buffer = SUNSCREEN.to_vec();
+        let tag = seal(&subkey, &nonce, &hex(AAD), &mut buffer);
+        let expected = hex(
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb
+             731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452
+             2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9
+             21f9664c97637da9768812f615c68b13b52e",
+        );
+        assert_eq!(buffer, expected);
+        assert_eq!(&tag[..], &hex("c0875924c1c7987947deafd8780acf49")[..]);
+    }
+
+    #[test]
+    fn open_refuses_tags_of_the_wrong_size() {
+        let key = key(0..);
+        let nonce = [1; NONCE_SIZE];
+        let mut buffer = *b"set look";
+        let tag = seal(&key, &nonce, b"header", &mut buffer);
+        let sealed = buffer;
+
+        for len in [0, 1, TAG_SIZE - 1, TAG_SIZE + 1, 16].iter() {
+            assert_eq!(open(&key, &nonce, b"header", &mut buffer, &tag[..*len]), Err(AuthError));
+            assert_eq!(buffer, sealed);
+        }
+        let mut forged = tag;
+        forged[0] ^= 1;
+        assert_eq!(open(&key, &nonce, b"header", &mut buffer, &forged[..TAG_SIZE]), Err(AuthError));
+        assert_eq!(open(&key, &nonce, b"other", &mut buffer, &tag[..TAG_SIZE]), Err(AuthError));
+        assert_eq!(open(&key, &nonce, b"header", &mut buffer, &tag[..TAG_SIZE]), Ok(()));
+        assert_eq!(&buffer, b"set look");
+    }
+}
@@ -0,0 +1,461 @@
+//! Cue lists: scenes played one after the other, for shows that run on
+//! their own.
+//!
+//! A [`CueList`] is a sequence of [`Cue`]s, each recalling a scene over its
+//! fade time and then holding it. Cues with a hold time follow on by
+//! themselves once it's up, the others wait for [`Transport::Go`]. A list
+//! that loops starts over after its last cue.
+//!
+//! The [`Player`] runs off the network time, see `timesync`, which is what
+//! the fixtures fade on too. It doesn't send anything itself: feed it the
+//! time regularly and recall the scenes of the cues it returns.
+//!
+//! Cue lists are stored on the controller as:
+//!
+//! ```text
+//! | looping | scene | fade (u32) | hold (u32) | scene | ...
+//! ```
+
+use crate::protocol::DecodeError;
+
+/// Cues a list holds
+pub const MAX_CUES: usize = 32;
+/// Size of an encoded cue
+pub const CUE_SIZE: usize = 9;
+/// Size of the largest encoded list
+pub const MAX_ENCODED_SIZE: usize = 1 + MAX_CUES * CUE_SIZE;
+/// Stands for a cue that waits for go in an encoded hold time
+const MANUAL: u32 = u32::MAX;
+
+/// A step of a cue list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cue {
+    /// The scene to recall
+    pub scene: u8,
+    /// How long recalling it takes, in ms
+    pub fade: u32,
+    /// How long to stay once the fade is done before the next cue goes by
+    /// itself, in ms. `None` waits for go.
+    pub hold: Option<u32>,
+}
+
+impl Cue {
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.scene;
+        out[1..5].copy_from_slice(&self.fade.to_le_bytes());
+        out[5..9].copy_from_slice(&self.hold.unwrap_or(MANUAL).to_le_bytes());
+        CUE_SIZE
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < CUE_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            scene: data[0],
+            fade: word(1),
+            hold: Some(word(5)).filter(|&hold| hold != MANUAL),
+        })
+    }
+
+    /// How long after going the cue is over, in µs
+    fn duration(&self) -> Option<u64> {
+        self.hold.map(|hold| (self.fade as u64 + hold as u64) * 1_000)
+    }
+}
+
+/// The list is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CueList {
+    cues: [Option<Cue>; MAX_CUES],
+    len: u8,
+    /// Start over after the last cue
+    pub looping: bool,
+}
+
+impl CueList {
+    pub const fn new() -> Self {
+        Self {
+            cues: [None; MAX_CUES],
+            len: 0,
+            looping: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: u8) -> Option<&Cue> {
+        self.cues.get(index as usize)?.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cue> {
+        self.cues.iter().flatten()
+    }
+
+    /// Put `cue` before cue `index`, or at the end if that's past it
+    pub fn insert(&mut self, index: u8, cue: Cue) -> Result<(), Full> {
+        if self.len() == MAX_CUES {
+            return Err(Full);
+        }
+        let index = (index as usize).min(self.len());
+        self.cues[index..].rotate_right(1);
+        self.cues[index] = Some(cue);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn push(&mut self, cue: Cue) -> Result<(), Full> {
+        self.insert(self.len, cue)
+    }
+
+    /// Delete cue `index`, returning whether there was one
+    pub fn remove(&mut self, index: u8) -> bool {
+        if index as usize >= self.len() {
+            return false;
+        }
+        self.cues[index as usize..].rotate_left(1);
+        self.cues[MAX_CUES - 1] = None;
+        self.len -= 1;
+        true
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self {
+            looping: self.looping,
+            ..Self::new()
+        };
+    }
+
+    /// The cue after `index`, if there's one to follow on with
+    fn next(&self, index: u8) -> Option<u8> {
+        match index as usize + 1 {
+            next if next < self.len() => Some(next as u8),
+            _ if self.looping && !self.is_empty() => Some(0),
+            _ => None,
+        }
+    }
+
+    /// The cue before `index`, if there's one to go back to
+    fn previous(&self, index: u8) -> Option<u8> {
+        match index {
+            0 if self.looping && !self.is_empty() => Some(self.len - 1),
+            0 => None,
+            index => Some(index - 1),
+        }
+    }
+
+    /// Write the list into `out`, returning its length
+    pub fn encode(&self, out: &mut [u8; MAX_ENCODED_SIZE]) -> usize {
+        out[0] = self.looping as u8;
+        let mut len = 1;
+        for cue in self.iter() {
+            len += cue.encode(&mut out[len..]);
+        }
+        len
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let (&looping, cues) = data.split_first().ok_or(DecodeError::TooShort)?;
+        if cues.len() % CUE_SIZE != 0 || cues.len() / CUE_SIZE > MAX_CUES {
+            return Err(DecodeError::InvalidValue);
+        }
+        let mut list = Self {
+            looping: looping != 0,
+            ..Self::new()
+        };
+        for cue in cues.chunks(CUE_SIZE) {
+            list.push(Cue::decode(cue)?).map_err(|_| DecodeError::InvalidValue)?;
+        }
+        Ok(list)
+    }
+}
+
+impl Default for CueList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Controls for playing a cue list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Start the list, or go on to the next cue
+    Go,
+    /// Go back to the previous cue
+    Back,
+    /// Stop following on, or carry on after a pause
+    Pause,
+    /// Stop playing, leaving the fixtures as they are
+    Stop,
+}
+
+impl Transport {
+    const ALL: [Transport; 4] = [Transport::Go, Transport::Back, Transport::Pause, Transport::Stop];
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Transport::Go => 0,
+            Transport::Back => 1,
+            Transport::Pause => 2,
+            Transport::Stop => 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Go => "go",
+            Transport::Back => "back",
+            Transport::Pause => "pause",
+            Transport::Stop => "stop",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|transport| transport.name() == name)
+    }
+}
+
+/// Plays a cue list. Times are network time in µs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Player {
+    /// The live cue, `None` while stopped
+    current: Option<u8>,
+    /// When the live cue went
+    went_at: u64,
+    /// How far into the live cue we paused
+    paused: Option<u64>,
+}
+
+impl Player {
+    pub const fn new() -> Self {
+        Self {
+            current: None,
+            went_at: 0,
+            paused: None,
+        }
+    }
+
+    /// The live cue, `None` while stopped
+    pub fn current(&self) -> Option<u8> {
+        self.current
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Make cue `index` live at `now`, returning it if there's one
+    pub fn jump(&mut self, list: &CueList, index: u8, now: u64) -> Option<Cue> {
+        let cue = *list.get(index)?;
+        self.current = Some(index);
+        self.went_at = now;
+        self.paused = None;
+        Some(cue)
+    }
+
+    /// Carry out `transport`, returning the cue that goes if any
+    pub fn apply(&mut self, transport: Transport, list: &CueList, now: u64) -> Option<Cue> {
+        match transport {
+            Transport::Go => {
+                let next = match self.current {
+                    Some(index) => list.next(index)?,
+                    None => 0,
+                };
+                self.jump(list, next, now)
+            }
+            Transport::Back => {
+                let previous = list.previous(self.current?)?;
+                self.jump(list, previous, now)
+            }
+            Transport::Pause => {
+                self.current?;
+                self.paused = match self.paused {
+                    Some(elapsed) => {
+                        self.went_at = now.saturating_sub(elapsed);
+                        None
+                    }
+                    None => Some(now.saturating_sub(self.went_at)),
+                };
+                None
+            }
+            Transport::Stop => {
+                *self = Self::new();
+                None
+            }
+        }
+    }
+
+    /// Follow on to the next cue once the live one is over, returning it.
+    /// Call this regularly.
+    pub fn update(&mut self, list: &CueList, now: u64) -> Option<Cue> {
+        if self.paused.is_some() {
+            return None;
+        }
+        let index = self.current?;
+        let duration = match list.get(index) {
+            Some(cue) => cue.duration()?,
+            // The list was edited under us
+            None => {
+                *self = Self::new();
+                return None;
+            }
+        };
+        let due = self.went_at + duration;
+        if now < due {
+            return None;
+        }
+        // From when it was due, so late updates don't add up over a show
+        let next = list.next(index)?;
+        self.jump(list, next, due)
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Network time `n` ms in
+    fn ms(n: u64) -> u64 {
+        n * 1_000
+    }
+
+    fn cue(scene: u8, fade: u32, hold: Option<u32>) -> Cue {
+        Cue { scene, fade, hold }
+    }
+
+    /// Scene 1 for 1 s, scene 2 for 1.5 s, then scene 3 until go
+    fn show() -> CueList {
+        let mut list = CueList::new();
+        list.push(cue(1, 500, Some(500))).unwrap();
+        list.push(cue(2, 1_000, Some(500))).unwrap();
+        list.push(cue(3, 0, None)).unwrap();
+        list
+    }
+
+    /// Update `player` every ms from `from` up to `to`, returning the scenes
+    /// that went and when
+    fn run(player: &mut Player, list: &CueList, from: u64, to: u64) -> Vec<(u64, u8)> {
+        (from..to).filter_map(|t| player.update(list, ms(t)).map(|cue| (t, cue.scene))).collect()
+    }
+
+    #[test]
+    fn cues_follow_on_once_fade_and_hold_are_up() {
+        let list = show();
+        let mut player = Player::new();
+        assert_eq!(player.update(&list, 0), None);
+        assert_eq!(player.apply(Transport::Go, &list, ms(100)).map(|cue| cue.scene), Some(1));
+
+        // Not a ms early, and the manual cue holds on until go
+        assert_eq!(run(&mut player, &list, 100, 10_000), [(1_100, 2), (2_600, 3)]);
+        assert_eq!(player.current(), Some(2));
+        assert_eq!(player.apply(Transport::Go, &list, ms(10_000)), None);
+        assert_eq!(player.current(), Some(2));
+    }
+
+    #[test]
+    fn late_updates_keep_to_the_schedule() {
+        let list = show();
+        let mut player = Player::new();
+        player.apply(Transport::Go, &list, 0);
+        assert_eq!(player.update(&list, ms(1_300)).map(|cue| cue.scene), Some(2));
+        // Cue 2 went at 1 s, when it was due, not when we got to it
+        assert_eq!(player.update(&list, ms(2_499)), None);
+        assert_eq!(player.update(&list, ms(2_500)).map(|cue| cue.scene), Some(3));
+    }
+
+    #[test]
+    fn pausing_holds_the_time_left() {
+        let list = show();
+        let mut player = Player::new();
+        player.apply(Transport::Go, &list, 0);
+        assert_eq!(player.apply(Transport::Pause, &list, ms(400)), None);
+        assert!(player.is_paused());
+        assert_eq!(run(&mut player, &list, 400, 5_000), []);
+
+        // 600 ms of cue 1 were left
+        player.apply(Transport::Pause, &list, ms(5_000));
+        assert!(!player.is_paused());
+        assert_eq!(run(&mut player, &list, 5_000, 7_000), [(5_600, 2)]);
+    }
+
+    #[test]
+    fn back_and_go_step_through_the_list() {
+        let list = show();
+        let mut player = Player::new();
+        assert_eq!(player.apply(Transport::Back, &list, 0), None);
+        assert_eq!(player.apply(Transport::Pause, &list, 0), None);
+        assert!(!player.is_paused());
+
+        let mut step = |transport, at| player.apply(transport, &list, ms(at)).map(|cue| cue.scene);
+        assert_eq!(step(Transport::Go, 0), Some(1));
+        assert_eq!(step(Transport::Go, 200), Some(2));
+        assert_eq!(step(Transport::Back, 300), Some(1));
+        assert_eq!(step(Transport::Back, 400), None);
+        assert_eq!(step(Transport::Go, 500), Some(2));
+        assert_eq!(step(Transport::Stop, 600), None);
+        assert_eq!(player.current(), None);
+
+        // Going while paused moves on to the next cue, playing
+        player.apply(Transport::Go, &list, 0);
+        player.apply(Transport::Pause, &list, ms(100));
+        player.apply(Transport::Go, &list, ms(200));
+        assert!(!player.is_paused());
+        assert_eq!(run(&mut player, &list, 200, 2_000), [(1_700, 3)]);
+    }
+
+    #[test]
+    fn looping_lists_start_over() {
+        let mut list = show();
+        list.looping = true;
+        list.remove(2);
+        let mut player = Player::new();
+        player.apply(Transport::Go, &list, 0);
+        assert_eq!(run(&mut player, &list, 0, 5_100), [(1_000, 2), (2_500, 1), (3_500, 2), (5_000, 1)]);
+        assert_eq!(player.apply(Transport::Back, &list, ms(5_100)).map(|cue| cue.scene), Some(2));
+    }
+
+    #[test]
+    fn editing_the_live_cue_away_stops() {
+        let mut list = show();
+        let mut player = Player::new();
+        player.apply(Transport::Go, &list, 0);
+        player.apply(Transport::Go, &list, 0);
+        player.apply(Transport::Go, &list, 0);
+        list.remove(2);
+        assert_eq!(player.update(&list, ms(1)), None);
+        assert_eq!(player.current(), None);
+    }
+
+    #[test]
+    fn lists_survive_encoding() {
+        let mut list = show();
+        list.looping = true;
+        list.insert(0, cue(7, u32::MAX - 1, Some(0))).unwrap();
+        let mut out = [0; MAX_ENCODED_SIZE];
+        let len = list.encode(&mut out);
+        assert_eq!(len, 1 + 4 * CUE_SIZE);
+        assert_eq!(CueList::decode(&out[..len]), Ok(list));
+        assert_eq!(CueList::decode(&out[..len - 1]), Err(DecodeError::InvalidValue));
+        assert_eq!(CueList::decode(&[]), Err(DecodeError::TooShort));
+    }
+}
@@ -0,0 +1,393 @@
+//! DMX512 from a lighting desk, on a cable.
+//!
+//! Desks send packets over and over at 250 kbaud, 8 bits and two stop bits.
+//! Each starts with a break, the line held low for longer than a byte, which
+//! the USART reports as a framing error. A start code follows, then up to
+//! 512 channel levels:
+//!
+//! ```text
+//! | break | start code (0) | channel 1 | channel 2 | ... | channel 512 |
+//! ```
+//!
+//! Packets with another start code carry something other than levels and
+//! are skipped. A fixture listens to the channels from its start address
+//! on, laid out by its [`Personality`]:
+//!
+//! ```text
+//! pixels: | red | green | blue | red | green | blue | ...    3 a pixel
+//! look:   | brightness | effect | speed | red | green | blue |
+//! ```
+//!
+//! The effect channel picks off, solid or rainbow by thirds. Both settings
+//! are kept in [`key::DMX`] as a [`DmxConfig`].
+//!
+//! [`key::DMX`]: crate::settings::key::DMX
+
+use crate::protocol::{DecodeError, Effect};
+use crate::scene::Look;
+use smart_leds::RGB8;
+
+/// Baud rate of DMX512
+pub const BAUD_RATE: u32 = 250_000;
+/// Channels in a universe
+pub const UNIVERSE_SIZE: usize = 512;
+/// Start code of packets carrying levels
+pub const START_CODE: u8 = 0;
+/// Size of an encoded [`DmxConfig`]
+pub const CONFIG_SIZE: usize = 3;
+/// Channels of the look personality
+pub const LOOK_CHANNELS: usize = 6;
+/// Steps of an effect channel, a third of its range each
+const EFFECT_STEP: u8 = 86;
+
+/// The effect an effect channel at `level` picks
+pub fn effect(level: u8) -> Effect {
+    Effect::from_byte(level / EFFECT_STEP).unwrap_or(Effect::Rainbow)
+}
+
+/// How a fixture's channels are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+    /// Red, green and blue for each pixel
+    Pixels,
+    /// Brightness, effect, speed and colour, like a [`Look`]
+    Look,
+}
+
+impl Personality {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Personality::Pixels),
+            1 => Some(Personality::Look),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Personality::Pixels => 0,
+            Personality::Look => 1,
+        }
+    }
+
+    /// Look a personality up by its [`Personality::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pixels" => Some(Personality::Pixels),
+            "look" => Some(Personality::Look),
+            _ => None,
+        }
+    }
+
+    /// What the personality is called on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Personality::Pixels => "pixels",
+            Personality::Look => "look",
+        }
+    }
+
+    /// Channels a fixture with `leds` pixels takes
+    pub fn footprint(self, leds: usize) -> usize {
+        match self {
+            Personality::Pixels => leds * 3,
+            Personality::Look => LOOK_CHANNELS,
+        }
+    }
+}
+
+/// Where a fixture is patched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmxConfig {
+    /// Its first channel, from 1 to 512
+    pub address: u16,
+    pub personality: Personality,
+}
+
+impl DmxConfig {
+    pub const fn new() -> Self {
+        Self {
+            address: 1,
+            personality: Personality::Pixels,
+        }
+    }
+
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[..2].copy_from_slice(&self.address.to_le_bytes());
+        out[2] = self.personality.to_byte();
+        CONFIG_SIZE
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < CONFIG_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let address = u16::from_le_bytes([data[0], data[1]]);
+        if address == 0 || address as usize > UNIVERSE_SIZE {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(Self {
+            address,
+            personality: Personality::from_byte(data[2]).ok_or(DecodeError::InvalidValue)?,
+        })
+    }
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a break
+    Idle,
+    StartCode,
+    Levels,
+}
+
+/// Puts packets back together from what the USART receives
+#[derive(Clone, Copy)]
+pub struct Receiver {
+    levels: [u8; UNIVERSE_SIZE],
+    len: usize,
+    state: State,
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self {
+            levels: [0; UNIVERSE_SIZE],
+            len: 0,
+            state: State::Idle,
+        }
+    }
+
+    /// A break, which starts a packet, returning the levels of the one it
+    /// ended
+    pub fn on_break(&mut self) -> Option<&[u8]> {
+        let ended = self.state == State::Levels && self.len > 0;
+        self.state = State::StartCode;
+        let len = core::mem::replace(&mut self.len, 0);
+        if ended {
+            Some(&self.levels[..len])
+        } else {
+            None
+        }
+    }
+
+    /// A byte, returning the levels of the packet once it has all 512
+    pub fn on_byte(&mut self, byte: u8) -> Option<&[u8]> {
+        match self.state {
+            State::Idle => None,
+            State::StartCode => {
+                self.state = if byte == START_CODE { State::Levels } else { State::Idle };
+                None
+            }
+            State::Levels => {
+                self.levels[self.len] = byte;
+                self.len += 1;
+                if self.len < UNIVERSE_SIZE {
+                    return None;
+                }
+                self.state = State::Idle;
+                Some(&self.levels[..])
+            }
+        }
+    }
+
+    /// A byte was lost or garbled, so the packet is skipped
+    pub fn on_error(&mut self) {
+        self.state = State::Idle;
+        self.len = 0;
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A fixture's channels, from the last packet that had them
+#[derive(Clone, Copy)]
+pub struct Patch {
+    pub config: DmxConfig,
+    /// From the start address on
+    levels: [u8; UNIVERSE_SIZE],
+    len: usize,
+    /// When they came, in ticks of the caller's choosing
+    received: Option<u32>,
+}
+
+impl Patch {
+    pub const fn new(config: DmxConfig) -> Self {
+        Self {
+            config,
+            levels: [0; UNIVERSE_SIZE],
+            len: 0,
+            received: None,
+        }
+    }
+
+    /// Take our channels from the levels of a packet received at `now`,
+    /// unless it ends before them
+    pub fn update(&mut self, packet: &[u8], now: u32) {
+        let ours = match packet.get(self.config.address as usize - 1..) {
+            Some(ours) if !ours.is_empty() => ours,
+            _ => return,
+        };
+        self.levels[..ours.len()].copy_from_slice(ours);
+        self.len = ours.len();
+        self.received = Some(now);
+    }
+
+    /// Whether levels came within `timeout` of `now`, forgetting them once
+    /// they didn't
+    pub fn check(&mut self, now: u32, timeout: u32) -> bool {
+        match self.received {
+            Some(at) if now.wrapping_sub(at) < timeout => true,
+            _ => {
+                self.received = None;
+                false
+            }
+        }
+    }
+
+    /// The look the channels set, with the look personality
+    pub fn look(&self) -> Option<Look> {
+        match (self.config.personality, &self.levels[..self.len]) {
+            (Personality::Look, &[brightness, level, speed, r, g, b, ..]) => Some(Look {
+                effect: effect(level),
+                speed,
+                brightness,
+                color: RGB8::new(r, g, b),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The colour of each pixel, with the pixels personality. Pixels past
+    /// the end of the packet are left out.
+    pub fn pixels(&self) -> Option<impl Iterator<Item = RGB8> + '_> {
+        match self.config.personality {
+            Personality::Pixels => Some(self.levels[..self.len].chunks_exact(3).map(|c| RGB8::new(c[0], c[1], c[2]))),
+            Personality::Look => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `bytes` after a break, returning the packets that completed
+    fn receive(receiver: &mut Receiver, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets: Vec<Vec<u8>> = receiver.on_break().map(<[u8]>::to_vec).into_iter().collect();
+        packets.extend(bytes.iter().filter_map(|&byte| receiver.on_byte(byte).map(<[u8]>::to_vec)));
+        packets
+    }
+
+    /// A full packet of levels, channel n at n % 256
+    fn universe() -> Vec<u8> {
+        Some(START_CODE).into_iter().chain((1..=UNIVERSE_SIZE).map(|n| n as u8)).collect()
+    }
+
+    #[test]
+    fn packets_run_from_break_to_the_last_level() {
+        let mut receiver = Receiver::new();
+        // Nothing counts before the first break
+        assert!(receiver.on_byte(START_CODE).is_none());
+        assert!(receiver.on_byte(7).is_none());
+
+        let packet = universe();
+        assert_eq!(receive(&mut receiver, &packet), [packet[1..].to_vec()]);
+        // Bytes after the 512th wait for the next break
+        assert_eq!(receive(&mut receiver, &[]), Vec::<Vec<u8>>::new());
+        assert!(receiver.on_byte(START_CODE).is_none());
+    }
+
+    #[test]
+    fn short_packets_end_at_the_next_break() {
+        let mut receiver = Receiver::new();
+        assert!(receive(&mut receiver, &[START_CODE, 10, 20, 30]).is_empty());
+        assert_eq!(receive(&mut receiver, &[START_CODE]), [vec![10, 20, 30]]);
+        // A break right after the start code has no levels to hand over
+        assert!(receive(&mut receiver, &[]).is_empty());
+    }
+
+    #[test]
+    fn other_start_codes_are_skipped() {
+        let mut receiver = Receiver::new();
+        let mut packet = universe();
+        packet[0] = 0xCC;
+        assert!(receive(&mut receiver, &packet).is_empty());
+        assert!(receive(&mut receiver, &[]).is_empty());
+    }
+
+    #[test]
+    fn errors_drop_the_packet() {
+        let mut receiver = Receiver::new();
+        let packet = universe();
+        receive(&mut receiver, &packet[..100]);
+        receiver.on_error();
+        assert!(packet[100..].iter().all(|&byte| receiver.on_byte(byte).is_none()));
+        assert!(receive(&mut receiver, &[]).is_empty());
+        assert_eq!(receive(&mut receiver, &packet), [packet[1..].to_vec()]);
+    }
+
+    #[test]
+    fn patches_near_the_end_of_the_universe() {
+        let packet: Vec<u8> = universe()[1..].to_vec();
+        let patch = |address, personality| {
+            let mut patch = Patch::new(DmxConfig { address, personality });
+            patch.update(&packet, 0);
+            patch
+        };
+
+        // Channels 507 to 512 are the last whole look, 508 on runs out
+        let level = |channel: u16| channel as u8;
+        let look = patch(507, Personality::Look).look().unwrap();
+        assert_eq!((look.brightness, look.effect, look.speed), (level(507), effect(level(508)), level(509)));
+        assert_eq!(look.color, RGB8::new(level(510), level(511), level(512)));
+        assert_eq!(patch(508, Personality::Look).look(), None);
+
+        // Only whole pixels count
+        let pixels: Vec<_> = patch(509, Personality::Pixels).pixels().unwrap().collect();
+        assert_eq!(pixels, [RGB8::new(level(509), level(510), level(511))]);
+        assert_eq!(patch(512, Personality::Pixels).pixels().unwrap().count(), 0);
+        assert!(patch(1, Personality::Pixels).look().is_none());
+    }
+
+    #[test]
+    fn packets_ending_before_a_patch_leave_it_be() {
+        let mut patch = Patch::new(DmxConfig { address: 5, personality: Personality::Look });
+        patch.update(&[0, 0, 0, 0, 255, 100, 2, 1, 2, 3], 10);
+        patch.update(&[9; 4], 20);
+        assert_eq!(patch.look().map(|look| look.brightness), Some(255));
+        assert!(patch.check(30, 25));
+        assert!(!patch.check(35, 25));
+        // Once forgotten they stay so
+        assert!(!patch.check(11, 25));
+    }
+
+    #[test]
+    fn effect_channels_pick_by_thirds() {
+        let effects: Vec<_> = [0, 85, 86, 171, 172, 255].iter().map(|&level| effect(level)).collect();
+        let expected = [Effect::Off, Effect::Off, Effect::Solid, Effect::Solid, Effect::Rainbow, Effect::Rainbow];
+        assert_eq!(effects, expected);
+    }
+
+    #[test]
+    fn configs_survive_encoding() {
+        let config = DmxConfig { address: 512, personality: Personality::Look };
+        let mut out = [0; CONFIG_SIZE];
+        config.encode(&mut out);
+        assert_eq!(DmxConfig::decode(&out), Ok(config));
+        assert_eq!(DmxConfig::decode(&[0, 0, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(DmxConfig::decode(&[1, 2, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(DmxConfig::decode(&[1, 0, 2]), Err(DecodeError::InvalidValue));
+        assert_eq!(DmxConfig::decode(&[1, 0]), Err(DecodeError::TooShort));
+    }
+}
@@ -0,0 +1,315 @@
+//! Packets between a PC and the `tx` app running as a USB dongle.
+//!
+//! In dongle mode the USB serial port carries [`cobs`](crate::cobs) framed
+//! packets instead of lines of text. Each starts with a kind byte:
+//!
+//! ```text
+//! | 0x01 | frame ...                           |  send, PC to dongle
+//! | 0x02 | seq | dest | outcome | status (9)? |  sent, dongle to PC
+//! | 0x03 | frame ...                           |  received, dongle to PC
+//! | 0x04 | text ...                            |  text, both ways
+//! ```
+//!
+//! Frames are [`Frame::encode`]d in the clear: the dongle seals them with
+//! its session, and stamps its own id as the source. Every frame the PC
+//! sends is answered with a `sent` packet telling how it went, carrying the
+//! fixture's latest [`FixtureStatus`] once it acknowledged. Requests that
+//! fixtures answer, like a status query, are followed by a `received`
+//! packet with the answer. A discover is followed by one for each fixture
+//! that announces itself within the window. Text from the PC is run as a
+//! command line, and the log comes back as text packets.
+
+use crate::address::Destination;
+use crate::cobs;
+use crate::protocol::{DecodeError, Frame, FRAME_SIZE};
+use crate::status::{FixtureStatus, STATUS_SIZE};
+
+/// Largest packet, before framing
+pub const MAX_PACKET_SIZE: usize = 64;
+/// Largest text packet's text
+pub const MAX_TEXT: usize = MAX_PACKET_SIZE - 1;
+/// Largest packet once framed
+pub const MAX_FRAMED_SIZE: usize = cobs::max_encoded_len(MAX_PACKET_SIZE);
+
+const SEND: u8 = 0x01;
+const SENT: u8 = 0x02;
+const RECEIVED: u8 = 0x03;
+const TEXT: u8 = 0x04;
+
+/// How sending a frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The fixture acknowledged it, with its status if it sent one
+    Acked(Option<FixtureStatus>),
+    /// A group or broadcast frame went out, nobody acknowledges those
+    Sent,
+    /// Nobody acknowledged it
+    Lost,
+    /// The dongle had too much to send already, and dropped it
+    Busy,
+}
+
+impl Outcome {
+    fn to_byte(self) -> u8 {
+        match self {
+            Outcome::Acked(_) => 0,
+            Outcome::Sent => 1,
+            Outcome::Lost => 2,
+            Outcome::Busy => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    /// Send a frame over the radio
+    Send(Frame),
+    /// How sending the frame with `seq` to `dest` went
+    Sent { seq: u8, dest: Destination, outcome: Outcome },
+    /// A frame a fixture sent us
+    Received(Frame),
+    /// A command line, or the log
+    Text(&'a str),
+}
+
+impl<'a> Packet<'a> {
+    /// Write the packet into `out`, returning its length. Text longer than
+    /// [`MAX_TEXT`] is cut short.
+    pub fn encode(&self, out: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+        match *self {
+            Packet::Send(frame) => encode_frame(SEND, &frame, out),
+            Packet::Received(frame) => encode_frame(RECEIVED, &frame, out),
+            Packet::Sent { seq, dest, outcome } => {
+                out[0] = SENT;
+                out[1] = seq;
+                out[2] = dest.to_byte();
+                out[3] = outcome.to_byte();
+                match outcome {
+                    Outcome::Acked(Some(status)) => {
+                        out[4..4 + STATUS_SIZE].copy_from_slice(&status.encode());
+                        4 + STATUS_SIZE
+                    }
+                    _ => 4,
+                }
+            }
+            Packet::Text(text) => {
+                out[0] = TEXT;
+                let len = text.len().min(MAX_TEXT);
+                out[1..1 + len].copy_from_slice(&text.as_bytes()[..len]);
+                1 + len
+            }
+        }
+    }
+
+    pub fn decode(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let (&kind, rest) = data.split_first().ok_or(DecodeError::TooShort)?;
+        Ok(match kind {
+            SEND => Packet::Send(Frame::decode(rest)?),
+            RECEIVED => Packet::Received(Frame::decode(rest)?),
+            SENT => {
+                if rest.len() < 3 {
+                    return Err(DecodeError::TooShort);
+                }
+                let outcome = match rest[2] {
+                    0 if rest.len() > 3 => Outcome::Acked(Some(FixtureStatus::decode(&rest[3..])?)),
+                    0 => Outcome::Acked(None),
+                    1 => Outcome::Sent,
+                    2 => Outcome::Lost,
+                    3 => Outcome::Busy,
+                    _ => return Err(DecodeError::InvalidValue),
+                };
+                Packet::Sent {
+                    seq: rest[0],
+                    dest: Destination::from_byte(rest[1]),
+                    outcome,
+                }
+            }
+            TEXT => Packet::Text(core::str::from_utf8(rest).map_err(|_| DecodeError::InvalidValue)?),
+            other => return Err(DecodeError::UnknownKind(other)),
+        })
+    }
+
+    /// Encode and frame the packet into `out`, returning the length
+    pub fn frame(&self, out: &mut [u8; MAX_FRAMED_SIZE]) -> usize {
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let len = self.encode(&mut packet);
+        cobs::encode(&packet[..len], out)
+    }
+}
+
+/// Write `kind` and `frame` into `out`, returning the length
+fn encode_frame(kind: u8, frame: &Frame, out: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+    let mut buf = [0u8; FRAME_SIZE];
+    let len = frame.encode(&mut buf);
+    out[0] = kind;
+    out[1..1 + len].copy_from_slice(&buf[..len]);
+    1 + len
+}
+
+/// Picks framed packets out of a byte stream
+pub struct Deframer {
+    buf: [u8; MAX_FRAMED_SIZE],
+    len: usize,
+    packet: [u8; MAX_PACKET_SIZE],
+    /// Bytes were dropped since the last zero, so the packet is cut short
+    overrun: bool,
+}
+
+impl Deframer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAMED_SIZE],
+            len: 0,
+            packet: [0; MAX_PACKET_SIZE],
+            overrun: false,
+        }
+    }
+
+    /// Add a byte, returning the length of the packet it ended. Packets that
+    /// don't decode are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<usize> {
+        if byte != 0 {
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overrun = true,
+            }
+            return None;
+        }
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overrun, false) || len == 0 {
+            return None;
+        }
+        cobs::decode(&self.buf[..len], &mut self.packet).ok()
+    }
+
+    /// The packet [`push`](Self::push) last returned the length of
+    pub fn packet(&self, len: usize) -> &[u8] {
+        &self.packet[..len]
+    }
+}
+
+impl Default for Deframer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::GroupId;
+    use crate::protocol::{Effect, Message};
+    use crate::status::STATUS_VERSION;
+
+    const STATUS: FixtureStatus = FixtureStatus {
+        effect: Effect::Solid,
+        brightness: 200,
+        power_limited: false,
+        temperature: 31,
+        uptime: 4_000,
+    };
+
+    fn framed(packet: &Packet) -> Vec<u8> {
+        let mut out = [0; MAX_FRAMED_SIZE];
+        let len = packet.frame(&mut out);
+        out[..len].to_vec()
+    }
+
+    /// Feed `bytes` to `deframer`, collecting the packets that come out
+    fn deframe(deframer: &mut Deframer, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        for &byte in bytes {
+            if let Some(len) = deframer.push(byte) {
+                packets.push(deframer.packet(len).to_vec());
+            }
+        }
+        packets
+    }
+
+    fn packets() -> Vec<Packet<'static>> {
+        let frame = Frame::new(Destination::Node(3), 0, 17, Message::SetBrightness(99));
+        let group = Destination::Group(GroupId::new(2).unwrap());
+        vec![
+            Packet::Send(frame),
+            Packet::Received(Frame::new(Destination::Node(0), 3, 18, Message::StatusQuery)),
+            Packet::Sent { seq: 17, dest: Destination::Node(3), outcome: Outcome::Acked(Some(STATUS)) },
+            Packet::Sent { seq: 18, dest: Destination::Node(3), outcome: Outcome::Acked(None) },
+            Packet::Sent { seq: 19, dest: group, outcome: Outcome::Sent },
+            Packet::Sent { seq: 20, dest: Destination::Broadcast, outcome: Outcome::Lost },
+            Packet::Sent { seq: 21, dest: Destination::Node(1), outcome: Outcome::Busy },
+            Packet::Text("status 3"),
+            Packet::Text(""),
+        ]
+    }
+
+    #[test]
+    fn packets_survive_the_wire() {
+        let mut deframer = Deframer::new();
+        for packet in packets() {
+            let bytes = framed(&packet);
+            assert!(bytes.len() <= MAX_FRAMED_SIZE);
+            let out = deframe(&mut deframer, &bytes);
+            assert_eq!(out.len(), 1, "{:?} came out as {:?}", packet, out);
+            assert_eq!(Packet::decode(&out[0]), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn sent_packets_carry_the_status_only_when_acked() {
+        let mut out = [0; MAX_PACKET_SIZE];
+        let acked = Packet::Sent { seq: 5, dest: Destination::Node(2), outcome: Outcome::Acked(Some(STATUS)) };
+        assert_eq!(acked.encode(&mut out), 4 + STATUS_SIZE);
+        assert_eq!(out[..5], [SENT, 5, 2, 0, STATUS_VERSION]);
+        let lost = Packet::Sent { seq: 5, dest: Destination::Node(2), outcome: Outcome::Lost };
+        assert_eq!(lost.encode(&mut out), 4);
+    }
+
+    #[test]
+    fn long_text_is_cut_short() {
+        let text = "x".repeat(MAX_TEXT + 10);
+        let mut out = [0; MAX_PACKET_SIZE];
+        assert_eq!(Packet::Text(&text).encode(&mut out), MAX_PACKET_SIZE);
+        assert_eq!(Packet::decode(&out), Ok(Packet::Text(&text[..MAX_TEXT])));
+    }
+
+    #[test]
+    fn bad_packets_are_refused() {
+        assert_eq!(Packet::decode(&[]), Err(DecodeError::TooShort));
+        assert_eq!(Packet::decode(&[0x09, 1, 2]), Err(DecodeError::UnknownKind(0x09)));
+        assert_eq!(Packet::decode(&[SENT, 1, 2]), Err(DecodeError::TooShort));
+        assert_eq!(Packet::decode(&[SENT, 1, 2, 4]), Err(DecodeError::InvalidValue));
+        assert_eq!(Packet::decode(&[SENT, 1, 2, 0, STATUS_VERSION]), Err(DecodeError::TooShort));
+        assert_eq!(Packet::decode(&[TEXT, 0xFF, 0xFE]), Err(DecodeError::InvalidValue));
+        assert!(Packet::decode(&[SEND]).is_err());
+    }
+
+    #[test]
+    fn deframing_picks_up_after_garbage() {
+        let mut deframer = Deframer::new();
+        let packet = framed(&Packet::Text("hello"));
+        // Zeros on their own, a corrupt packet and the tail of a packet we
+        // started listening halfway through are all dropped
+        let mut bytes = vec![0, 0, 9, 1, 0];
+        bytes.extend_from_slice(&packet[3..]);
+        bytes.extend_from_slice(&packet);
+        assert_eq!(deframe(&mut deframer, &bytes), [b"\x04hello"]);
+    }
+
+    #[test]
+    fn deframing_drops_packets_that_overrun() {
+        let mut deframer = Deframer::new();
+        let mut bytes = vec![0x42; MAX_FRAMED_SIZE + 5];
+        bytes.push(0);
+        assert!(deframe(&mut deframer, &bytes).is_empty());
+        // Those that just fit still come through, and so does the next one
+        let mut bytes = framed(&Packet::Text(&"y".repeat(MAX_TEXT)));
+        bytes.extend(framed(&Packet::Text("next")));
+        let out = deframe(&mut deframer, &bytes);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].len(), MAX_PACKET_SIZE);
+        assert_eq!(Packet::decode(&out[1]), Ok(Packet::Text("next")));
+    }
+}
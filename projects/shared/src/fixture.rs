@@ -0,0 +1,183 @@
+//! State of a light fixture, as changed by incoming messages.
+
+use crate::address::{GroupTable, NodeId};
+use crate::hopping::ChannelPlan;
+use crate::protocol::{Effect, Frame, Message};
+use crate::scene::{Look, SceneCache};
+use smart_leds::RGB8;
+
+/// Current drawn by one colour channel of a WS2812 at full brightness, in mA
+pub const MILLIAMPS_PER_CHANNEL: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixtureState {
+    /// Our own node id
+    pub id: NodeId,
+    pub groups: GroupTable,
+    pub effect: Effect,
+    pub speed: u8,
+    pub brightness: u8,
+    pub color: RGB8,
+    pub channel_plan: ChannelPlan,
+    /// Our looks in the scenes the controller told us about
+    pub scenes: SceneCache,
+    /// The change to the current look, if it's still fading in
+    pub fade: Option<Fade>,
+}
+
+/// Where a fade to a new look started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fade {
+    pub effect: Effect,
+    pub brightness: u8,
+    pub color: RGB8,
+    /// Network time the fade started at, in µs
+    pub start: u64,
+    /// In µs
+    pub duration: u64,
+}
+
+impl FixtureState {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            groups: GroupTable::new(),
+            effect: Effect::Rainbow,
+            speed: 1,
+            brightness: 255,
+            color: RGB8::new(255, 255, 255),
+            channel_plan: ChannelPlan::default(),
+            scenes: SceneCache::new(),
+            fade: None,
+        }
+    }
+
+    /// Apply a received frame at network time `now`, returning `false` if it
+    /// wasn't meant for us
+    pub fn handle(&mut self, frame: &Frame, now: u64) -> bool {
+        if !self.groups.accepts(self.id, frame.header.dest) {
+            return false;
+        }
+        self.apply(&frame.message, now);
+        true
+    }
+
+    pub fn apply(&mut self, message: &Message, now: u64) {
+        match *message {
+            Message::SetBrightness(brightness) => self.brightness = brightness,
+            Message::SetEffect { effect, speed } => {
+                self.effect = effect;
+                self.speed = speed;
+            }
+            Message::SetColor(color) => self.color = color,
+            Message::JoinGroup(group) => self.groups.join(group),
+            Message::LeaveGroup(group) => self.groups.leave(group),
+            Message::ClearGroups => self.groups.clear(),
+            Message::SetChannelPlan(plan) => self.channel_plan = plan,
+            Message::SetLook { look, fade } => self.set_look(look, fade, now),
+            Message::StoreScene { scene, look } => self.scenes.store(scene, look),
+            Message::RecallScene { scene, fade } => {
+                if let Some(look) = self.scenes.get(scene) {
+                    self.set_look(look, fade, now);
+                }
+            }
+            // Handled by the radio loop
+            Message::PairRequest { .. }
+            | Message::PairResponse { .. }
+            | Message::HopSync { .. }
+            | Message::TimeSync { .. }
+            | Message::StatusQuery
+            | Message::LinkStatus(_)
+            | Message::Discover { .. }
+            | Message::Announce(_)
+            | Message::OtaBegin { .. }
+            | Message::OtaChunk { .. }
+            | Message::OtaEnd
+            | Message::OtaStatus(_) => {}
+            // Drawn by the fixture's `Stream`
+            Message::StreamPalette { .. } | Message::StreamPixels { .. } | Message::StreamShow { .. } => {}
+        }
+    }
+
+    pub fn look(&self) -> Look {
+        Look {
+            effect: self.effect,
+            speed: self.speed,
+            brightness: self.brightness,
+            color: self.color,
+        }
+    }
+
+    /// Switch to `look`, fading from what we show at network time `now` over
+    /// `fade` ms
+    pub fn set_look(&mut self, look: Look, fade: u32, now: u64) {
+        // A recalled scene is sent to fixtures that cached it too
+        if look == self.look() {
+            return;
+        }
+        let (effect, brightness, color) = self.output(now);
+        self.fade = if fade > 0 {
+            Some(Fade {
+                effect,
+                brightness,
+                color,
+                start: now,
+                duration: fade as u64 * 1_000,
+            })
+        } else {
+            None
+        };
+        self.effect = look.effect;
+        self.speed = look.speed;
+        self.brightness = look.brightness;
+        self.color = look.color;
+    }
+
+    /// The effect, brightness and colour to show at network time `now`,
+    /// which may be part way through a fade
+    pub fn output(&self, now: u64) -> (Effect, u8, RGB8) {
+        let fade = match self.fade {
+            Some(fade) if now.saturating_sub(fade.start) < fade.duration => fade,
+            _ => return (self.effect, self.brightness, self.color),
+        };
+        let progress = now.saturating_sub(fade.start) * 256 / fade.duration;
+        let mix = |from: u8, to: u8| ((from as u64 * (256 - progress) + to as u64 * progress) / 256) as u8;
+
+        // Turning off dims the old effect out, turning on dims the new one in
+        let (effect, to) = match self.effect {
+            Effect::Off => (fade.effect, 0),
+            effect => (effect, self.brightness),
+        };
+        let from = match fade.effect {
+            Effect::Off => 0,
+            _ => fade.brightness,
+        };
+        let color = RGB8::new(
+            mix(fade.color.r, self.color.r),
+            mix(fade.color.g, self.color.g),
+            mix(fade.color.b, self.color.b),
+        );
+        (effect, mix(from, to), color)
+    }
+}
+
+/// Scale a colour by a fixture brightness
+pub fn dim(color: RGB8, brightness: u8) -> RGB8 {
+    let scale = |c: u8| ((c as u16 * (brightness as u16 + 1)) >> 8) as u8;
+    RGB8::new(scale(color.r), scale(color.g), scale(color.b))
+}
+
+/// Dim `pixels` evenly so they draw at most `budget` mA, returning whether
+/// they had to be dimmed
+pub fn limit_power(pixels: &mut [RGB8], budget: u32) -> bool {
+    let total: u32 = pixels.iter().map(|p| p.r as u32 + p.g as u32 + p.b as u32).sum();
+    let current = total * MILLIAMPS_PER_CHANNEL / 255;
+    if current <= budget {
+        return false;
+    }
+    let scale = |c: u8| (c as u32 * budget / current) as u8;
+    for pixel in pixels.iter_mut() {
+        *pixel = RGB8::new(scale(pixel.r), scale(pixel.g), scale(pixel.b));
+    }
+    true
+}
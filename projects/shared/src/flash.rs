@@ -0,0 +1,181 @@
+//! On-chip flash: how it is split up, and access to it.
+//!
+//! The STM32F103C8 has 64 pages of 1K:
+//!
+//! ```text
+//! 0x0800_0000  bootloader     8K
+//! 0x0800_2000  app slot      25K   header, then the running firmware at +0x200
+//! 0x0800_8400  staging slot  25K   where updates are received
+//! 0x0800_E800  scratch        1K   one page of a slot swap in flight
+//! 0x0800_EC00  boot state     1K   see crate::boot
+//! 0x0800_F000  settings       4K   see crate::settings
+//! ```
+//!
+//! Apps started by the bootloader link at [`APP_SLOT`] + [`SLOT_HEADER_SIZE`],
+//! see `memory.x` of `lights`.
+
+#[cfg(feature = "device")]
+use stm32f1xx_hal::flash::{Error, FlashWriter};
+
+/// Where flash is mapped
+pub const FLASH_BASE: u32 = 0x0800_0000;
+/// Erase granularity
+pub const PAGE_SIZE: u32 = 1024;
+
+/// A range of flash pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub const fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub const fn pages(&self) -> u32 {
+        self.size / PAGE_SIZE
+    }
+
+    /// Address of page `n` of the region
+    pub const fn page(&self, n: u32) -> u32 {
+        self.start + n * PAGE_SIZE
+    }
+}
+
+pub const BOOTLOADER: Region = Region { start: 0x0800_0000, size: 8 * PAGE_SIZE };
+pub const APP_SLOT: Region = Region { start: 0x0800_2000, size: 25 * PAGE_SIZE };
+pub const STAGING_SLOT: Region = Region { start: 0x0800_8400, size: 25 * PAGE_SIZE };
+pub const SCRATCH: Region = Region { start: 0x0800_E800, size: PAGE_SIZE };
+pub const BOOT_STATE: Region = Region { start: 0x0800_EC00, size: PAGE_SIZE };
+pub const SETTINGS: Region = Region { start: 0x0800_F000, size: 4 * PAGE_SIZE };
+
+/// Space at the start of a slot for the image header, keeping the vector
+/// table after it aligned as the Cortex-M3 requires
+pub const SLOT_HEADER_SIZE: u32 = 0x200;
+
+/// Flash as storage code sees it: absolute addresses, pages that erase to
+/// `0xFF`, and writes of whole half words to erased flash
+pub trait Flash {
+    type Error;
+
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erase the page starting at `page`
+    fn erase(&mut self, page: u32) -> Result<(), Self::Error>;
+
+    /// Program `data` at `address`, both of which have to be even
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[cfg(feature = "device")]
+impl<'a> Flash for FlashWriter<'a> {
+    type Error = Error;
+
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let data = FlashWriter::read(self, address - FLASH_BASE, buf.len())?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase(&mut self, page: u32) -> Result<(), Error> {
+        FlashWriter::erase(self, page - FLASH_BASE, PAGE_SIZE as usize)
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        FlashWriter::write(self, address - FLASH_BASE, data)
+    }
+}
+
+/// Copy `len` bytes from `from` to `to`, which has to be erased
+pub fn copy<F: Flash>(flash: &mut F, from: u32, to: u32, len: u32) -> Result<(), F::Error> {
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(buf.len() as u32) as usize;
+        flash.read(from + offset, &mut buf[..n])?;
+        flash.write(to + offset, &buf[..n])?;
+        offset += n as u32;
+    }
+    Ok(())
+}
+
+/// Flash in RAM for tests, which can lose power part way
+#[cfg(test)]
+pub mod ram {
+    use super::{Flash, FLASH_BASE, PAGE_SIZE};
+
+    /// Power ran out, the operation may be partly done
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PowerCut;
+
+    /// The whole 64K of the chip
+    #[derive(Clone)]
+    pub struct RamFlash {
+        memory: Vec<u8>,
+        /// Half words that can still be written or pages erased, if limited
+        pub power: Option<usize>,
+    }
+
+    impl RamFlash {
+        pub fn new() -> Self {
+            Self {
+                memory: vec![0xFF; 64 * PAGE_SIZE as usize],
+                power: None,
+            }
+        }
+
+        fn index(address: u32) -> usize {
+            (address - FLASH_BASE) as usize
+        }
+
+        /// Spend one unit of power
+        fn spend(&mut self) -> Result<(), PowerCut> {
+            match &mut self.power {
+                Some(0) => Err(PowerCut),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Default for RamFlash {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = PowerCut;
+
+        fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), PowerCut> {
+            let start = Self::index(address);
+            buf.copy_from_slice(&self.memory[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn erase(&mut self, page: u32) -> Result<(), PowerCut> {
+            assert_eq!((page - FLASH_BASE) % PAGE_SIZE, 0, "not a page");
+            self.spend()?;
+            let start = Self::index(page);
+            self.memory[start..start + PAGE_SIZE as usize].iter_mut().for_each(|byte| *byte = 0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, address: u32, data: &[u8]) -> Result<(), PowerCut> {
+            assert_eq!((address as usize | data.len()) & 1, 0, "not whole half words");
+            let start = Self::index(address);
+            for (i, half) in data.chunks(2).enumerate() {
+                let at = start + 2 * i;
+                assert_eq!(self.memory[at..at + 2], [0xFF, 0xFF], "writing over {:#x}", address as usize + 2 * i);
+                self.spend()?;
+                self.memory[at..at + 2].copy_from_slice(half);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Channel hopping, so a busy Wi-Fi channel only costs us a few slots.
+//!
+//! Time is split into slots of [`DWELL`] ticks. Every slot the paired devices
+//! move to the next channel of a pseudo-random sequence seeded at pairing.
+//! The controller is the time master: it broadcasts [`Message::HopSync`]
+//! beacons with its current slot, which fixtures use to line up their own
+//! slot boundaries. A fixture that misses beacons for [`LOSS_SLOTS`] slots
+//! parks on the plan's fixed channel until it hears the controller again, so
+//! the controller also sends a beacon there every [`RENDEZVOUS_EVERY`] slots.
+//! After [`FALLBACK_SLOTS`] it spends every other slot on [`DEFAULT_CHANNEL`]
+//! too, where the controller also looks for fixtures to pair with, in case
+//! the controller picked another channel while the fixture was away.
+//!
+//! Ticks are CYCCNT cycles, which wrap; only differences between them are
+//! ever used.
+//!
+//! [`Message::HopSync`]: crate::protocol::Message::HopSync
+
+use crate::protocol::DecodeError;
+
+/// Channels the nRF24 can tune to. `n` is `2400 + n` MHz.
+pub const CHANNEL_COUNT: u8 = 126;
+/// Where unpaired fixtures wait to be paired, and the fixed and rendezvous
+/// channel until the controller picks another: 2476 MHz, above the top of
+/// Wi-Fi channel 11
+pub const DEFAULT_CHANNEL: u8 = 76;
+/// Lowest channel used for hopping
+pub const LOWEST_HOP_CHANNEL: u8 = 2;
+/// Highest channel used for hopping, the top of the 2.4 GHz ISM band
+pub const HIGHEST_HOP_CHANNEL: u8 = 81;
+/// Number of channels in a hop sequence
+pub const HOP_COUNT: usize = 16;
+
+/// How long we stay on each channel: 100 ms at 48 MHz
+pub const DWELL: u32 = 4_800_000;
+/// Slots without a beacon before a fixture considers itself lost
+pub const LOSS_SLOTS: u32 = 8;
+/// How often the controller also beacons on the rendezvous channel
+pub const RENDEZVOUS_EVERY: u32 = 4;
+/// Slots without a beacon before a lost fixture also listens on
+/// [`DEFAULT_CHANNEL`]: 10 s
+pub const FALLBACK_SLOTS: u32 = 100;
+/// Size of an encoded [`ChannelPlan`]
+pub const PLAN_SIZE: usize = 2;
+
+/// How the radio picks its channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelPlan {
+    /// The fixed channel, also where lost fixtures wait for a beacon
+    pub channel: u8,
+    pub hopping: bool,
+}
+
+impl ChannelPlan {
+    pub fn fixed(channel: u8) -> Self {
+        Self {
+            channel,
+            hopping: false,
+        }
+    }
+
+    pub fn hopping(rendezvous: u8) -> Self {
+        Self {
+            channel: rendezvous,
+            hopping: true,
+        }
+    }
+
+    /// Where a controller on this plan looks for fixtures to pair with: the
+    /// plan's own channel first, where fixtures that lost the controller wait,
+    /// then [`DEFAULT_CHANNEL`], where new ones do
+    pub fn pairing_channels(self) -> impl Iterator<Item = u8> {
+        let own = self.channel;
+        core::iter::once(own).chain(Some(DEFAULT_CHANNEL).filter(move |&channel| channel != own))
+    }
+
+    pub fn encode(&self) -> [u8; PLAN_SIZE] {
+        [self.channel, self.hopping as u8]
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        match *data {
+            [channel, _, ..] if channel >= CHANNEL_COUNT => Err(DecodeError::InvalidValue),
+            [channel, hopping, ..] => Ok(Self {
+                channel,
+                hopping: hopping != 0,
+            }),
+            _ => Err(DecodeError::TooShort),
+        }
+    }
+}
+
+impl Default for ChannelPlan {
+    fn default() -> Self {
+        Self::hopping(DEFAULT_CHANNEL)
+    }
+}
+
+/// A pseudo-random order of distinct channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HopSequence {
+    channels: [u8; HOP_COUNT],
+}
+
+impl HopSequence {
+    /// Shuffle the hop channels with xorshift seeded by `seed`
+    pub fn new(seed: u32) -> Self {
+        const SPAN: usize = (HIGHEST_HOP_CHANNEL - LOWEST_HOP_CHANNEL + 1) as usize;
+        let mut all = [0u8; SPAN];
+        for (i, channel) in all.iter_mut().enumerate() {
+            *channel = LOWEST_HOP_CHANNEL + i as u8;
+        }
+
+        // xorshift gets stuck on zero
+        let mut state = if seed == 0 { 0x9e37_79b9 } else { seed };
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        // Only the first HOP_COUNT entries of a Fisher-Yates shuffle are needed
+        for i in 0..HOP_COUNT {
+            let j = i + (random() as usize % (SPAN - i));
+            all.swap(i, j);
+        }
+        let mut channels = [0u8; HOP_COUNT];
+        channels.copy_from_slice(&all[..HOP_COUNT]);
+        Self { channels }
+    }
+
+    /// The channel used during `slot`
+    pub fn channel(&self, slot: u32) -> u8 {
+        self.channels[slot as usize % HOP_COUNT]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopState {
+    /// Following the hop sequence
+    Synced,
+    /// Waiting on the rendezvous channel for a beacon
+    Lost,
+}
+
+/// Tracks which channel we should be on at any moment
+#[derive(Debug, Clone, Copy)]
+pub struct Hopper {
+    plan: ChannelPlan,
+    sequence: HopSequence,
+    slot: u32,
+    slot_start: u32,
+    unsynced_slots: u32,
+    state: HopState,
+}
+
+impl Hopper {
+    /// A hopper that starts out lost, as fixtures do until the first beacon
+    pub fn new(plan: ChannelPlan, seed: u32, now: u32) -> Self {
+        Self {
+            plan,
+            sequence: HopSequence::new(seed),
+            slot: 0,
+            slot_start: now,
+            unsynced_slots: 0,
+            state: HopState::Lost,
+        }
+    }
+
+    /// A hopper that is the time master, as the controller is
+    pub fn master(plan: ChannelPlan, seed: u32, now: u32) -> Self {
+        let mut hopper = Self::new(plan, seed, now);
+        hopper.state = HopState::Synced;
+        hopper
+    }
+
+    pub fn plan(&self) -> ChannelPlan {
+        self.plan
+    }
+
+    pub fn state(&self) -> HopState {
+        self.state
+    }
+
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    /// Ticks since the current slot started
+    pub fn elapsed(&self, now: u32) -> u32 {
+        now.wrapping_sub(self.slot_start)
+    }
+
+    /// Switch to a new plan and seed, as after pairing
+    pub fn reseed(&mut self, plan: ChannelPlan, seed: u32) {
+        self.plan = plan;
+        self.sequence = HopSequence::new(seed);
+    }
+
+    /// The channel we should currently be on
+    pub fn channel(&self) -> u8 {
+        match (self.plan.hopping, self.state) {
+            (true, HopState::Synced) => self.sequence.channel(self.slot),
+            (_, HopState::Lost) if self.unsynced_slots > FALLBACK_SLOTS && self.slot % 2 == 1 => DEFAULT_CHANNEL,
+            _ => self.plan.channel,
+        }
+    }
+
+    /// Advance to `now`, returning the new channel if we have to retune
+    pub fn update(&mut self, now: u32, master: bool) -> Option<u8> {
+        let before = self.channel();
+        while self.elapsed(now) >= DWELL {
+            self.slot_start = self.slot_start.wrapping_add(DWELL);
+            self.slot = self.slot.wrapping_add(1);
+            if !master {
+                self.unsynced_slots = self.unsynced_slots.saturating_add(1);
+            }
+        }
+        if self.unsynced_slots > LOSS_SLOTS {
+            self.state = HopState::Lost;
+        }
+
+        let after = self.channel();
+        if after != before {
+            Some(after)
+        } else {
+            None
+        }
+    }
+
+    /// Line up with a beacon saying the master is `elapsed` ticks into `slot`
+    pub fn sync(&mut self, now: u32, slot: u32, elapsed: u32) -> Option<u8> {
+        let before = self.channel();
+        self.slot = slot;
+        self.slot_start = now.wrapping_sub(elapsed);
+        self.unsynced_slots = 0;
+        self.state = HopState::Synced;
+
+        let after = self.channel();
+        if after != before {
+            Some(after)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the master should also beacon on the rendezvous channel this slot
+    pub fn is_rendezvous_slot(&self) -> bool {
+        self.plan.hopping && self.slot.is_multiple_of(RENDEZVOUS_EVERY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairing_looks_on_the_rendezvous_channel_first() {
+        let channels: Vec<u8> = ChannelPlan::hopping(12).pairing_channels().collect();
+        assert_eq!(channels, [12, DEFAULT_CHANNEL]);
+        let channels: Vec<u8> = ChannelPlan::fixed(DEFAULT_CHANNEL).pairing_channels().collect();
+        assert_eq!(channels, [DEFAULT_CHANNEL]);
+    }
+
+    #[test]
+    fn lost_fixtures_also_wait_on_the_default_channel_eventually() {
+        let mut hopper = Hopper::new(ChannelPlan::hopping(12), 1, 0);
+        let mut channels = Vec::new();
+        for slot in 1..=FALLBACK_SLOTS + 4 {
+            hopper.update(slot * DWELL, false);
+            channels.push(hopper.channel());
+        }
+        assert!(channels[..FALLBACK_SLOTS as usize].iter().all(|&channel| channel == 12));
+        assert_eq!(channels[FALLBACK_SLOTS as usize..], [DEFAULT_CHANNEL, 12, DEFAULT_CHANNEL, 12]);
+
+        // Until a beacon comes in
+        hopper.sync((FALLBACK_SLOTS + 5) * DWELL, 7, 0);
+        assert_eq!(hopper.channel(), HopSequence::new(1).channel(7));
+        hopper.update((FALLBACK_SLOTS + 5 + LOSS_SLOTS + 1) * DWELL, false);
+        assert_eq!(hopper.state(), HopState::Lost);
+        assert_eq!(hopper.channel(), 12);
+    }
+
+    #[test]
+    fn plans_survive_encoding() {
+        for plan in [ChannelPlan::fixed(0), ChannelPlan::hopping(DEFAULT_CHANNEL), ChannelPlan::fixed(125)].iter() {
+            assert_eq!(ChannelPlan::decode(&plan.encode()), Ok(*plan));
+        }
+        assert_eq!(ChannelPlan::decode(&[CHANNEL_COUNT, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(ChannelPlan::decode(&[DEFAULT_CHANNEL]), Err(DecodeError::TooShort));
+    }
+}
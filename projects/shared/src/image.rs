@@ -0,0 +1,313 @@
+//! Firmware images, as stored in a flash slot.
+//!
+//! A slot starts with a header describing the image, and the image itself
+//! follows [`SLOT_HEADER_SIZE`] bytes in:
+//!
+//! ```text
+//! | magic | version | length | crc32 | nonce (8) | tag (16) |
+//! ```
+//!
+//! All fields are little endian. The CRC catches images damaged in transfer
+//! or in flash. The tag is a Poly1305 MAC over the rest of the header and the
+//! image, keyed from the site key and the nonce, so fixtures only install
+//! images built by someone who holds the site key.
+
+use crate::crypto::{self, Key, Poly1305, NONCE_SIZE};
+use crate::flash::{Flash, Region, SLOT_HEADER_SIZE};
+
+/// Marks the start of a valid header: "FLSH"
+pub const IMAGE_MAGIC: u32 = 0x4853_4C46;
+/// Size of an encoded header
+pub const IMAGE_HEADER_SIZE: usize = 40;
+/// Largest image that fits in a slot
+pub const MAX_IMAGE_SIZE: u32 = crate::flash::APP_SLOT.size - SLOT_HEADER_SIZE;
+
+/// Bytes of the header covered by the tag
+const SIGNED_SIZE: usize = 24;
+
+/// Why an image was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// The slot doesn't start with a header
+    BadMagic,
+    /// The header claims more than a slot holds
+    TooLarge,
+    /// The image doesn't match its CRC
+    BadCrc,
+    /// The tag doesn't match, so the image wasn't built with our site key
+    BadSignature,
+}
+
+/// Errors while checking an image in flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError<E> {
+    Image(ImageError),
+    Flash(E),
+}
+
+impl<E> From<ImageError> for VerifyError<E> {
+    fn from(e: ImageError) -> Self {
+        VerifyError::Image(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub version: u32,
+    /// Size of the image, without the header
+    pub length: u32,
+    pub crc: u32,
+    /// Makes the key of every image's tag unique
+    pub nonce: [u8; 8],
+    pub tag: [u8; 16],
+}
+
+impl ImageHeader {
+    /// Describe and sign `image`, `nonce` has to be unpredictable
+    pub fn sign(site_key: &Key, version: u32, nonce: [u8; 8], image: &[u8]) -> Self {
+        let mut header = Self {
+            version,
+            length: image.len() as u32,
+            crc: crc32(image),
+            nonce,
+            tag: [0; 16],
+        };
+        let mut mac = header.mac(site_key);
+        mac.update(image);
+        header.tag = mac.finalize();
+        header
+    }
+
+    pub fn encode(&self) -> [u8; IMAGE_HEADER_SIZE] {
+        let mut out = [0u8; IMAGE_HEADER_SIZE];
+        out[..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        out[4..8].copy_from_slice(&self.version.to_le_bytes());
+        out[8..12].copy_from_slice(&self.length.to_le_bytes());
+        out[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        out[16..24].copy_from_slice(&self.nonce);
+        out[24..40].copy_from_slice(&self.tag);
+        out
+    }
+
+    pub fn decode(data: &[u8; IMAGE_HEADER_SIZE]) -> Result<Self, ImageError> {
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        if word(0) != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let length = word(8);
+        if length > MAX_IMAGE_SIZE {
+            return Err(ImageError::TooLarge);
+        }
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&data[16..24]);
+        let mut tag = [0u8; 16];
+        tag.copy_from_slice(&data[24..40]);
+        Ok(Self {
+            version: word(4),
+            length,
+            crc: word(12),
+            nonce,
+            tag,
+        })
+    }
+
+    /// A MAC keyed for this header, fed with the signed part of it
+    fn mac(&self, site_key: &Key) -> Poly1305 {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&self.nonce);
+        let block = crypto::chacha20_block(&crypto::firmware_key(site_key), 0, &nonce);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&block[..32]);
+        let mut mac = Poly1305::new(&key);
+        mac.update(&self.encode()[..SIGNED_SIZE]);
+        mac
+    }
+}
+
+/// Check the image in `slot` against its header, and its tag too if
+/// `site_key` is given
+pub fn verify<F: Flash>(
+    flash: &mut F,
+    slot: Region,
+    site_key: Option<&Key>,
+) -> Result<ImageHeader, VerifyError<F::Error>> {
+    let header = read_header(flash, slot)?;
+    let mut crc = Crc32::new();
+    let mut mac = site_key.map(|key| header.mac(key));
+
+    let mut buf = [0u8; 64];
+    let start = slot.start + SLOT_HEADER_SIZE;
+    let mut offset = 0;
+    while offset < header.length {
+        let n = (header.length - offset).min(buf.len() as u32) as usize;
+        flash.read(start + offset, &mut buf[..n]).map_err(VerifyError::Flash)?;
+        crc.update(&buf[..n]);
+        if let Some(mac) = mac.as_mut() {
+            mac.update(&buf[..n]);
+        }
+        offset += n as u32;
+    }
+
+    if crc.finalize() != header.crc {
+        return Err(ImageError::BadCrc.into());
+    }
+    if let Some(mac) = mac {
+        // Compare in constant time
+        let diff = mac
+            .finalize()
+            .iter()
+            .zip(header.tag.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return Err(ImageError::BadSignature.into());
+        }
+    }
+    Ok(header)
+}
+
+/// The header at the start of `slot`, without checking the image
+pub fn read_header<F: Flash>(flash: &mut F, slot: Region) -> Result<ImageHeader, VerifyError<F::Error>> {
+    let mut data = [0u8; IMAGE_HEADER_SIZE];
+    flash.read(slot.start, &mut data).map_err(VerifyError::Flash)?;
+    Ok(ImageHeader::decode(&data)?)
+}
+
+/// CRC-32 as used by zlib and Ethernet, computed a nibble at a time to keep
+/// the table small
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+const CRC_TABLE: [u32; 16] = [
+    0x0000_0000, 0x1DB7_1064, 0x3B6E_20C8, 0x26D9_30AC, 0x76DC_4190, 0x6B6B_51F4, 0x4DB2_6158, 0x5005_713C,
+    0xEDB8_8320, 0xF00F_9344, 0xD6D6_A3E8, 0xCB61_B38C, 0x9B64_C2B0, 0x86D3_D2D4, 0xA00A_E278, 0xBDBD_F21C,
+];
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= byte as u32;
+            self.state = CRC_TABLE[(self.state & 0xF) as usize] ^ (self.state >> 4);
+            self.state = CRC_TABLE[(self.state & 0xF) as usize] ^ (self.state >> 4);
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::ram::RamFlash;
+    use crate::flash::STAGING_SLOT;
+
+    const SITE_KEY: Key = [7; 32];
+
+    fn image() -> Vec<u8> {
+        (0..3000u32).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    /// A flash with `header` and `image` in the staging slot
+    fn slot(header: &[u8], image: &[u8]) -> RamFlash {
+        let mut flash = RamFlash::new();
+        flash.write(STAGING_SLOT.start, header).unwrap();
+        flash.write(STAGING_SLOT.start + SLOT_HEADER_SIZE, image).unwrap();
+        flash
+    }
+
+    fn signed() -> ImageHeader {
+        ImageHeader::sign(&SITE_KEY, 3, [1, 2, 3, 4, 5, 6, 7, 8], &image())
+    }
+
+    #[test]
+    fn signed_images_verify() {
+        let header = signed();
+        assert_eq!(ImageHeader::decode(&header.encode()), Ok(header));
+        let mut flash = slot(&header.encode(), &image());
+        assert_eq!(verify(&mut flash, STAGING_SLOT, Some(&SITE_KEY)), Ok(header));
+        assert_eq!(verify(&mut flash, STAGING_SLOT, None), Ok(header));
+        assert_eq!(read_header(&mut flash, STAGING_SLOT), Ok(header));
+    }
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn headers_without_the_magic_are_refused() {
+        let mut data = signed().encode();
+        data[0] ^= 1;
+        assert_eq!(ImageHeader::decode(&data), Err(ImageError::BadMagic));
+        // An erased slot
+        let mut flash = RamFlash::new();
+        assert_eq!(verify(&mut flash, STAGING_SLOT, None), Err(VerifyError::Image(ImageError::BadMagic)));
+    }
+
+    #[test]
+    fn images_larger_than_a_slot_are_refused() {
+        let mut header = signed();
+        header.length = MAX_IMAGE_SIZE;
+        assert_eq!(ImageHeader::decode(&header.encode()).map(|h| h.length), Ok(MAX_IMAGE_SIZE));
+        header.length = MAX_IMAGE_SIZE + 1;
+        assert_eq!(ImageHeader::decode(&header.encode()), Err(ImageError::TooLarge));
+        let mut flash = slot(&header.encode(), &image());
+        assert_eq!(verify(&mut flash, STAGING_SLOT, None), Err(VerifyError::Image(ImageError::TooLarge)));
+    }
+
+    #[test]
+    fn damaged_images_fail_the_crc() {
+        let mut image = image();
+        image[1234] ^= 0x10;
+        let mut flash = slot(&signed().encode(), &image);
+        assert_eq!(verify(&mut flash, STAGING_SLOT, None), Err(VerifyError::Image(ImageError::BadCrc)));
+
+        // Or a header claiming a different length
+        let mut header = signed();
+        header.length -= 1;
+        let mut flash = slot(&header.encode(), &self::image());
+        assert_eq!(verify(&mut flash, STAGING_SLOT, None), Err(VerifyError::Image(ImageError::BadCrc)));
+    }
+
+    #[test]
+    fn tampering_with_a_signed_image_is_caught() {
+        let bad = Err(VerifyError::Image(ImageError::BadSignature));
+
+        let mut header = signed();
+        header.tag[5] ^= 1;
+        assert_eq!(verify(&mut slot(&header.encode(), &image()), STAGING_SLOT, Some(&SITE_KEY)), bad);
+        // Only the CRC is checked without a key
+        assert_eq!(verify(&mut slot(&header.encode(), &image()), STAGING_SLOT, None), Ok(header));
+
+        // A newer version number on an old image
+        let mut header = signed();
+        header.version += 1;
+        assert_eq!(verify(&mut slot(&header.encode(), &image()), STAGING_SLOT, Some(&SITE_KEY)), bad);
+
+        // A different image with a matching CRC, signed by someone else
+        let mut image = image();
+        image[0] ^= 0xff;
+        let forged = ImageHeader::sign(&[8; 32], 3, [1, 2, 3, 4, 5, 6, 7, 8], &image);
+        assert_eq!(verify(&mut slot(&forged.encode(), &image), STAGING_SLOT, Some(&SITE_KEY)), bad);
+    }
+}
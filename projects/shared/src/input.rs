@@ -0,0 +1,307 @@
+//! Turning raw control readings into events: button gestures, encoder
+//! detents and steady potentiometer levels.
+//!
+//! Nothing here touches hardware. Callers sample their inputs at a steady
+//! rate and pass the readings in with the time in milliseconds.
+
+/// Samples a button has to read the same before it counts as changed
+pub const DEBOUNCE_SAMPLES: u8 = 4;
+/// Holding a button this long is a long press, in ms
+pub const LONG_PRESS: u32 = 600;
+/// A second press within this long of releasing the first is a double press, in ms
+pub const DOUBLE_PRESS: u32 = 300;
+/// Quadrature counts between two detents of the encoder
+pub const COUNTS_PER_DETENT: i16 = 4;
+/// Share of each new potentiometer sample in its average, as a right shift
+pub const SMOOTHING: u32 = 3;
+/// How far a potentiometer level has to move before it's reported
+pub const HYSTERESIS: u8 = 2;
+/// Largest reading of the 12 bit ADC
+pub const ADC_MAX: u32 = 4095;
+
+/// What a button did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// Pressed and released once, reported once it can't be a double press anymore
+    Short,
+    /// Held down, reported while still held
+    Long,
+    /// Pressed twice in quick succession
+    Double,
+}
+
+/// Ignores bounces shorter than [`DEBOUNCE_SAMPLES`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Debouncer {
+    state: bool,
+    count: u8,
+}
+
+impl Debouncer {
+    pub const fn new() -> Self {
+        Self { state: false, count: 0 }
+    }
+
+    /// Feed a raw reading, returning the debounced state
+    pub fn update(&mut self, raw: bool) -> bool {
+        if raw == self.state {
+            self.count = 0;
+        } else {
+            self.count += 1;
+            if self.count >= DEBOUNCE_SAMPLES {
+                self.state = raw;
+                self.count = 0;
+            }
+        }
+        self.state
+    }
+}
+
+/// A debounced button that tells short, long and double presses apart
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Button {
+    debouncer: Debouncer,
+    /// When the button went down, while it's down
+    pressed_at: Option<u32>,
+    /// When a short press was released, while waiting for a second one
+    released_at: Option<u32>,
+    /// The current press already counts as long or as the second of a double
+    handled: bool,
+    second: bool,
+}
+
+impl Button {
+    pub const fn new() -> Self {
+        Self {
+            debouncer: Debouncer::new(),
+            pressed_at: None,
+            released_at: None,
+            handled: false,
+            second: false,
+        }
+    }
+
+    /// Feed a raw reading taken at `now` ms, `true` meaning pressed
+    pub fn update(&mut self, raw: bool, now: u32) -> Option<Gesture> {
+        let pressed = self.debouncer.update(raw);
+        match (pressed, self.pressed_at) {
+            (true, None) => {
+                self.pressed_at = Some(now);
+                self.handled = false;
+                match self.released_at.take() {
+                    Some(released) if now.wrapping_sub(released) <= DOUBLE_PRESS => {
+                        self.second = true;
+                        None
+                    }
+                    // Too late to be the second press of a double
+                    Some(_) => Some(Gesture::Short),
+                    None => None,
+                }
+            }
+            (true, Some(at)) => {
+                if !self.handled && !self.second && now.wrapping_sub(at) >= LONG_PRESS {
+                    self.handled = true;
+                    Some(Gesture::Long)
+                } else {
+                    None
+                }
+            }
+            (false, Some(_)) => {
+                self.pressed_at = None;
+                if self.second {
+                    self.second = false;
+                    Some(Gesture::Double)
+                } else {
+                    if !self.handled {
+                        self.released_at = Some(now);
+                    }
+                    None
+                }
+            }
+            (false, None) => match self.released_at {
+                Some(released) if now.wrapping_sub(released) > DOUBLE_PRESS => {
+                    self.released_at = None;
+                    Some(Gesture::Short)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Detents turned, from the counter of a timer in encoder mode
+#[derive(Debug, Clone, Copy)]
+pub struct Encoder {
+    last: u16,
+    counts: i16,
+}
+
+impl Encoder {
+    /// Start from the counter's current value
+    pub fn new(count: u16) -> Self {
+        Self { last: count, counts: 0 }
+    }
+
+    /// Detents turned since the last reading, positive clockwise
+    pub fn update(&mut self, count: u16) -> i16 {
+        let delta = count.wrapping_sub(self.last) as i16;
+        self.last = count;
+        self.counts = self.counts.saturating_add(delta);
+        let detents = self.counts / COUNTS_PER_DETENT;
+        self.counts -= detents * COUNTS_PER_DETENT;
+        detents
+    }
+}
+
+/// Averages potentiometer readings and only reports real movement
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Smoother {
+    /// Running average in 1/16 of an ADC step
+    average: Option<u32>,
+    level: u8,
+}
+
+impl Smoother {
+    pub const fn new() -> Self {
+        Self { average: None, level: 0 }
+    }
+
+    /// The last level reported
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Feed a 12 bit reading, returning the new level from 0 to 255 when it moved
+    pub fn update(&mut self, sample: u16) -> Option<u8> {
+        let sample = (sample as u32).min(ADC_MAX) << 4;
+        let average = match self.average {
+            // Steps round up, so the average settles on the reading rather
+            // than short of it and the ends can be reached
+            Some(average) => {
+                if sample >= average {
+                    average + (sample - average).div_ceil(1 << SMOOTHING)
+                } else {
+                    average - (average - sample).div_ceil(1 << SMOOTHING)
+                }
+            }
+            None => sample,
+        };
+        self.average = Some(average);
+
+        let level = ((average >> 4) * 255 / ADC_MAX) as u8;
+        let moved = level.abs_diff(self.level);
+        // Let the ends through, so the full range can be reached
+        if moved >= HYSTERESIS || (moved > 0 && (level == 0 || level == u8::MAX)) {
+            self.level = level;
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hold the button at `raw` from `from` to `to` ms, sampled every ms,
+    /// returning the gestures and when they came
+    fn hold(button: &mut Button, raw: bool, from: u32, to: u32) -> Vec<(u32, Gesture)> {
+        (from..to).filter_map(|now| button.update(raw, now).map(|gesture| (now, gesture))).collect()
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let mut debouncer = Debouncer::new();
+        for &raw in &[true, true, false, true, true, true, false] {
+            assert!(!debouncer.update(raw));
+        }
+        for _ in 1..DEBOUNCE_SAMPLES {
+            assert!(!debouncer.update(true));
+        }
+        assert!(debouncer.update(true));
+    }
+
+    #[test]
+    fn short_presses_wait_out_the_double_press() {
+        let mut button = Button::new();
+        assert!(hold(&mut button, true, 0, 100).is_empty());
+        let gestures = hold(&mut button, false, 100, 1000);
+        assert_eq!(gestures.len(), 1);
+        let (at, gesture) = gestures[0];
+        assert_eq!(gesture, Gesture::Short);
+        assert!(at > 100 + DOUBLE_PRESS, "reported at {}", at);
+    }
+
+    #[test]
+    fn long_presses_come_while_held_and_once() {
+        let mut button = Button::new();
+        let gestures = hold(&mut button, true, 0, 2000);
+        assert_eq!(gestures.len(), 1);
+        assert_eq!(gestures[0].1, Gesture::Long);
+        assert!(gestures[0].0 >= LONG_PRESS);
+        // Letting go of a long press is no short press
+        assert!(hold(&mut button, false, 2000, 3000).is_empty());
+    }
+
+    #[test]
+    fn double_presses_come_on_the_second_release() {
+        let mut button = Button::new();
+        assert!(hold(&mut button, true, 0, 50).is_empty());
+        assert!(hold(&mut button, false, 50, 150).is_empty());
+        // Holding the second press is no long press
+        assert!(hold(&mut button, true, 150, 1000).is_empty());
+        let gestures = hold(&mut button, false, 1000, 2000);
+        assert_eq!(gestures.iter().map(|&(_, gesture)| gesture).collect::<Vec<_>>(), [Gesture::Double]);
+    }
+
+    #[test]
+    fn late_second_presses_are_two_short_ones() {
+        let mut button = Button::new();
+        hold(&mut button, true, 0, 50);
+        assert!(hold(&mut button, false, 50, 60).is_empty());
+        // Not sampled for a while, so the first press is only reported when
+        // the second comes down
+        let gestures = hold(&mut button, true, 60 + DOUBLE_PRESS + 100, 60 + DOUBLE_PRESS + 150);
+        assert_eq!(gestures.iter().map(|&(_, gesture)| gesture).collect::<Vec<_>>(), [Gesture::Short]);
+        let gestures = hold(&mut button, false, 1000, 2000);
+        assert_eq!(gestures.iter().map(|&(_, gesture)| gesture).collect::<Vec<_>>(), [Gesture::Short]);
+    }
+
+    #[test]
+    fn encoders_count_detents_across_the_wrap() {
+        let mut encoder = Encoder::new(u16::MAX - 1);
+        assert_eq!(encoder.update(2), 1);
+        assert_eq!(encoder.update(u16::MAX - 5), -2);
+        // Part of a detent is kept for the next reading
+        assert_eq!(encoder.update(u16::MAX - 2), 0);
+        assert_eq!(encoder.update(u16::MAX - 1), 1);
+        assert_eq!(encoder.update(u16::MAX - 1), 0);
+    }
+
+    #[test]
+    fn smoothed_levels_reach_both_ends() {
+        let mut smoother = Smoother::new();
+        assert_eq!(smoother.update(0), None);
+        let levels: Vec<u8> = (0..200).filter_map(|_| smoother.update(ADC_MAX as u16)).collect();
+        assert_eq!(levels.last(), Some(&u8::MAX));
+        assert_eq!(smoother.level(), u8::MAX);
+        for pair in levels[..levels.len() - 1].windows(2) {
+            assert!(pair[1] - pair[0] >= HYSTERESIS, "{:?}", pair);
+        }
+        let levels: Vec<u8> = (0..200).filter_map(|_| smoother.update(0)).collect();
+        assert_eq!(levels.last(), Some(&0));
+        // Readings past the ADC's range are taken as its top
+        assert_eq!(Smoother::new().update(u16::MAX), Some(u8::MAX));
+    }
+
+    #[test]
+    fn jitter_is_not_reported() {
+        let mut smoother = Smoother::new();
+        let level = smoother.update(2048).unwrap();
+        for i in 0..100 {
+            assert_eq!(smoother.update(if i % 2 == 0 { 2040 } else { 2056 }), None);
+        }
+        assert_eq!(smoother.level(), level);
+    }
+}
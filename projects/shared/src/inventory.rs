@@ -0,0 +1,410 @@
+//! Finding out which fixtures are out there, and keeping track of them.
+//!
+//! The controller broadcasts [`Message::Discover`] with a window in ms. Every
+//! fixture that hears it waits a random part of the window, so they don't
+//! all answer at once, then answers with a [`Message::Announce`] describing
+//! itself, sealed for whoever asked:
+//!
+//! ```text
+//! | unique id (8, LE) | firmware (2, LE) | LEDs (2, LE) | chipset << 4 | layout | capabilities |
+//! ```
+//!
+//! The unique id is the microcontroller's own, so a fixture is recognised
+//! even if its node id changes. Discovery is repeated every so often and the
+//! answers double as heartbeats: an [`Inventory`] counts the rounds each
+//! fixture missed, and has it offline after [`MISSED_ROUNDS`] in a row.
+//!
+//! [`Message::Discover`]: crate::protocol::Message::Discover
+//! [`Message::Announce`]: crate::protocol::Message::Announce
+
+use crate::address::NodeId;
+use crate::protocol::DecodeError;
+use core::fmt;
+
+/// Size of an encoded [`FixtureInfo`]
+pub const INFO_SIZE: usize = 14;
+/// How long fixtures spread their answers over, unless told otherwise, in ms
+pub const DISCOVER_WINDOW: u16 = 50;
+/// Longest window a fixture waits out, so a stray discover can't hold its
+/// answer back for long
+pub const MAX_DISCOVER_WINDOW: u16 = 1_000;
+/// Rounds in a row a fixture may miss before it's offline
+pub const MISSED_ROUNDS: u8 = 3;
+/// Fixtures an [`Inventory`] keeps track of
+pub const MAX_FIXTURES: usize = 16;
+
+/// The driver chip of a fixture's LEDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chipset {
+    Ws2812,
+    Sk6812,
+    Apa102,
+}
+
+impl Chipset {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Chipset::Ws2812),
+            1 => Some(Chipset::Sk6812),
+            2 => Some(Chipset::Apa102),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Chipset::Ws2812 => 0,
+            Chipset::Sk6812 => 1,
+            Chipset::Apa102 => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Chipset::Ws2812 => "ws2812",
+            Chipset::Sk6812 => "sk6812",
+            Chipset::Apa102 => "apa102",
+        }
+    }
+}
+
+/// How a fixture's LEDs are arranged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Strip,
+    Ring,
+    Matrix,
+}
+
+impl Layout {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Layout::Strip),
+            1 => Some(Layout::Ring),
+            2 => Some(Layout::Matrix),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Layout::Strip => 0,
+            Layout::Ring => 1,
+            Layout::Matrix => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Strip => "strip",
+            Layout::Ring => "ring",
+            Layout::Matrix => "matrix",
+        }
+    }
+}
+
+/// What a fixture can do besides showing effects, a bit each
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// Forwards frames for fixtures out of the controller's range
+    pub const RELAY: Self = Self(0b0000_0001);
+    /// Takes DMX512 on a cable
+    pub const DMX: Self = Self(0b0000_0010);
+    /// Shows streamed pixels
+    pub const STREAM: Self = Self(0b0000_0100);
+    /// Takes firmware updates over the air
+    pub const OTA: Self = Self(0b0000_1000);
+    /// Dims its LEDs to stay within a power budget
+    pub const POWER_LIMIT: Self = Self(0b0001_0000);
+
+    /// Each capability and what it's called
+    pub const ALL: [(Self, &'static str); 5] = [
+        (Self::RELAY, "relay"),
+        (Self::DMX, "dmx"),
+        (Self::STREAM, "stream"),
+        (Self::OTA, "ota"),
+        (Self::POWER_LIMIT, "power-limit"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Bits we don't know about yet are kept
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The names of the capabilities we know about
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::ALL.iter().filter(move |(capability, _)| self.contains(*capability)).map(|(_, name)| *name)
+    }
+}
+
+/// What a fixture tells about itself when discovered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixtureInfo {
+    /// The microcontroller's unique id, folded into 64 bits
+    pub uid: u64,
+    /// Version of the image it runs, 0 if it was loaded by a debugger
+    pub firmware: u16,
+    pub leds: u16,
+    pub chipset: Chipset,
+    pub layout: Layout,
+    pub capabilities: Capabilities,
+}
+
+impl FixtureInfo {
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[..8].copy_from_slice(&self.uid.to_le_bytes());
+        out[8..10].copy_from_slice(&self.firmware.to_le_bytes());
+        out[10..12].copy_from_slice(&self.leds.to_le_bytes());
+        out[12] = self.chipset.to_byte() << 4 | self.layout.to_byte();
+        out[13] = self.capabilities.bits();
+        INFO_SIZE
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < INFO_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let mut uid = [0u8; 8];
+        uid.copy_from_slice(&data[..8]);
+        Ok(Self {
+            uid: u64::from_le_bytes(uid),
+            firmware: u16::from_le_bytes([data[8], data[9]]),
+            leds: u16::from_le_bytes([data[10], data[11]]),
+            chipset: Chipset::from_byte(data[12] >> 4).ok_or(DecodeError::InvalidValue)?,
+            layout: Layout::from_byte(data[12] & 0x0F).ok_or(DecodeError::InvalidValue)?,
+            capabilities: Capabilities::from_bits(data[13]),
+        })
+    }
+}
+
+impl fmt::Display for FixtureInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:016x}, firmware {}, {} {} LEDs in a {}",
+            self.uid,
+            self.firmware,
+            self.leds,
+            self.chipset.name(),
+            self.layout.name()
+        )?;
+        for name in self.capabilities.names() {
+            write!(f, ", {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to hold an answer back for a discover over `window` ms, in
+/// ticks: a random part of the window, which is clamped to
+/// [`MAX_DISCOVER_WINDOW`]
+pub fn backoff(window: u16, random: u32, ticks_per_ms: u32) -> u32 {
+    random % (window.min(MAX_DISCOVER_WINDOW) as u32 * ticks_per_ms).max(1)
+}
+
+/// How an answer changed what we know about a fixture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// We hadn't heard of it
+    Found,
+    /// It was offline
+    Online,
+    /// Another fixture took its node id, or it was updated
+    Replaced,
+}
+
+/// A fixture we've heard from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub id: NodeId,
+    pub info: FixtureInfo,
+    pub online: bool,
+    /// Rounds missed in a row
+    pub missed: u8,
+    answered: bool,
+}
+
+/// Every fixture discovered, and whether it still answers
+#[derive(Debug, Clone, Copy)]
+pub struct Inventory {
+    entries: [Option<Entry>; MAX_FIXTURES],
+}
+
+impl Inventory {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_FIXTURES],
+        }
+    }
+
+    /// Record an answer from fixture `id`, returning what changed. Fixtures
+    /// past [`MAX_FIXTURES`] are left out.
+    pub fn seen(&mut self, id: NodeId, info: FixtureInfo) -> Option<Change> {
+        if let Some(entry) = self.entries.iter_mut().flatten().find(|entry| entry.id == id) {
+            let change = if entry.info != info {
+                Some(Change::Replaced)
+            } else if !entry.online {
+                Some(Change::Online)
+            } else {
+                None
+            };
+            *entry = Entry { id, info, online: true, missed: 0, answered: true };
+            return change;
+        }
+        let slot = self.entries.iter_mut().find(|entry| entry.is_none())?;
+        *slot = Some(Entry { id, info, online: true, missed: 0, answered: true });
+        Some(Change::Found)
+    }
+
+    /// Close a round of discovery, calling `offline` for each fixture that
+    /// just missed one round too many
+    pub fn end_round(&mut self, mut offline: impl FnMut(&Entry)) {
+        for entry in self.entries.iter_mut().flatten() {
+            if !entry.answered {
+                entry.missed = entry.missed.saturating_add(1);
+                if entry.online && entry.missed >= MISSED_ROUNDS {
+                    entry.online = false;
+                    offline(entry);
+                }
+            }
+            entry.answered = false;
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Entry> {
+        self.iter().find(|entry| entry.id == id)
+    }
+
+    /// Known fixtures, in the order they were found
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn online(&self) -> usize {
+        self.iter().filter(|entry| entry.online).count()
+    }
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(uid: u64) -> FixtureInfo {
+        FixtureInfo {
+            uid,
+            firmware: 3,
+            leds: 60,
+            chipset: Chipset::Sk6812,
+            layout: Layout::Ring,
+            capabilities: Capabilities::RELAY.with(Capabilities::OTA),
+        }
+    }
+
+    #[test]
+    fn info_survives_a_round_trip() {
+        let info = FixtureInfo { capabilities: Capabilities::from_bits(0xFF), ..info(0x0123_4567_89AB_CDEF) };
+        let mut buffer = [0; INFO_SIZE];
+        assert_eq!(info.encode(&mut buffer), INFO_SIZE);
+        assert_eq!(buffer[12], 0x11);
+        assert_eq!(FixtureInfo::decode(&buffer), Ok(info));
+        assert_eq!(FixtureInfo::decode(&buffer[..INFO_SIZE - 1]), Err(DecodeError::TooShort));
+    }
+
+    #[test]
+    fn unknown_chipsets_and_layouts_are_refused() {
+        let mut buffer = [0; INFO_SIZE];
+        info(1).encode(&mut buffer);
+        for &byte in &[0x31, 0x13, 0xF0] {
+            buffer[12] = byte;
+            assert_eq!(FixtureInfo::decode(&buffer), Err(DecodeError::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn info_shows_its_capabilities() {
+        assert_eq!(info(0xAB).to_string(), "00000000000000ab, firmware 3, 60 sk6812 LEDs in a ring, relay, ota");
+    }
+
+    #[test]
+    fn answers_tell_what_changed() {
+        let mut inventory = Inventory::new();
+        assert_eq!(inventory.seen(4, info(1)), Some(Change::Found));
+        assert_eq!(inventory.seen(4, info(1)), None);
+        assert_eq!(inventory.seen(4, info(2)), Some(Change::Replaced));
+        assert_eq!(inventory.seen(7, info(1)), Some(Change::Found));
+        let ids: Vec<NodeId> = inventory.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [4, 7]);
+        assert_eq!(inventory.get(4).unwrap().info, info(2));
+        assert_eq!(inventory.online(), 2);
+    }
+
+    #[test]
+    fn fixtures_go_offline_after_missing_rounds() {
+        let mut inventory = Inventory::new();
+        inventory.seen(1, info(1));
+        inventory.seen(2, info(2));
+        inventory.end_round(|_| panic!("both answered"));
+
+        let mut offline = Vec::new();
+        for _ in 0..MISSED_ROUNDS {
+            inventory.seen(2, info(2));
+            inventory.end_round(|entry| offline.push(entry.id));
+        }
+        assert_eq!(offline, [1]);
+        assert_eq!(inventory.get(1).map(|entry| (entry.online, entry.missed)), Some((false, MISSED_ROUNDS)));
+        assert_eq!(inventory.online(), 1);
+
+        // Going offline is only reported once
+        inventory.end_round(|entry| offline.push(entry.id));
+        assert_eq!(offline, [1]);
+        assert_eq!(inventory.seen(1, info(1)), Some(Change::Online));
+        assert_eq!(inventory.get(1).map(|entry| (entry.online, entry.missed)), Some((true, 0)));
+    }
+
+    #[test]
+    fn fixtures_past_the_table_are_left_out() {
+        let mut inventory = Inventory::new();
+        for id in 0..MAX_FIXTURES as NodeId {
+            assert_eq!(inventory.seen(id, info(id as u64)), Some(Change::Found));
+        }
+        assert_eq!(inventory.seen(MAX_FIXTURES as NodeId, info(0)), None);
+        assert!(inventory.get(MAX_FIXTURES as NodeId).is_none());
+        assert_eq!(inventory.online(), MAX_FIXTURES);
+    }
+
+    #[test]
+    fn backoff_stays_within_the_window() {
+        assert_eq!(backoff(50, 0, 1_000), 0);
+        assert_eq!(backoff(50, 49_999, 1_000), 49_999);
+        assert_eq!(backoff(50, 50_000, 1_000), 0);
+        // However long a window is asked for
+        for &random in &[0, 12_345, u32::MAX] {
+            assert!(backoff(u16::MAX, random, 48_000) < MAX_DISCOVER_WINDOW as u32 * 48_000);
+        }
+        assert_eq!(backoff(0, u32::MAX, 48_000), 0);
+    }
+}
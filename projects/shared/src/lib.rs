@@ -1,5 +1,5 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 /// Copyright (c) 2016 Josh Robson Chase 
 /// Permission is hereby granted, free of charge, to any person obtaining a copy
@@ -18,9 +18,11 @@
 /// THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 
+#[cfg(feature = "device")]
 const LED_COUNT: usize = 32;
 
 use smart_leds::RGB8;
+#[cfg(feature = "device")]
 use stm32f1xx_futures::hal::{
     prelude::*,
     pac::{SPI1},
@@ -32,9 +34,41 @@ use stm32f1xx_futures::hal::{
     },
     gpio::{gpioa::{PA5, PA6, PA7}, Input, PushPull, Alternate, Floating},
 };
+#[cfg(feature = "device")]
 use cortex_m::singleton;
+#[cfg(feature = "device")]
 use as_slice::AsSlice;
 
+pub mod address;
+pub mod bindings;
+pub mod boot;
+pub mod cli;
+pub mod cobs;
+pub mod crypto;
+pub mod cue;
+pub mod dmx;
+pub mod dongle;
+pub mod fixture;
+pub mod flash;
+pub mod hopping;
+pub mod image;
+pub mod input;
+pub mod inventory;
+pub mod mesh;
+pub mod ota;
+pub mod protocol;
+pub mod radio;
+pub mod scan;
+pub mod scene;
+pub mod secure;
+pub mod settings;
+pub mod status;
+pub mod stream;
+pub mod telemetry;
+pub mod timesync;
+#[cfg(feature = "device")]
+pub mod usb;
+
 /// Trait for a struct that can drive an RGB led strip
 pub trait RgbDriver {
     /// Prepare to set the colour of the specified LED
@@ -44,6 +78,7 @@ pub trait RgbDriver {
 }
 
 
+#[cfg(feature = "device")]
 type Pins = (
     PA5<Alternate<PushPull>>,
     PA6<Input<Floating>>,
@@ -53,6 +88,7 @@ type Pins = (
 /**
   Poor man's const generics ;)
 */
+#[cfg(feature = "device")]
 macro_rules! spi_bit_container {
     ($name:ident, $led_amount:expr) => {
         struct $name {
@@ -90,8 +126,10 @@ macro_rules! spi_bit_container {
     }
 }
 
+#[cfg(feature = "device")]
 spi_bit_container!(RgbBitContainer, LED_COUNT);
 
+#[cfg(feature = "device")]
 impl AsSlice for RgbBitContainer {
     type Element = u8;
     fn as_slice(&self) -> &[u8] {
@@ -99,12 +137,14 @@ impl AsSlice for RgbBitContainer {
     }
 }
 
+#[cfg(feature = "device")]
 pub struct Ws2812Driver {
     spi: Option<SpiTxDma<SPI1, Pins, C3>>,
     led_data: [RGB8; LED_COUNT],
     bit_storage: Option<&'static mut RgbBitContainer>
 }
 
+#[cfg(feature = "device")]
 impl Ws2812Driver {
     pub fn new(spi: SpiTxDma<SPI1, Pins, C3>) -> Self {
         Self {
@@ -117,6 +157,7 @@ impl Ws2812Driver {
     }
 }
 
+#[cfg(feature = "device")]
 impl RgbDriver for Ws2812Driver {
     fn prepare_color(&mut self, index: usize, color: RGB8) {
         // TODO: Handle out of bounds
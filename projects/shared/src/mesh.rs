@@ -0,0 +1,583 @@
+//! Multi-hop relaying, for fixtures out of the controller's range.
+//!
+//! Every frame on air ends with a two byte trailer, outside of what
+//! [`crate::secure`] authenticates:
+//!
+//! ```text
+//! | frame ... | ttl | via |
+//! ```
+//!
+//! `ttl` is how many more times the frame may be forwarded and `via` the node
+//! that put it on air last. Relays forward frames that aren't only for them
+//! with the trailer updated and the frame itself untouched, so the original
+//! sender's seal still holds.
+//!
+//! Every node learns a route back to each sender it hears from the `via` of
+//! its frames, once [`crate::secure`] accepted them, see [`Mesh::learn`].
+//! Anyone can rewrite a trailer, so `via` is only believed when it names a
+//! neighbour we heard an authenticated frame from directly. Unicast frames
+//! follow a learned route when there is one and are flooded otherwise, group
+//! and broadcast frames are always flooded.
+//!
+//! A cache of recently seen frames stops floods from looping. Relays can't
+//! check frames meant for others, so frames are told apart by a checksum of
+//! all of their bytes, tag included: a forged frame only ever hides copies of
+//! itself, never the genuine frame with the same counter.
+
+use crate::address::{Destination, NodeId};
+use crate::image::Crc32;
+use crate::protocol::{FRAME_SIZE, HEADER_SIZE};
+use crate::secure::MAX_PEERS;
+
+/// Size of the trailer after every frame
+pub const TRAILER_SIZE: usize = 2;
+/// Forwards a frame may take before it's dropped
+pub const DEFAULT_TTL: u8 = 3;
+/// Frames remembered to spot duplicates
+pub const DUPLICATE_CACHE_SIZE: usize = 32;
+/// How long a frame is remembered: 500 ms at 48 MHz
+pub const DUPLICATE_WINDOW: u32 = 24_000_000;
+/// How long a route is trusted without hearing from its destination: 10 s at 48 MHz
+pub const ROUTE_LIFETIME: u32 = 480_000_000;
+/// Destinations we keep a route to
+pub const MAX_ROUTES: usize = MAX_PEERS;
+
+/// The part of a frame relays may change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trailer {
+    pub ttl: u8,
+    pub via: NodeId,
+}
+
+impl Trailer {
+    /// Forwards the frame already took
+    pub fn hops(&self) -> u8 {
+        DEFAULT_TTL.saturating_sub(self.ttl)
+    }
+}
+
+/// Split a received frame into the frame itself and its trailer
+pub fn split(data: &[u8]) -> Option<(&[u8], Trailer)> {
+    if data.len() < HEADER_SIZE + TRAILER_SIZE {
+        return None;
+    }
+    let (frame, trailer) = data.split_at(data.len() - TRAILER_SIZE);
+    Some((
+        frame,
+        Trailer {
+            ttl: trailer[0],
+            via: trailer[1],
+        },
+    ))
+}
+
+/// What duplicates of a frame have in common, whoever relayed them
+fn fingerprint(frame: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(frame);
+    crc.finalize()
+}
+
+/// Recently seen frames, by source and fingerprint
+#[derive(Debug, Clone, Copy)]
+pub struct DuplicateCache {
+    entries: [Option<(NodeId, u32, u32)>; DUPLICATE_CACHE_SIZE],
+    next: usize,
+}
+
+impl DuplicateCache {
+    pub fn new() -> Self {
+        Self {
+            entries: [None; DUPLICATE_CACHE_SIZE],
+            next: 0,
+        }
+    }
+
+    /// Remember a frame, returning `false` if we saw it in the last [`DUPLICATE_WINDOW`]
+    pub fn insert(&mut self, src: NodeId, fingerprint: u32, now: u32) -> bool {
+        let seen = self.entries.iter().flatten().any(|&(s, print, at)| {
+            s == src && print == fingerprint && now.wrapping_sub(at) < DUPLICATE_WINDOW
+        });
+        if seen {
+            return false;
+        }
+        self.entries[self.next] = Some((src, fingerprint, now));
+        self.next = (self.next + 1) % DUPLICATE_CACHE_SIZE;
+        true
+    }
+}
+
+impl Default for DuplicateCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How to reach a node that isn't in range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub dest: NodeId,
+    /// The neighbour to hand frames for `dest` to
+    pub next_hop: NodeId,
+    /// Forwards between `next_hop` and `dest`
+    pub hops: u8,
+    learned_at: u32,
+}
+
+/// Routes learned from the frames we hear
+#[derive(Debug, Clone, Copy)]
+pub struct Routes {
+    routes: [Option<Route>; MAX_ROUTES],
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self {
+            routes: [None; MAX_ROUTES],
+        }
+    }
+
+    /// Learn from a frame `src` sent that reached us as described by `trailer`.
+    ///
+    /// A route replaces an older one if it is at most as long, or if the
+    /// older one expired.
+    pub fn learn(&mut self, src: NodeId, trailer: Trailer, now: u32) {
+        let route = Route {
+            dest: src,
+            next_hop: trailer.via,
+            hops: trailer.hops(),
+            learned_at: now,
+        };
+        let existing = self.routes.iter().position(|slot| matches!(slot, Some(r) if r.dest == src));
+        let free = self.routes.iter().position(|slot| slot.is_none());
+        let index = match (existing, free) {
+            (Some(i), _) => i,
+            (None, Some(i)) => i,
+            // Full, make room by dropping the stalest route
+            (None, None) => (0..MAX_ROUTES)
+                .max_by_key(|&i| self.routes[i].map_or(0, |r| now.wrapping_sub(r.learned_at)))
+                .unwrap_or(0),
+        };
+        if let Some(old) = self.routes[index] {
+            let alive = now.wrapping_sub(old.learned_at) < ROUTE_LIFETIME;
+            if old.dest == src && old.hops < route.hops && alive {
+                return;
+            }
+        }
+        self.routes[index] = Some(route);
+    }
+
+    /// The live route to `dest`, if we know one
+    pub fn get(&self, dest: NodeId, now: u32) -> Option<Route> {
+        self.routes
+            .iter()
+            .flatten()
+            .find(|r| r.dest == dest && now.wrapping_sub(r.learned_at) < ROUTE_LIFETIME)
+            .copied()
+    }
+
+    /// Drop the route to `dest`, as when its next hop stopped answering
+    pub fn forget(&mut self, dest: NodeId) {
+        for slot in self.routes.iter_mut() {
+            if matches!(slot, Some(r) if r.dest == dest) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A frame to put back on air for someone else
+#[derive(Debug, Clone, Copy)]
+pub struct Forward {
+    /// Who to send it to over the air, the frame header keeps the final destination
+    pub next: Destination,
+    buffer: [u8; FRAME_SIZE],
+    len: usize,
+}
+
+impl Forward {
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// What to do with a received frame
+#[derive(Debug, Clone, Copy)]
+pub struct Incoming<'a> {
+    /// The frame without its trailer
+    pub frame: &'a [u8],
+    pub trailer: Trailer,
+    /// Whether the frame is for us, group membership aside
+    pub deliver: bool,
+    /// The frame to pass on if we are a relay
+    pub forward: Option<Forward>,
+}
+
+/// Mesh state of one node
+#[derive(Debug, Clone, Copy)]
+pub struct Mesh {
+    id: NodeId,
+    relay: bool,
+    duplicates: DuplicateCache,
+    routes: Routes,
+}
+
+impl Mesh {
+    /// Mesh state for node `id`, which forwards frames for others if `relay` is set
+    pub fn new(id: NodeId, relay: bool) -> Self {
+        Self {
+            id,
+            relay,
+            duplicates: DuplicateCache::new(),
+            routes: Routes::new(),
+        }
+    }
+
+    pub fn routes(&self) -> &Routes {
+        &self.routes
+    }
+
+    /// Append a fresh trailer to a frame of `len` bytes we send ourselves,
+    /// returning the length to transmit
+    pub fn stamp(&self, buffer: &mut [u8; FRAME_SIZE], len: usize) -> usize {
+        buffer[len] = DEFAULT_TTL;
+        buffer[len + 1] = self.id;
+        len + TRAILER_SIZE
+    }
+
+    /// Where to send a frame for `dest` over the air
+    pub fn next_hop(&self, dest: Destination, now: u32) -> Destination {
+        match dest {
+            Destination::Node(id) => match self.routes.get(id, now) {
+                Some(route) => Destination::Node(route.next_hop),
+                None => dest,
+            },
+            _ => dest,
+        }
+    }
+
+    /// Learn the route back to the sender of a frame we received. Only pass
+    /// frames [`crate::secure`] accepted: anyone can send a frame claiming to
+    /// be from a fixture, and pull its traffic their way.
+    ///
+    /// The trailer isn't authenticated, and a genuine frame can be put back
+    /// on air with any `via`. A relayed frame only teaches a route if its
+    /// `via` is a neighbour we have a live direct route to, learned from that
+    /// neighbour's own frames, so a forged trailer can't send traffic to a
+    /// node that isn't there.
+    pub fn learn(&mut self, incoming: &Incoming, now: u32) {
+        let src = incoming.frame[1];
+        let via = incoming.trailer.via;
+        let neighbour = matches!(self.routes.get(via, now), Some(route) if route.next_hop == via && route.hops == 0);
+        if via != src && !neighbour {
+            return;
+        }
+        self.routes.learn(src, incoming.trailer, now);
+    }
+
+    /// Give up on the route to `dest`, so the next frame for it is sent
+    /// directly or flooded
+    pub fn forget(&mut self, dest: NodeId) {
+        self.routes.forget(dest);
+    }
+
+    /// Work out what to do with a received frame.
+    ///
+    /// Returns `None` for malformed frames, our own frames relayed back to
+    /// us, and frames we already handled. Nothing is learned from it until
+    /// it's passed to [`Mesh::learn`].
+    pub fn receive<'a>(&mut self, data: &'a [u8], now: u32) -> Option<Incoming<'a>> {
+        let (frame, trailer) = split(data)?;
+        let src = frame[1];
+        let dest = Destination::from_byte(frame[0]);
+        if src == self.id || !self.duplicates.insert(src, fingerprint(frame), now) {
+            return None;
+        }
+
+        let for_us = match dest {
+            Destination::Node(id) => id == self.id,
+            _ => true,
+        };
+        // Nobody sends with more than the default, whatever the trailer says
+        let ttl = trailer.ttl.min(DEFAULT_TTL);
+        let forward = if self.relay && ttl > 0 && dest != Destination::Node(self.id) {
+            let mut buffer = [0u8; FRAME_SIZE];
+            buffer[..frame.len()].copy_from_slice(frame);
+            buffer[frame.len()] = ttl - 1;
+            buffer[frame.len() + 1] = self.id;
+            let next = match dest {
+                // Flood unicast frames we know no route for
+                Destination::Node(id) => match self.routes.get(id, now) {
+                    Some(route) if route.next_hop != trailer.via => Destination::Node(route.next_hop),
+                    _ => Destination::Broadcast,
+                },
+                _ => Destination::Broadcast,
+            };
+            Some(Forward {
+                next,
+                buffer,
+                len: frame.len() + TRAILER_SIZE,
+            })
+        } else {
+            None
+        };
+
+        Some(Incoming {
+            frame,
+            trailer,
+            deliver: for_us,
+            forward,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Frame, Message};
+    use crate::secure::{ControllerLink, FixtureLink, SecureError};
+    use std::collections::VecDeque;
+
+    const SITE_KEY: [u8; 32] = [0x42; 32];
+    const RELAY: NodeId = 1;
+    const FAR: NodeId = 2;
+
+    enum Link {
+        Controller(Box<ControllerLink>),
+        Fixture(FixtureLink),
+    }
+
+    struct Node {
+        id: NodeId,
+        mesh: Mesh,
+        link: Link,
+        delivered: Vec<Frame>,
+    }
+
+    impl Node {
+        fn receive(&mut self, frame: &[u8]) -> Result<Frame, SecureError> {
+            match &mut self.link {
+                Link::Controller(link) => link.receive(frame),
+                Link::Fixture(link) => link.receive(frame),
+            }
+        }
+    }
+
+    /// The controller, a relay in its range, and a fixture only the relay
+    /// can hear, all paired
+    struct Air {
+        nodes: Vec<Node>,
+        range: Vec<(NodeId, NodeId)>,
+        now: u32,
+        transmissions: usize,
+    }
+
+    impl Air {
+        fn new() -> Self {
+            let mut controller = ControllerLink::new(SITE_KEY, [1; 8]);
+            let mut fixtures = Vec::new();
+            for (id, relay) in [(RELAY, true), (FAR, false)].iter().copied() {
+                let mut link = FixtureLink::new(id, SITE_KEY);
+                let nonce = match controller.pair_request(id) {
+                    Message::PairRequest { nonce, .. } => nonce,
+                    _ => unreachable!(),
+                };
+                link.pair(0, &nonce, [id; 8]);
+                controller.paired(id, &[id; 8]).unwrap();
+                fixtures.push(Node { id, mesh: Mesh::new(id, relay), link: Link::Fixture(link), delivered: Vec::new() });
+            }
+            let mut nodes = vec![Node { id: 0, mesh: Mesh::new(0, false), link: Link::Controller(Box::new(controller)), delivered: Vec::new() }];
+            nodes.extend(fixtures);
+            Self { nodes, range: vec![(0, RELAY), (RELAY, FAR)], now: 1_000, transmissions: 0 }
+        }
+
+        fn node(&mut self, id: NodeId) -> &mut Node {
+            self.nodes.iter_mut().find(|node| node.id == id).unwrap()
+        }
+
+        fn route(&mut self, at: NodeId, to: NodeId) -> Option<Route> {
+            let now = self.now;
+            self.node(at).mesh.routes().get(to, now)
+        }
+
+        fn in_range(&self, a: NodeId, b: NodeId) -> bool {
+            self.range.contains(&(a, b)) || self.range.contains(&(b, a))
+        }
+
+        /// Seal `message` at `from` and send it the way the apps do: along a
+        /// route, or flooded if nobody acknowledged it
+        fn send(&mut self, from: NodeId, dest: Destination, message: Message) {
+            let now = self.now;
+            let node = self.node(from);
+            let frame = Frame::new(dest, from, 0, message);
+            let mut buffer = [0u8; FRAME_SIZE];
+            let len = match &mut node.link {
+                Link::Controller(link) => link.seal(&frame, &mut buffer),
+                Link::Fixture(link) => link.seal(&frame, &mut buffer),
+            }
+            .unwrap();
+            let len = node.mesh.stamp(&mut buffer, len);
+            let next = node.mesh.next_hop(dest, now);
+            if !self.transmit(from, next, &buffer[..len]) && next.is_acknowledged() {
+                self.transmit(from, Destination::Broadcast, &buffer[..len]);
+            }
+        }
+
+        /// Put `data` on air and let every relay that hears it pass it on,
+        /// returning whether a unicast was acknowledged
+        fn transmit(&mut self, from: NodeId, next: Destination, data: &[u8]) -> bool {
+            let mut acknowledged = false;
+            let mut queue = VecDeque::new();
+            queue.push_back((from, next, data.to_vec()));
+            while let Some((from, next, data)) = queue.pop_front() {
+                self.transmissions += 1;
+                let now = self.now;
+                let hearing: Vec<NodeId> = self
+                    .nodes
+                    .iter()
+                    .map(|node| node.id)
+                    .filter(|&id| self.in_range(from, id) && (next == Destination::Node(id) || !next.is_acknowledged()))
+                    .collect();
+                for id in hearing {
+                    acknowledged |= next == Destination::Node(id);
+                    let node = self.node(id);
+                    let incoming = match node.mesh.receive(&data, now) {
+                        Some(incoming) => incoming,
+                        None => continue,
+                    };
+                    if let Some(forward) = incoming.forward {
+                        queue.push_back((id, forward.next, forward.data().to_vec()));
+                    }
+                    if incoming.deliver {
+                        if let Ok(frame) = node.receive(incoming.frame) {
+                            node.mesh.learn(&incoming, now);
+                            node.delivered.push(frame);
+                        }
+                    }
+                }
+            }
+            acknowledged
+        }
+    }
+
+    #[test]
+    fn broadcasts_reach_fixtures_out_of_range_once() {
+        let mut air = Air::new();
+        air.send(0, Destination::Broadcast, Message::SetBrightness(10));
+
+        assert_eq!(air.node(RELAY).delivered.len(), 1);
+        assert_eq!(air.node(FAR).delivered.len(), 1);
+        assert_eq!(air.node(FAR).delivered[0].message, Message::SetBrightness(10));
+        // Ours, then the relay's copy, which comes back to us but no further
+        assert_eq!(air.transmissions, 2);
+    }
+
+    #[test]
+    fn replies_teach_routes_through_the_relay() {
+        let mut air = Air::new();
+        air.send(0, Destination::Node(FAR), Message::StatusQuery);
+        assert_eq!(air.node(FAR).delivered.len(), 1);
+        assert!(air.node(RELAY).delivered.is_empty());
+        // The far fixture can't check the relay's own frames, so it doesn't
+        // take its word for being the way back
+        assert_eq!(air.route(FAR, 0), None);
+
+        air.now += 1_000;
+        air.send(RELAY, Destination::Node(0), Message::SetBrightness(1));
+        assert_eq!(air.route(0, RELAY).map(|route| route.hops), Some(0));
+        air.send(FAR, Destination::Node(0), Message::SetBrightness(1));
+        assert_eq!(air.node(0).delivered.len(), 2);
+        let route = air.route(0, FAR).unwrap();
+        assert_eq!((route.next_hop, route.hops), (RELAY, 1));
+
+        // Now straight to the relay, without a flood first
+        air.now += 1_000;
+        air.transmissions = 0;
+        air.send(0, Destination::Node(FAR), Message::SetBrightness(2));
+        assert_eq!(air.node(FAR).delivered.len(), 2);
+        assert_eq!(air.transmissions, 2);
+    }
+
+    #[test]
+    fn forged_frames_neither_reroute_nor_hide_genuine_ones() {
+        let mut air = Air::new();
+        air.range.push((0, 9));
+
+        // Claims to be the far fixture's first frame, right next to the controller
+        let mut forged = [0u8; FRAME_SIZE];
+        let frame = Frame::new(Destination::Node(0), FAR, 0, Message::SetBrightness(1));
+        let len = crate::secure::seal(&frame, &[0; 32], 0, &mut forged).unwrap();
+        forged[len] = DEFAULT_TTL;
+        forged[len + 1] = 9;
+        air.transmit(9, Destination::Node(0), &forged[..len + TRAILER_SIZE]);
+
+        assert!(air.node(0).delivered.is_empty());
+        assert_eq!(air.route(0, FAR), None);
+
+        // The real one, with the same counter, still gets through
+        air.send(RELAY, Destination::Node(0), Message::SetBrightness(1));
+        air.send(FAR, Destination::Node(0), Message::SetBrightness(1));
+        assert_eq!(air.node(0).delivered.len(), 2);
+        assert_eq!(air.route(0, FAR).unwrap().next_hop, RELAY);
+    }
+
+    #[test]
+    fn genuine_frames_with_a_forged_via_teach_nothing() {
+        let mut air = Air::new();
+        air.range.push((0, 9));
+
+        // The far fixture's own frame, caught and put back on air as if
+        // node 9 had relayed it
+        let frame = Frame::new(Destination::Node(0), FAR, 0, Message::SetBrightness(1));
+        let mut buffer = [0u8; FRAME_SIZE];
+        let len = match &mut air.node(FAR).link {
+            Link::Fixture(link) => link.seal(&frame, &mut buffer).unwrap(),
+            Link::Controller(_) => unreachable!(),
+        };
+        buffer[len] = DEFAULT_TTL;
+        buffer[len + 1] = 9;
+        air.transmit(9, Destination::Node(0), &buffer[..len + TRAILER_SIZE]);
+
+        assert_eq!(air.node(0).delivered.len(), 1);
+        assert_eq!(air.route(0, FAR), None);
+        assert_eq!(air.route(0, 9), None);
+    }
+
+    #[test]
+    fn forwards_never_get_more_than_the_default_ttl() {
+        let mut mesh = Mesh::new(RELAY, true);
+        let mut buffer = [0u8; FRAME_SIZE];
+        let len = Frame::new(Destination::Broadcast, 0, 7, Message::StatusQuery).encode(&mut buffer);
+        buffer[len] = u8::MAX;
+        buffer[len + 1] = 0;
+
+        let incoming = mesh.receive(&buffer[..len + TRAILER_SIZE], 0).unwrap();
+        let forward = incoming.forward.unwrap();
+        let (_, trailer) = split(forward.data()).unwrap();
+        assert_eq!(trailer, Trailer { ttl: DEFAULT_TTL - 1, via: RELAY });
+        assert_eq!(incoming.trailer.hops(), 0);
+    }
+
+    #[test]
+    fn floods_stop_at_the_ttl() {
+        let mut mesh = Mesh::new(RELAY, true);
+        let mut buffer = [0u8; FRAME_SIZE];
+        let len = Frame::new(Destination::Broadcast, 0, 7, Message::StatusQuery).encode(&mut buffer);
+        buffer[len] = 0;
+        buffer[len + 1] = 0;
+
+        let incoming = mesh.receive(&buffer[..len + TRAILER_SIZE], 0).unwrap();
+        assert!(incoming.deliver);
+        assert!(incoming.forward.is_none());
+        // Heard again through someone else
+        buffer[len + 1] = 5;
+        assert!(mesh.receive(&buffer[..len + TRAILER_SIZE], 10).is_none());
+        assert!(mesh.receive(&buffer[..len + TRAILER_SIZE], DUPLICATE_WINDOW).is_some());
+    }
+}
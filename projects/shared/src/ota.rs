@@ -0,0 +1,406 @@
+//! Firmware updates over the radio.
+//!
+//! The controller streams an image to a single fixture, header first, in
+//! chunks small enough for a secure frame:
+//!
+//! ```text
+//! controller                               fixture
+//! OtaBegin { size }          ---->         erases the staging slot
+//!                            <----         OtaStatus(Ready)
+//! OtaChunk { index, data }   ---->         writes it to the staging slot
+//! ...
+//! OtaEnd                     ---->         checks what it received
+//!                            <----         OtaStatus(Missing(index)) or
+//!                                          OtaStatus(Accepted) and restarts
+//! ```
+//!
+//! Chunks are acknowledged by the radio like any unicast frame, and the
+//! fixture keeps track of which ones it has, so the controller only resends
+//! what is missing once it reaches the end. An accepted image is installed
+//! by the bootloader and rolled back unless it checks in, see [`crate::boot`].
+//! The fixture restarts without its session keys, so the controller unpairs
+//! it on [`OtaStatus::Accepted`] and pairs with it again, which is when it
+//! checks in.
+
+use crate::boot::{self, BootError};
+use crate::crypto::Key;
+use crate::flash::{Flash, SLOT_HEADER_SIZE, STAGING_SLOT};
+use crate::image::{self, ImageError, ImageHeader, VerifyError, IMAGE_HEADER_SIZE, MAX_IMAGE_SIZE};
+use crate::protocol::Message;
+use crate::secure::MAX_SECURE_PAYLOAD;
+
+/// Image bytes per chunk, after the two byte index
+pub const CHUNK_SIZE: usize = MAX_SECURE_PAYLOAD - 2;
+/// Chunks in the largest image
+pub const MAX_CHUNKS: usize = (IMAGE_HEADER_SIZE + MAX_IMAGE_SIZE as usize).div_ceil(CHUNK_SIZE);
+
+/// Why a fixture refused an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaError {
+    /// The image doesn't fit in the staging slot
+    TooLarge,
+    /// A chunk or end arrived without an update being started
+    NotStarted,
+    /// A chunk beyond the end of the image, or of the wrong size
+    OutOfRange,
+    /// The received image failed its checks
+    Image(ImageError),
+    /// Writing to flash failed
+    Flash,
+}
+
+impl OtaError {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            OtaError::TooLarge => 0,
+            OtaError::NotStarted => 1,
+            OtaError::OutOfRange => 2,
+            OtaError::Image(ImageError::BadMagic) => 3,
+            OtaError::Image(ImageError::TooLarge) => 4,
+            OtaError::Image(ImageError::BadCrc) => 5,
+            OtaError::Image(ImageError::BadSignature) => 6,
+            OtaError::Flash => 7,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => OtaError::TooLarge,
+            1 => OtaError::NotStarted,
+            2 => OtaError::OutOfRange,
+            3 => OtaError::Image(ImageError::BadMagic),
+            4 => OtaError::Image(ImageError::TooLarge),
+            5 => OtaError::Image(ImageError::BadCrc),
+            6 => OtaError::Image(ImageError::BadSignature),
+            7 => OtaError::Flash,
+            _ => return None,
+        })
+    }
+}
+
+impl<E> From<VerifyError<E>> for OtaError {
+    fn from(e: VerifyError<E>) -> Self {
+        match e {
+            VerifyError::Image(e) => OtaError::Image(e),
+            VerifyError::Flash(_) => OtaError::Flash,
+        }
+    }
+}
+
+impl<E> From<BootError<E>> for OtaError {
+    fn from(_: BootError<E>) -> Self {
+        OtaError::Flash
+    }
+}
+
+/// A fixture's answer to [`Message::OtaBegin`] and [`Message::OtaEnd`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaStatus {
+    /// The staging slot is erased, send the chunks
+    Ready,
+    /// Resend chunks from this one on
+    Missing(u16),
+    /// The image checked out and is installed on the next boot
+    Accepted,
+    Failed(OtaError),
+}
+
+/// Number of chunks an image of `size` bytes is sent in, header included
+pub fn chunk_count(size: u32) -> usize {
+    (IMAGE_HEADER_SIZE + size as usize).div_ceil(CHUNK_SIZE)
+}
+
+/// Where byte `offset` of the stream goes in the staging slot: the header at
+/// its start, the image after the space reserved for the header
+fn stream_address(offset: usize) -> u32 {
+    if offset < IMAGE_HEADER_SIZE {
+        STAGING_SLOT.start + offset as u32
+    } else {
+        STAGING_SLOT.start + SLOT_HEADER_SIZE + (offset - IMAGE_HEADER_SIZE) as u32
+    }
+}
+
+/// Write part of the stream, which never crosses the end of the header
+fn write_stream<F: Flash>(flash: &mut F, offset: usize, data: &[u8]) -> Result<(), F::Error> {
+    // Flash is written in half words, pad with the erased value
+    let mut padded = [0xFF; CHUNK_SIZE + 1];
+    padded[..data.len()].copy_from_slice(data);
+    let len = data.len() + data.len() % 2;
+    flash.write(stream_address(offset), &padded[..len])
+}
+
+/// The fixture side of an update
+pub struct Receiver {
+    /// Size of the image being received, header excluded
+    size: Option<u32>,
+    received: [u32; MAX_CHUNKS.div_ceil(32)],
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self {
+            size: None,
+            received: [0; MAX_CHUNKS.div_ceil(32)],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.size.is_some()
+    }
+
+    fn has(&self, index: usize) -> bool {
+        self.received[index / 32] & 1 << (index % 32) != 0
+    }
+
+    /// Start receiving an image of `size` bytes, erasing the staging slot
+    pub fn begin<F: Flash>(&mut self, flash: &mut F, size: u32) -> Result<(), OtaError> {
+        self.size = None;
+        if size > MAX_IMAGE_SIZE {
+            return Err(OtaError::TooLarge);
+        }
+        for page in 0..STAGING_SLOT.pages() {
+            flash.erase(STAGING_SLOT.page(page)).map_err(|_| OtaError::Flash)?;
+        }
+        self.received = [0; MAX_CHUNKS.div_ceil(32)];
+        self.size = Some(size);
+        Ok(())
+    }
+
+    /// Store a chunk, ignoring ones we already have
+    pub fn chunk<F: Flash>(&mut self, flash: &mut F, index: u16, data: &[u8]) -> Result<(), OtaError> {
+        let size = self.size.ok_or(OtaError::NotStarted)?;
+        let index = index as usize;
+        let stream_len = IMAGE_HEADER_SIZE + size as usize;
+        let offset = index * CHUNK_SIZE;
+        if index >= chunk_count(size) || data.len() != CHUNK_SIZE.min(stream_len - offset) {
+            return Err(OtaError::OutOfRange);
+        }
+        if self.has(index) {
+            return Ok(());
+        }
+
+        // The chunk with the end of the header is split across the gap after it
+        let split = IMAGE_HEADER_SIZE.saturating_sub(offset).min(data.len());
+        let (header, body) = data.split_at(split);
+        if !header.is_empty() {
+            write_stream(flash, offset, header).map_err(|_| OtaError::Flash)?;
+        }
+        if !body.is_empty() {
+            write_stream(flash, offset + split, body).map_err(|_| OtaError::Flash)?;
+        }
+        self.received[index / 32] |= 1 << (index % 32);
+        Ok(())
+    }
+
+    /// The first chunk we don't have yet
+    pub fn missing(&self) -> Option<u16> {
+        let size = self.size?;
+        (0..chunk_count(size)).find(|&i| !self.has(i)).map(|i| i as u16)
+    }
+
+    /// Check the received image and have the bootloader install it
+    pub fn finish<F: Flash>(&mut self, flash: &mut F, site_key: &Key) -> OtaStatus {
+        if self.size.is_none() {
+            return OtaStatus::Failed(OtaError::NotStarted);
+        }
+        if let Some(index) = self.missing() {
+            return OtaStatus::Missing(index);
+        }
+        self.size = None;
+        let result = image::verify(flash, STAGING_SLOT, Some(site_key))
+            .map_err(OtaError::from)
+            .and_then(|_| boot::request_install(flash).map_err(OtaError::from));
+        match result {
+            Ok(()) => OtaStatus::Accepted,
+            Err(e) => OtaStatus::Failed(e),
+        }
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The controller side of an update: an image and how far along it is
+pub struct Sender<'a> {
+    header: [u8; IMAGE_HEADER_SIZE],
+    image: &'a [u8],
+    /// The fixture erased its staging slot and takes chunks
+    started: bool,
+    next: u16,
+}
+
+impl<'a> Sender<'a> {
+    pub fn new(header: &ImageHeader, image: &'a [u8]) -> Self {
+        Self {
+            header: header.encode(),
+            image,
+            started: false,
+            next: 0,
+        }
+    }
+
+    /// The next message to send the fixture.
+    ///
+    /// [`Message::OtaBegin`] and [`Message::OtaEnd`] are answered with an
+    /// [`OtaStatus`], which goes to [`Sender::handle`]. They are repeated
+    /// until it arrives.
+    pub fn next_message(&mut self) -> Message {
+        if !self.started {
+            return Message::OtaBegin {
+                size: self.image.len() as u32,
+            };
+        }
+        let index = self.next as usize;
+        let stream_len = IMAGE_HEADER_SIZE + self.image.len();
+        let start = index * CHUNK_SIZE;
+        if start >= stream_len {
+            return Message::OtaEnd;
+        }
+        let end = (start + CHUNK_SIZE).min(stream_len);
+        let mut data = [0u8; CHUNK_SIZE];
+        for (offset, byte) in (start..end).zip(data.iter_mut()) {
+            *byte = if offset < IMAGE_HEADER_SIZE {
+                self.header[offset]
+            } else {
+                self.image[offset - IMAGE_HEADER_SIZE]
+            };
+        }
+        self.next += 1;
+        Message::OtaChunk {
+            index: index as u16,
+            len: (end - start) as u8,
+            data,
+        }
+    }
+
+    /// Act on the fixture's answer, returning the outcome once the update is over
+    pub fn handle(&mut self, status: OtaStatus) -> Option<Result<(), OtaError>> {
+        match status {
+            OtaStatus::Ready => {
+                self.started = true;
+                self.next = 0;
+                None
+            }
+            OtaStatus::Missing(index) => {
+                self.next = index;
+                None
+            }
+            OtaStatus::Accepted => Some(Ok(())),
+            OtaStatus::Failed(e) => {
+                self.started = false;
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Share of the chunks sent, in percent
+    pub fn progress(&self) -> u32 {
+        self.next as u32 * 100 / chunk_count(self.image.len() as u32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Boot;
+    use crate::flash::ram::RamFlash;
+    use crate::address::Destination;
+    use crate::protocol::{Frame, FRAME_SIZE};
+    use crate::secure::{session_nonce, ControllerLink, FixtureLink, SecureError};
+
+    const KEY: Key = [0x5A; 32];
+    const FIXTURE: u8 = 1;
+
+    /// A fixture as it comes up after `boots` boots, paired with `controller`
+    fn pair(controller: &mut ControllerLink, boots: u32) -> FixtureLink {
+        let mut link = FixtureLink::new(FIXTURE, KEY);
+        let nonce = match controller.pair_request(FIXTURE) {
+            Message::PairRequest { nonce, .. } => nonce,
+            _ => unreachable!(),
+        };
+        let fixture_nonce = session_nonce(boots, [FIXTURE; 8]);
+        link.pair(0, &nonce, fixture_nonce);
+        controller.paired(FIXTURE, &fixture_nonce).unwrap();
+        link
+    }
+
+    /// Seal `message` for the fixture and open it there, as the radio would
+    fn deliver(
+        controller: &mut ControllerLink,
+        fixture: &mut FixtureLink,
+        message: Message,
+    ) -> Result<Frame, SecureError> {
+        let mut buffer = [0; FRAME_SIZE];
+        let len = controller.seal(&Frame::new(Destination::Node(FIXTURE), 0, 0, message), &mut buffer)?;
+        fixture.receive(&buffer[..len])
+    }
+
+    /// What the fixture answers, as its main loop does
+    fn handle(receiver: &mut Receiver, flash: &mut RamFlash, message: Message) -> Option<OtaStatus> {
+        match message {
+            Message::OtaBegin { size } => Some(match receiver.begin(flash, size) {
+                Ok(()) => OtaStatus::Ready,
+                Err(e) => OtaStatus::Failed(e),
+            }),
+            Message::OtaChunk { index, len, data } => {
+                receiver.chunk(flash, index, &data[..len as usize]).ok();
+                None
+            }
+            Message::OtaEnd => Some(receiver.finish(flash, &KEY)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn updated_fixtures_are_paired_again_and_confirm_the_image() {
+        let mut flash = RamFlash::new();
+        let image: Vec<u8> = (0..1_000u32).map(|i| (i * 7) as u8).collect();
+        let header = ImageHeader::sign(&KEY, 2, [3; 8], &image);
+        let mut sender = Sender::new(&header, &image);
+        let mut receiver = Receiver::new();
+        let mut controller = ControllerLink::new(KEY, session_nonce(0, [1; 8]));
+        let mut fixture = pair(&mut controller, 0);
+
+        // Chunk 5 is lost on the way, and resent once the fixture asks for it
+        let mut lost = Some(5);
+        let outcome = loop {
+            let message = sender.next_message();
+            if let Message::OtaChunk { index, .. } = message {
+                if lost == Some(index) {
+                    lost = None;
+                    continue;
+                }
+            }
+            let frame = deliver(&mut controller, &mut fixture, message).unwrap();
+            if let Some(status) = handle(&mut receiver, &mut flash, frame.message) {
+                if let Some(outcome) = sender.handle(status) {
+                    break outcome;
+                }
+            }
+        };
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(lost, None);
+
+        // The fixture restarts without its keys, and drops frames sealed
+        // with the old ones, so the controller drops its own
+        assert_eq!(boot::prepare(&mut flash), Ok(Boot::Trial));
+        let mut fixture = FixtureLink::new(FIXTURE, KEY);
+        let stale = deliver(&mut controller, &mut fixture, Message::StatusQuery);
+        assert_eq!(stale.unwrap_err(), SecureError::NotPaired);
+        controller.unpair(FIXTURE);
+        assert!(!controller.is_paired(FIXTURE));
+
+        // Pairing again gets frames through, and the new image checks in
+        let mut fixture = pair(&mut controller, 1);
+        assert!(deliver(&mut controller, &mut fixture, Message::StatusQuery).is_ok());
+        boot::confirm(&mut flash).unwrap();
+        assert_eq!(boot::prepare(&mut flash), Ok(Boot::Normal));
+
+        let mut app = vec![0; image.len()];
+        flash.read(crate::flash::APP_SLOT.start + SLOT_HEADER_SIZE, &mut app).unwrap();
+        assert_eq!(app, image);
+    }
+}
@@ -0,0 +1,641 @@
+//! Messages sent between the controller and the fixtures.
+//!
+//! Every frame fits in a single 32 byte nRF24 payload and starts with a four
+//! byte header:
+//!
+//! ```text
+//! | dest | src | seq | kind | payload ... |
+//! ```
+//!
+//! `dest` is an encoded [`Destination`], `src` the id of the sender, `seq` a
+//! wrapping sequence number and `kind` identifies the message in the payload.
+
+use crate::address::{Destination, GroupId, NodeId};
+use crate::crypto::TAG_SIZE;
+use crate::hopping::{ChannelPlan, PLAN_SIZE};
+use crate::inventory::FixtureInfo;
+use crate::ota::{OtaError, OtaStatus, CHUNK_SIZE};
+use crate::scene::{Look, LOOK_SIZE};
+use crate::stream::{MAX_RUNS, MAX_STREAM_COLORS};
+use crate::telemetry::LinkReport;
+use smart_leds::RGB8;
+
+/// Largest frame the radio can carry
+pub const FRAME_SIZE: usize = 32;
+/// Size of the frame header
+pub const HEADER_SIZE: usize = 4;
+/// Largest payload that fits after the header
+pub const MAX_PAYLOAD: usize = FRAME_SIZE - HEADER_SIZE;
+/// Set in the kind byte of frames secured by [`crate::secure`]
+pub const SECURE_FLAG: u8 = 0x80;
+
+/// Things that can go wrong while decoding a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame or payload is shorter than its kind requires
+    TooShort,
+    /// The kind byte isn't one we know about
+    UnknownKind(u8),
+    /// A field holds a value outside of its range
+    InvalidValue,
+    /// The frame is secured and has to be opened with [`crate::secure`]
+    Secured,
+}
+
+/// The animations a fixture knows how to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Off,
+    Solid,
+    Rainbow,
+}
+
+impl Effect {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Effect::Off),
+            1 => Some(Effect::Solid),
+            2 => Some(Effect::Rainbow),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Effect::Off => 0,
+            Effect::Solid => 1,
+            Effect::Rainbow => 2,
+        }
+    }
+
+    /// Look an effect up by its [`Effect::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Effect::Off),
+            "solid" => Some(Effect::Solid),
+            "rainbow" => Some(Effect::Rainbow),
+            _ => None,
+        }
+    }
+
+    /// What the effect is called on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Effect::Off => "off",
+            Effect::Solid => "solid",
+            Effect::Rainbow => "rainbow",
+        }
+    }
+}
+
+/// The header at the start of every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub dest: Destination,
+    pub src: NodeId,
+    pub seq: u8,
+}
+
+/// A message and the header it travels with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub header: Header,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// Set the global brightness of a fixture
+    SetBrightness(u8),
+    /// Switch to another effect, `speed` is effect specific
+    SetEffect { effect: Effect, speed: u8 },
+    /// Set the colour used by the solid effect
+    SetColor(RGB8),
+    /// Change everything a fixture shows at once, fading over `fade` ms
+    SetLook { look: Look, fade: u32 },
+    /// Add the fixture to a group
+    JoinGroup(GroupId),
+    /// Remove the fixture from a group
+    LeaveGroup(GroupId),
+    /// Remove the fixture from every group
+    ClearGroups,
+    /// Ask a fixture to pair, carrying the controller's session nonce and a
+    /// tag made with the site key, see [`crate::secure`]
+    PairRequest { nonce: [u8; 8], tag: [u8; TAG_SIZE] },
+    /// A fixture's answer to a pair request, carrying its own nonce and tag
+    PairResponse { nonce: [u8; 8], tag: [u8; TAG_SIZE] },
+    /// Beacon from the controller: it is `elapsed` ticks into hop `slot`
+    HopSync { slot: u32, elapsed: u32 },
+    /// Choose between hopping and a fixed channel
+    SetChannelPlan(ChannelPlan),
+    /// Beacon from the controller: the network time is `time` microseconds
+    TimeSync { time: u64 },
+    /// Ask a fixture how its link to us is doing
+    StatusQuery,
+    /// A fixture's answer to a status query
+    LinkStatus(LinkReport),
+    /// Ask every fixture to announce itself within `window` ms, see
+    /// [`crate::inventory`]
+    Discover { window: u16 },
+    /// A fixture's answer to a discover
+    Announce(FixtureInfo),
+    /// Start a firmware update with an image of `size` bytes
+    OtaBegin { size: u32 },
+    /// Part of the image, `index` counts from the start of the image header
+    OtaChunk { index: u16, len: u8, data: [u8; CHUNK_SIZE] },
+    /// Every chunk was sent, check the image
+    OtaEnd,
+    /// A fixture's answer to the start and end of an update
+    OtaStatus(OtaStatus),
+    /// Remember the look to fade to when `scene` is recalled
+    StoreScene { scene: u8, look: Look },
+    /// Fade to the look stored for `scene` over `fade` ms
+    RecallScene { scene: u8, fade: u32 },
+    /// Set the stream palette from entry `first` on, for `frame`
+    StreamPalette { frame: u8, first: u8, len: u8, colors: [RGB8; MAX_STREAM_COLORS] },
+    /// Draw pixels from `start` on for `frame`, see [`crate::stream`]
+    StreamPixels { frame: u8, start: u8, len: u8, runs: [u8; MAX_RUNS] },
+    /// Show the streamed `frame`
+    StreamShow { frame: u8 },
+}
+
+mod kind {
+    pub const SET_BRIGHTNESS: u8 = 0x01;
+    pub const SET_EFFECT: u8 = 0x02;
+    pub const SET_COLOR: u8 = 0x03;
+    pub const SET_LOOK: u8 = 0x04;
+    pub const JOIN_GROUP: u8 = 0x10;
+    pub const LEAVE_GROUP: u8 = 0x11;
+    pub const CLEAR_GROUPS: u8 = 0x12;
+    pub const PAIR_REQUEST: u8 = 0x20;
+    pub const PAIR_RESPONSE: u8 = 0x21;
+    pub const HOP_SYNC: u8 = 0x30;
+    pub const SET_CHANNEL_PLAN: u8 = 0x31;
+    pub const TIME_SYNC: u8 = 0x32;
+    pub const STATUS_QUERY: u8 = 0x40;
+    pub const LINK_STATUS: u8 = 0x41;
+    pub const DISCOVER: u8 = 0x42;
+    pub const ANNOUNCE: u8 = 0x43;
+    pub const OTA_BEGIN: u8 = 0x50;
+    pub const OTA_CHUNK: u8 = 0x51;
+    pub const OTA_END: u8 = 0x52;
+    pub const OTA_STATUS: u8 = 0x53;
+    pub const STORE_SCENE: u8 = 0x60;
+    pub const RECALL_SCENE: u8 = 0x61;
+    pub const STREAM_PALETTE: u8 = 0x70;
+    pub const STREAM_PIXELS: u8 = 0x71;
+    pub const STREAM_SHOW: u8 = 0x72;
+}
+
+impl Message {
+    /// The kind byte identifying this message in the header
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::SetBrightness(_) => kind::SET_BRIGHTNESS,
+            Message::SetEffect { .. } => kind::SET_EFFECT,
+            Message::SetColor(_) => kind::SET_COLOR,
+            Message::SetLook { .. } => kind::SET_LOOK,
+            Message::JoinGroup(_) => kind::JOIN_GROUP,
+            Message::LeaveGroup(_) => kind::LEAVE_GROUP,
+            Message::ClearGroups => kind::CLEAR_GROUPS,
+            Message::PairRequest { .. } => kind::PAIR_REQUEST,
+            Message::PairResponse { .. } => kind::PAIR_RESPONSE,
+            Message::HopSync { .. } => kind::HOP_SYNC,
+            Message::SetChannelPlan(_) => kind::SET_CHANNEL_PLAN,
+            Message::TimeSync { .. } => kind::TIME_SYNC,
+            Message::StatusQuery => kind::STATUS_QUERY,
+            Message::LinkStatus(_) => kind::LINK_STATUS,
+            Message::Discover { .. } => kind::DISCOVER,
+            Message::Announce(_) => kind::ANNOUNCE,
+            Message::OtaBegin { .. } => kind::OTA_BEGIN,
+            Message::OtaChunk { .. } => kind::OTA_CHUNK,
+            Message::OtaEnd => kind::OTA_END,
+            Message::OtaStatus(_) => kind::OTA_STATUS,
+            Message::StoreScene { .. } => kind::STORE_SCENE,
+            Message::RecallScene { .. } => kind::RECALL_SCENE,
+            Message::StreamPalette { .. } => kind::STREAM_PALETTE,
+            Message::StreamPixels { .. } => kind::STREAM_PIXELS,
+            Message::StreamShow { .. } => kind::STREAM_SHOW,
+        }
+    }
+
+    /// Write the payload of the message into `out`, returning its length
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        match *self {
+            Message::SetBrightness(brightness) => {
+                out[0] = brightness;
+                1
+            }
+            Message::SetEffect { effect, speed } => {
+                out[0] = effect.to_byte();
+                out[1] = speed;
+                2
+            }
+            Message::SetColor(color) => {
+                out[..3].copy_from_slice(&[color.r, color.g, color.b]);
+                3
+            }
+            Message::SetLook { look, fade } => {
+                let len = look.encode(out);
+                out[len..len + 4].copy_from_slice(&fade.to_le_bytes());
+                len + 4
+            }
+            Message::JoinGroup(group) | Message::LeaveGroup(group) => {
+                out[0] = group.get();
+                1
+            }
+            Message::ClearGroups => 0,
+            Message::PairRequest { nonce, tag } | Message::PairResponse { nonce, tag } => {
+                out[..8].copy_from_slice(&nonce);
+                out[8..8 + TAG_SIZE].copy_from_slice(&tag);
+                8 + TAG_SIZE
+            }
+            Message::HopSync { slot, elapsed } => {
+                out[..4].copy_from_slice(&slot.to_le_bytes());
+                out[4..8].copy_from_slice(&elapsed.to_le_bytes());
+                8
+            }
+            Message::SetChannelPlan(plan) => {
+                out[..PLAN_SIZE].copy_from_slice(&plan.encode());
+                PLAN_SIZE
+            }
+            Message::TimeSync { time } => {
+                out[..8].copy_from_slice(&time.to_le_bytes());
+                8
+            }
+            Message::StatusQuery => 0,
+            Message::LinkStatus(report) => {
+                let fields = [
+                    report.sent,
+                    report.acked,
+                    report.retransmits,
+                    report.received,
+                    report.strong,
+                    report.rejected,
+                    report.latency,
+                ];
+                for (i, field) in fields.iter().enumerate() {
+                    out[i * 2..i * 2 + 2].copy_from_slice(&field.to_le_bytes());
+                }
+                fields.len() * 2
+            }
+            Message::Discover { window } => {
+                out[..2].copy_from_slice(&window.to_le_bytes());
+                2
+            }
+            Message::Announce(info) => info.encode(out),
+            Message::OtaBegin { size } => {
+                out[..4].copy_from_slice(&size.to_le_bytes());
+                4
+            }
+            Message::OtaChunk { index, len, data } => {
+                let len = len as usize;
+                out[..2].copy_from_slice(&index.to_le_bytes());
+                out[2..2 + len].copy_from_slice(&data[..len]);
+                2 + len
+            }
+            Message::OtaEnd => 0,
+            Message::OtaStatus(status) => {
+                let (code, arg) = match status {
+                    OtaStatus::Ready => (0, 0),
+                    OtaStatus::Missing(index) => (1, index),
+                    OtaStatus::Accepted => (2, 0),
+                    OtaStatus::Failed(e) => (3, e.to_byte() as u16),
+                };
+                out[0] = code;
+                out[1..3].copy_from_slice(&arg.to_le_bytes());
+                3
+            }
+            Message::StoreScene { scene, look } => {
+                out[0] = scene;
+                1 + look.encode(&mut out[1..])
+            }
+            Message::RecallScene { scene, fade } => {
+                out[0] = scene;
+                out[1..5].copy_from_slice(&fade.to_le_bytes());
+                5
+            }
+            Message::StreamPalette { frame, first, len, colors } => {
+                out[0] = frame;
+                out[1] = first;
+                for (i, color) in colors[..len as usize].iter().enumerate() {
+                    out[2 + i * 3..5 + i * 3].copy_from_slice(&[color.r, color.g, color.b]);
+                }
+                2 + len as usize * 3
+            }
+            Message::StreamPixels { frame, start, len, runs } => {
+                let len = len as usize;
+                out[0] = frame;
+                out[1] = start;
+                out[2..2 + len].copy_from_slice(&runs[..len]);
+                2 + len
+            }
+            Message::StreamShow { frame } => {
+                out[0] = frame;
+                1
+            }
+        }
+    }
+
+    /// Decode a message of the given kind from its payload
+    pub fn decode(kind: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        let byte = |i: usize| payload.get(i).copied().ok_or(DecodeError::TooShort);
+        let group = |i: usize| byte(i).and_then(|g| GroupId::new(g).ok_or(DecodeError::InvalidValue));
+        let half = |i: usize| {
+            let bytes = payload.get(i..i + 2).ok_or(DecodeError::TooShort)?;
+            Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+        };
+        let word = |i: usize| {
+            let bytes = payload.get(i..i + 4).ok_or(DecodeError::TooShort)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let nonce = || {
+            let mut nonce = [0u8; 8];
+            nonce.copy_from_slice(payload.get(..8).ok_or(DecodeError::TooShort)?);
+            Ok(nonce)
+        };
+        let tag = || {
+            let mut tag = [0u8; TAG_SIZE];
+            tag.copy_from_slice(payload.get(8..8 + TAG_SIZE).ok_or(DecodeError::TooShort)?);
+            Ok(tag)
+        };
+
+        Ok(match kind {
+            kind::SET_BRIGHTNESS => Message::SetBrightness(byte(0)?),
+            kind::SET_EFFECT => Message::SetEffect {
+                effect: Effect::from_byte(byte(0)?).ok_or(DecodeError::InvalidValue)?,
+                speed: byte(1)?,
+            },
+            kind::SET_COLOR => Message::SetColor(RGB8::new(byte(0)?, byte(1)?, byte(2)?)),
+            kind::SET_LOOK => Message::SetLook {
+                look: Look::decode(payload)?,
+                fade: word(LOOK_SIZE)?,
+            },
+            kind::JOIN_GROUP => Message::JoinGroup(group(0)?),
+            kind::LEAVE_GROUP => Message::LeaveGroup(group(0)?),
+            kind::CLEAR_GROUPS => Message::ClearGroups,
+            kind::PAIR_REQUEST => Message::PairRequest { nonce: nonce()?, tag: tag()? },
+            kind::PAIR_RESPONSE => Message::PairResponse { nonce: nonce()?, tag: tag()? },
+            kind::HOP_SYNC => Message::HopSync {
+                slot: word(0)?,
+                elapsed: word(4)?,
+            },
+            kind::SET_CHANNEL_PLAN => Message::SetChannelPlan(ChannelPlan::decode(payload)?),
+            kind::TIME_SYNC => Message::TimeSync {
+                time: word(0)? as u64 | (word(4)? as u64) << 32,
+            },
+            kind::STATUS_QUERY => Message::StatusQuery,
+            kind::LINK_STATUS => Message::LinkStatus(LinkReport {
+                sent: half(0)?,
+                acked: half(2)?,
+                retransmits: half(4)?,
+                received: half(6)?,
+                strong: half(8)?,
+                rejected: half(10)?,
+                latency: half(12)?,
+            }),
+            kind::DISCOVER => Message::Discover { window: half(0)? },
+            kind::ANNOUNCE => Message::Announce(FixtureInfo::decode(payload)?),
+            kind::OTA_BEGIN => Message::OtaBegin { size: word(0)? },
+            kind::OTA_CHUNK => {
+                let chunk = payload.get(2..).ok_or(DecodeError::TooShort)?;
+                if chunk.is_empty() || chunk.len() > CHUNK_SIZE {
+                    return Err(DecodeError::InvalidValue);
+                }
+                let mut data = [0u8; CHUNK_SIZE];
+                data[..chunk.len()].copy_from_slice(chunk);
+                Message::OtaChunk {
+                    index: half(0)?,
+                    len: chunk.len() as u8,
+                    data,
+                }
+            }
+            kind::OTA_END => Message::OtaEnd,
+            kind::OTA_STATUS => Message::OtaStatus(match (byte(0)?, half(1)?) {
+                (0, _) => OtaStatus::Ready,
+                (1, index) => OtaStatus::Missing(index),
+                (2, _) => OtaStatus::Accepted,
+                (3, e) => OtaStatus::Failed(OtaError::from_byte(e as u8).ok_or(DecodeError::InvalidValue)?),
+                _ => return Err(DecodeError::InvalidValue),
+            }),
+            kind::STORE_SCENE => Message::StoreScene {
+                scene: byte(0)?,
+                look: Look::decode(payload.get(1..).ok_or(DecodeError::TooShort)?)?,
+            },
+            kind::RECALL_SCENE => Message::RecallScene {
+                scene: byte(0)?,
+                fade: word(1)?,
+            },
+            kind::STREAM_PALETTE => {
+                let entries = payload.get(2..).ok_or(DecodeError::TooShort)?;
+                if entries.is_empty() || entries.len() % 3 != 0 || entries.len() / 3 > MAX_STREAM_COLORS {
+                    return Err(DecodeError::InvalidValue);
+                }
+                let mut colors = [RGB8::default(); MAX_STREAM_COLORS];
+                for (color, entry) in colors.iter_mut().zip(entries.chunks(3)) {
+                    *color = RGB8::new(entry[0], entry[1], entry[2]);
+                }
+                Message::StreamPalette {
+                    frame: byte(0)?,
+                    first: byte(1)?,
+                    len: (entries.len() / 3) as u8,
+                    colors,
+                }
+            }
+            kind::STREAM_PIXELS => {
+                let encoded = payload.get(2..).ok_or(DecodeError::TooShort)?;
+                if encoded.is_empty() || encoded.len() > MAX_RUNS {
+                    return Err(DecodeError::InvalidValue);
+                }
+                let mut runs = [0u8; MAX_RUNS];
+                runs[..encoded.len()].copy_from_slice(encoded);
+                Message::StreamPixels {
+                    frame: byte(0)?,
+                    start: byte(1)?,
+                    len: encoded.len() as u8,
+                    runs,
+                }
+            }
+            kind::STREAM_SHOW => Message::StreamShow { frame: byte(0)? },
+            other => return Err(DecodeError::UnknownKind(other)),
+        })
+    }
+}
+
+impl Frame {
+    pub fn new(dest: Destination, src: NodeId, seq: u8, message: Message) -> Self {
+        Self {
+            header: Header { dest, src, seq },
+            message,
+        }
+    }
+
+    /// Write the frame into `out`, returning the number of bytes to transmit
+    pub fn encode(&self, out: &mut [u8; FRAME_SIZE]) -> usize {
+        out[0] = self.header.dest.to_byte();
+        out[1] = self.header.src;
+        out[2] = self.header.seq;
+        out[3] = self.message.kind();
+        HEADER_SIZE + self.message.encode(&mut out[HEADER_SIZE..])
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        if data[3] & SECURE_FLAG != 0 {
+            return Err(DecodeError::Secured);
+        }
+        Ok(Self {
+            header: Header {
+                dest: Destination::from_byte(data[0]),
+                src: data[1],
+                seq: data[2],
+            },
+            message: Message::decode(data[3], &data[HEADER_SIZE..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageError;
+    use crate::inventory::{Capabilities, Chipset, Layout};
+
+    /// A message of every kind
+    fn messages() -> Vec<Message> {
+        let look = Look { effect: Effect::Solid, speed: 3, brightness: 200, color: RGB8::new(1, 2, 3) };
+        let group = GroupId::new(5).unwrap();
+        let mut data = [0u8; CHUNK_SIZE];
+        data.iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+        let mut colors = [RGB8::default(); MAX_STREAM_COLORS];
+        colors[1] = RGB8::new(9, 8, 7);
+        vec![
+            Message::SetBrightness(77),
+            Message::SetEffect { effect: Effect::Rainbow, speed: 9 },
+            Message::SetColor(RGB8::new(10, 20, 30)),
+            Message::SetLook { look, fade: 1500 },
+            Message::JoinGroup(group),
+            Message::LeaveGroup(group),
+            Message::ClearGroups,
+            Message::PairRequest { nonce: [1, 2, 3, 4, 5, 6, 7, 8], tag: [9; TAG_SIZE] },
+            Message::PairResponse { nonce: [8, 7, 6, 5, 4, 3, 2, 1], tag: [10; TAG_SIZE] },
+            Message::HopSync { slot: 0x0102_0304, elapsed: 48_000 },
+            Message::SetChannelPlan(ChannelPlan::hopping(12)),
+            Message::TimeSync { time: 0x0123_4567_89AB_CDEF },
+            Message::StatusQuery,
+            Message::LinkStatus(LinkReport {
+                sent: 1,
+                acked: 2,
+                retransmits: 3,
+                received: 4,
+                strong: 5,
+                rejected: 6,
+                latency: 700,
+            }),
+            Message::Discover { window: 250 },
+            Message::Announce(FixtureInfo {
+                uid: 0xDEAD_BEEF_0000_0001,
+                firmware: 3,
+                leds: 50,
+                chipset: Chipset::Sk6812,
+                layout: Layout::Ring,
+                capabilities: Capabilities::OTA,
+            }),
+            Message::OtaBegin { size: 40_000 },
+            Message::OtaChunk { index: 300, len: CHUNK_SIZE as u8, data },
+            Message::OtaEnd,
+            Message::OtaStatus(OtaStatus::Ready),
+            Message::OtaStatus(OtaStatus::Missing(17)),
+            Message::OtaStatus(OtaStatus::Accepted),
+            Message::OtaStatus(OtaStatus::Failed(OtaError::Image(ImageError::BadSignature))),
+            Message::StoreScene { scene: 4, look },
+            Message::RecallScene { scene: 4, fade: 2000 },
+            Message::StreamPalette { frame: 2, first: 6, len: 2, colors },
+            Message::StreamPixels { frame: 2, start: 10, len: 3, runs: [0x15; MAX_RUNS] },
+            Message::StreamShow { frame: 2 },
+        ]
+    }
+
+    /// The first `len` entries of a message's array, the rest zeroed as
+    /// decoding leaves them
+    fn decoded(message: Message) -> Message {
+        match message {
+            Message::StreamPixels { frame, start, len, runs } => {
+                let mut kept = [0u8; MAX_RUNS];
+                kept[..len as usize].copy_from_slice(&runs[..len as usize]);
+                Message::StreamPixels { frame, start, len, runs: kept }
+            }
+            Message::StreamPalette { frame, first, len, colors } => {
+                let mut kept = [RGB8::default(); MAX_STREAM_COLORS];
+                kept[..len as usize].copy_from_slice(&colors[..len as usize]);
+                Message::StreamPalette { frame, first, len, colors: kept }
+            }
+            message => message,
+        }
+    }
+
+    #[test]
+    fn every_message_survives_a_round_trip() {
+        for message in messages() {
+            let frame = Frame::new(Destination::Node(3), 0, 42, message);
+            let mut out = [0u8; FRAME_SIZE];
+            let len = frame.encode(&mut out);
+            assert!(len <= FRAME_SIZE);
+            assert_eq!(out[3] & SECURE_FLAG, 0, "{:?} looks secured", message);
+            let expected = Frame::new(Destination::Node(3), 0, 42, decoded(message));
+            assert_eq!(Frame::decode(&out[..len]), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn cut_frames_are_never_mistaken_for_whole_ones() {
+        for message in messages() {
+            let mut out = [0u8; FRAME_SIZE];
+            let len = Frame::new(Destination::Broadcast, 0, 0, message).encode(&mut out);
+            for cut in 0..len {
+                if let Ok(frame) = Frame::decode(&out[..cut]) {
+                    // Only chunks and stream messages carry as much as they have
+                    assert_ne!(frame.message, decoded(message), "cut to {}", cut);
+                }
+            }
+        }
+        assert_eq!(Frame::decode(&[0xFF, 0, 0]), Err(DecodeError::TooShort));
+        assert_eq!(Message::decode(kind::SET_LOOK, &[1, 2, 3]), Err(DecodeError::TooShort));
+        assert_eq!(Message::decode(kind::HOP_SYNC, &[0; 7]), Err(DecodeError::TooShort));
+        assert_eq!(Message::decode(kind::OTA_CHUNK, &[0, 0]), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn secured_and_unknown_frames_are_refused() {
+        let mut out = [0u8; FRAME_SIZE];
+        let len = Frame::new(Destination::Broadcast, 0, 0, Message::StatusQuery).encode(&mut out);
+        out[3] |= SECURE_FLAG;
+        assert_eq!(Frame::decode(&out[..len]), Err(DecodeError::Secured));
+        assert_eq!(Frame::decode(&[0xFF, 0, 0, 0x7F]), Err(DecodeError::UnknownKind(0x7F)));
+        assert_eq!(Message::decode(0x00, &[]), Err(DecodeError::UnknownKind(0x00)));
+    }
+
+    #[test]
+    fn values_out_of_range_are_refused() {
+        assert_eq!(Message::decode(kind::SET_EFFECT, &[9, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(kind::JOIN_GROUP, &[0x7F]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(kind::OTA_STATUS, &[9, 0, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(kind::OTA_STATUS, &[3, 99, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(kind::STREAM_PALETTE, &[0, 0, 1, 2]), Err(DecodeError::InvalidValue));
+        let long = [0u8; MAX_PAYLOAD];
+        assert_eq!(Message::decode(kind::STREAM_PIXELS, &long), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn any_payload_decodes_without_panicking() {
+        let payload: Vec<u8> = (0..MAX_PAYLOAD as u8).map(|i| i.wrapping_mul(37)).collect();
+        for kind in 0..SECURE_FLAG {
+            for len in 0..=MAX_PAYLOAD {
+                let _ = Message::decode(kind, &payload[..len]);
+                let _ = Message::decode(kind, &[0xFF; MAX_PAYLOAD][..len]);
+            }
+        }
+    }
+}
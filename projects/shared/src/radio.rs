@@ -0,0 +1,151 @@
+//! nRF24 setup shared by every device on the network.
+//!
+//! ACK payloads need the `W_ACK_PAYLOAD` command and a way to read the RX
+//! FIFO from TX mode, which our fork of `embedded-nrf24l01` adds on top of
+//! the upstream API.
+
+use crate::address::{node_address, pipe_address, Destination, NodeId, BROADCAST_PIPE_LSB, GROUP_PIPE_LSB};
+use crate::hopping::CHANNEL_COUNT;
+use crate::scan::Survey;
+use crate::status::FixtureStatus;
+use crate::telemetry::LinkStats;
+use cortex_m::peripheral::DWT;
+use embedded_nrf24l01::{Configuration, CrcMode, DataRate, Device, RxMode, StandbyMode, TxMode};
+
+/// Error type of the radio behind a [`Configuration`]
+pub type RadioError<C> = <<C as Configuration>::Inner as Device>::Error;
+
+/// Delay between retransmits, in steps of 250 us
+pub const RETRANSMIT_DELAY: u8 = 0b0100;
+/// How often an unacknowledged frame is retransmitted
+pub const RETRANSMIT_COUNT: u8 = 15;
+/// Cycles to wait in RX before the power detector can be read: 130 us to
+/// settle and 40 us of signal, at 48 MHz
+pub const RPD_SETTLE_CYCLES: u32 = 170 * 48;
+/// Clock cycles per microsecond, every device runs at 48 MHz
+pub const CYCLES_PER_US: u32 = 48;
+
+/// The RX pipes used by a fixture
+pub mod pipe {
+    /// Receives ACKs while transmitting, never used to listen
+    pub const ACK: usize = 0;
+    /// Frames sent to this fixture only
+    pub const NODE: usize = 1;
+    /// Frames sent to any group
+    pub const GROUP: usize = 2;
+    /// Frames sent to every fixture
+    pub const BROADCAST: usize = 3;
+}
+
+/// Apply the settings every device on the network has to agree on
+pub fn configure<C: Configuration>(radio: &mut C, channel: u8) -> Result<(), RadioError<C>> {
+    radio.set_frequency(channel)?;
+    radio.set_rf(DataRate::R250Kbps, 0)?;
+    radio.set_auto_retransmit(RETRANSMIT_DELAY, RETRANSMIT_COUNT)?;
+    radio.set_crc(Some(CrcMode::TwoBytes))?;
+    radio.set_pipes_rx_lengths(&[None; 6])?;
+    radio.set_ack_payloads(true)?;
+    radio.flush_tx()?;
+    radio.flush_rx()
+}
+
+/// Listen on the node, group and broadcast pipes of fixture `id`.
+///
+/// Only the node pipe is acknowledged, as every fixture in range receives
+/// group and broadcast frames.
+pub fn listen_as_fixture<C: Configuration>(radio: &mut C, id: NodeId) -> Result<(), RadioError<C>> {
+    radio.set_rx_addr(pipe::NODE, &node_address(id))?;
+    radio.set_rx_addr(pipe::GROUP, &pipe_address(GROUP_PIPE_LSB))?;
+    radio.set_rx_addr(pipe::BROADCAST, &pipe_address(BROADCAST_PIPE_LSB))?;
+    radio.set_auto_ack(&[true, true, false, false, false, false])?;
+    radio.set_pipes_rx_enable(&[false, true, true, true, false, false])
+}
+
+/// Listen for frames sent back to the controller, directly or flooded by relays
+pub fn listen_as_controller<C: Configuration>(radio: &mut C, id: NodeId) -> Result<(), RadioError<C>> {
+    radio.set_rx_addr(pipe::NODE, &node_address(id))?;
+    radio.set_rx_addr(pipe::BROADCAST, &pipe_address(BROADCAST_PIPE_LSB))?;
+    radio.set_auto_ack(&[true, true, false, false, false, false])?;
+    radio.set_pipes_rx_enable(&[false, true, false, true, false, false])
+}
+
+/// Point the transmitter at `dest`.
+///
+/// Unicast frames are acknowledged and retransmitted. Group and broadcast
+/// frames are sent once without waiting for an ACK, so callers should repeat
+/// them a few times if they matter.
+pub fn address_to<C: Configuration>(radio: &mut C, dest: Destination) -> Result<(), RadioError<C>> {
+    let address = dest.radio_address();
+    radio.set_tx_addr(&address)?;
+    // ACKs arrive on pipe 0, which has to match the TX address
+    radio.set_rx_addr(pipe::ACK, &address)?;
+    if dest.is_acknowledged() {
+        radio.set_auto_ack(&[true; 6])?;
+        radio.set_auto_retransmit(RETRANSMIT_DELAY, RETRANSMIT_COUNT)
+    } else {
+        radio.set_auto_ack(&[false; 6])?;
+        radio.set_auto_retransmit(0, 0)
+    }
+}
+
+/// Sweep every channel `rounds` times, recording the power detector in `survey`.
+///
+/// The radio is returned on the channel it started on.
+pub fn survey<D: Device>(mut standby: StandbyMode<D>, survey: &mut Survey, rounds: u16) -> StandbyMode<D> {
+    // Failed mode changes hand the device back, which can't be printed
+    let original = standby.get_frequency().unwrap_or_else(|_| panic!("couldn't read the channel"));
+    for _ in 0..rounds {
+        for channel in 0..CHANNEL_COUNT {
+            standby.set_frequency(channel).unwrap_or_else(|_| panic!("couldn't set the channel"));
+            let mut rx = standby.rx().unwrap_or_else(|_| panic!("couldn't enter RX mode"));
+            cortex_m::asm::delay(RPD_SETTLE_CYCLES);
+            let carrier = rx.has_carrier().unwrap_or_else(|_| panic!("couldn't read the power detector"));
+            survey.record(channel, carrier);
+            standby = rx.standby();
+        }
+    }
+    standby.set_frequency(original).unwrap_or_else(|_| panic!("couldn't set the channel"));
+    standby
+}
+
+/// Send `data` to `dest` and wait until it is out, returning whether it was
+/// acknowledged, or merely sent for group and broadcast frames.
+///
+/// Unicast frames are recorded in `stats`, with their retransmits from
+/// `ARC_CNT` and the time until the ACK. Frames lost on the current channel
+/// are taken from `PLOS_CNT`.
+pub fn transmit<D: Device>(
+    tx: &mut TxMode<D>,
+    dest: Destination,
+    data: &[u8],
+    stats: &mut LinkStats,
+) -> Result<bool, D::Error> {
+    let start = DWT::get_cycle_count();
+    tx.send(data)?;
+    let sent = tx.wait_empty().is_ok();
+    let latency = DWT::get_cycle_count().wrapping_sub(start) / CYCLES_PER_US;
+
+    let observe = tx.observe()?;
+    stats.observe(tx.get_frequency()?, observe.plos_cnt());
+    if let Destination::Node(peer) = dest {
+        if let Some(peer) = stats.peer_mut(peer) {
+            peer.record_sent(sent, observe.arc_cnt(), latency);
+        }
+    }
+    Ok(sent)
+}
+
+/// Load `status` as the payload of the next ACK a fixture sends.
+///
+/// Any status still waiting in the TX FIFO is dropped first, so the
+/// controller always gets the latest one.
+pub fn preload_status<D: Device>(rx: &mut RxMode<D>, status: &FixtureStatus) -> Result<(), D::Error> {
+    rx.flush_tx()?;
+    rx.write_ack_payload(pipe::NODE, &status.encode())
+}
+
+/// The status a fixture sent back with the ACK to our last frame, if any
+pub fn ack_status<D: Device>(tx: &mut TxMode<D>) -> Result<Option<FixtureStatus>, D::Error> {
+    let payload = tx.read_ack_payload()?;
+    Ok(payload.and_then(|payload| FixtureStatus::decode(payload.as_ref()).ok()))
+}
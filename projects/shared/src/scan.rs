@@ -0,0 +1,178 @@
+//! Spectrum surveys using the nRF24's received power detector.
+//!
+//! The RPD bit is set when the radio sees more than -64 dBm on its channel
+//! for at least 40 us. Sampling it many times per channel gives a rough
+//! occupancy figure, which is enough to steer clear of busy Wi-Fi channels.
+
+use crate::hopping::{CHANNEL_COUNT, HIGHEST_HOP_CHANNEL, LOWEST_HOP_CHANNEL};
+use core::fmt;
+
+/// Width of the bar drawn for each channel in a report
+const BAR_WIDTH: u32 = 40;
+
+/// How often the received power detector fired on each channel
+#[derive(Clone)]
+pub struct Survey {
+    hits: [u16; CHANNEL_COUNT as usize],
+    samples: [u16; CHANNEL_COUNT as usize],
+}
+
+impl Survey {
+    pub fn new() -> Self {
+        Self {
+            hits: [0; CHANNEL_COUNT as usize],
+            samples: [0; CHANNEL_COUNT as usize],
+        }
+    }
+
+    /// Record one sample of the power detector on `channel`
+    pub fn record(&mut self, channel: u8, carrier: bool) {
+        let i = channel as usize;
+        if i >= self.hits.len() || self.samples[i] == u16::MAX {
+            return;
+        }
+        self.samples[i] += 1;
+        if carrier {
+            self.hits[i] += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.hits = [0; CHANNEL_COUNT as usize];
+        self.samples = [0; CHANNEL_COUNT as usize];
+    }
+
+    /// Share of samples on `channel` that saw a carrier, in parts per thousand
+    pub fn occupancy(&self, channel: u8) -> u16 {
+        let i = channel as usize;
+        match self.samples.get(i) {
+            Some(&samples) if samples > 0 => (self.hits[i] as u32 * 1000 / samples as u32) as u16,
+            _ => 0,
+        }
+    }
+
+    /// The channel in the hopping range with the least activity on and around it.
+    ///
+    /// Wi-Fi spreads over many nRF24 channels, so each channel is scored
+    /// together with its neighbours. Ties go to the highest channel, as the
+    /// top of the band is above the common Wi-Fi channels.
+    pub fn quietest_channel(&self) -> u8 {
+        let score = |channel: u8| {
+            let below = self.occupancy(channel.saturating_sub(1)) as u32;
+            let above = self.occupancy((channel + 1).min(CHANNEL_COUNT - 1)) as u32;
+            below + 2 * self.occupancy(channel) as u32 + above
+        };
+        (LOWEST_HOP_CHANNEL..=HIGHEST_HOP_CHANNEL)
+            .rev()
+            .min_by_key(|&channel| score(channel))
+            .unwrap_or(LOWEST_HOP_CHANNEL)
+    }
+
+    /// A printable histogram row for `channel`
+    pub fn row(&self, channel: u8) -> Row {
+        Row {
+            channel,
+            occupancy: self.occupancy(channel),
+        }
+    }
+}
+
+impl Default for Survey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line of a survey report, e.g. `2476 MHz |####         | 10.2%`
+pub struct Row {
+    channel: u8,
+    occupancy: u16,
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} MHz |", 2400 + self.channel as u32)?;
+        let filled = (self.occupancy as u32 * BAR_WIDTH).div_ceil(1000);
+        for i in 0..BAR_WIDTH {
+            f.write_str(if i < filled { "#" } else { " " })?;
+        }
+        write!(f, "| {}.{}%", self.occupancy / 10, self.occupancy % 10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A survey where every channel saw a carrier `busy` times in 10
+    fn survey(busy: impl Fn(u8) -> u16) -> Survey {
+        let mut survey = Survey::new();
+        for channel in 0..CHANNEL_COUNT {
+            for sample in 0..10 {
+                survey.record(channel, sample < busy(channel));
+            }
+        }
+        survey
+    }
+
+    #[test]
+    fn occupancy_counts_carriers() {
+        let survey = survey(|channel| channel as u16 % 11);
+        assert_eq!(survey.occupancy(3), 300);
+        assert_eq!(survey.occupancy(10), 1000);
+        assert_eq!(survey.occupancy(11), 0);
+        // Channels that weren't sampled, or don't exist, are quiet
+        assert_eq!(Survey::new().occupancy(40), 0);
+        assert_eq!(survey.occupancy(CHANNEL_COUNT), 0);
+    }
+
+    #[test]
+    fn samples_stop_counting_when_full() {
+        let mut survey = Survey::new();
+        survey.record(CHANNEL_COUNT, true);
+        for _ in 0..u16::MAX {
+            survey.record(5, true);
+        }
+        survey.record(5, false);
+        assert_eq!(survey.occupancy(5), 1000);
+        survey.clear();
+        assert_eq!(survey.occupancy(5), 0);
+    }
+
+    #[test]
+    fn the_quietest_neighbourhood_wins() {
+        // 40 is quiet, but its neighbours aren't, while 61 to 63 are all
+        // fairly quiet
+        let survey = survey(|channel| match channel {
+            40 => 0,
+            61..=63 => 2,
+            _ => 8,
+        });
+        assert_eq!(survey.quietest_channel(), 62);
+    }
+
+    #[test]
+    fn ties_go_to_the_highest_channel() {
+        assert_eq!(Survey::new().quietest_channel(), HIGHEST_HOP_CHANNEL);
+        assert_eq!(survey(|_| 10).quietest_channel(), HIGHEST_HOP_CHANNEL);
+        assert_eq!(survey(|channel| if channel == 20 || channel == 30 { 0 } else { 5 }).quietest_channel(), 30);
+    }
+
+    #[test]
+    fn only_hopping_channels_are_picked() {
+        // Quiet below and above the hopping range, busy within it
+        let hopping = LOWEST_HOP_CHANNEL..=HIGHEST_HOP_CHANNEL;
+        let outside = survey(|channel| if hopping.contains(&channel) { 10 } else { 0 });
+        assert_eq!(outside.quietest_channel(), HIGHEST_HOP_CHANNEL);
+        let below = survey(|channel| if channel < LOWEST_HOP_CHANNEL { 0 } else { 10 });
+        assert_eq!(below.quietest_channel(), LOWEST_HOP_CHANNEL);
+    }
+
+    #[test]
+    fn rows_draw_a_bar() {
+        let survey = survey(|channel| if channel == 76 { 1 } else { 0 });
+        let row = survey.row(76).to_string();
+        assert_eq!(row, format!("2476 MHz |####{}| 10.0%", " ".repeat(36)));
+        assert_eq!(survey.row(2).to_string(), format!("2402 MHz |{}| 0.0%", " ".repeat(40)));
+    }
+}
@@ -0,0 +1,365 @@
+//! Scenes: complete lighting states, saved on the controller and recalled
+//! with one command.
+//!
+//! A scene gives each fixture or group it covers a [`Look`]. Recalling it
+//! broadcasts [`Message::RecallScene`], which fixtures that cached the
+//! scene act on straight away, followed by a [`Message::SetLook`] per part
+//! for fixtures that didn't. Both fade to the new look over the scene's
+//! transition time. Fixtures learn scenes from [`Message::StoreScene`] and
+//! keep them until they lose power.
+//!
+//! Scenes are stored on the controller as a [`Scenes`] table:
+//!
+//! ```text
+//! | index | name length | name | fade (u32) | part count | dest | look (6) | ... | index | ...
+//! ```
+//!
+//! [`Message::RecallScene`]: crate::protocol::Message::RecallScene
+//! [`Message::SetLook`]: crate::protocol::Message::SetLook
+//! [`Message::StoreScene`]: crate::protocol::Message::StoreScene
+
+use crate::address::Destination;
+use crate::protocol::{DecodeError, Effect, Message};
+use smart_leds::RGB8;
+
+/// Scenes the controller stores
+pub const MAX_SCENES: usize = 8;
+/// Fixtures and groups a scene can cover
+pub const MAX_PARTS: usize = 8;
+/// Longest scene name, in bytes
+pub const NAME_SIZE: usize = 12;
+/// Size of an encoded look
+pub const LOOK_SIZE: usize = 6;
+/// Size of an encoded part
+pub const PART_SIZE: usize = 1 + LOOK_SIZE;
+/// Size of the largest encoded scene
+pub const MAX_SCENE_SIZE: usize = 1 + NAME_SIZE + 4 + 1 + MAX_PARTS * PART_SIZE;
+/// Size of the largest encoded table
+pub const MAX_ENCODED_SIZE: usize = MAX_SCENES * (1 + MAX_SCENE_SIZE);
+/// Scenes a fixture caches
+pub const MAX_CACHED_SCENES: usize = 8;
+/// Transition time of new scenes, in ms
+pub const DEFAULT_FADE: u32 = 1_000;
+
+/// Everything about what a fixture shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Look {
+    pub effect: Effect,
+    /// Effect specific
+    pub speed: u8,
+    pub brightness: u8,
+    /// The palette, so far the colour of the solid effect
+    pub color: RGB8,
+}
+
+impl Look {
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[..LOOK_SIZE].copy_from_slice(&[
+            self.effect.to_byte(),
+            self.speed,
+            self.brightness,
+            self.color.r,
+            self.color.g,
+            self.color.b,
+        ]);
+        LOOK_SIZE
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < LOOK_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        Ok(Self {
+            effect: Effect::from_byte(data[0]).ok_or(DecodeError::InvalidValue)?,
+            speed: data[1],
+            brightness: data[2],
+            color: RGB8::new(data[3], data[4], data[5]),
+        })
+    }
+}
+
+/// The look of a fixture or group in a scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    pub dest: Destination,
+    pub look: Look,
+}
+
+/// A scene name, any UTF-8 up to [`NAME_SIZE`] bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Name {
+    bytes: [u8; NAME_SIZE],
+    len: u8,
+}
+
+impl Name {
+    /// `None` if `name` is too long
+    pub fn new(name: &str) -> Option<Self> {
+        if name.len() > NAME_SIZE {
+            return None;
+        }
+        let mut bytes = [0u8; NAME_SIZE];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Self {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+/// The table of parts is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scene {
+    pub name: Name,
+    /// How long recalling the scene takes, in ms
+    pub fade: u32,
+    parts: [Option<Part>; MAX_PARTS],
+}
+
+impl Scene {
+    pub fn new(name: Name) -> Self {
+        Self {
+            name,
+            fade: DEFAULT_FADE,
+            parts: [None; MAX_PARTS],
+        }
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().flatten()
+    }
+
+    /// The look of `dest`, if the scene covers it
+    pub fn look(&self, dest: Destination) -> Option<Look> {
+        self.parts().find(|part| part.dest == dest).map(|part| part.look)
+    }
+
+    /// Give `dest` a look, replacing the one it had
+    pub fn set(&mut self, dest: Destination, look: Look) -> Result<(), Full> {
+        let slot = self
+            .parts
+            .iter()
+            .position(|part| matches!(part, Some(part) if part.dest == dest))
+            .or_else(|| self.parts.iter().position(Option::is_none))
+            .ok_or(Full)?;
+        self.parts[slot] = Some(Part { dest, look });
+        Ok(())
+    }
+
+    /// Stop covering `dest`, returning whether the scene did
+    pub fn remove(&mut self, dest: Destination) -> bool {
+        match self.parts.iter().position(|part| matches!(part, Some(part) if part.dest == dest)) {
+            Some(position) => {
+                self.parts[position..].rotate_left(1);
+                self.parts[MAX_PARTS - 1] = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// What to send to recall the scene as number `index` over `fade` ms:
+    /// the broadcast for fixtures that cached it, then a look per part
+    pub fn recall(&self, index: u8, fade: u32) -> impl Iterator<Item = (Destination, Message)> + '_ {
+        let recall = Message::RecallScene { scene: index, fade };
+        core::iter::once((Destination::Broadcast, recall))
+            .chain(self.parts().map(move |part| (part.dest, Message::SetLook { look: part.look, fade })))
+    }
+
+    /// Write the scene into `out`, returning its length
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        let name = self.name.as_str().as_bytes();
+        out[0] = name.len() as u8;
+        let mut len = 1;
+        out[len..len + name.len()].copy_from_slice(name);
+        len += name.len();
+        out[len..len + 4].copy_from_slice(&self.fade.to_le_bytes());
+        len += 4;
+        out[len] = self.parts().count() as u8;
+        len += 1;
+        for part in self.parts() {
+            out[len] = part.dest.to_byte();
+            len += 1;
+            len += part.look.encode(&mut out[len..]);
+        }
+        len
+    }
+
+    /// Read a scene from the start of `data`, returning it and its length
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let byte = |i: usize| data.get(i).copied().ok_or(DecodeError::TooShort);
+        let name_len = byte(0)? as usize;
+        let mut len = 1;
+        let name = data.get(len..len + name_len).ok_or(DecodeError::TooShort)?;
+        let name = core::str::from_utf8(name).ok().and_then(Name::new).ok_or(DecodeError::InvalidValue)?;
+        len += name_len;
+        let fade = data.get(len..len + 4).ok_or(DecodeError::TooShort)?;
+        let fade = u32::from_le_bytes([fade[0], fade[1], fade[2], fade[3]]);
+        len += 4;
+        let count = byte(len)? as usize;
+        len += 1;
+        if count > MAX_PARTS {
+            return Err(DecodeError::InvalidValue);
+        }
+
+        let mut scene = Scene { fade, ..Scene::new(name) };
+        for part in scene.parts.iter_mut().take(count) {
+            let dest = Destination::from_byte(byte(len)?);
+            let look = Look::decode(data.get(len + 1..).ok_or(DecodeError::TooShort)?)?;
+            *part = Some(Part { dest, look });
+            len += PART_SIZE;
+        }
+        Ok((scene, len))
+    }
+}
+
+/// The scenes the controller has, by number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scenes {
+    scenes: [Option<Scene>; MAX_SCENES],
+}
+
+impl Scenes {
+    pub const fn new() -> Self {
+        Self { scenes: [None; MAX_SCENES] }
+    }
+
+    pub fn get(&self, index: u8) -> Option<&Scene> {
+        self.scenes.get(index as usize)?.as_ref()
+    }
+
+    /// Scene `index`, created without a name if there isn't one yet. `None`
+    /// if `index` is out of range.
+    pub fn entry(&mut self, index: u8) -> Option<&mut Scene> {
+        let slot = self.scenes.get_mut(index as usize)?;
+        if slot.is_none() {
+            *slot = Some(Scene::new(Name::default()));
+        }
+        slot.as_mut()
+    }
+
+    /// Delete scene `index`, returning whether there was one
+    pub fn remove(&mut self, index: u8) -> bool {
+        matches!(self.scenes.get_mut(index as usize).map(Option::take), Some(Some(_)))
+    }
+
+    /// Stored scenes and their numbers
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Scene)> {
+        self.scenes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, scene)| scene.as_ref().map(|scene| (index as u8, scene)))
+    }
+
+    /// Write the table into `out`, returning its length
+    pub fn encode(&self, out: &mut [u8; MAX_ENCODED_SIZE]) -> usize {
+        let mut len = 0;
+        for (index, scene) in self.iter() {
+            out[len] = index;
+            len += 1;
+            len += scene.encode(&mut out[len..]);
+        }
+        len
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self, DecodeError> {
+        let mut scenes = Self::new();
+        while let Some((&index, rest)) = data.split_first() {
+            let (scene, len) = Scene::decode(rest)?;
+            *scenes.scenes.get_mut(index as usize).ok_or(DecodeError::InvalidValue)? = Some(scene);
+            data = &rest[len..];
+        }
+        Ok(scenes)
+    }
+}
+
+impl Default for Scenes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The looks a fixture was given for each scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SceneCache {
+    entries: [Option<(u8, Look)>; MAX_CACHED_SCENES],
+}
+
+impl SceneCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_CACHED_SCENES],
+        }
+    }
+
+    /// Remember our look in `scene`, dropping the oldest one when full
+    pub fn store(&mut self, scene: u8, look: Look) {
+        let slot = match self.entries.iter().position(|entry| matches!(entry, Some((s, _)) if *s == scene)) {
+            Some(slot) => slot,
+            None => {
+                self.entries.rotate_right(1);
+                0
+            }
+        };
+        self.entries[slot] = Some((scene, look));
+    }
+
+    pub fn get(&self, scene: u8) -> Option<Look> {
+        self.entries.iter().flatten().find(|(s, _)| *s == scene).map(|(_, look)| *look)
+    }
+}
+
+impl Default for SceneCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Frame, FRAME_SIZE};
+    use crate::secure::{session_nonce, ControllerLink, SecureError};
+
+    const LOOK: Look = Look {
+        effect: Effect::Solid,
+        speed: 0,
+        brightness: 200,
+        color: RGB8 { r: 255, g: 128, b: 0 },
+    };
+
+    #[test]
+    fn recalling_skips_fixtures_that_are_not_paired() {
+        let mut scene = Scene::new(Name::new("warm").unwrap());
+        for fixture in 1..=3 {
+            scene.set(Destination::Node(fixture), LOOK).unwrap();
+        }
+        let mut link = ControllerLink::new([0x5A; 32], session_nonce(0, [1; 8]));
+        link.paired(1, &[1; 8]).unwrap();
+        link.paired(3, &[3; 8]).unwrap();
+
+        // Fixture 2 is refused, the rest of the scene still goes out
+        let mut buffer = [0; FRAME_SIZE];
+        let sealed: Vec<_> = scene
+            .recall(4, 500)
+            .map(|(dest, message)| (dest, link.seal(&Frame::new(dest, 0, 0, message), &mut buffer).map(|_| message)))
+            .collect();
+        let fade = 500;
+        assert_eq!(
+            sealed,
+            [
+                (Destination::Broadcast, Ok(Message::RecallScene { scene: 4, fade })),
+                (Destination::Node(1), Ok(Message::SetLook { look: LOOK, fade })),
+                (Destination::Node(2), Err(SecureError::NotPaired)),
+                (Destination::Node(3), Ok(Message::SetLook { look: LOOK, fade })),
+            ]
+        );
+    }
+}
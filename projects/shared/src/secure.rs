@@ -0,0 +1,559 @@
+//! Authenticated, encrypted frames and the pairing that sets up their keys.
+//!
+//! A secure frame keeps the plain header, with [`SECURE_FLAG`] set in the kind
+//! byte, followed by a four byte counter, the encrypted payload and a
+//! truncated tag:
+//!
+//! ```text
+//! | dest | src | seq | kind | counter | ciphertext ... | tag |
+//! ```
+//!
+//! The header and counter are authenticated but not encrypted. The counter
+//! only ever goes up, so every frame gets a unique nonce and receivers drop
+//! anything that isn't newer than the last frame from the same sender.
+//!
+//! Pairing happens in the clear: the controller sends a
+//! [`Message::PairRequest`] with its session nonce, the fixture answers with a
+//! [`Message::PairResponse`] carrying its own, and both derive the pair key
+//! from the site key and the two nonces. Group and broadcast frames use a
+//! multicast key derived from the controller's session nonce alone, so every
+//! fixture paired in the same session can read them.
+//!
+//! Both pairing messages carry a tag keyed from the site key and the
+//! controller's nonce. A request's covers the fixture it's for, a response's
+//! the fixture's nonce too, so nobody without the site key can pair a fixture
+//! or answer in its name, and a response only completes the session it
+//! answers. Fixtures accept authenticated requests at any time. A recorded
+//! request can still be played back to make a fixture start a session nobody
+//! else completes, which is no worse than jamming it.
+//!
+//! Counters start over with every session, so a nonce used for pairing must
+//! never come round again: the entropy gathered at power up can be much the
+//! same every time. [`session_nonce`] puts the boot count kept in the
+//! settings in front of it.
+
+use crate::address::{Destination, NodeId};
+use crate::crypto::{self, Key, NONCE_SIZE, TAG_SIZE};
+use crate::mesh::TRAILER_SIZE;
+use crate::protocol::{DecodeError, Frame, Header, Message, FRAME_SIZE, HEADER_SIZE, SECURE_FLAG};
+
+/// Size of the counter following the header
+pub const COUNTER_SIZE: usize = 4;
+/// Largest message payload that still fits in a secure frame, with room for
+/// the mesh trailer
+pub const MAX_SECURE_PAYLOAD: usize = FRAME_SIZE - HEADER_SIZE - COUNTER_SIZE - TAG_SIZE - TRAILER_SIZE;
+
+/// How many fixtures a controller can be paired with at once
+pub const MAX_PEERS: usize = 16;
+
+/// The key every device on the site shares, kept out of the repository.
+///
+/// Generate one with `head -c 32 /dev/urandom > site.key` in the repository root.
+pub const SITE_KEY: &Key = include_bytes!("../../../site.key");
+
+const AAD_SIZE: usize = HEADER_SIZE + COUNTER_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureError {
+    Decode(DecodeError),
+    /// The tag did not match
+    Auth,
+    /// The counter was not newer than the last one we accepted
+    Replay,
+    /// We have no key for this sender yet
+    NotPaired,
+    /// A plain frame arrived that has to be secured
+    Unsecured,
+    /// The message does not fit in a secure frame
+    TooLong,
+    /// Our counter ran out, the link has to be paired again
+    CounterExhausted,
+    /// Every peer slot is taken
+    TooManyPeers,
+}
+
+impl From<DecodeError> for SecureError {
+    fn from(e: DecodeError) -> Self {
+        SecureError::Decode(e)
+    }
+}
+
+/// Whether a received frame is a secure one
+pub fn is_secure(data: &[u8]) -> bool {
+    data.len() > 3 && data[3] & SECURE_FLAG != 0
+}
+
+/// A pairing nonce that doesn't repeat on this device: `boots` from
+/// [`Settings::count_boot`], then random bytes, so devices sharing the site
+/// key are unlikely to pick the same one
+///
+/// [`Settings::count_boot`]: crate::settings::Settings::count_boot
+pub fn session_nonce(boots: u32, random: [u8; 8]) -> [u8; 8] {
+    let mut nonce = random;
+    nonce[..4].copy_from_slice(&boots.to_le_bytes());
+    nonce
+}
+
+fn nonce(src: NodeId, dest: Destination, counter: u32) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = src;
+    nonce[1] = dest.to_byte();
+    nonce[4..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypt `frame` with `key` and `counter` into `out`, returning its length
+pub fn seal(frame: &Frame, key: &Key, counter: u32, out: &mut [u8; FRAME_SIZE]) -> Result<usize, SecureError> {
+    let mut payload = [0u8; crate::protocol::MAX_PAYLOAD];
+    let len = frame.message.encode(&mut payload);
+    if len > MAX_SECURE_PAYLOAD {
+        return Err(SecureError::TooLong);
+    }
+
+    frame.encode(out);
+    out[3] |= SECURE_FLAG;
+    out[HEADER_SIZE..AAD_SIZE].copy_from_slice(&counter.to_le_bytes());
+    out[AAD_SIZE..AAD_SIZE + len].copy_from_slice(&payload[..len]);
+
+    let nonce = nonce(frame.header.src, frame.header.dest, counter);
+    let (aad, rest) = out.split_at_mut(AAD_SIZE);
+    let tag = crypto::seal(key, &nonce, aad, &mut rest[..len]);
+    rest[len..len + TAG_SIZE].copy_from_slice(&tag[..TAG_SIZE]);
+    Ok(AAD_SIZE + len + TAG_SIZE)
+}
+
+/// Read the header of a secure frame without checking it
+pub fn peek_header(data: &[u8]) -> Result<Header, SecureError> {
+    if data.len() < AAD_SIZE + TAG_SIZE {
+        return Err(DecodeError::TooShort.into());
+    }
+    Ok(Header {
+        dest: Destination::from_byte(data[0]),
+        src: data[1],
+        seq: data[2],
+    })
+}
+
+/// Check and decrypt a secure frame, returning it with its counter
+pub fn open(data: &[u8], key: &Key) -> Result<(Frame, u32), SecureError> {
+    let header = peek_header(data)?;
+    if !is_secure(data) {
+        return Err(SecureError::Unsecured);
+    }
+    let counter = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let len = data.len() - AAD_SIZE - TAG_SIZE;
+
+    let mut payload = [0u8; MAX_SECURE_PAYLOAD];
+    let payload = payload.get_mut(..len).ok_or(SecureError::TooLong)?;
+    payload.copy_from_slice(&data[AAD_SIZE..AAD_SIZE + len]);
+
+    let nonce = nonce(header.src, header.dest, counter);
+    crypto::open(key, &nonce, &data[..AAD_SIZE], payload, &data[AAD_SIZE + len..])
+        .map_err(|_| SecureError::Auth)?;
+
+    let message = Message::decode(data[3] & !SECURE_FLAG, payload)?;
+    Ok((Frame { header, message }, counter))
+}
+
+/// The nonce of a pair request for `fixture`, or of its response carrying
+/// `fixture_nonce`
+fn pair_nonce(fixture: NodeId, fixture_nonce: Option<&[u8; 8]>) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = fixture;
+    if let Some(fixture_nonce) = fixture_nonce {
+        nonce[1] = 1;
+        nonce[4..].copy_from_slice(fixture_nonce);
+    }
+    nonce
+}
+
+fn pair_tag(
+    site_key: &Key,
+    controller_nonce: &[u8; 8],
+    fixture: NodeId,
+    fixture_nonce: Option<&[u8; 8]>,
+) -> [u8; TAG_SIZE] {
+    let key = crypto::pairing_key(site_key, controller_nonce);
+    let full = crypto::seal(&key, &pair_nonce(fixture, fixture_nonce), &[], &mut []);
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(&full[..TAG_SIZE]);
+    tag
+}
+
+fn check_pair_tag(
+    site_key: &Key,
+    controller_nonce: &[u8; 8],
+    fixture: NodeId,
+    fixture_nonce: Option<&[u8; 8]>,
+    tag: &[u8; TAG_SIZE],
+) -> Result<(), SecureError> {
+    let key = crypto::pairing_key(site_key, controller_nonce);
+    crypto::open(&key, &pair_nonce(fixture, fixture_nonce), &[], &mut [], tag).map_err(|_| SecureError::Auth)
+}
+
+fn hop_seed(multicast_key: &Key) -> u32 {
+    let seed = crypto::hchacha20(multicast_key, b"hop sequence....");
+    u32::from_le_bytes([seed[0], seed[1], seed[2], seed[3]])
+}
+
+/// Rejects frames whose counter isn't newer than the last accepted one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayGuard {
+    last: Option<u32>,
+}
+
+impl ReplayGuard {
+    /// Accept `counter` if it is newer than anything seen before
+    pub fn accept(&mut self, counter: u32) -> Result<(), SecureError> {
+        match self.last {
+            Some(last) if counter <= last => Err(SecureError::Replay),
+            _ => {
+                self.last = Some(counter);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The counter used as a nonce for every frame a device sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Counter(u32);
+
+impl Counter {
+    pub fn advance(&mut self) -> Result<u32, SecureError> {
+        let value = self.0;
+        self.0 = self.0.checked_add(1).ok_or(SecureError::CounterExhausted)?;
+        Ok(value)
+    }
+}
+
+/// The secure side of a fixture: one controller to talk to
+pub struct FixtureLink {
+    id: NodeId,
+    site_key: Key,
+    session: Option<FixtureSession>,
+    counter: Counter,
+}
+
+struct FixtureSession {
+    controller: NodeId,
+    pair_key: Key,
+    multicast_key: Key,
+    guard: ReplayGuard,
+}
+
+impl FixtureLink {
+    pub fn new(id: NodeId, site_key: Key) -> Self {
+        Self {
+            id,
+            site_key,
+            session: None,
+            counter: Counter::default(),
+        }
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Seed of the hop sequence shared with the controller's session
+    pub fn hop_seed(&self) -> Option<u32> {
+        self.session.as_ref().map(|session| hop_seed(&session.multicast_key))
+    }
+
+    /// Derive new keys after a pair request [`receive`] let through,
+    /// returning the response to send. `fixture_nonce` mustn't repeat either,
+    /// see [`session_nonce`].
+    ///
+    /// [`receive`]: FixtureLink::receive
+    pub fn pair(&mut self, controller: NodeId, controller_nonce: &[u8; 8], fixture_nonce: [u8; 8]) -> Frame {
+        let tag = pair_tag(&self.site_key, controller_nonce, self.id, Some(&fixture_nonce));
+        self.session = Some(FixtureSession {
+            controller,
+            pair_key: crypto::pair_key(&self.site_key, controller_nonce, &fixture_nonce),
+            multicast_key: crypto::multicast_key(&self.site_key, controller_nonce),
+            guard: ReplayGuard::default(),
+        });
+        self.counter = Counter::default();
+        Frame::new(
+            Destination::Node(controller),
+            self.id,
+            0,
+            Message::PairResponse { nonce: fixture_nonce, tag },
+        )
+    }
+
+    /// Decode a received frame.
+    ///
+    /// Pair requests for us with a valid tag are the only plain frames let
+    /// through, everything else has to be secured by the controller we're
+    /// paired with.
+    pub fn receive(&mut self, data: &[u8]) -> Result<Frame, SecureError> {
+        if !is_secure(data) {
+            let frame = Frame::decode(data)?;
+            return match frame.message {
+                Message::PairRequest { nonce, tag } if frame.header.dest == Destination::Node(self.id) => {
+                    check_pair_tag(&self.site_key, &nonce, self.id, None, &tag)?;
+                    Ok(frame)
+                }
+                _ => Err(SecureError::Unsecured),
+            };
+        }
+
+        let header = peek_header(data)?;
+        let session = self.session.as_mut().ok_or(SecureError::NotPaired)?;
+        if header.src != session.controller {
+            return Err(SecureError::NotPaired);
+        }
+        let key = if header.dest.is_acknowledged() {
+            &session.pair_key
+        } else {
+            &session.multicast_key
+        };
+        let (frame, counter) = open(data, key)?;
+        session.guard.accept(counter)?;
+        Ok(frame)
+    }
+
+    /// Secure a frame for the controller
+    pub fn seal(&mut self, frame: &Frame, out: &mut [u8; FRAME_SIZE]) -> Result<usize, SecureError> {
+        let session = self.session.as_ref().ok_or(SecureError::NotPaired)?;
+        seal(frame, &session.pair_key, self.counter.advance()?, out)
+    }
+}
+
+/// The secure side of a controller: any number of paired fixtures
+pub struct ControllerLink {
+    site_key: Key,
+    nonce: [u8; 8],
+    multicast_key: Key,
+    peers: [Option<Peer>; MAX_PEERS],
+    counter: Counter,
+}
+
+#[derive(Clone, Copy)]
+struct Peer {
+    id: NodeId,
+    pair_key: Key,
+    guard: ReplayGuard,
+}
+
+impl ControllerLink {
+    /// Start a new session, `nonce` must never be reused with the same site
+    /// key, see [`session_nonce`]
+    pub fn new(site_key: Key, nonce: [u8; 8]) -> Self {
+        Self {
+            multicast_key: crypto::multicast_key(&site_key, &nonce),
+            site_key,
+            nonce,
+            peers: [None; MAX_PEERS],
+            counter: Counter::default(),
+        }
+    }
+
+    /// Seed of the hop sequence for this session
+    pub fn hop_seed(&self) -> u32 {
+        hop_seed(&self.multicast_key)
+    }
+
+    /// The message that starts pairing with `fixture`
+    pub fn pair_request(&self, fixture: NodeId) -> Message {
+        Message::PairRequest {
+            nonce: self.nonce,
+            tag: pair_tag(&self.site_key, &self.nonce, fixture, None),
+        }
+    }
+
+    /// Finish pairing with `fixture` once [`receive`] let its response
+    /// through
+    ///
+    /// [`receive`]: ControllerLink::receive
+    pub fn paired(&mut self, fixture: NodeId, fixture_nonce: &[u8; 8]) -> Result<(), SecureError> {
+        let peer = Peer {
+            id: fixture,
+            pair_key: crypto::pair_key(&self.site_key, &self.nonce, fixture_nonce),
+            guard: ReplayGuard::default(),
+        };
+        let slot = self
+            .peers
+            .iter_mut()
+            .find(|slot| match slot {
+                Some(p) => p.id == fixture,
+                None => true,
+            })
+            .ok_or(SecureError::TooManyPeers)?;
+        *slot = Some(peer);
+        Ok(())
+    }
+
+    pub fn is_paired(&self, fixture: NodeId) -> bool {
+        self.peer(fixture).is_some()
+    }
+
+    /// Drop the keys of `fixture`, as when it restarts and loses its own, so
+    /// it's paired again
+    pub fn unpair(&mut self, fixture: NodeId) {
+        for slot in self.peers.iter_mut() {
+            if matches!(slot, Some(peer) if peer.id == fixture) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn peer(&self, fixture: NodeId) -> Option<&Peer> {
+        self.peers.iter().flatten().find(|p| p.id == fixture)
+    }
+
+    /// Secure a frame for a fixture, a group or everyone
+    pub fn seal(&mut self, frame: &Frame, out: &mut [u8; FRAME_SIZE]) -> Result<usize, SecureError> {
+        let key = match frame.header.dest {
+            Destination::Node(id) => self.peer(id).ok_or(SecureError::NotPaired)?.pair_key,
+            _ => self.multicast_key,
+        };
+        seal(frame, &key, self.counter.advance()?, out)
+    }
+
+    /// Decode a frame sent back by one of our fixtures.
+    ///
+    /// Pair responses to this session with a valid tag are the only plain
+    /// frames let through. Frames that fail to authenticate change nothing,
+    /// as anyone can send them in a fixture's name. Callers count them in
+    /// their link statistics.
+    pub fn receive(&mut self, data: &[u8]) -> Result<Frame, SecureError> {
+        if !is_secure(data) {
+            let frame = Frame::decode(data)?;
+            return match frame.message {
+                Message::PairResponse { nonce, tag } => {
+                    check_pair_tag(&self.site_key, &self.nonce, frame.header.src, Some(&nonce), &tag)?;
+                    Ok(frame)
+                }
+                _ => Err(SecureError::Unsecured),
+            };
+        }
+
+        let header = peek_header(data)?;
+        let peer = self
+            .peers
+            .iter_mut()
+            .flatten()
+            .find(|p| p.id == header.src)
+            .ok_or(SecureError::NotPaired)?;
+        let (frame, counter) = open(data, &peer.pair_key)?;
+        peer.guard.accept(counter)?;
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: Key = [0x5A; 32];
+
+    #[test]
+    fn sessions_differ_even_if_the_entropy_does_not() {
+        let random = [7; 8];
+        let mut before = ControllerLink::new(KEY, session_nonce(0, random));
+        let mut after = ControllerLink::new(KEY, session_nonce(1, random));
+        assert_ne!(before.hop_seed(), after.hop_seed());
+
+        // Both start counting at 0, the keystream must still differ
+        let frame = Frame::new(Destination::Broadcast, 0, 0, Message::SetBrightness(255));
+        let (mut first, mut second) = ([0; FRAME_SIZE], [0; FRAME_SIZE]);
+        let len = before.seal(&frame, &mut first).unwrap();
+        after.seal(&frame, &mut second).unwrap();
+        assert_ne!(first[..len], second[..len]);
+    }
+
+    /// Encode a plain frame from `src` to `dest`
+    fn plain(dest: NodeId, src: NodeId, message: Message) -> Vec<u8> {
+        let mut buffer = [0; FRAME_SIZE];
+        let len = Frame::new(Destination::Node(dest), src, 0, message).encode(&mut buffer);
+        buffer[..len].to_vec()
+    }
+
+    /// Pair `fixture` with `controller` the way the radio loops do
+    fn pair(controller: &mut ControllerLink, fixture: &mut FixtureLink, id: NodeId, fixture_nonce: [u8; 8]) {
+        let request = fixture.receive(&plain(id, 0, controller.pair_request(id))).unwrap();
+        let nonce = match request.message {
+            Message::PairRequest { nonce, .. } => nonce,
+            _ => unreachable!(),
+        };
+        let mut buffer = [0; FRAME_SIZE];
+        let len = fixture.pair(request.header.src, &nonce, fixture_nonce).encode(&mut buffer);
+        match controller.receive(&buffer[..len]).unwrap().message {
+            Message::PairResponse { nonce, .. } => controller.paired(id, &nonce).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn pairing_takes_the_site_key() {
+        let mut controller = ControllerLink::new(KEY, session_nonce(0, [1; 8]));
+        let mut fixture = FixtureLink::new(1, KEY);
+        let request = controller.pair_request(1);
+
+        // Requests from elsewhere, or meant for another fixture
+        let stranger = ControllerLink::new([0; 32], session_nonce(0, [1; 8]));
+        assert_eq!(fixture.receive(&plain(1, 0, stranger.pair_request(1))).unwrap_err(), SecureError::Auth);
+        assert_eq!(fixture.receive(&plain(1, 0, controller.pair_request(2))).unwrap_err(), SecureError::Auth);
+        assert_eq!(fixture.receive(&plain(2, 0, request)).unwrap_err(), SecureError::Unsecured);
+        if let Message::PairRequest { nonce, mut tag } = request {
+            tag[0] ^= 1;
+            let forged = Message::PairRequest { nonce, tag };
+            assert_eq!(fixture.receive(&plain(1, 0, forged)).unwrap_err(), SecureError::Auth);
+        }
+        assert!(!fixture.is_paired());
+
+        // Responses made without the site key, by another fixture or for another session
+        let mut response = [0; FRAME_SIZE];
+        let len = FixtureLink::new(1, [0; 32]).pair(0, &session_nonce(0, [1; 8]), [2; 8]).encode(&mut response);
+        assert_eq!(controller.receive(&response[..len]).unwrap_err(), SecureError::Auth);
+        let len = FixtureLink::new(1, KEY).pair(0, &session_nonce(0, [1; 8]), [2; 8]).encode(&mut response);
+        response[1] = 2;
+        assert_eq!(controller.receive(&response[..len]).unwrap_err(), SecureError::Auth);
+        let len = FixtureLink::new(1, KEY).pair(0, &session_nonce(1, [1; 8]), [2; 8]).encode(&mut response);
+        assert_eq!(controller.receive(&response[..len]).unwrap_err(), SecureError::Auth);
+        assert!(!controller.is_paired(1));
+
+        pair(&mut controller, &mut fixture, 1, [2; 8]);
+        let frame = Frame::new(Destination::Node(1), 0, 0, Message::StatusQuery);
+        let mut buffer = [0; FRAME_SIZE];
+        let len = controller.seal(&frame, &mut buffer).unwrap();
+        assert_eq!(fixture.receive(&buffer[..len]), Ok(frame));
+    }
+
+    #[test]
+    fn paired_fixtures_pair_again_with_any_controller_on_the_site() {
+        let mut old = ControllerLink::new(KEY, session_nonce(0, [1; 8]));
+        let mut fixture = FixtureLink::new(1, KEY);
+        pair(&mut old, &mut fixture, 1, [2; 8]);
+
+        let mut new = ControllerLink::new(KEY, session_nonce(1, [1; 8]));
+        pair(&mut new, &mut fixture, 1, [3; 8]);
+        let frame = Frame::new(Destination::Node(1), 0, 0, Message::StatusQuery);
+        let mut buffer = [0; FRAME_SIZE];
+        let len = old.seal(&frame, &mut buffer).unwrap();
+        assert_eq!(fixture.receive(&buffer[..len]).unwrap_err(), SecureError::Auth);
+        let len = new.seal(&frame, &mut buffer).unwrap();
+        assert_eq!(fixture.receive(&buffer[..len]), Ok(frame));
+    }
+
+    #[test]
+    fn forged_frames_never_unpair_a_fixture() {
+        let mut controller = ControllerLink::new(KEY, session_nonce(0, [1; 8]));
+        let mut fixture = FixtureLink::new(1, KEY);
+        pair(&mut controller, &mut fixture, 1, [2; 8]);
+
+        // Anyone can send frames in the fixture's name, however often
+        let frame = Frame::new(Destination::Node(0), 1, 0, Message::StatusQuery);
+        let mut buffer = [0; FRAME_SIZE];
+        for counter in 0..10 {
+            let len = seal(&frame, &[0; 32], counter, &mut buffer).unwrap();
+            assert_eq!(controller.receive(&buffer[..len]).unwrap_err(), SecureError::Auth);
+        }
+        assert!(controller.is_paired(1));
+        let len = fixture.seal(&frame, &mut buffer).unwrap();
+        assert_eq!(controller.receive(&buffer[..len]), Ok(frame));
+    }
+}
@@ -0,0 +1,506 @@
+//! Settings that survive a power cycle: a key/value store in the
+//! [`SETTINGS`] pages.
+//!
+//! Values are appended to a log and never changed in place, reading a key
+//! finds its last intact record. Each page of the log starts with a sequence
+//! number, which orders the pages. Once the page being written fills up the
+//! log carries on in the next free one, and once only one is left free the
+//! oldest page is compacted: the records in it that are still current are
+//! copied to the end of the log, then it's erased. Pages are used in turn,
+//! so each is erased about as often as the others.
+//!
+//! Records carry a CRC of their key, length and value, so a record cut short
+//! by a reset fails it and is skipped. Compaction copies before it erases,
+//! and the copies are newer, so a reset part way leaves duplicates the newer
+//! of which wins.
+//!
+//! ```text
+//! page:   | sequence (u32) | magic (u16) | 0xFFFF | record | record | ...
+//! record: | key (u16) | length (u16) | crc32 | value, padded to even |
+//! ```
+//!
+//! An empty value stands for a removed key.
+//!
+//! [`SETTINGS`]: crate::flash::SETTINGS
+
+use crate::flash::{Flash, Region, PAGE_SIZE};
+use crate::image::Crc32;
+
+/// Size of the header at the start of each page
+pub const PAGE_HEADER_SIZE: u32 = 8;
+/// Size of the header in front of each value
+pub const RECORD_HEADER_SIZE: u32 = 8;
+/// Largest value, one that fills a page on its own
+pub const MAX_VALUE_SIZE: usize = (PAGE_SIZE - PAGE_HEADER_SIZE - RECORD_HEADER_SIZE) as usize;
+/// Most pages a store spans
+pub const MAX_PAGES: usize = 8;
+/// Marks a page of the log: "KV"
+const PAGE_MAGIC: u16 = 0x564B;
+/// What the key of a record that isn't written yet reads as
+const ERASED_KEY: u16 = 0xFFFF;
+
+/// Keys in use, listed here so apps sharing code don't clash
+pub mod key {
+    /// How many times the device started, see [`Settings::count_boot`]
+    ///
+    /// [`Settings::count_boot`]: super::Settings::count_boot
+    pub const BOOTS: u16 = 0x0001;
+    /// The controller's input bindings
+    pub const BINDINGS: u16 = 0x0100;
+    /// The controller's scenes
+    pub const SCENES: u16 = 0x0101;
+    /// The controller's cue list
+    pub const CUES: u16 = 0x0102;
+    /// What a fixture last showed
+    pub const LOOK: u16 = 0x0200;
+    /// A fixture's DMX start address and personality
+    pub const DMX: u16 = 0x0201;
+    /// A fixture's node id, one byte
+    pub const NODE_ID: u16 = 0x0202;
+    /// A device's channel plan: a fixture's as the controller or the command
+    /// line last set it, a controller's as its first survey picked it
+    pub const CHANNEL_PLAN: u16 = 0x0203;
+}
+
+/// Errors while changing settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError<E> {
+    Flash(E),
+    /// The value is over [`MAX_VALUE_SIZE`], or the key is reserved
+    Invalid,
+    /// The current values leave no room for it
+    Full,
+}
+
+impl<E> From<E> for SettingsError<E> {
+    fn from(e: E) -> Self {
+        SettingsError::Flash(e)
+    }
+}
+
+/// A record in the log
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    key: u16,
+    len: u16,
+    crc: u32,
+    /// Where its header starts
+    address: u32,
+}
+
+impl Record {
+    fn value(&self) -> u32 {
+        self.address + RECORD_HEADER_SIZE
+    }
+
+    fn size(&self) -> u32 {
+        RECORD_HEADER_SIZE + even(self.len as usize)
+    }
+}
+
+fn even(len: usize) -> u32 {
+    (len as u32 + 1) & !1
+}
+
+/// Where the log is. The store itself is in flash, this only saves finding
+/// the end of the log on every write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    region: Region,
+    /// Sequence number of each page in the log, `None` for free pages
+    pages: [Option<u32>; MAX_PAGES],
+    /// The page being written and where in it the next record goes
+    head: Option<(usize, u32)>,
+}
+
+impl Settings {
+    /// Find the log in `region`, which spans 2 to [`MAX_PAGES`] pages
+    pub fn mount<F: Flash>(flash: &mut F, region: Region) -> Result<Self, F::Error> {
+        let mut settings = Self {
+            region,
+            pages: [None; MAX_PAGES],
+            head: None,
+        };
+        for page in 0..settings.page_count() {
+            let mut header = [0u8; PAGE_HEADER_SIZE as usize];
+            flash.read(region.page(page as u32), &mut header)?;
+            if u16::from_le_bytes([header[4], header[5]]) == PAGE_MAGIC {
+                settings.pages[page] = Some(u32::from_le_bytes([header[0], header[1], header[2], header[3]]));
+            }
+        }
+
+        let (log, len) = settings.log();
+        if len > 0 {
+            let page = log[len - 1];
+            let address = region.page(page as u32);
+            let mut offset = PAGE_HEADER_SIZE;
+            while let Some(record) = read_record(flash, address, offset)? {
+                offset += record.size();
+            }
+            // A header cut short, nothing more fits after it
+            if offset < PAGE_SIZE && !erased(flash, address + offset, RECORD_HEADER_SIZE.min(PAGE_SIZE - offset))? {
+                offset = PAGE_SIZE;
+            }
+            settings.head = Some((page, offset));
+        }
+        Ok(settings)
+    }
+
+    /// Read the value of `key` into `buf`, returning its length, or `None`
+    /// if it isn't set or doesn't fit
+    pub fn read<F: Flash>(&self, flash: &mut F, key: u16, buf: &mut [u8]) -> Result<Option<usize>, F::Error> {
+        let record = match self.find(flash, key)? {
+            Some(record) if record.len > 0 && record.len as usize <= buf.len() => record,
+            _ => return Ok(None),
+        };
+        let len = record.len as usize;
+        flash.read(record.value(), &mut buf[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Set `key` to `value`, compacting the log if it's full
+    pub fn write<F: Flash>(&mut self, flash: &mut F, key: u16, value: &[u8]) -> Result<(), SettingsError<F::Error>> {
+        if key == ERASED_KEY || value.len() > MAX_VALUE_SIZE {
+            return Err(SettingsError::Invalid);
+        }
+        let size = RECORD_HEADER_SIZE + even(value.len());
+        // Compacting every page in turn frees all there is to free
+        for _ in 0..=self.page_count() {
+            if self.fits(size) {
+                self.append(flash, key, value)?;
+                return Ok(());
+            }
+            // Keep a page free for compaction
+            if self.head.is_none() || self.free_pages() > 1 {
+                self.open(flash)?;
+            } else {
+                self.compact(flash)?;
+            }
+        }
+        Err(SettingsError::Full)
+    }
+
+    /// Count a boot in [`key::BOOTS`], returning the count. It never repeats
+    /// while the settings last: a count that didn't make it to flash was
+    /// never used either, as it's written before it's returned.
+    pub fn count_boot<F: Flash>(&mut self, flash: &mut F) -> Result<u32, SettingsError<F::Error>> {
+        let mut value = [0u8; 4];
+        let boots = match self.read(flash, key::BOOTS, &mut value)? {
+            Some(4) => u32::from_le_bytes(value).wrapping_add(1),
+            _ => 0,
+        };
+        self.write(flash, key::BOOTS, &boots.to_le_bytes())?;
+        Ok(boots)
+    }
+
+    /// Unset `key`
+    pub fn remove<F: Flash>(&mut self, flash: &mut F, key: u16) -> Result<(), SettingsError<F::Error>> {
+        match self.find(flash, key)? {
+            Some(record) if record.len > 0 => self.write(flash, key, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    fn page_count(&self) -> usize {
+        (self.region.pages() as usize).min(MAX_PAGES)
+    }
+
+    fn free_pages(&self) -> usize {
+        self.pages[..self.page_count()].iter().filter(|page| page.is_none()).count()
+    }
+
+    /// The pages in the log, oldest first
+    fn log(&self) -> ([usize; MAX_PAGES], usize) {
+        let mut log = [0; MAX_PAGES];
+        let mut len = 0;
+        for (page, seq) in self.pages.iter().enumerate() {
+            if let Some(seq) = seq {
+                let at = log[..len].iter().position(|&other| self.pages[other] > Some(*seq)).unwrap_or(len);
+                log[at..=len].rotate_right(1);
+                log[at] = page;
+                len += 1;
+            }
+        }
+        (log, len)
+    }
+
+    fn fits(&self, size: u32) -> bool {
+        matches!(self.head, Some((_, offset)) if offset + size <= PAGE_SIZE)
+    }
+
+    /// The last intact record of `key`
+    fn find<F: Flash>(&self, flash: &mut F, key: u16) -> Result<Option<Record>, F::Error> {
+        let mut found = None;
+        let (log, len) = self.log();
+        for &page in &log[..len] {
+            let address = self.region.page(page as u32);
+            let mut offset = PAGE_HEADER_SIZE;
+            while let Some(record) = read_record(flash, address, offset)? {
+                offset += record.size();
+                if record.key == key && intact(flash, &record)? {
+                    found = Some(record);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Start a new page at the end of the log
+    fn open<F: Flash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        let (log, len) = self.log();
+        let seq = if len > 0 { self.pages[log[len - 1]].unwrap_or(0) + 1 } else { 0 };
+        // The free page after the head, so pages are used in turn
+        let count = self.page_count();
+        let start = self.head.map_or(0, |(page, _)| page + 1);
+        let page = (0..count)
+            .map(|i| (start + i) % count)
+            .find(|&page| self.pages[page].is_none())
+            .expect("a free page");
+
+        let address = self.region.page(page as u32);
+        // Erased when it was compacted, unless that was cut short
+        if !erased(flash, address, PAGE_SIZE)? {
+            flash.erase(address)?;
+        }
+        let mut header = [0xFF; PAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&seq.to_le_bytes());
+        header[4..6].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        flash.write(address, &header)?;
+        self.pages[page] = Some(seq);
+        self.head = Some((page, PAGE_HEADER_SIZE));
+        Ok(())
+    }
+
+    fn append<F: Flash>(&mut self, flash: &mut F, key: u16, value: &[u8]) -> Result<(), F::Error> {
+        let (page, offset) = self.head.expect("an open page");
+        let address = self.region.page(page as u32) + offset;
+        let mut crc = Crc32::new();
+        crc.update(&key.to_le_bytes());
+        crc.update(&(value.len() as u16).to_le_bytes());
+        crc.update(value);
+
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        header[..2].copy_from_slice(&key.to_le_bytes());
+        header[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        header[4..].copy_from_slice(&crc.finalize().to_le_bytes());
+        // The header first, so a value cut short still has its length
+        flash.write(address, &header)?;
+
+        let start = address + RECORD_HEADER_SIZE;
+        let even_len = value.len() & !1;
+        if even_len > 0 {
+            flash.write(start, &value[..even_len])?;
+        }
+        if even_len < value.len() {
+            // Pad with the erased value
+            flash.write(start + even_len as u32, &[value[even_len], 0xFF])?;
+        }
+        self.head = Some((page, offset + RECORD_HEADER_SIZE + even(value.len())));
+        Ok(())
+    }
+
+    /// Move the current records of the oldest page to the end of the log and
+    /// erase it
+    fn compact<F: Flash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        let (log, _) = self.log();
+        let page = log[0];
+        let address = self.region.page(page as u32);
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = read_record(flash, address, offset)? {
+            offset += record.size();
+            let current = self.find(flash, record.key)?.map(|found| found.address) == Some(record.address);
+            // A removed key's record can go, there's nothing older to hide
+            if !current || record.len == 0 {
+                continue;
+            }
+            if !self.fits(record.size()) {
+                self.open(flash)?;
+            }
+            let (head, head_offset) = self.head.expect("an open page");
+            let to = self.region.page(head as u32) + head_offset;
+            let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+            header[..2].copy_from_slice(&record.key.to_le_bytes());
+            header[2..4].copy_from_slice(&record.len.to_le_bytes());
+            header[4..].copy_from_slice(&record.crc.to_le_bytes());
+            flash.write(to, &header)?;
+            crate::flash::copy(flash, record.value(), to + RECORD_HEADER_SIZE, even(record.len as usize))?;
+            self.head = Some((head, head_offset + record.size()));
+        }
+        flash.erase(address)?;
+        self.pages[page] = None;
+        Ok(())
+    }
+}
+
+/// The record at `offset` in the page at `page`, if there's one
+fn read_record<F: Flash>(flash: &mut F, page: u32, offset: u32) -> Result<Option<Record>, F::Error> {
+    if offset + RECORD_HEADER_SIZE > PAGE_SIZE {
+        return Ok(None);
+    }
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    flash.read(page + offset, &mut header)?;
+    let record = Record {
+        key: u16::from_le_bytes([header[0], header[1]]),
+        len: u16::from_le_bytes([header[2], header[3]]),
+        crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        address: page + offset,
+    };
+    if record.key == ERASED_KEY || record.len as usize > MAX_VALUE_SIZE || offset + record.size() > PAGE_SIZE {
+        return Ok(None);
+    }
+    Ok(Some(record))
+}
+
+/// Whether the value of `record` is what was written
+fn intact<F: Flash>(flash: &mut F, record: &Record) -> Result<bool, F::Error> {
+    let mut crc = Crc32::new();
+    crc.update(&record.key.to_le_bytes());
+    crc.update(&record.len.to_le_bytes());
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < record.len as u32 {
+        let n = (record.len as u32 - offset).min(buf.len() as u32) as usize;
+        flash.read(record.value() + offset, &mut buf[..n])?;
+        crc.update(&buf[..n]);
+        offset += n as u32;
+    }
+    Ok(crc.finalize() == record.crc)
+}
+
+/// Whether the `len` bytes at `address` are all erased
+fn erased<F: Flash>(flash: &mut F, address: u32, len: u32) -> Result<bool, F::Error> {
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(buf.len() as u32) as usize;
+        flash.read(address + offset, &mut buf[..n])?;
+        if buf[..n].iter().any(|&byte| byte != 0xFF) {
+            return Ok(false);
+        }
+        offset += n as u32;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::ram::{PowerCut, RamFlash};
+    use crate::flash::SETTINGS;
+
+    /// Read `key` back as a vector, `None` if it isn't set
+    fn get(settings: &Settings, flash: &mut RamFlash, key: u16) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        settings.read(flash, key, &mut buf).unwrap().map(|len| buf[..len].to_vec())
+    }
+
+    /// A value that differs with the round and the key, of a size that
+    /// doesn't line up with the pages
+    fn value(round: usize, key: u16) -> Vec<u8> {
+        let len = 1 + (round * 7 + key as usize * 13) % 90;
+        (0..len).map(|i| (round + i + key as usize) as u8).collect()
+    }
+
+    #[test]
+    fn values_survive_churn_and_remounting() {
+        let mut flash = RamFlash::new();
+        let mut settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+        assert_eq!(get(&settings, &mut flash, 7), None);
+
+        // Enough rounds to go round every page many times
+        for round in 0..400 {
+            for key in 0..5 {
+                settings.write(&mut flash, key, &value(round, key)).unwrap();
+            }
+            if round % 50 == 0 {
+                settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+            }
+        }
+        settings.remove(&mut flash, 3).unwrap();
+
+        let settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+        for key in 0..5 {
+            let expected = if key == 3 { None } else { Some(value(399, key)) };
+            assert_eq!(get(&settings, &mut flash, key), expected, "key {}", key);
+        }
+    }
+
+    #[test]
+    fn values_that_do_not_fit_are_refused() {
+        let mut flash = RamFlash::new();
+        let mut settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+        let big = [0xA5; MAX_VALUE_SIZE];
+        assert_eq!(settings.write(&mut flash, 1, &[0; MAX_VALUE_SIZE + 1]), Err(SettingsError::Invalid));
+        assert_eq!(settings.write(&mut flash, ERASED_KEY, &[0]), Err(SettingsError::Invalid));
+
+        // Every page but the one kept for compaction holds one
+        for key in 1..SETTINGS.pages() as u16 {
+            settings.write(&mut flash, key, &big).unwrap();
+        }
+        assert_eq!(settings.write(&mut flash, 0, &big), Err(SettingsError::Full));
+        // Trying to make room lost nothing
+        let settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+        for key in 1..SETTINGS.pages() as u16 {
+            assert_eq!(get(&settings, &mut flash, key), Some(big.to_vec()));
+        }
+        assert_eq!(get(&settings, &mut flash, 0), None);
+    }
+
+    #[test]
+    fn boots_count_up() {
+        let mut flash = RamFlash::new();
+        for boot in 0..5 {
+            let mut settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+            assert_eq!(settings.count_boot(&mut flash), Ok(boot));
+        }
+    }
+
+    /// Losing power at any point of a write leaves the old value or the new
+    /// one, never touches the other keys, and the store carries on working
+    #[test]
+    fn power_cuts_at_every_write() {
+        // Writes that go through compaction and opening pages
+        let writes: Vec<(u16, Vec<u8>)> = (0..60).map(|round| ((round % 3) as u16, value(round, 0))).collect();
+
+        let mut cut = 0;
+        loop {
+            let mut flash = RamFlash::new();
+            let mut settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+            let mut current: [Option<Vec<u8>>; 3] = Default::default();
+            flash.power = Some(cut);
+            let mut interrupted = None;
+            for (key, value) in writes.iter() {
+                match settings.write(&mut flash, *key, value) {
+                    Ok(()) => current[*key as usize] = Some(value.clone()),
+                    Err(e) => {
+                        assert_eq!(e, SettingsError::Flash(PowerCut));
+                        interrupted = Some((*key, value.clone()));
+                        break;
+                    }
+                }
+            }
+            let (key, written) = match interrupted {
+                Some(interrupted) => interrupted,
+                // Enough power for all of them, every cut was tried
+                None => break,
+            };
+
+            flash.power = None;
+            let mut settings = Settings::mount(&mut flash, SETTINGS).unwrap();
+            for other in 0..3 {
+                let found = get(&settings, &mut flash, other);
+                if other == key {
+                    assert!(found == current[other as usize] || found == Some(written.clone()), "cut {}", cut);
+                } else {
+                    assert_eq!(found, current[other as usize], "cut {}, key {}", cut, other);
+                }
+            }
+            for round in 0..20 {
+                settings.write(&mut flash, 9, &value(round, 9)).unwrap();
+            }
+            assert_eq!(get(&settings, &mut flash, 9), Some(value(19, 9)), "cut {}", cut);
+            cut += 1;
+        }
+        assert!(cut > 500);
+    }
+}
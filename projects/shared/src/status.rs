@@ -0,0 +1,126 @@
+//! Fixture status, carried back to the controller in ACK payloads.
+//!
+//! A fixture keeps its latest status loaded as the payload of its next ACK,
+//! so the controller learns it with every unicast frame it sends, at no
+//! extra airtime. The payload is small and unencrypted: it only ever
+//! describes the fixture, and the controller never acts on it.
+//!
+//! ```text
+//! | version | effect | brightness | flags | temperature | uptime (4, LE) |
+//! ```
+
+use crate::protocol::{DecodeError, Effect};
+use core::fmt;
+
+/// Size of an encoded status
+pub const STATUS_SIZE: usize = 9;
+/// Bumped whenever the layout changes
+pub const STATUS_VERSION: u8 = 1;
+
+/// Set in the flags byte while the power limiter dims the LEDs
+const POWER_LIMITED: u8 = 0b0000_0001;
+
+/// What a fixture is doing, as reported in ACK payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixtureStatus {
+    pub effect: Effect,
+    pub brightness: u8,
+    /// The LEDs are dimmed to stay within the power budget
+    pub power_limited: bool,
+    /// Die temperature of the microcontroller, in °C
+    pub temperature: i8,
+    /// Seconds since power up
+    pub uptime: u32,
+}
+
+impl FixtureStatus {
+    pub fn encode(&self) -> [u8; STATUS_SIZE] {
+        let mut out = [0u8; STATUS_SIZE];
+        out[0] = STATUS_VERSION;
+        out[1] = self.effect.to_byte();
+        out[2] = self.brightness;
+        out[3] = if self.power_limited { POWER_LIMITED } else { 0 };
+        out[4] = self.temperature as u8;
+        out[5..9].copy_from_slice(&self.uptime.to_le_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < STATUS_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        // Another layout comes with another version, and its own length
+        if data[0] != STATUS_VERSION || data.len() != STATUS_SIZE {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(Self {
+            effect: Effect::from_byte(data[1]).ok_or(DecodeError::InvalidValue)?,
+            brightness: data[2],
+            power_limited: data[3] & POWER_LIMITED != 0,
+            temperature: data[4] as i8,
+            uptime: u32::from_le_bytes([data[5], data[6], data[7], data[8]]),
+        })
+    }
+}
+
+impl fmt::Display for FixtureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {}/255{}, {} °C, up {} s",
+            self.effect,
+            self.brightness,
+            if self.power_limited { " (power limited)" } else { "" },
+            self.temperature,
+            self.uptime
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: FixtureStatus = FixtureStatus {
+        effect: Effect::Rainbow,
+        brightness: 128,
+        power_limited: true,
+        temperature: -12,
+        uptime: 0x0102_0304,
+    };
+
+    #[test]
+    fn statuses_survive_a_round_trip() {
+        let encoded = STATUS.encode();
+        assert_eq!(encoded, [STATUS_VERSION, 2, 128, POWER_LIMITED, 0xF4, 4, 3, 2, 1]);
+        assert_eq!(FixtureStatus::decode(&encoded), Ok(STATUS));
+        let unlimited = FixtureStatus { power_limited: false, temperature: 40, ..STATUS };
+        assert_eq!(FixtureStatus::decode(&unlimited.encode()), Ok(unlimited));
+    }
+
+    #[test]
+    fn payloads_of_the_wrong_length_are_refused() {
+        let encoded = STATUS.encode();
+        for len in 0..STATUS_SIZE {
+            assert_eq!(FixtureStatus::decode(&encoded[..len]), Err(DecodeError::TooShort));
+        }
+        let mut long = [0u8; STATUS_SIZE + 1];
+        long[..STATUS_SIZE].copy_from_slice(&encoded);
+        assert_eq!(FixtureStatus::decode(&long), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn other_versions_and_effects_are_refused() {
+        let mut encoded = STATUS.encode();
+        encoded[0] = STATUS_VERSION + 1;
+        assert_eq!(FixtureStatus::decode(&encoded), Err(DecodeError::InvalidValue));
+        let mut encoded = STATUS.encode();
+        encoded[1] = 9;
+        assert_eq!(FixtureStatus::decode(&encoded), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn statuses_print_what_matters() {
+        assert_eq!(STATUS.to_string(), "Rainbow at 128/255 (power limited), -12 °C, up 16909060 s");
+    }
+}
@@ -0,0 +1,223 @@
+//! Pixels streamed live from a PC, for shows synced to video.
+//!
+//! The PC draws every frame itself and sends fixtures only what changed
+//! since the frame before. Pixels are indices into a palette of
+//! [`PALETTE_SIZE`] colours, set with [`Message::StreamPalette`]. Ranges of
+//! them come in [`Message::StreamPixels`], run length encoded a byte a run:
+//!
+//! ```text
+//! | length - 1 (4 bits) | palette index (4 bits) |
+//! ```
+//!
+//! Both carry the number of the frame they're part of. A fixture draws them
+//! on a canvas and only shows it when [`Message::StreamShow`] arrives for
+//! the frame it was last drawn for, which is broadcast so every fixture
+//! changes at once and none shows a frame half drawn. Once nothing was shown
+//! for [`STREAM_TIMEOUT`] the fixture goes back to its own effect.
+//!
+//! [`Message::StreamPalette`]: crate::protocol::Message::StreamPalette
+//! [`Message::StreamPixels`]: crate::protocol::Message::StreamPixels
+//! [`Message::StreamShow`]: crate::protocol::Message::StreamShow
+
+use crate::protocol::Message;
+use crate::secure::MAX_SECURE_PAYLOAD;
+use smart_leds::RGB8;
+
+/// Colours in the palette
+pub const PALETTE_SIZE: usize = 16;
+/// Pixels a stream can address
+pub const MAX_STREAM_PIXELS: usize = 256;
+/// Palette entries per message, after the frame and first index
+pub const MAX_STREAM_COLORS: usize = (MAX_SECURE_PAYLOAD - 2) / 3;
+/// Runs per message, after the frame and start
+pub const MAX_RUNS: usize = MAX_SECURE_PAYLOAD - 2;
+/// Longest run a byte can hold
+pub const MAX_RUN: usize = 16;
+/// How long after the last frame was shown fixtures go back to their own
+/// effect, in µs
+pub const STREAM_TIMEOUT: u64 = 1_000_000;
+
+/// A run of `len` pixels showing palette entry `index`
+pub fn encode_run(len: usize, index: u8) -> u8 {
+    ((len as u8 - 1) << 4) | (index & 0x0f)
+}
+
+/// The length and palette index of a run
+pub fn decode_run(run: u8) -> (usize, u8) {
+    ((run >> 4) as usize + 1, run & 0x0f)
+}
+
+/// The stream as a fixture sees it
+#[derive(Clone)]
+pub struct Stream {
+    /// Being drawn on
+    canvas: [u8; MAX_STREAM_PIXELS],
+    palette: [RGB8; PALETTE_SIZE],
+    /// Frame the canvas was last drawn for
+    drawing: u8,
+    /// The canvas changed since it was last shown
+    drawn: bool,
+    /// What's shown
+    shown: [u8; MAX_STREAM_PIXELS],
+    shown_palette: [RGB8; PALETTE_SIZE],
+    /// Network time the last frame was shown at
+    shown_at: Option<u64>,
+}
+
+impl Stream {
+    pub const fn new() -> Self {
+        Self {
+            canvas: [0; MAX_STREAM_PIXELS],
+            palette: [RGB8 { r: 0, g: 0, b: 0 }; PALETTE_SIZE],
+            drawing: 0,
+            drawn: false,
+            shown: [0; MAX_STREAM_PIXELS],
+            shown_palette: [RGB8 { r: 0, g: 0, b: 0 }; PALETTE_SIZE],
+            shown_at: None,
+        }
+    }
+
+    /// Apply a stream message received at network time `now`, ignoring the
+    /// others
+    pub fn apply(&mut self, message: &Message, now: u64) {
+        match *message {
+            Message::StreamPalette { frame, first, len, colors } => {
+                self.drawing = frame;
+                self.drawn = true;
+                let first = first as usize;
+                for (entry, &color) in self.palette.iter_mut().skip(first).zip(&colors[..len as usize]) {
+                    *entry = color;
+                }
+            }
+            Message::StreamPixels { frame, start, len, runs } => {
+                self.drawing = frame;
+                self.drawn = true;
+                let mut pixel = start as usize;
+                for &run in &runs[..len as usize] {
+                    let (len, index) = decode_run(run);
+                    let end = (pixel + len).min(MAX_STREAM_PIXELS);
+                    for p in &mut self.canvas[pixel..end] {
+                        *p = index;
+                    }
+                    pixel = end;
+                }
+            }
+            // Frames that didn't change anything come without pixels, but
+            // ones drawn for another frame must wait for theirs
+            Message::StreamShow { frame } if !self.drawn || frame == self.drawing => {
+                self.shown = self.canvas;
+                self.shown_palette = self.palette;
+                self.shown_at = Some(now);
+                self.drawn = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether frames are being shown at network time `now`
+    pub fn is_live(&self, now: u64) -> bool {
+        matches!(self.shown_at, Some(at) if now.saturating_sub(at) < STREAM_TIMEOUT)
+    }
+
+    /// The colours of the shown frame, from the first pixel on, while the
+    /// stream is live
+    pub fn pixels(&self, now: u64) -> Option<impl Iterator<Item = RGB8> + '_> {
+        if !self.is_live(now) {
+            return None;
+        }
+        Some(self.shown.iter().map(move |&index| self.shown_palette[index as usize]))
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DecodeError, Message};
+
+    const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+
+    fn palette(first: u8, entries: &[RGB8]) -> Message {
+        let mut colors = [RGB8::default(); MAX_STREAM_COLORS];
+        colors[..entries.len()].copy_from_slice(entries);
+        Message::StreamPalette { frame: 1, first, len: entries.len() as u8, colors }
+    }
+
+    fn pixels(frame: u8, start: u8, encoded: &[u8]) -> Message {
+        let mut runs = [0u8; MAX_RUNS];
+        runs[..encoded.len()].copy_from_slice(encoded);
+        Message::StreamPixels { frame, start, len: encoded.len() as u8, runs }
+    }
+
+    #[test]
+    fn runs_hold_a_length_and_an_index() {
+        for len in 1..=MAX_RUN {
+            for index in 0..PALETTE_SIZE as u8 {
+                assert_eq!(decode_run(encode_run(len, index)), (len, index));
+            }
+        }
+    }
+
+    #[test]
+    fn frames_are_shown_once_drawn_whole() {
+        let mut stream = Stream::new();
+        assert!(stream.pixels(0).is_none());
+        stream.apply(&palette(0, &[BLUE, RED]), 0);
+        stream.apply(&pixels(1, 2, &[encode_run(3, 1), encode_run(1, 0)]), 0);
+        // A show for another frame doesn't show this one half drawn
+        stream.apply(&Message::StreamShow { frame: 0 }, 10);
+        assert!(stream.pixels(10).is_none());
+        stream.apply(&Message::StreamShow { frame: 1 }, 20);
+        let shown: Vec<RGB8> = stream.pixels(20).unwrap().take(7).collect();
+        assert_eq!(shown, [BLUE, BLUE, RED, RED, RED, BLUE, BLUE]);
+
+        // Nothing changed, so the next frame comes without pixels
+        stream.apply(&Message::StreamShow { frame: 2 }, STREAM_TIMEOUT);
+        assert!(stream.is_live(STREAM_TIMEOUT * 2 - 1));
+        assert!(!stream.is_live(STREAM_TIMEOUT * 2));
+        assert!(stream.pixels(STREAM_TIMEOUT * 2).is_none());
+    }
+
+    #[test]
+    fn drawing_stops_at_the_ends() {
+        let mut stream = Stream::new();
+        // Entries past the palette are dropped
+        stream.apply(&palette(PALETTE_SIZE as u8 - 1, &[RED, BLUE, BLUE]), 0);
+        // A run past the last pixel is cut short
+        stream.apply(&pixels(1, u8::MAX, &[encode_run(MAX_RUN, PALETTE_SIZE as u8 - 1)]), 0);
+        stream.apply(&Message::StreamShow { frame: 1 }, 0);
+        let shown: Vec<RGB8> = stream.pixels(0).unwrap().collect();
+        assert_eq!(shown.len(), MAX_STREAM_PIXELS);
+        assert_eq!(shown[MAX_STREAM_PIXELS - 1], RED);
+        assert_eq!(shown[MAX_STREAM_PIXELS - 2], RGB8::default());
+    }
+
+    #[test]
+    fn short_chunks_draw_only_what_they_carry() {
+        let run = encode_run(2, 1);
+        let kind = pixels(1, 4, &[run]).kind();
+        assert_eq!(Message::decode(kind, &[1, 4, run]), Ok(pixels(1, 4, &[run])));
+        let palette_kind = palette(0, &[RED]).kind();
+        assert_eq!(Message::decode(palette_kind, &[1, 0, 255, 0, 0]), Ok(palette(0, &[RED])));
+
+        let mut stream = Stream::new();
+        stream.apply(&palette(1, &[RED]), 0);
+        stream.apply(&pixels(1, 4, &[run]), 0);
+        stream.apply(&Message::StreamShow { frame: 1 }, 0);
+        let shown: Vec<RGB8> = stream.pixels(0).unwrap().collect();
+        let lit: Vec<usize> = (0..MAX_STREAM_PIXELS).filter(|&i| shown[i] == RED).collect();
+        assert_eq!(lit, [4, 5]);
+
+        // Headers without any runs or colours are refused, as are cut colours
+        assert_eq!(Message::decode(kind, &[1, 4]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(palette_kind, &[1, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(palette_kind, &[1, 0, 255, 0]), Err(DecodeError::InvalidValue));
+        assert_eq!(Message::decode(kind, &[1]), Err(DecodeError::TooShort));
+    }
+}
@@ -0,0 +1,312 @@
+//! Link-quality statistics, kept per peer so flaky installs can be debugged.
+//!
+//! The radio layer records every unicast frame it sends and receives here.
+//! Besides our own view of a link, a fixture reports its side of it in a
+//! [`LinkReport`] when the controller sends a [`Message::StatusQuery`].
+//!
+//! [`Message::StatusQuery`]: crate::protocol::Message::StatusQuery
+
+use crate::address::NodeId;
+use crate::hopping::CHANNEL_COUNT;
+use crate::secure::MAX_PEERS;
+use crate::status::FixtureStatus;
+use core::fmt;
+
+/// How one side of a link has been doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Unicast frames sent to the peer
+    pub sent: u32,
+    /// Frames the peer acknowledged
+    pub acked: u32,
+    /// Retransmits needed, summed from `ARC_CNT`
+    pub retransmits: u32,
+    /// Frames received from the peer
+    pub received: u32,
+    /// Received frames stronger than -64 dBm, according to the power detector
+    pub strong: u32,
+    /// Frames from the peer that failed to decode or authenticate, or were replayed
+    pub rejected: u32,
+    /// Sum of the time from sending a frame until its ACK, in microseconds
+    latency_total: u32,
+    /// Longest time from sending a frame until its ACK, in microseconds
+    pub latency_max: u32,
+    /// The last status the peer sent back in an ACK payload
+    pub status: Option<FixtureStatus>,
+}
+
+impl PeerStats {
+    /// Frames sent that were never acknowledged
+    pub fn lost(&self) -> u32 {
+        self.sent.wrapping_sub(self.acked)
+    }
+
+    /// Share of frames sent that were lost, in parts per thousand
+    pub fn loss(&self) -> u32 {
+        if self.sent == 0 {
+            0
+        } else {
+            // Once `sent` wraps it may be below `lost` for a while
+            (self.lost() as u64 * 1000 / self.sent as u64).min(1000) as u32
+        }
+    }
+
+    /// Average time from sending a frame until its ACK, in microseconds
+    pub fn latency(&self) -> u32 {
+        self.latency_total.checked_div(self.acked).unwrap_or(0)
+    }
+
+    /// Record a frame we sent, `latency` is only meaningful if it was acked
+    pub fn record_sent(&mut self, acked: bool, retransmits: u8, latency: u32) {
+        self.sent = self.sent.wrapping_add(1);
+        self.retransmits = self.retransmits.wrapping_add(retransmits as u32);
+        if acked {
+            self.acked = self.acked.wrapping_add(1);
+            self.latency_total = self.latency_total.saturating_add(latency);
+            self.latency_max = self.latency_max.max(latency);
+        }
+    }
+
+    /// Record a frame received from the peer
+    pub fn record_received(&mut self, strong: bool) {
+        self.received = self.received.wrapping_add(1);
+        if strong {
+            self.strong = self.strong.wrapping_add(1);
+        }
+    }
+
+    pub fn record_rejected(&mut self) {
+        self.rejected = self.rejected.wrapping_add(1);
+    }
+
+    /// A compact copy of these statistics to send over the air
+    pub fn report(&self) -> LinkReport {
+        let clamp = |n: u32| n.min(u16::MAX as u32) as u16;
+        LinkReport {
+            sent: clamp(self.sent),
+            acked: clamp(self.acked),
+            retransmits: clamp(self.retransmits),
+            received: clamp(self.received),
+            strong: clamp(self.strong),
+            rejected: clamp(self.rejected),
+            latency: clamp(self.latency()),
+        }
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.report().fmt(f)?;
+        write!(f, ", peak {} us", self.latency_max)
+    }
+}
+
+/// A peer's view of its link, as carried by [`Message::LinkStatus`].
+///
+/// Counters saturate at `u16::MAX`.
+///
+/// [`Message::LinkStatus`]: crate::protocol::Message::LinkStatus
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkReport {
+    pub sent: u16,
+    pub acked: u16,
+    pub retransmits: u16,
+    pub received: u16,
+    pub strong: u16,
+    pub rejected: u16,
+    /// Average time until an ACK, in microseconds
+    pub latency: u16,
+}
+
+impl fmt::Display for LinkReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lost = self.sent.wrapping_sub(self.acked);
+        let loss = if self.sent == 0 { 0 } else { lost as u32 * 1000 / self.sent as u32 };
+        write!(
+            f,
+            "sent {}, lost {} ({}.{}%), {} retransmits, received {} ({} strong), {} rejected, latency {} us",
+            self.sent,
+            lost,
+            loss / 10,
+            loss % 10,
+            self.retransmits,
+            self.received,
+            self.strong,
+            self.rejected,
+            self.latency
+        )
+    }
+}
+
+/// Statistics for every peer we talk to, and frames lost on each channel
+#[derive(Clone)]
+pub struct LinkStats {
+    peers: [Option<(NodeId, PeerStats)>; MAX_PEERS],
+    /// Frames lost on each channel, from `PLOS_CNT`
+    channel_losses: [u16; CHANNEL_COUNT as usize],
+    /// Channel and `PLOS_CNT` seen by the last [`LinkStats::observe`]
+    last_observed: (u8, u8),
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        Self {
+            peers: [None; MAX_PEERS],
+            channel_losses: [0; CHANNEL_COUNT as usize],
+            last_observed: (0, 0),
+        }
+    }
+
+    /// Statistics for `id`, or `None` if we never heard of it
+    pub fn peer(&self, id: NodeId) -> Option<&PeerStats> {
+        self.peers.iter().flatten().find(|(peer, _)| *peer == id).map(|(_, stats)| stats)
+    }
+
+    /// Statistics for `id`, starting a new entry if there is room.
+    ///
+    /// Once the table is full, new peers are not tracked.
+    pub fn peer_mut(&mut self, id: NodeId) -> Option<&mut PeerStats> {
+        let slot = self.peers.iter_mut().find(|slot| match slot {
+            Some((peer, _)) => *peer == id,
+            None => true,
+        })?;
+        let (_, stats) = slot.get_or_insert((id, PeerStats::default()));
+        Some(stats)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &(NodeId, PeerStats)> {
+        self.peers.iter().flatten()
+    }
+
+    /// Fold in the `PLOS_CNT` read while on `channel`.
+    ///
+    /// The radio clears the counter whenever the channel is set, so only
+    /// increases since the last observation on the same channel are counted.
+    pub fn observe(&mut self, channel: u8, lost: u8) {
+        let (last_channel, last_lost) = self.last_observed;
+        let new = if channel == last_channel && lost >= last_lost {
+            lost - last_lost
+        } else {
+            lost
+        };
+        if let Some(losses) = self.channel_losses.get_mut(channel as usize) {
+            *losses = losses.saturating_add(new as u16);
+        }
+        self.last_observed = (channel, lost);
+    }
+
+    /// Frames lost on `channel`
+    pub fn channel_losses(&self, channel: u8) -> u16 {
+        self.channel_losses.get(channel as usize).copied().unwrap_or(0)
+    }
+}
+
+impl Default for LinkStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Message, FRAME_SIZE};
+
+    #[test]
+    fn counters_wrap_without_losing_track() {
+        let mut peer = PeerStats { sent: u32::MAX - 1, acked: u32::MAX - 10, ..PeerStats::default() };
+        for &acked in &[true, false, true, true] {
+            peer.record_sent(acked, 2, 100);
+        }
+        assert_eq!((peer.sent, peer.acked), (2, u32::MAX - 7));
+        assert_eq!(peer.lost(), 10);
+        // More lost than sent since the wrap is still no more than all of it
+        assert_eq!(peer.loss(), 1000);
+        assert_eq!(peer.retransmits, 8);
+        assert_eq!(peer.latency(), 0);
+        assert_eq!(peer.latency_max, 100);
+
+        let mut peer = PeerStats { received: u32::MAX, strong: u32::MAX, rejected: u32::MAX, ..PeerStats::default() };
+        peer.record_received(true);
+        peer.record_rejected();
+        assert_eq!((peer.received, peer.strong, peer.rejected), (0, 0, 0));
+    }
+
+    #[test]
+    fn latency_is_averaged_over_acked_frames() {
+        let mut peer = PeerStats::default();
+        peer.record_sent(true, 0, 300);
+        peer.record_sent(false, 15, 99_999);
+        peer.record_sent(true, 1, 500);
+        assert_eq!(peer.latency(), 400);
+        assert_eq!(peer.latency_max, 500);
+        assert_eq!(peer.loss(), 333);
+        assert_eq!(peer.to_string(), format!("{}, peak 500 us", peer.report()));
+    }
+
+    #[test]
+    fn reports_saturate() {
+        let peer = PeerStats { sent: 100_000, acked: 70_000, received: 5, ..PeerStats::default() };
+        let report = peer.report();
+        assert_eq!((report.sent, report.acked, report.received), (u16::MAX, u16::MAX, 5));
+        assert_eq!(
+            report.to_string(),
+            "sent 65535, lost 0 (0.0%), 0 retransmits, received 5 (0 strong), 0 rejected, latency 0 us"
+        );
+    }
+
+    #[test]
+    fn reports_survive_the_air() {
+        let report = LinkReport {
+            sent: u16::MAX,
+            acked: 1,
+            retransmits: 0x1234,
+            received: 2,
+            strong: 3,
+            rejected: 4,
+            latency: 0xABCD,
+        };
+        let message = Message::LinkStatus(report);
+        let mut out = [0u8; FRAME_SIZE];
+        let len = message.encode(&mut out);
+        assert_eq!(Message::decode(message.kind(), &out[..len]), Ok(message));
+        assert!(Message::decode(message.kind(), &out[..len - 1]).is_err());
+    }
+
+    #[test]
+    fn channel_losses_count_what_the_radio_added() {
+        let mut stats = LinkStats::new();
+        stats.observe(10, 3);
+        stats.observe(10, 5);
+        // Retuning clears the radio's counter
+        stats.observe(20, 2);
+        stats.observe(10, 1);
+        // So does hopping back, even without us seeing it
+        stats.observe(10, 0);
+        stats.observe(10, 4);
+        assert_eq!(stats.channel_losses(10), 3 + 2 + 1 + 4);
+        assert_eq!(stats.channel_losses(20), 2);
+        stats.observe(CHANNEL_COUNT, 9);
+        assert_eq!(stats.channel_losses(CHANNEL_COUNT), 0);
+
+        let mut stats = LinkStats::new();
+        for _ in 0..5000 {
+            stats.observe(1, 15);
+            stats.observe(2, 15);
+        }
+        assert_eq!(stats.channel_losses(1), u16::MAX);
+    }
+
+    #[test]
+    fn peers_past_the_table_are_not_tracked() {
+        let mut stats = LinkStats::new();
+        for id in 1..=MAX_PEERS as NodeId {
+            stats.peer_mut(id).unwrap().record_received(false);
+        }
+        assert!(stats.peer_mut(MAX_PEERS as NodeId + 1).is_none());
+        stats.peer_mut(1).unwrap().record_received(false);
+        assert_eq!(stats.peer(1).unwrap().received, 2);
+        assert!(stats.peer(MAX_PEERS as NodeId + 1).is_none());
+        assert_eq!(stats.peers().count(), MAX_PEERS);
+    }
+}
@@ -0,0 +1,187 @@
+//! Network time, so effects on every fixture stay in step.
+//!
+//! The controller's clock is the network time. It broadcasts
+//! [`Message::TimeSync`] beacons with its time in microseconds, and fixtures
+//! fit their own clock to it: the offset between the two, and the drift of
+//! their oscillator against the controller's. Effects then run off the
+//! network time instead of the local one.
+//!
+//! Each beacon moves the estimate half way towards what it measured, so a
+//! single late frame only nudges it. Beacons that are far off, as after the
+//! controller restarted, start the estimate over.
+//!
+//! [`Message::TimeSync`]: crate::protocol::Message::TimeSync
+
+use crate::radio::CYCLES_PER_US;
+
+/// How often the controller sends a beacon: 1 s at 48 MHz
+pub const SYNC_INTERVAL: u32 = 48_000_000;
+/// Time from stamping a beacon until it is received: a full frame at 250 kbps
+pub const AIR_TIME_US: u64 = 1_300;
+/// Beacons further off than this restart synchronisation
+pub const STEP_THRESHOLD_US: i64 = 50_000;
+
+/// Share of each measured error applied to the drift, as a right shift
+const DRIFT_GAIN: u32 = 2;
+
+/// CYCCNT extended to 64 bits, so it doesn't wrap every 89 seconds.
+///
+/// Has to be read at least every 44 seconds. Readings taken a little while
+/// ago, as by a task that was preempted, are fine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalClock {
+    last: u32,
+    ticks: u64,
+}
+
+impl LocalClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ticks since the counter started, given its value at some recent point
+    pub fn ticks(&mut self, cycles: u32) -> u64 {
+        let delta = cycles.wrapping_sub(self.last) as i32;
+        if delta >= 0 {
+            self.ticks += delta as u64;
+            self.last = cycles;
+            self.ticks
+        } else {
+            // From before we started counting, if the counter was read
+            // before the clock was made
+            self.ticks.saturating_sub((-(delta as i64)) as u64)
+        }
+    }
+
+    pub fn micros(&mut self, cycles: u32) -> u64 {
+        self.ticks(cycles) / CYCLES_PER_US as u64
+    }
+}
+
+/// An estimate of the network time from local time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockSync {
+    /// A local time and the network time it corresponds to
+    reference: Option<(u64, u64)>,
+    /// How much faster the network clock runs than ours, in parts per billion
+    drift: i64,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Drift of our clock against the network, in parts per billion
+    pub fn drift(&self) -> i64 {
+        self.drift
+    }
+
+    /// The network time at `local` microseconds, once synchronised
+    pub fn network_time(&self, local: u64) -> Option<u64> {
+        let (reference_local, reference_network) = self.reference?;
+        let elapsed = local.wrapping_sub(reference_local) as i64;
+        let drift = elapsed * self.drift / 1_000_000_000;
+        Some(reference_network.wrapping_add((elapsed + drift) as u64))
+    }
+
+    /// Fit to a beacon saying the network time was `network` at `local`,
+    /// returning how far off our estimate was in microseconds
+    pub fn update(&mut self, local: u64, network: u64) -> i64 {
+        let (reference_local, predicted) = match (self.reference, self.network_time(local)) {
+            (Some((reference_local, _)), Some(predicted)) => (reference_local, predicted),
+            _ => {
+                self.reference = Some((local, network));
+                return 0;
+            }
+        };
+
+        let error = network.wrapping_sub(predicted) as i64;
+        if error.abs() > STEP_THRESHOLD_US {
+            self.reference = Some((local, network));
+            self.drift = 0;
+            return error;
+        }
+
+        let elapsed = local.wrapping_sub(reference_local) as i64;
+        if elapsed > 0 {
+            self.drift += (error * 1_000_000_000 / elapsed) >> DRIFT_GAIN;
+        }
+        self.reference = Some((local, predicted.wrapping_add((error / 2) as u64)));
+        error
+    }
+}
+
+/// Local and network time in one place, as effects need them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkClock {
+    local: LocalClock,
+    sync: ClockSync,
+}
+
+impl NetworkClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sync(&self) -> &ClockSync {
+        &self.sync
+    }
+
+    /// Local time in microseconds, given the current CYCCNT
+    pub fn local_micros(&mut self, cycles: u32) -> u64 {
+        self.local.micros(cycles)
+    }
+
+    /// Network time in microseconds, or local time until the first beacon
+    pub fn now(&mut self, cycles: u32) -> u64 {
+        let local = self.local.micros(cycles);
+        self.sync.network_time(local).unwrap_or(local)
+    }
+
+    /// Handle a beacon stamped with `network` that arrived at `cycles`
+    pub fn synchronise(&mut self, cycles: u32, network: u64) -> i64 {
+        let local = self.local.micros(cycles);
+        self.sync.update(local, network + AIR_TIME_US)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_clock_carries_on_past_the_wrap() {
+        let mut clock = LocalClock::new();
+        assert_eq!(clock.ticks(0x6000_0000), 0x6000_0000);
+        assert_eq!(clock.ticks(0xC000_0000), 0xC000_0000);
+        assert_eq!(clock.ticks(10), 0x1_0000_000A);
+        // A reading from a preempted task
+        assert_eq!(clock.ticks(u32::MAX), 0xFFFF_FFFF);
+        assert_eq!(clock.ticks(20), 0x1_0000_0014);
+    }
+
+    #[test]
+    fn readings_from_before_the_start_stay_at_zero() {
+        let mut clock = LocalClock::new();
+        assert_eq!(clock.ticks(u32::MAX - 99), 0);
+        assert_eq!(clock.ticks(100), 100);
+        assert_eq!(clock.ticks(50), 50);
+    }
+
+    #[test]
+    fn far_off_beacons_start_over() {
+        let mut sync = ClockSync::new();
+        assert_eq!(sync.update(1_000, 5_000_000), 0);
+        assert_eq!(sync.network_time(2_000), Some(5_001_000));
+        assert_eq!(sync.update(1_001_000, 6_000_100), 100);
+        // 25 us of drift fitted from the last beacon
+        assert_eq!(sync.update(2_001_000, 100), 100 - 7_000_075);
+        assert_eq!(sync.drift(), 0);
+        assert_eq!(sync.network_time(2_002_000), Some(1_100));
+    }
+}
@@ -0,0 +1,276 @@
+//! The bluepill's USB port as a serial port, carrying the log and commands.
+//!
+//! Every app shows up as a CDC-ACM device on PA11 (D-) and PA12 (D+), with
+//! the log and a prompt for commands, so nothing needs a debugger attached.
+//! [`log!`] writes to a buffer that the USB interrupt empties into the port
+//! while it's open, and keeps the last [`LOG_SIZE`] bytes until it is.
+//!
+//! The bluepill pulls D+ up to 3.3 V for good, so the host doesn't notice
+//! the device restart. [`UsbSerial::new`] holds D+ low for a moment first to
+//! make it enumerate again. Many boards also fit 10k for that pull-up
+//! (R10) instead of the 1.5k USB asks for, which some hosts won't take:
+//! replace it, or add 1.8k from PA12 to 3.3 V.
+//!
+//! `tx` also works as a dongle, with [`set_framed`] switching the port over
+//! to [`dongle`](crate::dongle) packets until the host closes it. The log
+//! then goes out in text packets, and lines come in as them.
+//!
+//! USB needs the PLL at 48 or 72 MHz from the 8 MHz crystal, see
+//! [`CRYSTAL`].
+//!
+//! [`log!`]: crate::log
+
+use crate::cli::Line;
+use crate::dongle::{Deframer, Packet, MAX_FRAMED_SIZE, MAX_TEXT};
+use crate::protocol::DecodeError;
+use core::cell::RefCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::NVIC;
+use cortex_m::singleton;
+use stm32f1xx_hal::{
+    gpio::{
+        gpioa::{CRH, PA11, PA12},
+        Floating, Input,
+    },
+    pac::{Interrupt, USB},
+    prelude::*,
+    rcc::Clocks,
+    time::MegaHertz,
+    usb::{Peripheral, UsbBus, UsbBusType},
+};
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+/// The bluepill's crystal, which USB has to run from
+pub const CRYSTAL: MegaHertz = MegaHertz(8);
+/// Log kept while nobody reads it
+pub const LOG_SIZE: usize = 1024;
+/// Shared by pid.codes for CDC-ACM test devices
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+/// How long D+ is held low to make the host enumerate us again, in ms
+const RESET_TIME: u32 = 10;
+
+/// Set once the USB interrupt can empty the log
+static READY: AtomicBool = AtomicBool::new(false);
+/// Set while the port carries packets instead of text
+static FRAMED: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring::new()));
+
+/// Write a line to the log, like `println!`
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write as _;
+        writeln!($crate::usb::Log, $($arg)*).ok();
+    }};
+}
+
+/// The log as a [`fmt::Write`], see [`log!`](crate::log)
+pub struct Log;
+
+impl fmt::Write for Log {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        if !is_framed() {
+            push(s.as_bytes());
+            return Ok(());
+        }
+        while !s.is_empty() {
+            let mut end = s.len().min(MAX_TEXT);
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (text, rest) = s.split_at(end);
+            send(&Packet::Text(text));
+            s = rest;
+        }
+        Ok(())
+    }
+}
+
+/// Send a packet to the host, if the port is framed
+pub fn send(packet: &Packet) {
+    if is_framed() {
+        let mut framed = [0u8; MAX_FRAMED_SIZE];
+        let len = packet.frame(&mut framed);
+        push(&framed[..len]);
+    }
+}
+
+/// Switch the port between packets and text, until the host closes it
+pub fn set_framed(framed: bool) {
+    FRAMED.store(framed, Ordering::Relaxed);
+}
+
+/// Whether the port carries packets
+pub fn is_framed() -> bool {
+    FRAMED.load(Ordering::Relaxed)
+}
+
+/// Queue bytes for the host, all of them in one go
+fn push(data: &[u8]) {
+    interrupt::free(|cs| LOG.borrow(cs).borrow_mut().push(data));
+    if READY.load(Ordering::Relaxed) {
+        NVIC::pend(Interrupt::USB_LP_CAN_RX0);
+    }
+}
+
+/// What came in from the host
+pub enum Input<'a> {
+    /// A command line, typed or in a text packet
+    Line(Line),
+    /// Any other packet
+    Packet(Packet<'a>),
+    /// A packet that didn't decode
+    Invalid(DecodeError),
+}
+
+/// Bytes waiting to be sent, the oldest dropped when it's full
+struct Ring {
+    buf: [u8; LOG_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.buf[(self.start + self.len) % LOG_SIZE] = byte;
+            if self.len < LOG_SIZE {
+                self.len += 1;
+            } else {
+                self.start = (self.start + 1) % LOG_SIZE;
+            }
+        }
+    }
+
+    /// The oldest bytes that are next to each other
+    fn front(&self) -> &[u8] {
+        let end = (self.start + self.len).min(LOG_SIZE);
+        &self.buf[self.start..end]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start = (self.start + n) % LOG_SIZE;
+        self.len -= n;
+    }
+}
+
+/// The USB serial port. Poll it from both USB interrupts.
+pub struct UsbSerial {
+    device: UsbDevice<'static, UsbBusType>,
+    serial: SerialPort<'static, UsbBusType>,
+    line: Line,
+    deframer: Deframer,
+}
+
+impl UsbSerial {
+    /// Take over the USB port, calling the device `product`. Only one can be
+    /// made.
+    pub fn new(
+        usb: USB,
+        dm: PA11<Input<Floating>>,
+        dp: PA12<Input<Floating>>,
+        crh: &mut CRH,
+        clocks: &Clocks,
+        product: &'static str,
+    ) -> Self {
+        assert!(clocks.usbclk_valid(), "USB needs the PLL at 48 or 72 MHz from the crystal");
+
+        // Look like we were unplugged, so the host enumerates us again
+        let mut dp = dp.into_push_pull_output(crh);
+        dp.set_low().ok();
+        cortex_m::asm::delay(clocks.sysclk().0 / 1_000 * RESET_TIME);
+        let dp = dp.into_floating_input(crh);
+
+        let bus: &'static UsbBusAllocator<UsbBusType> = singleton!(: UsbBusAllocator<UsbBusType> = UsbBus::new(Peripheral {
+            usb,
+            pin_dm: dm,
+            pin_dp: dp,
+        }))
+        .expect("only one USB port");
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, VID_PID)
+            .manufacturer("flash")
+            .product(product)
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        READY.store(true, Ordering::Relaxed);
+        Self {
+            device,
+            serial,
+            line: Line::new(),
+            deframer: Deframer::new(),
+        }
+    }
+
+    /// Answer the host and send what's in the log
+    pub fn poll(&mut self) {
+        self.device.poll(&mut [&mut self.serial]);
+        if self.device.state() != UsbDeviceState::Configured {
+            return;
+        }
+        // Closing the port ends dongle mode
+        if !self.serial.dtr() {
+            set_framed(false);
+        }
+        let serial = &mut self.serial;
+        interrupt::free(|cs| {
+            let mut log = LOG.borrow(cs).borrow_mut();
+            while log.len > 0 {
+                match serial.write(log.front()) {
+                    Ok(n) if n > 0 => log.consume(n),
+                    // The host isn't reading, try again on the next poll
+                    _ => break,
+                }
+            }
+        });
+    }
+
+    /// The next command line the host sent, if a whole one came in. Call
+    /// after [`poll`](Self::poll) until it returns `None`, and answer with
+    /// [`log!`](crate::log).
+    pub fn read_line(&mut self) -> Option<Line> {
+        loop {
+            match self.read()? {
+                Input::Line(line) => return Some(line),
+                Input::Packet(_) => log!("error: expected a command line"),
+                Input::Invalid(e) => log!("error: dropped a packet, {:?}", e),
+            }
+        }
+    }
+
+    /// Like [`read_line`](Self::read_line), taking packets too while the
+    /// port is framed
+    pub fn read(&mut self) -> Option<Input<'_>> {
+        let mut byte = [0u8; 1];
+        let len = loop {
+            match self.serial.read(&mut byte) {
+                Ok(1) => {}
+                _ => return None,
+            }
+            if !is_framed() {
+                if let Some(line) = self.line.push(byte[0]) {
+                    return Some(Input::Line(line));
+                }
+            } else if let Some(len) = self.deframer.push(byte[0]) {
+                break len;
+            }
+        };
+        Some(match Packet::decode(self.deframer.packet(len)) {
+            Ok(Packet::Text(text)) => Input::Line(Line::from(text)),
+            Ok(packet) => Input::Packet(packet),
+            Err(e) => Input::Invalid(e),
+        })
+    }
+}